@@ -20,7 +20,9 @@ use crate::{
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum UdpTracker {
     Aquatic,
+    AquaticNoBatching,
     AquaticIoUring,
+    AquaticSwarmWorkers,
     OpenTracker,
     Chihaya,
     TorrustTracker,
@@ -30,7 +32,9 @@ impl Tracker for UdpTracker {
     fn name(&self) -> String {
         match self {
             Self::Aquatic => "aquatic_udp".into(),
+            Self::AquaticNoBatching => "aquatic_udp (no batching)".into(),
             Self::AquaticIoUring => "aquatic_udp (io_uring)".into(),
+            Self::AquaticSwarmWorkers => "aquatic_udp (swarm workers)".into(),
             Self::OpenTracker => "opentracker".into(),
             Self::Chihaya => "chihaya".into(),
             Self::TorrustTracker => "torrust-tracker".into(),
@@ -70,6 +74,9 @@ impl UdpCommand {
                         // virtual machine
                         AquaticUdpRunner::with_mio(2, Priority::Low),
                     ],
+                    UdpTracker::AquaticNoBatching => vec![
+                        AquaticUdpRunner::with_mio_without_batching(1, Priority::Medium),
+                    ],
                     UdpTracker::AquaticIoUring => vec![
                         AquaticUdpRunner::with_io_uring(1, Priority::High),
                         AquaticUdpRunner::with_io_uring(2, Priority::Low),
@@ -97,10 +104,16 @@ impl UdpCommand {
                         AquaticUdpRunner::with_mio(2, Priority::High),
                         AquaticUdpRunner::with_mio(4, Priority::Low),
                     ],
+                    UdpTracker::AquaticNoBatching => vec![
+                        AquaticUdpRunner::with_mio_without_batching(2, Priority::Medium),
+                    ],
                     UdpTracker::AquaticIoUring => vec![
                         AquaticUdpRunner::with_io_uring(2, Priority::High),
                         AquaticUdpRunner::with_io_uring(4, Priority::Low),
                     ],
+                    UdpTracker::AquaticSwarmWorkers => vec![
+                        AquaticUdpRunner::with_swarm_workers(1, 1, Priority::Medium),
+                    ],
                     UdpTracker::OpenTracker => vec![
                         OpenTrackerUdpRunner::new(2, Priority::High),
                         OpenTrackerUdpRunner::new(4, Priority::Low),
@@ -123,10 +136,16 @@ impl UdpCommand {
                         AquaticUdpRunner::with_mio(4, Priority::High),
                         AquaticUdpRunner::with_mio(8, Priority::Low),
                     ],
+                    UdpTracker::AquaticNoBatching => vec![
+                        AquaticUdpRunner::with_mio_without_batching(4, Priority::Medium),
+                    ],
                     UdpTracker::AquaticIoUring => vec![
                         AquaticUdpRunner::with_io_uring(4, Priority::High),
                         AquaticUdpRunner::with_io_uring(8, Priority::Low),
                     ],
+                    UdpTracker::AquaticSwarmWorkers => vec![
+                        AquaticUdpRunner::with_swarm_workers(2, 2, Priority::Medium),
+                    ],
                     UdpTracker::OpenTracker => vec![
                         OpenTrackerUdpRunner::new(4, Priority::High),
                         OpenTrackerUdpRunner::new(8, Priority::Low),
@@ -149,10 +168,16 @@ impl UdpCommand {
                         AquaticUdpRunner::with_mio(6, Priority::High),
                         AquaticUdpRunner::with_mio(12, Priority::Low),
                     ],
+                    UdpTracker::AquaticNoBatching => vec![
+                        AquaticUdpRunner::with_mio_without_batching(6, Priority::Medium),
+                    ],
                     UdpTracker::AquaticIoUring => vec![
                         AquaticUdpRunner::with_io_uring(6, Priority::High),
                         AquaticUdpRunner::with_io_uring(12, Priority::Low),
                     ],
+                    UdpTracker::AquaticSwarmWorkers => vec![
+                        AquaticUdpRunner::with_swarm_workers(3, 3, Priority::Medium),
+                    ],
                     UdpTracker::OpenTracker => vec![
                         OpenTrackerUdpRunner::new(6, Priority::High),
                         OpenTrackerUdpRunner::new(12, Priority::Low),
@@ -175,10 +200,16 @@ impl UdpCommand {
                         AquaticUdpRunner::with_mio(8, Priority::High),
                         AquaticUdpRunner::with_mio(16, Priority::Low),
                     ],
+                    UdpTracker::AquaticNoBatching => vec![
+                        AquaticUdpRunner::with_mio_without_batching(8, Priority::Medium),
+                    ],
                     UdpTracker::AquaticIoUring => vec![
                         AquaticUdpRunner::with_io_uring(8, Priority::High),
                         AquaticUdpRunner::with_io_uring(16, Priority::Low),
                     ],
+                    UdpTracker::AquaticSwarmWorkers => vec![
+                        AquaticUdpRunner::with_swarm_workers(4, 4, Priority::Medium),
+                    ],
                     UdpTracker::OpenTracker => vec![
                         OpenTrackerUdpRunner::new(8, Priority::High),
                         OpenTrackerUdpRunner::new(16, Priority::Low),
@@ -201,10 +232,16 @@ impl UdpCommand {
                         AquaticUdpRunner::with_mio(12, Priority::High),
                         AquaticUdpRunner::with_mio(24, Priority::Low),
                     ],
+                    UdpTracker::AquaticNoBatching => vec![
+                        AquaticUdpRunner::with_mio_without_batching(12, Priority::Medium),
+                    ],
                     UdpTracker::AquaticIoUring => vec![
                         AquaticUdpRunner::with_io_uring(12, Priority::High),
                         AquaticUdpRunner::with_io_uring(24, Priority::Low),
                     ],
+                    UdpTracker::AquaticSwarmWorkers => vec![
+                        AquaticUdpRunner::with_swarm_workers(6, 6, Priority::Medium),
+                    ],
                     UdpTracker::OpenTracker => vec![
                         OpenTrackerUdpRunner::new(12, Priority::High),
                         OpenTrackerUdpRunner::new(24, Priority::Low),
@@ -227,10 +264,16 @@ impl UdpCommand {
                         AquaticUdpRunner::with_mio(16, Priority::High),
                         AquaticUdpRunner::with_mio(32, Priority::Low),
                     ],
+                    UdpTracker::AquaticNoBatching => vec![
+                        AquaticUdpRunner::with_mio_without_batching(16, Priority::Medium),
+                    ],
                     UdpTracker::AquaticIoUring => vec![
                         AquaticUdpRunner::with_io_uring(16, Priority::High),
                         AquaticUdpRunner::with_io_uring(32, Priority::Low),
                     ],
+                    UdpTracker::AquaticSwarmWorkers => vec![
+                        AquaticUdpRunner::with_swarm_workers(8, 8, Priority::Medium),
+                    ],
                     UdpTracker::OpenTracker => vec![
                         OpenTrackerUdpRunner::new(16, Priority::High),
                         OpenTrackerUdpRunner::new(32, Priority::Low),
@@ -260,7 +303,9 @@ impl UdpCommand {
 #[derive(Debug, Clone)]
 struct AquaticUdpRunner {
     socket_workers: usize,
+    swarm_workers: usize,
     use_io_uring: bool,
+    batch_size: usize,
     priority: Priority,
 }
 
@@ -271,7 +316,21 @@ impl AquaticUdpRunner {
     ) -> Rc<dyn ProcessRunner<Command = UdpCommand>> {
         Rc::new(Self {
             socket_workers,
+            swarm_workers: 0,
+            use_io_uring: false,
+            batch_size: aquatic_udp::config::NetworkConfig::default().batch_size,
+            priority,
+        })
+    }
+    fn with_mio_without_batching(
+        socket_workers: usize,
+        priority: Priority,
+    ) -> Rc<dyn ProcessRunner<Command = UdpCommand>> {
+        Rc::new(Self {
+            socket_workers,
+            swarm_workers: 0,
             use_io_uring: false,
+            batch_size: 1,
             priority,
         })
     }
@@ -281,7 +340,22 @@ impl AquaticUdpRunner {
     ) -> Rc<dyn ProcessRunner<Command = UdpCommand>> {
         Rc::new(Self {
             socket_workers,
+            swarm_workers: 0,
             use_io_uring: true,
+            batch_size: 1,
+            priority,
+        })
+    }
+    fn with_swarm_workers(
+        socket_workers: usize,
+        swarm_workers: usize,
+        priority: Priority,
+    ) -> Rc<dyn ProcessRunner<Command = UdpCommand>> {
+        Rc::new(Self {
+            socket_workers,
+            swarm_workers,
+            use_io_uring: false,
+            batch_size: aquatic_udp::config::NetworkConfig::default().batch_size,
             priority,
         })
     }
@@ -300,9 +374,11 @@ impl ProcessRunner for AquaticUdpRunner {
         let mut c = aquatic_udp::config::Config::default();
 
         c.socket_workers = self.socket_workers;
+        c.swarm_workers = self.swarm_workers;
         c.network.address_ipv4 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3000);
         c.network.use_ipv6 = false;
         c.network.use_io_uring = self.use_io_uring;
+        c.network.batch_size = self.batch_size;
         c.protocol.max_response_peers = 30;
 
         let c = toml::to_string_pretty(&c)?;
@@ -325,9 +401,15 @@ impl ProcessRunner for AquaticUdpRunner {
     }
 
     fn keys(&self) -> IndexMap<String, String> {
-        indexmap! {
+        let mut keys = indexmap! {
             "socket workers".to_string() => self.socket_workers.to_string(),
+        };
+
+        if self.swarm_workers > 0 {
+            keys.insert("swarm workers".to_string(), self.swarm_workers.to_string());
         }
+
+        keys
     }
 }
 
@@ -0,0 +1,151 @@
+//! Announce intervals scaled by swarm size and tracker load
+
+use aquatic_toml_config::TomlConfig;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnnounceIntervalConfig {
+    /// Scale announce interval by swarm size and tracker load
+    ///
+    /// If disabled, the fixed announce interval from the protocol settings
+    /// is always used.
+    pub dynamic: bool,
+    /// Announce interval for swarms with at most `small_swarm_peers` peers
+    /// (seconds)
+    pub min_interval: u32,
+    /// Announce interval for swarms with at least `large_swarm_peers` peers
+    /// (seconds)
+    ///
+    /// Intervals are never scaled beyond this value. It needs to be lower
+    /// than the maximum peer age, or peers would be removed before their
+    /// next announce.
+    pub max_interval: u32,
+    /// Swarms with at most this many peers get `min_interval`
+    pub small_swarm_peers: usize,
+    /// Swarms with at least this many peers get `max_interval`
+    ///
+    /// For swarm sizes in between, the interval is interpolated on a
+    /// logarithmic scale, so that it is multiplied by the same factor each
+    /// time the number of peers grows tenfold.
+    pub large_swarm_peers: usize,
+    /// When the tracker stores more peers than this per IP version,
+    /// multiply intervals by the number of stored peers divided by this
+    /// value (0 = don't scale by load)
+    ///
+    /// Stored peers are counted when cleaning torrents.
+    pub high_load_peers: usize,
+}
+
+impl AnnounceIntervalConfig {
+    /// Return error if peers announcing at the maximum interval would be
+    /// removed before their next announce
+    pub fn validate(&self, max_peer_age: u32) -> anyhow::Result<()> {
+        if self.dynamic && self.max_interval.max(self.min_interval.max(1)) >= max_peer_age {
+            return Err(anyhow::anyhow!(
+                "configuration: announce_interval.max_interval and announce_interval.min_interval must be lower than cleaning.max_peer_age"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Announce interval in seconds for swarm with `swarm_peers` peers,
+    /// given that the tracker stores `tracker_peers` peers
+    ///
+    /// Returns `fixed_interval` if dynamic intervals are disabled.
+    pub fn interval(&self, fixed_interval: u64, swarm_peers: usize, tracker_peers: usize) -> u64 {
+        if !self.dynamic {
+            return fixed_interval;
+        }
+
+        let min_interval = f64::from(self.min_interval.max(1));
+        let max_interval = f64::from(self.max_interval).max(min_interval);
+
+        let swarm_interval = if swarm_peers <= self.small_swarm_peers {
+            min_interval
+        } else if swarm_peers >= self.large_swarm_peers {
+            max_interval
+        } else {
+            // Add one to handle small_swarm_peers being zero
+            let position = ((swarm_peers + 1) as f64 / (self.small_swarm_peers + 1) as f64).ln()
+                / ((self.large_swarm_peers + 1) as f64 / (self.small_swarm_peers + 1) as f64).ln();
+
+            min_interval * (max_interval / min_interval).powf(position)
+        };
+
+        let load_factor = if self.high_load_peers != 0 && tracker_peers > self.high_load_peers {
+            tracker_peers as f64 / self.high_load_peers as f64
+        } else {
+            1.0
+        };
+
+        (swarm_interval * load_factor)
+            .round()
+            .max(min_interval)
+            .min(max_interval) as u64
+    }
+}
+
+impl Default for AnnounceIntervalConfig {
+    fn default() -> Self {
+        Self {
+            dynamic: false,
+            min_interval: 60 * 5,
+            max_interval: 60 * 15,
+            small_swarm_peers: 10,
+            large_swarm_peers: 10_000,
+            high_load_peers: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval() {
+        let mut config = AnnounceIntervalConfig {
+            dynamic: false,
+            min_interval: 100,
+            max_interval: 800,
+            small_swarm_peers: 9,
+            large_swarm_peers: 9_999,
+            high_load_peers: 0,
+        };
+
+        assert_eq!(config.interval(900, 100, 0), 900);
+
+        config.dynamic = true;
+
+        assert_eq!(config.interval(900, 0, 0), 100);
+        assert_eq!(config.interval(900, 9, 0), 100);
+        assert_eq!(config.interval(900, 99, 0), 200);
+        assert_eq!(config.interval(900, 999, 0), 400);
+        assert_eq!(config.interval(900, 9_999, 0), 800);
+        assert_eq!(config.interval(900, 1_000_000, 0), 800);
+
+        config.high_load_peers = 1_000;
+
+        assert_eq!(config.interval(900, 99, 1_000), 200);
+        assert_eq!(config.interval(900, 99, 3_000), 600);
+        assert_eq!(config.interval(900, 99, 10_000), 800);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = AnnounceIntervalConfig::default();
+
+        assert!(config.validate(config.max_interval).is_ok());
+
+        config.dynamic = true;
+
+        assert!(config.validate(config.max_interval + 1).is_ok());
+        assert!(config.validate(config.max_interval).is_err());
+
+        config.max_interval = 0;
+
+        assert!(config.validate(config.min_interval).is_err());
+    }
+}
@@ -4,9 +4,7 @@ use std::io::Read;
 use anyhow::Context;
 use aquatic_toml_config::TomlConfig;
 use git_testament::{git_testament, CommitKind};
-use log::LevelFilter;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use simplelog::{ColorChoice, TermLogger, TerminalMode, ThreadLogMode};
 
 /// Log level. Available values are off, error, warn, info, debug and trace.
 #[derive(Debug, Clone, Copy, PartialEq, TomlConfig, Serialize, Deserialize)]
@@ -26,10 +24,28 @@ impl Default for LogLevel {
     }
 }
 
+/// Log format. Available values are text and json.
+#[derive(Debug, Default, Clone, Copy, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with structured fields
+    Json,
+}
+
 pub trait Config: Default + TomlConfig + DeserializeOwned + std::fmt::Debug {
     fn get_log_level(&self) -> Option<LogLevel> {
         None
     }
+    fn get_log_format(&self) -> LogFormat {
+        LogFormat::Text
+    }
+    /// Comma-separated `module=level` pairs overriding log level
+    fn get_log_module_levels(&self) -> &str {
+        ""
+    }
 }
 
 #[derive(Debug, Default)]
@@ -151,7 +167,11 @@ where
         };
 
         if let Some(log_level) = config.get_log_level() {
-            start_logger(log_level)?;
+            crate::logging::start_logger(
+                log_level,
+                config.get_log_format(),
+                config.get_log_module_levels(),
+            )?;
         }
 
         if options.print_parsed_config {
@@ -202,40 +222,6 @@ where
     <T as TomlConfig>::default_to_string()
 }
 
-fn start_logger(log_level: LogLevel) -> ::anyhow::Result<()> {
-    let mut builder = simplelog::ConfigBuilder::new();
-
-    builder
-        .set_thread_mode(ThreadLogMode::Both)
-        .set_thread_level(LevelFilter::Error)
-        .set_target_level(LevelFilter::Error)
-        .set_location_level(LevelFilter::Off);
-
-    let config = match builder.set_time_offset_to_local() {
-        Ok(builder) => builder.build(),
-        Err(builder) => builder.build(),
-    };
-
-    let level_filter = match log_level {
-        LogLevel::Off => LevelFilter::Off,
-        LogLevel::Error => LevelFilter::Error,
-        LogLevel::Warn => LevelFilter::Warn,
-        LogLevel::Info => LevelFilter::Info,
-        LogLevel::Debug => LevelFilter::Debug,
-        LogLevel::Trace => LevelFilter::Trace,
-    };
-
-    TermLogger::init(
-        level_filter,
-        config,
-        TerminalMode::Stderr,
-        ColorChoice::Auto,
-    )
-    .context("Couldn't initialize logger")?;
-
-    Ok(())
-}
-
 fn get_commit_info() -> String {
     git_testament!(TESTAMENT);
 
@@ -0,0 +1,203 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use aquatic_peer_id::{PeerClient, PeerId};
+use aquatic_toml_config::TomlConfig;
+use arc_swap::ArcSwap;
+use hashbrown::HashSet;
+use serde::{Deserialize, Serialize};
+
+/// Client filter mode. Available modes are allow, deny and off.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientFilterMode {
+    /// Only accept announces from clients matching an entry in file
+    Allow,
+    /// Reject announces from clients matching an entry in file
+    Deny,
+    /// Turn off client filter functionality
+    Off,
+}
+
+impl ClientFilterMode {
+    pub fn is_on(&self) -> bool {
+        !matches!(self, Self::Off)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientFilterConfig {
+    pub mode: ClientFilterMode,
+    /// Path to client filter file with one entry per line
+    ///
+    /// Entries are either `prefix:` followed by the start of peer ids (e.g.
+    /// `prefix:-XL0`) or `client:` followed by a client name as identified
+    /// by aquatic_peer_id (e.g. `client:Xunlei`). Empty lines and lines
+    /// starting with `#` are ignored.
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub path: PathBuf,
+}
+
+impl Default for ClientFilterConfig {
+    fn default() -> Self {
+        Self {
+            mode: ClientFilterMode::Off,
+            path: "./client-filter.txt".into(),
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct ClientFilter {
+    prefixes: Vec<Vec<u8>>,
+    client_names: HashSet<String>,
+}
+
+impl ClientFilter {
+    pub fn insert_from_line(&mut self, line: &str) -> anyhow::Result<()> {
+        if let Some(prefix) = line.strip_prefix("prefix:") {
+            if prefix.is_empty() || prefix.len() > 20 {
+                return Err(anyhow::anyhow!("prefix must be 1-20 bytes long"));
+            }
+
+            self.prefixes.push(prefix.as_bytes().to_vec());
+        } else if let Some(name) = line.strip_prefix("client:") {
+            self.client_names.insert(name.trim().to_string());
+        } else {
+            return Err(anyhow::anyhow!("expected 'prefix:' or 'client:'"));
+        }
+
+        Ok(())
+    }
+
+    pub fn create_from_path(path: &PathBuf) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+
+        let mut new_filter = Self::default();
+
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            new_filter
+                .insert_from_line(line)
+                .with_context(|| format!("Invalid line in client filter: {}", line))?;
+        }
+
+        Ok(new_filter)
+    }
+
+    pub fn allows(&self, mode: ClientFilterMode, peer_id: &[u8; 20]) -> bool {
+        match mode {
+            ClientFilterMode::Allow => self.matches(peer_id),
+            ClientFilterMode::Deny => !self.matches(peer_id),
+            ClientFilterMode::Off => true,
+        }
+    }
+
+    fn matches(&self, peer_id: &[u8; 20]) -> bool {
+        if self
+            .prefixes
+            .iter()
+            .any(|prefix| peer_id.starts_with(prefix))
+        {
+            return true;
+        }
+
+        // Only identify client if needed
+        !self.client_names.is_empty()
+            && matches!(
+                PeerId(*peer_id).client(),
+                PeerClient::Known { name, .. } if self.client_names.contains(name.as_str())
+            )
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.prefixes.len() + self.client_names.len()
+    }
+}
+
+pub type ClientFilterArcSwap = ArcSwap<ClientFilter>;
+
+pub fn update_client_filter(
+    config: &ClientFilterConfig,
+    client_filter: &Arc<ClientFilterArcSwap>,
+) -> anyhow::Result<()> {
+    if config.mode.is_on() {
+        match ClientFilter::create_from_path(&config.path) {
+            Ok(new_filter) => {
+                client_filter.store(Arc::new(new_filter));
+
+                ::log::info!("Client filter updated")
+            }
+            Err(err) => {
+                ::log::error!("Updating client filter failed: {:#}", err);
+
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Label for metrics on rejected announces. Only names of known clients are
+/// used, to keep the number of label values bounded.
+pub fn rejected_client_label(peer_id: &[u8; 20]) -> String {
+    match PeerId(*peer_id).client() {
+        PeerClient::Known { name, .. } => name.to_string(),
+        _ => "Other".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_id(bytes: &[u8]) -> [u8; 20] {
+        let mut peer_id = [0; 20];
+
+        peer_id[..bytes.len()].copy_from_slice(bytes);
+
+        peer_id
+    }
+
+    #[test]
+    fn test_client_filter() {
+        let mut filter = ClientFilter::default();
+
+        filter.insert_from_line("prefix:-XL0").unwrap();
+        filter.insert_from_line("client:Transmission").unwrap();
+
+        assert!(filter.insert_from_line("-XL0").is_err());
+        assert!(filter.insert_from_line("prefix:").is_err());
+
+        let xunlei = peer_id(b"-XL0012-abcdefgh");
+        let transmission = peer_id(b"-TR2940-abcdefgh");
+        let qbittorrent = peer_id(b"-qB4250-abcdefgh");
+
+        assert!(!filter.allows(ClientFilterMode::Deny, &xunlei));
+        assert!(!filter.allows(ClientFilterMode::Deny, &transmission));
+        assert!(filter.allows(ClientFilterMode::Deny, &qbittorrent));
+
+        assert!(filter.allows(ClientFilterMode::Allow, &xunlei));
+        assert!(filter.allows(ClientFilterMode::Allow, &transmission));
+        assert!(!filter.allows(ClientFilterMode::Allow, &qbittorrent));
+
+        assert!(filter.allows(ClientFilterMode::Off, &xunlei));
+
+        assert_eq!(rejected_client_label(&transmission), "Transmission");
+        assert_eq!(rejected_client_label(&peer_id(b"abc")), "Other");
+    }
+}
@@ -1,22 +1,42 @@
 use std::fmt::Display;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::time::Instant;
 
 use ahash::RandomState;
 
 pub mod access_list;
+pub mod announce_interval;
 pub mod cli;
+#[cfg(feature = "client-filter")]
+pub mod client_filter;
 #[cfg(feature = "cpu-pinning")]
 pub mod cpu_pinning;
+pub mod logging;
+#[cfg(feature = "open-loop")]
+pub mod open_loop;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod peer_ip_limits;
+#[cfg(feature = "peer-lifecycle")]
+pub mod peer_lifecycle;
+pub mod peer_selection;
 pub mod privileges;
+#[cfg(any(feature = "otlp", feature = "statsd"))]
+pub mod push_recorder;
 #[cfg(feature = "rustls")]
 pub mod rustls_config;
+#[cfg(feature = "statistics")]
+pub mod statistics;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+pub mod torrent_limits;
 
 /// IndexMap using AHash hasher
 pub type IndexMap<K, V> = indexmap::IndexMap<K, V, RandomState>;
 
 /// Peer, connection or similar valid until this instant
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ValidUntil(SecondsSinceServerStart);
 
 impl ValidUntil {
@@ -34,6 +54,23 @@ impl ValidUntil {
     }
 }
 
+/// ValidUntil that can be updated through a shared reference
+#[derive(Debug)]
+pub struct AtomicValidUntil(AtomicU32);
+
+impl AtomicValidUntil {
+    pub fn new(valid_until: ValidUntil) -> Self {
+        Self(AtomicU32::new(valid_until.0 .0))
+    }
+    pub fn load(&self) -> ValidUntil {
+        ValidUntil(SecondsSinceServerStart(self.0.load(Ordering::Relaxed)))
+    }
+    /// Store value unless current value is later
+    pub fn store_max(&self, valid_until: ValidUntil) {
+        self.0.fetch_max(valid_until.0 .0, Ordering::Relaxed);
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct ServerStartInstant(Instant);
 
@@ -53,7 +90,7 @@ impl ServerStartInstant {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct SecondsSinceServerStart(u32);
 
 /// SocketAddr that is not an IPv6-mapped IPv4 address
@@ -108,6 +145,9 @@ pub fn spawn_prometheus_endpoint(
     addr: SocketAddr,
     timeout: Option<::std::time::Duration>,
     timeout_mask: Option<metrics_util::MetricKindMask>,
+    #[cfg(any(feature = "otlp", feature = "statsd"))] push_recorders: Vec<
+        push_recorder::PushRecorder,
+    >,
 ) -> anyhow::Result<::std::thread::JoinHandle<anyhow::Result<()>>> {
     use std::thread::Builder;
     use std::time::Duration;
@@ -136,6 +176,17 @@ pub fn spawn_prometheus_endpoint(
 
                 let recorder_handle = recorder.handle();
 
+                // Also record metrics for exporters pushing them elsewhere
+                #[cfg(any(feature = "otlp", feature = "statsd"))]
+                ::metrics::set_global_recorder(
+                    push_recorder::add_to_fanout(
+                        metrics_util::layers::FanoutBuilder::default().add_recorder(recorder),
+                        push_recorders,
+                    )
+                    .build(),
+                )
+                .map_err(|err| anyhow::anyhow!("set global metrics recorder: {:#}", err))?;
+                #[cfg(not(any(feature = "otlp", feature = "statsd")))]
                 ::metrics::set_global_recorder(recorder).context("set global metrics recorder")?;
 
                 ::tokio::spawn(async move {
@@ -168,6 +219,10 @@ pub enum WorkerType {
     Cleaning,
     #[cfg(feature = "prometheus")]
     Prometheus,
+    #[cfg(feature = "otlp")]
+    Otlp,
+    #[cfg(feature = "statsd")]
+    Statsd,
 }
 
 impl Display for WorkerType {
@@ -180,6 +235,10 @@ impl Display for WorkerType {
             Self::Cleaning => f.write_str("Cleaning worker"),
             #[cfg(feature = "prometheus")]
             Self::Prometheus => f.write_str("Prometheus worker"),
+            #[cfg(feature = "otlp")]
+            Self::Otlp => f.write_str("OTLP exporter"),
+            #[cfg(feature = "statsd")]
+            Self::Statsd => f.write_str("StatsD exporter"),
         }
     }
 }
@@ -0,0 +1,318 @@
+//! Logger with per-module level filters and optional JSON output
+//!
+//! Key-value pairs passed to the `log` macros (e.g.
+//! `::log::warn!(peer_addr:% = addr; "sending response failed")`) are
+//! emitted as fields in JSON output and appended to the message in text
+//! output. Records logged from worker threads additionally get the worker
+//! type and index as fields in JSON output.
+
+use std::cell::Cell;
+use std::fmt::{Display, Write as _};
+use std::io::Write;
+
+use anyhow::Context;
+use log::kv::{Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value as JsonValue};
+use simplelog::{ColorChoice, TermLogger, TerminalMode, ThreadLogMode};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::cli::{LogFormat, LogLevel};
+
+thread_local! {
+    static WORKER: Cell<Option<(&'static str, usize)>> = const { Cell::new(None) };
+}
+
+/// Include worker type and index in records logged from the current thread
+pub fn set_worker(worker_type: &'static str, worker_index: usize) {
+    WORKER.with(|worker| worker.set(Some((worker_type, worker_index))));
+}
+
+/// Display first four bytes of info hash as hex, which is usually enough to
+/// identify a torrent in logs
+pub struct InfoHashPrefix<'a>(pub &'a [u8; 20]);
+
+impl Display for InfoHashPrefix<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in &self.0[..4] {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn start_logger(
+    log_level: LogLevel,
+    log_format: LogFormat,
+    module_levels: &str,
+) -> ::anyhow::Result<()> {
+    let default_level = level_filter(log_level);
+    let module_levels = parse_module_levels(module_levels)?;
+
+    let max_level = module_levels
+        .iter()
+        .map(|(_, level)| *level)
+        .fold(default_level, ::std::cmp::max);
+
+    let output = match log_format {
+        LogFormat::Text => {
+            let mut builder = simplelog::ConfigBuilder::new();
+
+            builder
+                .set_thread_mode(ThreadLogMode::Both)
+                .set_thread_level(LevelFilter::Error)
+                .set_target_level(LevelFilter::Error)
+                .set_location_level(LevelFilter::Off);
+
+            let config = match builder.set_time_offset_to_local() {
+                Ok(builder) => builder.build(),
+                Err(builder) => builder.build(),
+            };
+
+            // Filtering is done before records are passed on
+            Output::Text(TermLogger::new(
+                LevelFilter::Trace,
+                config,
+                TerminalMode::Stderr,
+                ColorChoice::Auto,
+            ))
+        }
+        LogFormat::Json => Output::Json,
+    };
+
+    ::log::set_boxed_logger(Box::new(Logger {
+        default_level,
+        module_levels,
+        output,
+    }))
+    .context("Couldn't initialize logger")?;
+
+    ::log::set_max_level(max_level);
+
+    Ok(())
+}
+
+fn level_filter(log_level: LogLevel) -> LevelFilter {
+    match log_level {
+        LogLevel::Off => LevelFilter::Off,
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    }
+}
+
+/// Parse comma-separated `module=level` pairs
+///
+/// Returned filters are sorted with the most specific modules first.
+fn parse_module_levels(input: &str) -> anyhow::Result<Vec<(String, LevelFilter)>> {
+    let mut module_levels = input
+        .split(',')
+        .map(|pair| pair.trim())
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (module, level) = pair
+                .split_once('=')
+                .with_context(|| format!("log module level {:?} lacks '='", pair))?;
+
+            let level = level
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid log level in {:?}", pair))?;
+
+            Ok((module.trim().to_string(), level))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    module_levels.sort_by_key(|(module, _)| ::std::cmp::Reverse(module.len()));
+
+    Ok(module_levels)
+}
+
+enum Output {
+    Text(Box<TermLogger>),
+    Json,
+}
+
+struct Logger {
+    default_level: LevelFilter,
+    module_levels: Vec<(String, LevelFilter)>,
+    output: Output,
+}
+
+impl Logger {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.module_levels
+            .iter()
+            .find(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default_level)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match &self.output {
+            Output::Text(logger) => {
+                let mut fields = TextFields(String::new());
+
+                let _ = record.key_values().visit(&mut fields);
+
+                if fields.0.is_empty() {
+                    logger.log(record);
+                } else {
+                    logger.log(
+                        &record
+                            .to_builder()
+                            .args(format_args!("{}{}", record.args(), fields.0))
+                            .build(),
+                    );
+                }
+            }
+            Output::Json => {
+                let line = json_line(record, OffsetDateTime::now_utc());
+
+                let _ = writeln!(::std::io::stderr().lock(), "{}", line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Output::Text(logger) = &self.output {
+            logger.flush();
+        }
+    }
+}
+
+struct TextFields(String);
+
+impl<'kvs> VisitSource<'kvs> for TextFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        write!(self.0, " {}={}", key, value).map_err(|_| log::kv::Error::msg("format field"))
+    }
+}
+
+struct JsonFields(Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_bool() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+
+        self.0.insert(key.to_string(), value);
+
+        Ok(())
+    }
+}
+
+fn json_line(record: &Record, now: OffsetDateTime) -> String {
+    let mut fields = Map::new();
+
+    fields.insert(
+        "timestamp".into(),
+        now.format(&Rfc3339).unwrap_or_default().into(),
+    );
+    fields.insert("level".into(), record.level().as_str().into());
+    fields.insert("target".into(), record.target().into());
+
+    if let Some(thread_name) = ::std::thread::current().name() {
+        fields.insert("thread".into(), thread_name.into());
+    }
+    if let Some((worker_type, worker_index)) = WORKER.with(|worker| worker.get()) {
+        fields.insert("worker".into(), worker_type.into());
+        fields.insert("worker_index".into(), worker_index.into());
+    }
+
+    fields.insert("message".into(), record.args().to_string().into());
+
+    let mut fields = JsonFields(fields);
+
+    let _ = record.key_values().visit(&mut fields);
+
+    JsonValue::Object(fields.0).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_module_levels() {
+        let logger = Logger {
+            default_level: LevelFilter::Warn,
+            module_levels: parse_module_levels(
+                "aquatic_udp=info, aquatic_udp::workers::socket=debug,mio=off",
+            )
+            .unwrap(),
+            output: Output::Json,
+        };
+
+        assert_eq!(logger.level_for("aquatic_udp"), LevelFilter::Info);
+        assert_eq!(logger.level_for("aquatic_udp::swarm"), LevelFilter::Info);
+        assert_eq!(
+            logger.level_for("aquatic_udp::workers::socket::mio"),
+            LevelFilter::Debug
+        );
+        assert_eq!(logger.level_for("aquatic_udp_protocol"), LevelFilter::Warn);
+        assert_eq!(logger.level_for("mio::poll"), LevelFilter::Off);
+
+        assert!(parse_module_levels("aquatic_udp").is_err());
+        assert!(parse_module_levels("aquatic_udp=loud").is_err());
+    }
+
+    #[test]
+    fn test_json_line() {
+        let info_hash = InfoHashPrefix(&[0xab; 20]);
+        let kvs: &[(&str, Value)] = &[
+            ("peer_addr", Value::from_display(&"127.0.0.1:1234")),
+            ("info_hash", Value::from_display(&info_hash)),
+            ("num_peers", Value::from(5u64)),
+        ];
+
+        set_worker("socket", 2);
+
+        let line = json_line(
+            &Record::builder()
+                .args(format_args!("sending response failed"))
+                .level(log::Level::Warn)
+                .target("aquatic_udp::workers::socket")
+                .key_values(&kvs)
+                .build(),
+            OffsetDateTime::UNIX_EPOCH,
+        );
+
+        let value: JsonValue = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(value["timestamp"], "1970-01-01T00:00:00Z");
+        assert_eq!(value["level"], "WARN");
+        assert_eq!(value["target"], "aquatic_udp::workers::socket");
+        assert_eq!(value["worker"], "socket");
+        assert_eq!(value["worker_index"], 2);
+        assert_eq!(value["message"], "sending response failed");
+        assert_eq!(value["peer_addr"], "127.0.0.1:1234");
+        assert_eq!(value["info_hash"], "abababab");
+        assert_eq!(value["num_peers"], 5);
+    }
+}
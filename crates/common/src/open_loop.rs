@@ -0,0 +1,148 @@
+//! Open-loop (fixed request rate) mode for load testers
+
+use std::time::Duration;
+
+use aquatic_toml_config::TomlConfig;
+use serde::{Deserialize, Serialize};
+
+/// Open-loop (fixed request rate) mode
+///
+/// By default, new requests are sent as soon as responses come back
+/// (closed-loop mode). In open-loop mode, requests are instead sent according
+/// to a schedule that is independent of responses, and response latency is
+/// measured from the time each request should have been sent. This makes it
+/// possible to see how the tracker behaves when overloaded.
+#[derive(Clone, Debug, Default, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenLoopConfig {
+    /// Target number of requests per second, divided evenly between workers
+    /// (or connections, if requests are scheduled per connection)
+    ///
+    /// 0 = closed-loop mode
+    pub target_requests_per_second: u64,
+    /// Number of requests per second to send at start of run. The rate is
+    /// changed linearly until it reaches `target_requests_per_second` after
+    /// `ramp_duration` seconds.
+    pub ramp_start_requests_per_second: u64,
+    /// Ramp duration in seconds
+    ///
+    /// 0 = send at target rate from start of run
+    pub ramp_duration: u64,
+}
+
+impl OpenLoopConfig {
+    pub fn active(&self) -> bool {
+        self.target_requests_per_second != 0
+    }
+}
+
+/// Request schedule for open-loop mode
+///
+/// The request rate is changed linearly from the start rate to the target
+/// rate during the ramp period and then kept constant.
+#[derive(Clone, Copy, Debug)]
+pub struct RateSchedule {
+    start_rate: f64,
+    target_rate: f64,
+    ramp_duration: f64,
+}
+
+impl RateSchedule {
+    /// Create schedule for one of `num_senders` workers or connections
+    /// sharing the configured rate
+    pub fn new(config: &OpenLoopConfig, num_senders: usize) -> Self {
+        let num_senders = num_senders.max(1) as f64;
+        let target_rate = config.target_requests_per_second as f64 / num_senders;
+
+        let (start_rate, ramp_duration) = if config.ramp_duration == 0 {
+            (target_rate, 0.0)
+        } else {
+            (
+                config.ramp_start_requests_per_second as f64 / num_senders,
+                config.ramp_duration as f64,
+            )
+        };
+
+        Self {
+            start_rate,
+            target_rate,
+            ramp_duration,
+        }
+    }
+
+    /// Number of requests that should have been sent at `elapsed` after start
+    pub fn requests_due(&self, elapsed: Duration) -> f64 {
+        let elapsed = elapsed.as_secs_f64();
+
+        if elapsed >= self.ramp_duration {
+            self.ramp_requests() + self.target_rate * (elapsed - self.ramp_duration)
+        } else {
+            self.start_rate * elapsed + self.slope() * elapsed.powi(2) / 2.0
+        }
+    }
+
+    /// Time after start at which request number `n` (counting from zero)
+    /// should be sent
+    pub fn send_time(&self, n: u64) -> Duration {
+        let n = n as f64;
+        let ramp_requests = self.ramp_requests();
+
+        let seconds = if n >= ramp_requests {
+            self.ramp_duration + (n - ramp_requests) / self.target_rate
+        } else {
+            let slope = self.slope();
+
+            if slope == 0.0 {
+                n / self.start_rate
+            } else {
+                // Solve start_rate * t + slope * t^2 / 2 = n for t
+                ((self.start_rate.powi(2) + 2.0 * slope * n).sqrt() - self.start_rate) / slope
+            }
+        };
+
+        Duration::from_secs_f64(seconds)
+    }
+
+    fn slope(&self) -> f64 {
+        if self.ramp_duration == 0.0 {
+            0.0
+        } else {
+            (self.target_rate - self.start_rate) / self.ramp_duration
+        }
+    }
+
+    fn ramp_requests(&self) -> f64 {
+        (self.start_rate + self.target_rate) * self.ramp_duration / 2.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck_macros::quickcheck;
+
+    use super::*;
+
+    #[quickcheck]
+    fn test_rate_schedule_send_time(
+        target_requests_per_second: u16,
+        ramp_start_requests_per_second: u16,
+        ramp_duration: u8,
+        n: u32,
+    ) -> quickcheck::TestResult {
+        if target_requests_per_second == 0 {
+            return quickcheck::TestResult::discard();
+        }
+
+        let config = OpenLoopConfig {
+            target_requests_per_second: target_requests_per_second.into(),
+            ramp_start_requests_per_second: ramp_start_requests_per_second.into(),
+            ramp_duration: ramp_duration.into(),
+        };
+
+        let schedule = RateSchedule::new(&config, 1);
+
+        let due = schedule.requests_due(schedule.send_time(n.into()));
+
+        quickcheck::TestResult::from_bool((due - f64::from(n)).abs() < 0.01)
+    }
+}
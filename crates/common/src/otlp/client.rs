@@ -0,0 +1,115 @@
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use anyhow::Context;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RESPONSE_LEN: u64 = 64 * 1024;
+
+/// Minimal blocking OTLP/HTTP client, sending JSON encoded payloads
+///
+/// Only plain HTTP is supported, since the exporter is meant to talk to a
+/// collector or agent running close to the tracker.
+pub struct CollectorClient {
+    authority: String,
+    base_path: String,
+}
+
+impl CollectorClient {
+    pub fn new(endpoint: &str) -> anyhow::Result<Self> {
+        let rest = endpoint.strip_prefix("http://").with_context(|| {
+            format!(
+                "OTLP endpoint must start with http:// (got {:?}), https is not supported",
+                endpoint
+            )
+        })?;
+
+        let (authority, base_path) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+
+        if authority.is_empty() {
+            anyhow::bail!("OTLP endpoint {:?} has no host", endpoint);
+        }
+
+        let authority = if authority.contains(':') && !authority.ends_with(']') {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+
+        Ok(Self {
+            authority,
+            base_path: base_path.to_string(),
+        })
+    }
+
+    /// Post JSON body to path below endpoint, e.g. `/v1/metrics`
+    pub fn post(&self, path: &str, body: &[u8]) -> anyhow::Result<()> {
+        let addr = self
+            .authority
+            .to_socket_addrs()
+            .with_context(|| format!("resolve {}", self.authority))?
+            .next()
+            .with_context(|| format!("no addresses found for {}", self.authority))?;
+
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+            .with_context(|| format!("connect to {}", addr))?;
+
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        write!(
+            stream,
+            "POST {}{} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.base_path,
+            path,
+            self.authority,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        let mut response = Vec::new();
+
+        stream.take(MAX_RESPONSE_LEN).read_to_end(&mut response)?;
+
+        let status_line = response
+            .split(|b| *b == b'\n')
+            .next()
+            .map(String::from_utf8_lossy)
+            .unwrap_or_default();
+
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            Some(status) => Err(anyhow::anyhow!(
+                "collector responded with status {}",
+                status
+            )),
+            None => Err(anyhow::anyhow!("invalid response from collector")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_endpoint() {
+        let client = CollectorClient::new("http://localhost:4318").unwrap();
+
+        assert_eq!(client.authority, "localhost:4318");
+        assert_eq!(client.base_path, "");
+
+        let client = CollectorClient::new("http://collector/otlp/").unwrap();
+
+        assert_eq!(client.authority, "collector:80");
+        assert_eq!(client.base_path, "/otlp");
+
+        assert!(CollectorClient::new("https://localhost:4318").is_err());
+        assert!(CollectorClient::new("http:///v1").is_err());
+    }
+}
@@ -0,0 +1,396 @@
+//! Export of metrics and sampled spans over OTLP/HTTP
+//!
+//! Metrics are recorded through the `metrics` facade, just like for the
+//! prometheus endpoint, and periodically sent to a collector as JSON encoded
+//! OTLP. Spans are buffered when finished and sent along with the metrics.
+
+mod client;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread::{Builder, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use metrics::Key;
+use metrics_util::MetricKindMask;
+use serde_json::{json, Value};
+
+use crate::push_recorder::PushRecorder;
+
+use client::CollectorClient;
+
+/// Spans finished after this many are buffered are dropped until next export
+const MAX_BUFFERED_SPANS: usize = 8192;
+
+/// Bits of f64 share of requests to create spans for
+static SPAN_SAMPLE_RATIO: AtomicU64 = AtomicU64::new(0);
+static FINISHED_SPANS: Mutex<Vec<FinishedSpan>> = Mutex::new(Vec::new());
+
+/// Exporter settings, built from tracker configuration
+#[derive(Clone, Debug)]
+pub struct OtlpConfig {
+    /// Export the same metrics as the ones served on the prometheus endpoint
+    pub export_metrics: bool,
+    /// Create spans for this share of announce and scrape requests
+    pub span_sample_ratio: f64,
+    /// Collector base URL. Data is posted to `/v1/metrics` and `/v1/traces`
+    /// below it.
+    pub endpoint: String,
+    /// Send data to collector this often (seconds)
+    pub export_interval: u64,
+}
+
+impl OtlpConfig {
+    pub fn active(&self) -> bool {
+        self.export_metrics | (self.span_sample_ratio > 0.0)
+    }
+}
+
+/// Build OTLP JSON metrics data, leaving out (and removing) idle metrics
+fn render_metrics(recorder: &PushRecorder, resource: &Value, start_time: &str) -> Value {
+    let time = unix_nanos_string(SystemTime::now());
+
+    let mut sums: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    let mut gauges: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+
+    recorder.visit(
+        |key, value| {
+            sums.entry(key.name().to_string()).or_default().push(json!({
+                "attributes": attributes(key),
+                "startTimeUnixNano": start_time,
+                "timeUnixNano": time,
+                "asInt": value.to_string(),
+            }));
+        },
+        |key, value| {
+            gauges
+                .entry(key.name().to_string())
+                .or_default()
+                .push(json!({
+                    "attributes": attributes(key),
+                    "timeUnixNano": time,
+                    "asDouble": value,
+                }));
+        },
+    );
+
+    let metrics = sums
+        .into_iter()
+        .map(|(name, data_points)| {
+            json!({
+                "name": name,
+                "sum": {
+                    "dataPoints": data_points,
+                    // Cumulative
+                    "aggregationTemporality": 2,
+                    "isMonotonic": true,
+                },
+            })
+        })
+        .chain(gauges.into_iter().map(|(name, data_points)| {
+            json!({
+                "name": name,
+                "gauge": {
+                    "dataPoints": data_points,
+                },
+            })
+        }))
+        .collect::<Vec<_>>();
+
+    json!({
+        "resourceMetrics": [{
+            "resource": resource,
+            "scopeMetrics": [{
+                "scope": scope(),
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+/// Timed operation, exported to collector when finished
+pub struct Span {
+    name: &'static str,
+    start_time: SystemTime,
+    start_instant: Instant,
+    attributes: Vec<(&'static str, String)>,
+}
+
+impl Span {
+    /// Start span if it is selected by sampling
+    #[inline]
+    pub fn start_sampled(name: &'static str) -> Option<Self> {
+        let ratio = f64::from_bits(SPAN_SAMPLE_RATIO.load(Ordering::Relaxed));
+
+        if ratio <= 0.0 || (ratio < 1.0 && rand::random::<f64>() >= ratio) {
+            return None;
+        }
+
+        Some(Self {
+            name,
+            start_time: SystemTime::now(),
+            start_instant: Instant::now(),
+            attributes: Vec::new(),
+        })
+    }
+
+    pub fn set_attribute<V: Into<String>>(&mut self, key: &'static str, value: V) {
+        self.attributes.push((key, value.into()));
+    }
+
+    pub fn finish(self) {
+        let span = FinishedSpan {
+            end_time: self.start_time + self.start_instant.elapsed(),
+            span: self,
+        };
+
+        let mut spans = FINISHED_SPANS.lock().unwrap();
+
+        if spans.len() < MAX_BUFFERED_SPANS {
+            spans.push(span);
+        }
+    }
+}
+
+struct FinishedSpan {
+    span: Span,
+    end_time: SystemTime,
+}
+
+fn render_spans(spans: Vec<FinishedSpan>, resource: &Value) -> Value {
+    let spans = spans
+        .into_iter()
+        .map(|FinishedSpan { span, end_time }| {
+            let attributes = span
+                .attributes
+                .iter()
+                .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+                .collect::<Vec<_>>();
+
+            json!({
+                "traceId": hex::encode(rand::random::<[u8; 16]>()),
+                "spanId": hex::encode(rand::random::<[u8; 8]>()),
+                "name": span.name,
+                // Server
+                "kind": 2,
+                "startTimeUnixNano": unix_nanos_string(span.start_time),
+                "endTimeUnixNano": unix_nanos_string(end_time),
+                "attributes": attributes,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "resourceSpans": [{
+            "resource": resource,
+            "scopeSpans": [{
+                "scope": scope(),
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+/// Spawn thread regularly sending metrics and spans to collector
+///
+/// If metrics are to be exported, the returned recorder needs to be
+/// installed, either with `install_push_recorders` or through
+/// `spawn_prometheus_endpoint`.
+pub fn spawn_otlp_exporter(
+    config: OtlpConfig,
+    service_name: &'static str,
+    idle_timeout: Option<Duration>,
+    idle_timeout_mask: Option<MetricKindMask>,
+) -> anyhow::Result<(Option<PushRecorder>, JoinHandle<anyhow::Result<()>>)> {
+    let client = CollectorClient::new(&config.endpoint)?;
+
+    let opt_recorder = config
+        .export_metrics
+        .then(|| PushRecorder::new(idle_timeout, idle_timeout_mask));
+
+    SPAN_SAMPLE_RATIO.store(
+        config.span_sample_ratio.clamp(0.0, 1.0).to_bits(),
+        Ordering::Relaxed,
+    );
+
+    let exporter = Exporter {
+        client,
+        opt_recorder: opt_recorder.clone(),
+        resource: resource(service_name),
+        start_time: unix_nanos_string(SystemTime::now()),
+    };
+    let interval = Duration::from_secs(config.export_interval.max(1));
+
+    let handle = Builder::new()
+        .name("otlp".into())
+        .spawn(move || loop {
+            ::std::thread::sleep(interval);
+
+            if let Err(err) = exporter.export_metrics() {
+                ::log::warn!("couldn't export metrics over OTLP: {:#}", err);
+            }
+            if let Err(err) = exporter.export_spans() {
+                ::log::warn!("couldn't export spans over OTLP: {:#}", err);
+            }
+        })
+        .context("spawn otlp exporter")?;
+
+    Ok((opt_recorder, handle))
+}
+
+struct Exporter {
+    client: CollectorClient,
+    opt_recorder: Option<PushRecorder>,
+    resource: Value,
+    start_time: String,
+}
+
+impl Exporter {
+    fn export_metrics(&self) -> anyhow::Result<()> {
+        if let Some(recorder) = self.opt_recorder.as_ref() {
+            let body = render_metrics(recorder, &self.resource, &self.start_time);
+
+            self.client
+                .post("/v1/metrics", &serde_json::to_vec(&body)?)?;
+        }
+
+        Ok(())
+    }
+
+    fn export_spans(&self) -> anyhow::Result<()> {
+        let spans = ::std::mem::take(&mut *FINISHED_SPANS.lock().unwrap());
+
+        if !spans.is_empty() {
+            let body = render_spans(spans, &self.resource);
+
+            self.client
+                .post("/v1/traces", &serde_json::to_vec(&body)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn resource(service_name: &'static str) -> Value {
+    json!({
+        "attributes": [
+            { "key": "service.name", "value": { "stringValue": service_name } },
+            { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+        ],
+    })
+}
+
+fn scope() -> Value {
+    json!({ "name": "aquatic", "version": env!("CARGO_PKG_VERSION") })
+}
+
+fn attributes(key: &Key) -> Vec<Value> {
+    key.labels()
+        .map(|label| json!({ "key": label.key(), "value": { "stringValue": label.value() } }))
+        .collect()
+}
+
+/// OTLP JSON encodes 64-bit integers as strings
+fn unix_nanos_string(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// Accept one request, respond with 200 and return path and body
+    fn run_stub_collector(listener: TcpListener) -> ::std::thread::JoinHandle<(String, Value)> {
+        ::std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut request_line = String::new();
+            let mut content_length = 0;
+
+            reader.read_line(&mut request_line).unwrap();
+
+            loop {
+                let mut line = String::new();
+
+                reader.read_line(&mut line).unwrap();
+
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    content_length = value.trim().parse().unwrap();
+                }
+                if line.trim_end().is_empty() {
+                    break;
+                }
+            }
+
+            let mut body = vec![0; content_length];
+
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+
+            let path = request_line.split_whitespace().nth(1).unwrap().to_string();
+
+            (path, serde_json::from_slice(&body).unwrap())
+        })
+    }
+
+    #[test]
+    fn test_export_to_stub_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+
+        let recorder = PushRecorder::new(None, None);
+
+        ::metrics::with_local_recorder(&recorder, || {
+            ::metrics::counter!("aquatic_requests_total", "ip_version" => "4").increment(3);
+            ::metrics::gauge!("aquatic_peers", "ip_version" => "6").set(5.0);
+        });
+
+        let exporter = Exporter {
+            client: CollectorClient::new(&endpoint).unwrap(),
+            opt_recorder: Some(recorder),
+            resource: resource("aquatic_test"),
+            start_time: unix_nanos_string(SystemTime::now()),
+        };
+
+        let collector = run_stub_collector(listener);
+
+        exporter.export_metrics().unwrap();
+
+        let (path, body) = collector.join().unwrap();
+
+        assert_eq!(path, "/v1/metrics");
+
+        let resource_metrics = &body["resourceMetrics"][0];
+
+        assert_eq!(
+            resource_metrics["resource"]["attributes"][0]["value"]["stringValue"],
+            "aquatic_test"
+        );
+
+        let metrics = resource_metrics["scopeMetrics"][0]["metrics"]
+            .as_array()
+            .unwrap();
+
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0]["name"], "aquatic_requests_total");
+        assert_eq!(metrics[0]["sum"]["dataPoints"][0]["asInt"], "3");
+        assert_eq!(
+            metrics[0]["sum"]["dataPoints"][0]["attributes"][0]["value"]["stringValue"],
+            "4"
+        );
+        assert_eq!(metrics[1]["name"], "aquatic_peers");
+        assert_eq!(metrics[1]["gauge"]["dataPoints"][0]["asDouble"], 5.0);
+    }
+}
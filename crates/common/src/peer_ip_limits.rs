@@ -0,0 +1,172 @@
+//! Limits on the number of peers stored for a single source address
+
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use ahash::RandomState;
+use aquatic_toml_config::TomlConfig;
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+
+const NUM_SHARDS: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeerIpLimitConfig {
+    /// Maximum number of peers stored for a single source address, counted
+    /// over all torrents (0 for no limit)
+    ///
+    /// Announces that would add a peer beyond the limit get an error
+    /// response.
+    pub max_peers: usize,
+    /// IPv6 addresses sharing this number of leading bits count as a single
+    /// source address
+    pub ipv6_prefix_length: u8,
+}
+
+impl PeerIpLimitConfig {
+    pub fn is_active(&self) -> bool {
+        self.max_peers != 0
+    }
+}
+
+impl Default for PeerIpLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_peers: 0,
+            ipv6_prefix_length: 64,
+        }
+    }
+}
+
+/// Number of stored peers per source address, shared by all torrent maps
+///
+/// Torrent maps need to call `try_add` before storing a peer that is not
+/// already present and `remove` when removing one. All methods are no-ops
+/// if there is no limit.
+pub struct PeerIpCounter {
+    max_peers: usize,
+    ipv6_mask: u128,
+    hasher: RandomState,
+    shards: Box<[Mutex<HashMap<IpAddr, usize>>]>,
+    rejected_ipv4: AtomicUsize,
+    rejected_ipv6: AtomicUsize,
+}
+
+impl PeerIpCounter {
+    pub fn new(config: &PeerIpLimitConfig) -> Self {
+        let ipv6_mask = match config.ipv6_prefix_length.min(128) {
+            0 => 0,
+            n => u128::MAX << (128 - u32::from(n)),
+        };
+
+        Self {
+            max_peers: config.max_peers,
+            ipv6_mask,
+            hasher: RandomState::new(),
+            shards: (0..NUM_SHARDS).map(|_| Default::default()).collect(),
+            rejected_ipv4: Default::default(),
+            rejected_ipv6: Default::default(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.max_peers != 0
+    }
+
+    /// Count new peer for address
+    ///
+    /// Returns false and counts a rejection if the limit has been reached.
+    pub fn try_add(&self, ip: IpAddr) -> bool {
+        if !self.is_active() {
+            return true;
+        }
+
+        let key = self.key(ip);
+        let mut shard = self.shard(&key).lock().unwrap();
+        let num_peers = shard.entry(key).or_default();
+
+        if *num_peers < self.max_peers {
+            *num_peers += 1;
+
+            true
+        } else {
+            drop(shard);
+
+            if ip.is_ipv4() {
+                self.rejected_ipv4.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.rejected_ipv6.fetch_add(1, Ordering::Relaxed);
+            }
+
+            false
+        }
+    }
+
+    pub fn remove(&self, ip: IpAddr) {
+        if !self.is_active() {
+            return;
+        }
+
+        let key = self.key(ip);
+        let mut shard = self.shard(&key).lock().unwrap();
+
+        if let Some(num_peers) = shard.get_mut(&key) {
+            *num_peers -= 1;
+
+            if *num_peers == 0 {
+                shard.remove(&key);
+            }
+        }
+    }
+
+    /// Take number of rejections for IPv4 or IPv6 addresses since previous
+    /// call
+    pub fn take_rejected(&self, ipv4: bool) -> usize {
+        if ipv4 {
+            self.rejected_ipv4.swap(0, Ordering::Relaxed)
+        } else {
+            self.rejected_ipv6.swap(0, Ordering::Relaxed)
+        }
+    }
+
+    fn key(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(_) => ip,
+            IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & self.ipv6_mask)),
+        }
+    }
+
+    fn shard(&self, key: &IpAddr) -> &Mutex<HashMap<IpAddr, usize>> {
+        &self.shards[self.hasher.hash_one(key) as usize % NUM_SHARDS]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_ip_counter() {
+        let counter = PeerIpCounter::new(&PeerIpLimitConfig {
+            max_peers: 2,
+            ipv6_prefix_length: 64,
+        });
+
+        let a: IpAddr = "2001:db8::1".parse().unwrap();
+        let b: IpAddr = "2001:db8::2".parse().unwrap();
+        let c: IpAddr = "2001:db8:0:1::1".parse().unwrap();
+
+        assert!(counter.try_add(a));
+        assert!(counter.try_add(b));
+        assert!(!counter.try_add(a));
+        assert!(counter.try_add(c));
+        assert_eq!(counter.take_rejected(false), 1);
+        assert_eq!(counter.take_rejected(false), 0);
+
+        counter.remove(b);
+
+        assert!(counter.try_add(a));
+    }
+}
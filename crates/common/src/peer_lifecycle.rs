@@ -0,0 +1,494 @@
+//! Peer lifecycle simulation for load testers
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use aquatic_toml_config::TomlConfig;
+use rand::Rng;
+use rand_distr::{Distribution, Exp};
+use serde::{Deserialize, Serialize};
+
+/// Time to wait for an announce response before announcing again
+const RETRY_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Peer lifecycle simulation
+///
+/// By default, each announce request is sent on behalf of a random peer with
+/// a random event. In lifecycle mode, peers instead arrive at random, send a
+/// started event, re-announce at the interval returned by the tracker,
+/// possibly complete their download and finally leave, either by sending a
+/// stopped event or by silently disappearing. This exercises the peer expiry
+/// and cleaning logic of the tracker.
+///
+/// Only announce requests are sent in this mode, so the request weights are
+/// ignored. The peer seeder probability is used as the probability that an
+/// arriving peer is a seeder. Each arriving peer gets a new peer id.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LifecycleConfig {
+    /// Number of arriving peers per second, divided evenly between workers
+    /// (or connections, if peers are bound to connections)
+    ///
+    /// 0 = lifecycle simulation disabled
+    pub peer_arrivals_per_second: u64,
+    /// Mean peer session length in seconds (exponentially distributed)
+    pub mean_session_length: u64,
+    /// Probability that a leeching peer completes its download before
+    /// leaving
+    pub completion_probability: f64,
+    /// Probability that a leaving peer sends a stopped event. Other peers
+    /// just stop announcing and will eventually be removed by the tracker
+    /// when cleaning.
+    pub stopped_event_probability: f64,
+    /// Re-announce after at most this many seconds, even if the tracker
+    /// returns a longer interval. Can be used to shorten test runs.
+    ///
+    /// 0 = always use interval returned by tracker
+    pub max_announce_interval: u64,
+    /// Maximum number of simultaneously active peers, divided evenly in the
+    /// same way as arrivals. Arrivals are dropped when all peers are active.
+    ///
+    /// With no limit, the average number of active peers would approach the
+    /// arrival rate multiplied by the mean session length.
+    pub max_active_peers: usize,
+}
+
+impl LifecycleConfig {
+    pub fn active(&self) -> bool {
+        self.peer_arrivals_per_second != 0
+    }
+}
+
+impl Default for LifecycleConfig {
+    fn default() -> Self {
+        Self {
+            peer_arrivals_per_second: 0,
+            mean_session_length: 60 * 30,
+            completion_probability: 0.5,
+            stopped_event_probability: 0.8,
+            max_announce_interval: 0,
+            max_active_peers: 100_000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerEvent {
+    Started,
+    Completed,
+    Stopped,
+    /// Regular announce without event
+    None,
+}
+
+pub enum LifecycleEvent {
+    /// Send announce request on behalf of peer
+    Announce {
+        peer_index: usize,
+        peer_id: [u8; 20],
+        event: PeerEvent,
+        seeding: bool,
+    },
+    /// Peer left without sending a stopped event
+    Abandon { peer_index: usize },
+    /// Peer didn't arrive since all peers are already active
+    ArrivalDropped,
+}
+
+/// Lifecycle event counts, shared by load test workers
+#[derive(Default)]
+pub struct LifecycleStatistics {
+    active: AtomicUsize,
+    started: AtomicUsize,
+    completed: AtomicUsize,
+    stopped: AtomicUsize,
+    abandoned: AtomicUsize,
+    arrivals_dropped: AtomicUsize,
+}
+
+impl LifecycleStatistics {
+    pub fn record(&self, event: &LifecycleEvent) {
+        match event {
+            LifecycleEvent::Announce { event, .. } => match event {
+                PeerEvent::Started => {
+                    self.active.fetch_add(1, Ordering::Relaxed);
+                    self.started.fetch_add(1, Ordering::Relaxed);
+                }
+                PeerEvent::Completed => {
+                    self.completed.fetch_add(1, Ordering::Relaxed);
+                }
+                PeerEvent::Stopped => {
+                    self.active.fetch_sub(1, Ordering::Relaxed);
+                    self.stopped.fetch_add(1, Ordering::Relaxed);
+                }
+                PeerEvent::None => (),
+            },
+            LifecycleEvent::Abandon { .. } => {
+                self.active.fetch_sub(1, Ordering::Relaxed);
+                self.abandoned.fetch_add(1, Ordering::Relaxed);
+            }
+            LifecycleEvent::ArrivalDropped => {
+                self.arrivals_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Print number of active peers and event rates, given that counts were
+    /// last reset `elapsed` seconds ago, and reset counts
+    pub fn print_and_reset(&self, elapsed: f64) {
+        let fetch_and_reset =
+            |count: &AtomicUsize| count.swap(0, Ordering::Relaxed) as f64 / elapsed;
+
+        let arrivals_dropped = fetch_and_reset(&self.arrivals_dropped);
+
+        println!(
+            "Active simulated peers: {}",
+            self.active.load(Ordering::Relaxed)
+        );
+        println!("Peer lifecycle events:");
+        println!(
+            "  - Started:   {:.2}/second",
+            fetch_and_reset(&self.started)
+        );
+        println!(
+            "  - Completed: {:.2}/second",
+            fetch_and_reset(&self.completed)
+        );
+        println!(
+            "  - Stopped:   {:.2}/second",
+            fetch_and_reset(&self.stopped)
+        );
+        println!(
+            "  - Abandoned: {:.2}/second",
+            fetch_and_reset(&self.abandoned)
+        );
+
+        if arrivals_dropped != 0.0 {
+            println!(
+                "Dropped arrivals (all peers active): {:.2}/second",
+                arrivals_dropped
+            );
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PeerStatus {
+    Leeching,
+    Seeding,
+}
+
+struct SimulatedPeer {
+    peer_id: [u8; 20],
+    status: PeerStatus,
+    /// Whether the started event has been sent
+    started: bool,
+    awaiting_response: bool,
+    completes_at: Option<Instant>,
+    leaves_at: Instant,
+    sends_stopped_event: bool,
+    next_event_at: Instant,
+}
+
+/// Population of simulated peers, each of which goes through the lifecycle
+/// of arriving, announcing periodically, possibly completing and leaving
+///
+/// Peers are identified by an index below the population size. Indices of
+/// inactive peers are reused in FIFO order, so that late responses to
+/// requests from a departed peer are unlikely to be attributed to a new one.
+pub struct PeerPopulation {
+    peers: Vec<Option<SimulatedPeer>>,
+    idle_peers: VecDeque<usize>,
+    /// Scheduled events. Entries are stale if they don't match the peer's
+    /// `next_event_at`.
+    timers: BinaryHeap<Reverse<(Instant, usize)>>,
+    arrival_dist: Exp<f64>,
+    session_length_dist: Exp<f64>,
+    next_arrival_at: Instant,
+    seeder_probability: f64,
+    completion_probability: f64,
+    stopped_event_probability: f64,
+    max_announce_interval: Option<Duration>,
+}
+
+impl PeerPopulation {
+    /// Create population for one of `num_populations` populations sharing
+    /// the configured arrival rate and peer limit
+    ///
+    /// The population size can be further limited with `max_peers`.
+    pub fn new(
+        config: &LifecycleConfig,
+        seeder_probability: f64,
+        num_populations: usize,
+        max_peers: usize,
+        rng: &mut impl Rng,
+        now: Instant,
+    ) -> anyhow::Result<Self> {
+        let num_populations = num_populations.max(1);
+        let num_peers = (config.max_active_peers / num_populations)
+            .max(1)
+            .min(max_peers);
+
+        let arrival_rate = config.peer_arrivals_per_second as f64 / num_populations as f64;
+        let arrival_dist = Exp::new(arrival_rate)?;
+        let session_length_dist = Exp::new(1.0 / config.mean_session_length.max(1) as f64)?;
+
+        let next_arrival_at = now + Duration::from_secs_f64(arrival_dist.sample(rng));
+
+        Ok(Self {
+            peers: (0..num_peers).map(|_| None).collect(),
+            idle_peers: (0..num_peers).collect(),
+            timers: Default::default(),
+            arrival_dist,
+            session_length_dist,
+            next_arrival_at,
+            seeder_probability,
+            completion_probability: config.completion_probability,
+            stopped_event_probability: config.stopped_event_probability,
+            max_announce_interval: (config.max_announce_interval != 0)
+                .then(|| Duration::from_secs(config.max_announce_interval)),
+        })
+    }
+
+    /// Return next event that is due, if any
+    pub fn poll(&mut self, rng: &mut impl Rng, now: Instant) -> Option<LifecycleEvent> {
+        if self.next_arrival_at <= now {
+            let arrived_at = self.next_arrival_at;
+
+            self.next_arrival_at += Duration::from_secs_f64(self.arrival_dist.sample(rng));
+
+            if !self.arrive(rng, arrived_at) {
+                return Some(LifecycleEvent::ArrivalDropped);
+            }
+        }
+
+        while let Some(Reverse((at, peer_index))) = self.timers.peek().copied() {
+            if at > now {
+                break;
+            }
+
+            self.timers.pop();
+
+            let peer = match self.peers[peer_index].as_mut() {
+                Some(peer) if peer.next_event_at == at => peer,
+                _ => continue,
+            };
+
+            if peer.started && now >= peer.leaves_at {
+                let peer_id = peer.peer_id;
+                let seeding = peer.status == PeerStatus::Seeding;
+                let sends_stopped_event = peer.sends_stopped_event;
+
+                self.peers[peer_index] = None;
+                self.idle_peers.push_back(peer_index);
+
+                return if sends_stopped_event {
+                    Some(LifecycleEvent::Announce {
+                        peer_index,
+                        peer_id,
+                        event: PeerEvent::Stopped,
+                        seeding,
+                    })
+                } else {
+                    Some(LifecycleEvent::Abandon { peer_index })
+                };
+            }
+
+            let event = if !peer.started {
+                peer.started = true;
+
+                PeerEvent::Started
+            } else if matches!(peer.completes_at, Some(completes_at) if now >= completes_at) {
+                peer.status = PeerStatus::Seeding;
+                peer.completes_at = None;
+
+                PeerEvent::Completed
+            } else {
+                PeerEvent::None
+            };
+
+            peer.awaiting_response = true;
+
+            let peer_id = peer.peer_id;
+            let seeding = peer.status == PeerStatus::Seeding;
+
+            self.schedule(peer_index, now + RETRY_INTERVAL);
+
+            return Some(LifecycleEvent::Announce {
+                peer_index,
+                peer_id,
+                event,
+                seeding,
+            });
+        }
+
+        None
+    }
+
+    /// Time at which next event may be due
+    ///
+    /// Can be earlier than the actual time, but never later.
+    pub fn next_event_at(&self) -> Instant {
+        match self.timers.peek() {
+            Some(Reverse((at, _))) => (*at).min(self.next_arrival_at),
+            None => self.next_arrival_at,
+        }
+    }
+
+    /// Schedule next announce according to interval returned by tracker
+    pub fn handle_announce_response(
+        &mut self,
+        peer_index: usize,
+        announce_interval: Duration,
+        now: Instant,
+    ) {
+        let peer = match self.peers.get_mut(peer_index) {
+            Some(Some(peer)) if peer.awaiting_response => peer,
+            _ => return,
+        };
+
+        peer.awaiting_response = false;
+
+        let announce_interval = match self.max_announce_interval {
+            Some(max_announce_interval) => announce_interval.min(max_announce_interval),
+            None => announce_interval,
+        };
+
+        self.schedule(peer_index, now + announce_interval);
+    }
+
+    fn arrive(&mut self, rng: &mut impl Rng, now: Instant) -> bool {
+        let peer_index = match self.idle_peers.pop_front() {
+            Some(peer_index) => peer_index,
+            None => return false,
+        };
+
+        let session_length = Duration::from_secs_f64(self.session_length_dist.sample(rng));
+
+        let status = if rng.gen_bool(self.seeder_probability) {
+            PeerStatus::Seeding
+        } else {
+            PeerStatus::Leeching
+        };
+
+        let completes_at = (status == PeerStatus::Leeching
+            && rng.gen_bool(self.completion_probability))
+        .then(|| now + session_length.mul_f64(rng.gen()));
+
+        self.peers[peer_index] = Some(SimulatedPeer {
+            peer_id: rng.gen(),
+            status,
+            started: false,
+            awaiting_response: false,
+            completes_at,
+            leaves_at: now + session_length,
+            sends_stopped_event: rng.gen_bool(self.stopped_event_probability),
+            next_event_at: now,
+        });
+
+        self.timers.push(Reverse((now, peer_index)));
+
+        true
+    }
+
+    /// Set time of next event, bringing it forward if the peer completes or
+    /// leaves before then
+    fn schedule(&mut self, peer_index: usize, at: Instant) {
+        if let Some(peer) = self.peers[peer_index].as_mut() {
+            let mut at = at.min(peer.leaves_at);
+
+            if let Some(completes_at) = peer.completes_at {
+                at = at.min(completes_at);
+            }
+
+            peer.next_event_at = at;
+
+            self.timers.push(Reverse((at, peer_index)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn test_peer_lifecycle() {
+        let config = LifecycleConfig {
+            peer_arrivals_per_second: 10,
+            mean_session_length: 60,
+            max_announce_interval: 10,
+            max_active_peers: 100,
+            ..Default::default()
+        };
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let start = Instant::now();
+
+        let mut population =
+            PeerPopulation::new(&config, 0.25, 1, usize::MAX, &mut rng, start).unwrap();
+
+        let mut num_started = 0;
+        let mut num_left = 0;
+        let mut peer_ids: HashMap<usize, Vec<[u8; 20]>> = HashMap::new();
+
+        for second in 0..600 {
+            let now = start + Duration::from_secs(second);
+
+            while let Some(event) = population.poll(&mut rng, now) {
+                match event {
+                    LifecycleEvent::Announce {
+                        peer_index,
+                        peer_id,
+                        event,
+                        ..
+                    } => {
+                        match event {
+                            PeerEvent::Started => {
+                                num_started += 1;
+
+                                peer_ids.entry(peer_index).or_default().push(peer_id);
+                            }
+                            PeerEvent::Stopped => num_left += 1,
+                            _ => (),
+                        }
+
+                        assert_eq!(peer_ids[&peer_index].last(), Some(&peer_id));
+
+                        population.handle_announce_response(
+                            peer_index,
+                            Duration::from_secs(900),
+                            now,
+                        );
+                    }
+                    LifecycleEvent::Abandon { .. } => num_left += 1,
+                    LifecycleEvent::ArrivalDropped => (),
+                }
+
+                assert!(num_started - num_left <= config.max_active_peers);
+            }
+
+            assert!(population.next_event_at() > now);
+        }
+
+        let num_active = num_started - num_left;
+
+        assert!(num_started > config.max_active_peers);
+        assert!(num_active > 0);
+        assert!(num_active <= config.max_active_peers);
+
+        // Peers arriving in reused slots get new peer ids
+        for ids in peer_ids.values() {
+            for (i, id) in ids.iter().enumerate() {
+                assert!(!ids[..i].contains(id));
+            }
+        }
+        assert!(peer_ids.values().any(|ids| ids.len() > 1));
+    }
+}
@@ -0,0 +1,384 @@
+//! Strategies for selecting peers to include in announce responses
+
+use std::cmp::Reverse;
+use std::net::IpAddr;
+
+use aquatic_toml_config::TomlConfig;
+use indexmap::IndexMap;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::ValidUntil;
+
+/// How to treat peers with addresses in the same prefix as the announcing
+/// peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SamePrefixPolicy {
+    /// Don't consider prefixes
+    Ignore,
+    /// Return peers in the same prefix before other peers
+    Prefer,
+    /// Never return peers in the same prefix
+    Exclude,
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeerSelectionConfig {
+    /// Return leechers before seeders in responses to seeders
+    pub leechers_first_for_seeders: bool,
+    /// How to treat peers with addresses in the same prefix as the announcing
+    /// peer
+    ///
+    /// Peers are stored per IP version, so returned peers always have the
+    /// same address family as the announcing peer.
+    pub same_prefix: SamePrefixPolicy,
+    /// IPv4 addresses sharing this number of leading bits are in the same
+    /// prefix
+    pub ipv4_prefix_length: u8,
+    /// IPv6 addresses sharing this number of leading bits are in the same
+    /// prefix
+    pub ipv6_prefix_length: u8,
+    /// Return peers that announced recently before other peers
+    pub prefer_recently_announced: bool,
+    /// Maximum number of peers to consider when any of the above settings
+    /// is active and there are more peers than will be returned
+    ///
+    /// Candidates are taken from a random position in the peer map. Higher
+    /// values make the settings more effective at the cost of performance.
+    /// Values lower than the number of peers to return are ignored.
+    pub max_candidates: usize,
+}
+
+impl PeerSelectionConfig {
+    pub fn is_active(&self) -> bool {
+        self.leechers_first_for_seeders
+            || self.same_prefix != SamePrefixPolicy::Ignore
+            || self.prefer_recently_announced
+    }
+
+    /// Select up to `max_peers` peers from map
+    ///
+    /// Candidates are up to `max_candidates` consecutive peers (but at least
+    /// `max_peers`) starting at a random position, wrapping around at the
+    /// end of the map. Since peers are stored in insertion order, this is a
+    /// lot more cache-friendly than sampling peers randomly.
+    pub fn select_from_map<K: Copy, V, S>(
+        &self,
+        rng: &mut impl Rng,
+        peers: &IndexMap<K, V, S>,
+        to_candidate: impl Fn(K, &V) -> PeerCandidate<K>,
+        requester_ip: IpAddr,
+        requester_is_seeder: bool,
+        max_peers: usize,
+    ) -> Vec<K> {
+        let num_candidates = self.max_candidates.max(max_peers).min(peers.len());
+        let offset = if peers.len() > max_peers {
+            rng.gen_range(0..peers.len())
+        } else {
+            0
+        };
+
+        let candidates = peers
+            .get_range(offset..)
+            .into_iter()
+            .chain(peers.get_range(..offset))
+            .flat_map(|slice| slice.iter())
+            .take(num_candidates)
+            .map(|(key, peer)| to_candidate(*key, peer));
+
+        self.select(requester_ip, requester_is_seeder, candidates, max_peers)
+    }
+
+    /// Select up to `max_peers` of `candidates`
+    ///
+    /// Candidates should be in random order. Candidates that rank equally
+    /// keep their order.
+    pub fn select<K>(
+        &self,
+        requester_ip: IpAddr,
+        requester_is_seeder: bool,
+        candidates: impl IntoIterator<Item = PeerCandidate<K>>,
+        max_peers: usize,
+    ) -> Vec<K> {
+        let requester_prefix = self.prefix(requester_ip);
+        let seeders_last = self.leechers_first_for_seeders && requester_is_seeder;
+
+        let mut ranked = candidates
+            .into_iter()
+            .filter_map(|candidate| {
+                let same_prefix = match self.same_prefix {
+                    SamePrefixPolicy::Ignore => true,
+                    SamePrefixPolicy::Prefer | SamePrefixPolicy::Exclude => {
+                        self.prefix(candidate.ip) == requester_prefix
+                    }
+                };
+
+                if same_prefix && self.same_prefix == SamePrefixPolicy::Exclude {
+                    return None;
+                }
+
+                // Lower ranks are selected first
+                let rank = (
+                    seeders_last && candidate.is_seeder,
+                    !same_prefix,
+                    self.prefer_recently_announced
+                        .then_some(Reverse(candidate.valid_until)),
+                );
+
+                Some((rank, candidate.key))
+            })
+            .collect::<Vec<_>>();
+
+        ranked.sort_by_key(|(rank, _)| *rank);
+
+        ranked
+            .into_iter()
+            .take(max_peers)
+            .map(|(_, key)| key)
+            .collect()
+    }
+
+    fn prefix(&self, ip: IpAddr) -> (bool, u128) {
+        match ip {
+            IpAddr::V4(ip) => (
+                true,
+                u128::from(u32::from(ip) & mask(self.ipv4_prefix_length.min(32), 32) as u32),
+            ),
+            IpAddr::V6(ip) => (
+                false,
+                u128::from(ip) & mask(self.ipv6_prefix_length.min(128), 128),
+            ),
+        }
+    }
+}
+
+impl Default for PeerSelectionConfig {
+    fn default() -> Self {
+        Self {
+            leechers_first_for_seeders: false,
+            same_prefix: SamePrefixPolicy::Ignore,
+            ipv4_prefix_length: 24,
+            ipv6_prefix_length: 48,
+            prefer_recently_announced: false,
+            max_candidates: 256,
+        }
+    }
+}
+
+/// Peer that can be included in announce response
+pub struct PeerCandidate<K> {
+    pub key: K,
+    pub ip: IpAddr,
+    pub is_seeder: bool,
+    pub valid_until: ValidUntil,
+}
+
+/// Mask with `prefix_length` leading ones out of `bits` bits
+fn mask(prefix_length: u8, bits: u32) -> u128 {
+    match u32::from(prefix_length) {
+        0 => 0,
+        n => (u128::MAX << (128 - n)) >> (128 - bits),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use crate::SecondsSinceServerStart;
+
+    use super::*;
+
+    fn candidates(peers: &[(&str, bool, u32)]) -> Vec<PeerCandidate<usize>> {
+        peers
+            .iter()
+            .enumerate()
+            .map(|(key, (ip, is_seeder, valid_until))| PeerCandidate {
+                key,
+                ip: ip.parse().unwrap(),
+                is_seeder: *is_seeder,
+                valid_until: ValidUntil::new_with_now(SecondsSinceServerStart(0), *valid_until),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_select() {
+        let peers = [
+            ("10.0.1.1", true, 3),
+            ("10.0.0.2", true, 1),
+            ("10.0.1.3", false, 4),
+            ("10.0.0.4", false, 2),
+        ];
+        let requester_ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let mut config = PeerSelectionConfig::default();
+
+        assert_eq!(
+            config.select(requester_ip, true, candidates(&peers), 3),
+            vec![0, 1, 2]
+        );
+
+        config.leechers_first_for_seeders = true;
+
+        assert_eq!(
+            config.select(requester_ip, true, candidates(&peers), 3),
+            vec![2, 3, 0]
+        );
+        assert_eq!(
+            config.select(requester_ip, false, candidates(&peers), 3),
+            vec![0, 1, 2]
+        );
+
+        config.same_prefix = SamePrefixPolicy::Prefer;
+
+        assert_eq!(
+            config.select(requester_ip, true, candidates(&peers), 4),
+            vec![3, 2, 1, 0]
+        );
+
+        config.same_prefix = SamePrefixPolicy::Exclude;
+
+        assert_eq!(
+            config.select(requester_ip, false, candidates(&peers), 4),
+            vec![0, 2]
+        );
+
+        config.leechers_first_for_seeders = false;
+        config.same_prefix = SamePrefixPolicy::Ignore;
+        config.prefer_recently_announced = true;
+
+        assert_eq!(
+            config.select(requester_ip, false, candidates(&peers), 4),
+            vec![2, 0, 3, 1]
+        );
+    }
+
+    #[test]
+    fn test_select_from_map() {
+        let mut rng = SmallRng::seed_from_u64(0);
+
+        // Every other peer is a seeder and every fourth one is in the same
+        // /24 as the requester
+        let peers = (0..1000u32)
+            .map(|i| {
+                let ip = IpAddr::from([10, 0, (i % 4) as u8, (i / 4) as u8]);
+                let valid_until = ValidUntil::new_with_now(SecondsSinceServerStart(0), i);
+
+                (i, (ip, i % 2 == 1, valid_until))
+            })
+            .collect::<IndexMap<_, _>>();
+
+        let is_seeder = |key: &u32| peers[key].1;
+        let in_same_prefix = |key: &u32| key % 4 == 0;
+
+        let requester_ip = IpAddr::from([10, 0, 0, 1]);
+
+        let mut config = PeerSelectionConfig {
+            max_candidates: 64,
+            ..Default::default()
+        };
+
+        let mut select = |config: &PeerSelectionConfig, max_peers| {
+            (0..10_000)
+                .map(|_| {
+                    config.select_from_map(
+                        &mut rng,
+                        &peers,
+                        |key, (ip, is_seeder, valid_until)| PeerCandidate {
+                            key,
+                            ip: *ip,
+                            is_seeder: *is_seeder,
+                            valid_until: *valid_until,
+                        },
+                        requester_ip,
+                        true,
+                        max_peers,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Number of times each peer was selected
+        let counts = |responses: &[Vec<u32>]| {
+            let mut counts = vec![0usize; peers.len()];
+
+            for key in responses.iter().flatten() {
+                counts[*key as usize] += 1;
+            }
+
+            counts
+        };
+        let assert_counts = |counts: &[usize], keys: &dyn Fn(&u32) -> bool, expected: usize| {
+            for (key, count) in counts.iter().enumerate() {
+                if keys(&(key as u32)) {
+                    assert!(
+                        (expected * 4 / 5..expected * 6 / 5).contains(count),
+                        "peer {} selected {} times, expected about {}",
+                        key,
+                        count,
+                        expected
+                    );
+                }
+            }
+        };
+
+        assert!(select(&config, 1000)
+            .iter()
+            .all(|peers| peers.len() == 1000));
+
+        // Windows start at uniformly random positions, so without
+        // preferences, each peer is returned in 30 of 1000 responses
+        let responses = select(&config, 30);
+
+        assert!(responses.iter().all(|peers| peers.len() == 30));
+        assert_counts(&counts(&responses), &|_| true, 300);
+
+        // Each window contains 16 peers in the same prefix, which are always
+        // returned, so they are returned in 64 of 1000 responses
+        config.same_prefix = SamePrefixPolicy::Prefer;
+
+        let responses = select(&config, 30);
+        let prefer_counts = counts(&responses);
+
+        assert!(responses.iter().all(|peers| peers.len() == 30));
+        assert_counts(&prefer_counts, &in_same_prefix, 640);
+        assert!(prefer_counts
+            .iter()
+            .enumerate()
+            .all(|(key, count)| in_same_prefix(&(key as u32)) || *count < 300));
+
+        config.same_prefix = SamePrefixPolicy::Exclude;
+
+        let responses = select(&config, 30);
+
+        assert!(responses.iter().all(|peers| peers.len() == 30));
+        assert!(!responses.iter().flatten().any(in_same_prefix));
+
+        // Each window contains 32 leechers, of which the first 30 are
+        // returned, so they are returned in about 60 of 1000 responses
+        config.same_prefix = SamePrefixPolicy::Ignore;
+        config.leechers_first_for_seeders = true;
+
+        let responses = select(&config, 30);
+
+        assert!(responses.iter().all(|peers| peers.len() == 30));
+        assert!(!responses.iter().flatten().any(is_seeder));
+        assert_counts(&counts(&responses), &|key| !is_seeder(key), 600);
+    }
+
+    #[test]
+    fn test_prefix() {
+        let config = PeerSelectionConfig::default();
+
+        let prefix = |ip: &str| config.prefix(ip.parse().unwrap());
+
+        assert_eq!(prefix("192.0.2.1"), prefix("192.0.2.255"));
+        assert_ne!(prefix("192.0.2.1"), prefix("192.0.3.1"));
+        assert_eq!(prefix("2001:db8::1"), prefix("2001:db8:0:ffff::1"));
+        assert_ne!(prefix("2001:db8::1"), prefix("2001:db9::1"));
+    }
+}
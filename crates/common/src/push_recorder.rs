@@ -0,0 +1,109 @@
+//! Metrics recorder for exporters pushing metrics to external services
+//!
+//! Metrics are stored in a registry until an exporter visits them. The
+//! recorder is installed either directly with `install_push_recorders` or
+//! alongside the prometheus recorder through `spawn_prometheus_endpoint`.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+use metrics_util::layers::FanoutBuilder;
+use metrics_util::registry::{GenerationalAtomicStorage, Recency, Registry};
+use metrics_util::MetricKindMask;
+
+/// Metrics recorder storing values until they are pushed
+///
+/// Histograms are not supported and will not be recorded.
+#[derive(Clone)]
+pub struct PushRecorder(Arc<RecorderInner>);
+
+struct RecorderInner {
+    registry: Registry<Key, GenerationalAtomicStorage>,
+    recency: Recency<Key>,
+}
+
+impl PushRecorder {
+    pub(crate) fn new(
+        idle_timeout: Option<Duration>,
+        idle_timeout_mask: Option<MetricKindMask>,
+    ) -> Self {
+        Self(Arc::new(RecorderInner {
+            registry: Registry::new(GenerationalAtomicStorage::atomic()),
+            recency: Recency::new(
+                quanta::Clock::new(),
+                idle_timeout_mask.unwrap_or(MetricKindMask::ALL),
+                idle_timeout,
+            ),
+        }))
+    }
+
+    /// Call closures with current counter and gauge values, leaving out (and
+    /// removing) idle metrics
+    pub(crate) fn visit(
+        &self,
+        mut on_counter: impl FnMut(&Key, u64),
+        mut on_gauge: impl FnMut(&Key, f64),
+    ) {
+        let RecorderInner { registry, recency } = self.0.as_ref();
+
+        for (key, counter) in registry.get_counter_handles() {
+            if recency.should_store_counter(&key, counter.get_generation(), registry) {
+                on_counter(&key, counter.get_inner().load(Ordering::Relaxed));
+            }
+        }
+        for (key, gauge) in registry.get_gauge_handles() {
+            if recency.should_store_gauge(&key, gauge.get_generation(), registry) {
+                on_gauge(
+                    &key,
+                    f64::from_bits(gauge.get_inner().load(Ordering::Relaxed)),
+                );
+            }
+        }
+    }
+}
+
+impl Recorder for PushRecorder {
+    fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+    fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+    fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+        self.0
+            .registry
+            .get_or_create_counter(key, |counter| counter.clone().into())
+    }
+
+    fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+        self.0
+            .registry
+            .get_or_create_gauge(key, |gauge| gauge.clone().into())
+    }
+
+    fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+        Histogram::noop()
+    }
+}
+
+/// Add recorders to fanout recorder (no-op if none are passed)
+pub(crate) fn add_to_fanout(
+    mut builder: FanoutBuilder,
+    recorders: Vec<PushRecorder>,
+) -> FanoutBuilder {
+    for recorder in recorders {
+        builder = builder.add_recorder(recorder);
+    }
+
+    builder
+}
+
+/// Install push recorders as global metrics recorder
+///
+/// Use this when the prometheus endpoint isn't running.
+pub fn install_push_recorders(recorders: Vec<PushRecorder>) -> anyhow::Result<()> {
+    let recorder = add_to_fanout(FanoutBuilder::default(), recorders).build();
+
+    ::metrics::set_global_recorder(recorder)
+        .map_err(|err| anyhow::anyhow!("set global metrics recorder: {:#}", err))
+}
@@ -0,0 +1,226 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::Builder;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use arc_swap::ArcSwapOption;
+
+/// Maximum time spent on a single connection, including writing response
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_LEN: u64 = 8 * 1024;
+const MAX_REQUEST_HEADER_LINES: usize = 64;
+
+/// Latest rendered statistics, shared between statistics worker and HTTP
+/// server thread
+#[derive(Clone, Default)]
+pub struct StatisticsPages(Arc<ArcSwapOption<Pages>>);
+
+impl StatisticsPages {
+    pub fn update(&self, html: String, json: String) {
+        self.0.store(Some(Arc::new(Pages { html, json })));
+    }
+}
+
+struct Pages {
+    html: String,
+    json: String,
+}
+
+/// Bind to configured address and serve statistics pages on a separate
+/// thread
+///
+/// Requests are handled one at a time, since the server is only meant for
+/// occasional visits by people and monitoring systems. Request size and
+/// total time spent per connection are limited so that a slow or malicious
+/// client can't block it for long.
+pub fn spawn_http_server(address: SocketAddr, pages: StatisticsPages) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address)
+        .with_context(|| format!("bind statistics http server to {}", address))?;
+
+    Builder::new()
+        .name("statistics-http".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.map_err(anyhow::Error::from).and_then(|stream| {
+                    let deadline = Instant::now() + CONNECTION_TIMEOUT;
+
+                    handle_connection(stream, &pages, deadline)
+                });
+
+                if let Err(err) = result {
+                    ::log::debug!("statistics http server connection error: {:#}", err);
+                }
+            }
+        })
+        .context("spawn statistics http server thread")?;
+
+    Ok(())
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    pages: &StatisticsPages,
+    deadline: Instant,
+) -> anyhow::Result<()> {
+    let mut stream = DeadlineStream { stream, deadline };
+    let mut reader = BufReader::new((&mut stream).take(MAX_REQUEST_LEN));
+    let mut request_line = String::new();
+
+    reader.read_line(&mut request_line)?;
+
+    // Skip headers
+    for _ in 0..MAX_REQUEST_HEADER_LINES {
+        let mut line = String::new();
+
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts
+        .next()
+        .and_then(|path| path.split('?').next())
+        .unwrap_or_default();
+
+    drop(reader);
+
+    if method != "GET" {
+        return write_response(&mut stream, "405 Method Not Allowed", "text/plain", b"");
+    }
+
+    let pages = pages.0.load_full();
+
+    let (content_type, body) = match (path, pages.as_ref()) {
+        ("/" | "/index.html", Some(pages)) => ("text/html; charset=utf-8", pages.html.as_bytes()),
+        ("/statistics.json", Some(pages)) => ("application/json", pages.json.as_bytes()),
+        ("/" | "/index.html" | "/statistics.json", None) => {
+            return write_response(
+                &mut stream,
+                "503 Service Unavailable",
+                "text/plain",
+                b"Statistics not yet collected",
+            );
+        }
+        _ => {
+            return write_response(&mut stream, "404 Not Found", "text/plain", b"Not found");
+        }
+    };
+
+    write_response(&mut stream, "200 OK", content_type, body)
+}
+
+fn write_response(
+    stream: &mut impl Write,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// Stream that fails reads and writes once deadline has passed
+///
+/// Socket timeouts only apply to individual operations, so they are updated
+/// to the remaining time before each one.
+struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl DeadlineStream {
+    fn remaining(&self) -> io::Result<Duration> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "connection deadline passed",
+            ))
+        } else {
+            Ok(remaining)
+        }
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.remaining()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn connect_and_accept() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        (client, server)
+    }
+
+    #[test]
+    fn test_request_len_limit() {
+        let (mut client, server) = connect_and_accept();
+
+        // Request line without end that is longer than the limit. Without
+        // it, reading would continue until the deadline and then fail.
+        client
+            .write_all(&[b'a'; 2 * MAX_REQUEST_LEN as usize])
+            .unwrap();
+
+        let deadline = Instant::now() + CONNECTION_TIMEOUT;
+
+        handle_connection(server, &StatisticsPages::default(), deadline).unwrap();
+    }
+
+    #[test]
+    fn test_connection_deadline() {
+        let (mut client, server) = connect_and_accept();
+
+        // Keep sending header lines slowly enough not to trigger a socket
+        // timeout if it were reset on each read
+        thread::spawn(move || {
+            client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+
+            while client.write_all(b"a: b\r\n").is_ok() {
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(200);
+
+        assert!(handle_connection(server, &StatisticsPages::default(), deadline).is_err());
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
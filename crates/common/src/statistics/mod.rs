@@ -0,0 +1,1141 @@
+//! Statistics collection and output shared by the trackers
+//!
+//! Socket and swarm workers update atomic counters, which a separate
+//! statistics worker periodically collects and prints to stdout, writes as
+//! HTML to a file and/or serves as HTML and JSON over HTTP.
+
+mod http_server;
+
+use std::fs::File;
+use std::io::Write;
+use std::iter::repeat_with;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use aquatic_peer_id::{ClientDefinitions, ClientRegistry, PeerClient, PeerId};
+use aquatic_toml_config::TomlConfig;
+use crossbeam_utils::CachePadded;
+use num_format::{Locale, ToFormattedString};
+use serde::{Deserialize, Serialize, Serializer};
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+use tinytemplate::TinyTemplate;
+
+use crate::torrent_limits::TorrentLimitCounters;
+use crate::IndexMap;
+
+pub use http_server::{spawn_http_server, StatisticsPages};
+
+/// Stylesheet for statistics pages, wrapped in a style element
+pub const STYLESHEET: &str = concat!(
+    "<style>",
+    include_str!("../../templates/statistics.css"),
+    "</style>"
+);
+
+const TEMPLATE_KEY: &str = "statistics";
+const TEMPLATE_CONTENTS: &str = include_str!("../../templates/statistics.html");
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatisticsConfig {
+    /// Collect and print/write statistics this often (seconds)
+    pub interval: u64,
+    /// Collect statistics on peer clients.
+    ///
+    /// Expect a certain CPU hit and a bit higher memory use
+    pub peer_clients: bool,
+    /// Path to TOML file with additional peer client definitions (leave
+    /// empty for none)
+    ///
+    /// Definitions are added to the built-in ones, replacing any with the
+    /// same style and prefix. Also used for peer client metrics.
+    pub peer_client_definitions_path: PathBuf,
+    /// Report this many torrents with the most peers and this many with the
+    /// most announces since the previous torrent cleaning (0 to disable)
+    ///
+    /// Also exported as metrics when they are enabled. Will increase time
+    /// taken for torrent cleaning.
+    pub top_torrents: usize,
+    /// Print statistics to standard output
+    pub print_to_stdout: bool,
+    /// Save statistics as HTML to a file
+    pub write_html_to_file: bool,
+    /// Path to save HTML file to
+    pub html_file_path: PathBuf,
+    /// Serve statistics as HTML and JSON over HTTP
+    ///
+    /// The HTML page is available at `/` and the JSON version at
+    /// `/statistics.json`
+    pub run_http_server: bool,
+    /// Address to serve statistics on
+    pub http_server_address: SocketAddr,
+    /// Homepage to link to on the statistics page (leave empty for none)
+    pub homepage_url: String,
+    /// Tracker announce URL to show on the statistics page (leave empty for
+    /// none)
+    pub tracker_url: String,
+}
+
+impl StatisticsConfig {
+    pub fn active(&self) -> bool {
+        (self.interval != 0)
+            & (self.print_to_stdout | self.write_html_to_file | self.run_http_server)
+    }
+}
+
+impl Default for StatisticsConfig {
+    fn default() -> Self {
+        Self {
+            interval: 5,
+            peer_clients: false,
+            peer_client_definitions_path: PathBuf::new(),
+            top_torrents: 0,
+            print_to_stdout: false,
+            write_html_to_file: false,
+            html_file_path: "tmp/statistics.html".into(),
+            run_http_server: false,
+            http_server_address: SocketAddr::from(([127, 0, 0, 1], 9001)),
+            homepage_url: String::new(),
+            tracker_url: String::new(),
+        }
+    }
+}
+
+pub type CachePaddedArc<T> = CachePadded<Arc<CachePadded<T>>>;
+
+/// Counters shared between workers and the statistics worker
+#[derive(Clone)]
+pub struct Statistics {
+    pub socket: Vec<CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>>,
+    pub swarm: Vec<CachePaddedArc<IpVersionStatistics<SwarmWorkerStatistics>>>,
+    pub peer_clients: PeerClientCounter,
+}
+
+impl Statistics {
+    pub fn new(num_socket_workers: usize, num_swarm_workers: usize) -> Self {
+        Self {
+            socket: repeat_with(Default::default)
+                .take(num_socket_workers)
+                .collect(),
+            swarm: repeat_with(Default::default)
+                .take(num_swarm_workers)
+                .collect(),
+            peer_clients: Default::default(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct IpVersionStatistics<T> {
+    pub ipv4: T,
+    pub ipv6: T,
+}
+
+impl<T> IpVersionStatistics<T> {
+    pub fn get(&self, ipv4: bool) -> &T {
+        if ipv4 {
+            &self.ipv4
+        } else {
+            &self.ipv6
+        }
+    }
+
+    pub fn get_mut(&mut self, ipv4: bool) -> &mut T {
+        if ipv4 {
+            &mut self.ipv4
+        } else {
+            &mut self.ipv6
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SocketWorkerStatistics {
+    /// Number of currently open connections
+    pub connections: AtomicUsize,
+    pub requests_announce: AtomicUsize,
+    pub requests_scrape: AtomicUsize,
+    pub responses_announce: AtomicUsize,
+    pub responses_scrape: AtomicUsize,
+    /// Offers forwarded to peers (WebTorrent only)
+    pub responses_offer: AtomicUsize,
+    /// Answers forwarded to peers (WebTorrent only)
+    pub responses_answer: AtomicUsize,
+    pub responses_error: AtomicUsize,
+}
+
+/// Torrent and peer counts, updated by swarm workers when cleaning torrents
+#[derive(Default)]
+pub struct SwarmWorkerStatistics {
+    pub torrents: AtomicUsize,
+    pub peers: AtomicUsize,
+    pub top_torrents: Mutex<TopTorrents>,
+    /// New torrents not stored because of torrent limit (total)
+    pub rejected_torrents: AtomicUsize,
+    /// New peers not stored because of peer limit (total)
+    pub rejected_peers: AtomicUsize,
+    /// Torrents removed to stay within limits (total)
+    pub evicted_torrents: AtomicUsize,
+    /// New peers not stored because of per-address peer limit (total)
+    pub rejected_ip_peers: AtomicUsize,
+}
+
+impl SwarmWorkerStatistics {
+    pub fn add_torrent_limit_counters(&self, counters: TorrentLimitCounters) {
+        self.rejected_torrents
+            .fetch_add(counters.rejected_torrents, Ordering::Relaxed);
+        self.rejected_peers
+            .fetch_add(counters.rejected_peers, Ordering::Relaxed);
+        self.evicted_torrents
+            .fetch_add(counters.evicted_torrents, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct TopTorrent {
+    #[serde(serialize_with = "serialize_info_hash")]
+    pub info_hash: [u8; 20],
+    pub peers: usize,
+    /// Announces since previous torrent cleaning
+    pub announces: usize,
+}
+
+fn serialize_info_hash<S: Serializer>(
+    info_hash: &[u8; 20],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(info_hash))
+}
+
+/// Torrents with the most peers and torrents with the most announces
+///
+/// Filled in when cleaning torrents. Both lists are sorted in descending
+/// order and hold at most `limit` entries.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TopTorrents {
+    #[serde(skip)]
+    limit: usize,
+    pub by_peers: Vec<TopTorrent>,
+    pub by_announces: Vec<TopTorrent>,
+}
+
+impl TopTorrents {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            by_peers: Vec::with_capacity(limit + 1),
+            by_announces: Vec::with_capacity(limit + 1),
+        }
+    }
+
+    pub fn add(&mut self, torrent: TopTorrent) {
+        Self::insert(&mut self.by_peers, self.limit, torrent, |t| t.peers);
+        Self::insert(&mut self.by_announces, self.limit, torrent, |t| t.announces);
+    }
+
+    /// Merge with lists collected from a disjoint set of torrents
+    pub fn merge(&mut self, other: &Self) {
+        for torrent in other.by_peers.iter() {
+            Self::insert(&mut self.by_peers, self.limit, *torrent, |t| t.peers);
+        }
+        for torrent in other.by_announces.iter() {
+            Self::insert(&mut self.by_announces, self.limit, *torrent, |t| {
+                t.announces
+            });
+        }
+    }
+
+    /// Iterate over list name, rank (starting at 1), torrent and value for
+    /// both lists
+    pub fn iter_ranked(&self) -> impl Iterator<Item = (&'static str, usize, &TopTorrent, usize)> {
+        let by_peers = self
+            .by_peers
+            .iter()
+            .enumerate()
+            .map(|(i, t)| ("peers", i + 1, t, t.peers));
+        let by_announces = self
+            .by_announces
+            .iter()
+            .enumerate()
+            .map(|(i, t)| ("announces", i + 1, t, t.announces));
+
+        by_peers.chain(by_announces)
+    }
+
+    fn insert(
+        list: &mut Vec<TopTorrent>,
+        limit: usize,
+        torrent: TopTorrent,
+        key: impl Fn(&TopTorrent) -> usize,
+    ) {
+        let value = key(&torrent);
+
+        if value == 0 {
+            return;
+        }
+        // Fast path for the vast majority of torrents
+        if list.len() == limit && list.last().map_or(true, |t| key(t) >= value) {
+            return;
+        }
+
+        let index = list.partition_point(|t| key(t) >= value);
+
+        list.insert(index, torrent);
+        list.truncate(limit);
+    }
+}
+
+/// Exports top torrents of one IP version as metrics
+///
+/// Counts are exported as `aquatic_top_torrents` gauges labelled only by
+/// list and rank, so there are at most 2 × `top_torrents` of them. Info
+/// hashes are carried by `aquatic_top_torrent_info` gauges set to 1. Info
+/// gauges that no longer match a list position are set to 0 on the next
+/// export (and are then removed by the exporter idle timeout), so at most
+/// 2 × `top_torrents` of them are nonzero.
+#[cfg(feature = "metrics")]
+pub struct TopTorrentMetrics {
+    ip_version: &'static str,
+    /// List name, rank and info hash of info gauges set to 1 on previous
+    /// export
+    exported: Vec<(&'static str, usize, [u8; 20])>,
+}
+
+#[cfg(feature = "metrics")]
+impl TopTorrentMetrics {
+    pub fn new(ip_version: &'static str) -> Self {
+        Self {
+            ip_version,
+            exported: Vec::new(),
+        }
+    }
+
+    pub fn export(&mut self, top_torrents: &TopTorrents) {
+        let current = top_torrents
+            .iter_ranked()
+            .map(|(list, rank, torrent, _)| (list, rank, torrent.info_hash))
+            .collect::<Vec<_>>();
+
+        for &(list, rank, info_hash) in self.exported.iter() {
+            if !current.contains(&(list, rank, info_hash)) {
+                self.info_gauge(list, rank, info_hash).set(0.0);
+            }
+            if !current.iter().any(|&(l, r, _)| (l, r) == (list, rank)) {
+                self.value_gauge(list, rank).set(0.0);
+            }
+        }
+
+        for (list, rank, torrent, value) in top_torrents.iter_ranked() {
+            self.value_gauge(list, rank).set(value as f64);
+            self.info_gauge(list, rank, torrent.info_hash).set(1.0);
+        }
+
+        self.exported = current;
+    }
+
+    fn value_gauge(&self, list: &'static str, rank: usize) -> ::metrics::Gauge {
+        ::metrics::gauge!(
+            "aquatic_top_torrents",
+            "list" => list,
+            "rank" => rank.to_string(),
+            "ip_version" => self.ip_version,
+        )
+    }
+
+    fn info_gauge(&self, list: &'static str, rank: usize, info_hash: [u8; 20]) -> ::metrics::Gauge {
+        ::metrics::gauge!(
+            "aquatic_top_torrent_info",
+            "list" => list,
+            "rank" => rank.to_string(),
+            "info_hash" => hex::encode(info_hash),
+            "ip_version" => self.ip_version,
+        )
+    }
+}
+
+/// Exports top torrents of all swarm workers as metrics
+///
+/// For trackers where each swarm worker fills in its own lists. The latest
+/// lists of all workers are merged on every update, so that metric labels
+/// don't need to include the worker index.
+#[cfg(feature = "metrics")]
+#[derive(Clone)]
+pub struct SwarmTopTorrentMetrics {
+    limit: usize,
+    swarm: Vec<CachePaddedArc<IpVersionStatistics<SwarmWorkerStatistics>>>,
+    metrics: Arc<Mutex<IpVersionStatistics<TopTorrentMetrics>>>,
+}
+
+#[cfg(feature = "metrics")]
+impl SwarmTopTorrentMetrics {
+    pub fn new(config: &StatisticsConfig, statistics: &Statistics) -> Self {
+        Self {
+            limit: config.top_torrents,
+            swarm: statistics.swarm.clone(),
+            metrics: Arc::new(Mutex::new(IpVersionStatistics {
+                ipv4: TopTorrentMetrics::new("4"),
+                ipv6: TopTorrentMetrics::new("6"),
+            })),
+        }
+    }
+
+    /// Call after storing lists in swarm worker statistics
+    pub fn update(&self, ipv4: bool) {
+        // Merge while holding lock so that exports happen in order
+        let mut metrics = self.metrics.lock().unwrap();
+        let mut top_torrents = TopTorrents::new(self.limit);
+
+        for statistics in self.swarm.iter().map(|s| s.get(ipv4)) {
+            top_torrents.merge(&statistics.top_torrents.lock().unwrap());
+        }
+
+        metrics.get_mut(ipv4).export(&top_torrents);
+    }
+}
+
+/// Number of peer ids per BitTorrent client
+///
+/// Peer ids are only counted once, even if they are added multiple times
+/// (e.g., because they are in multiple torrents).
+///
+/// Counts per peer id can temporarily be negative when updates from
+/// different workers are applied out of order.
+#[derive(Clone, Default)]
+pub struct PeerClientCounter(Arc<Mutex<IndexMap<PeerId, (isize, PeerClient)>>>);
+
+impl PeerClientCounter {
+    pub fn add(&self, peer_id: PeerId) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(peer_id)
+            .or_insert_with(|| (0, peer_id.client()))
+            .0 += 1;
+    }
+
+    pub fn remove(&self, peer_id: PeerId) {
+        Self::update(&mut self.0.lock().unwrap(), peer_id, -1);
+    }
+
+    /// Apply and clear updates collected by a worker
+    pub fn apply(&self, updates: &mut PeerClientUpdates) {
+        if updates.0.is_empty() {
+            return;
+        }
+
+        let mut peers = self.0.lock().unwrap();
+
+        for (peer_id, delta) in updates.0.drain(..) {
+            Self::update(&mut peers, peer_id, delta);
+        }
+    }
+
+    fn update(peers: &mut IndexMap<PeerId, (isize, PeerClient)>, peer_id: PeerId, delta: isize) {
+        if delta == 0 {
+            return;
+        }
+
+        let (count, _) = peers
+            .entry(peer_id)
+            .or_insert_with(|| (0, peer_id.client()));
+
+        *count += delta;
+
+        if *count == 0 {
+            peers.swap_remove(&peer_id);
+        }
+    }
+
+    /// Number of peer ids per client, most common first
+    pub fn client_counts(&self) -> Vec<(PeerClient, usize)> {
+        let mut clients: IndexMap<PeerClient, usize> = IndexMap::default();
+
+        {
+            let mut peers = self.0.lock().unwrap();
+
+            for (_, client) in peers.values().filter(|(count, _)| *count > 0) {
+                *clients.entry(client.to_owned()).or_insert(0) += 1;
+            }
+
+            peers.shrink_to_fit();
+        }
+
+        clients.sort_unstable_by(|_, a, _, b| b.cmp(a));
+
+        clients.into_iter().collect()
+    }
+}
+
+/// Changes to peer client counts collected by a single worker
+///
+/// Applied to the shared [`PeerClientCounter`] in batches, so that workers
+/// handling many requests don't need to lock it for every change.
+#[derive(Default)]
+pub struct PeerClientUpdates(IndexMap<PeerId, isize>);
+
+impl PeerClientUpdates {
+    pub fn add(&mut self, peer_id: PeerId) {
+        *self.0.entry(peer_id).or_insert(0) += 1;
+    }
+
+    pub fn remove(&mut self, peer_id: PeerId) {
+        *self.0.entry(peer_id).or_insert(0) -= 1;
+    }
+}
+
+/// Add peer client definitions from file to the registry used for
+/// identifying peer clients
+///
+/// Needs to be called before workers are started. Does nothing if path is
+/// empty.
+pub fn load_peer_client_definitions(path: &Path) -> anyhow::Result<()> {
+    if path.as_os_str().is_empty() {
+        return Ok(());
+    }
+
+    let contents = ::std::fs::read_to_string(path)
+        .with_context(|| format!("read peer client definitions from {}", path.display()))?;
+    let definitions: ClientDefinitions = ::toml::from_str(&contents)
+        .with_context(|| format!("parse peer client definitions in {}", path.display()))?;
+
+    let mut registry = ClientRegistry::builtin();
+
+    for definition in definitions.clients {
+        registry.add(definition)?;
+    }
+
+    aquatic_peer_id::set_client_registry(registry)
+        .map_err(|_| anyhow::anyhow!("peer client registry already in use"))?;
+
+    Ok(())
+}
+
+/// Tracker information needed by statistics worker
+#[derive(Clone, Debug)]
+pub struct TrackerInfo {
+    /// Used as title of statistics page
+    pub title: &'static str,
+    pub ipv4_active: bool,
+    pub ipv6_active: bool,
+    /// Report offer and answer counts
+    pub webtorrent: bool,
+    /// How often swarm workers update torrent and peer counts (seconds)
+    pub peer_update_interval: u64,
+    /// Report torrents and peers rejected or evicted because of limits
+    pub torrent_limits: bool,
+    /// Report peers rejected because of per-address peer limit
+    pub peer_ip_limits: bool,
+}
+
+pub fn run_statistics_worker(
+    config: StatisticsConfig,
+    tracker_info: TrackerInfo,
+    statistics: Statistics,
+) -> anyhow::Result<()> {
+    let opt_template = if config.write_html_to_file | config.run_http_server {
+        Some(PageTemplate::new()?)
+    } else {
+        None
+    };
+
+    let opt_pages = if config.run_http_server {
+        let pages = StatisticsPages::default();
+
+        spawn_http_server(config.http_server_address, pages.clone())?;
+
+        Some(pages)
+    } else {
+        None
+    };
+
+    let mut last_update = Instant::now();
+
+    loop {
+        sleep(Duration::from_secs(config.interval));
+
+        let elapsed = {
+            let now = Instant::now();
+            let elapsed = (now - last_update).as_secs_f64();
+
+            last_update = now;
+
+            elapsed
+        };
+
+        let report = Report::collect(&config, &tracker_info, &statistics, elapsed);
+
+        if config.print_to_stdout {
+            report.print_to_stdout();
+        }
+
+        if let Some(template) = opt_template.as_ref() {
+            let html = match report.render_html(&config, &tracker_info, template) {
+                Ok(html) => html,
+                Err(err) => {
+                    ::log::error!("Couldn't render statistics html: {:#}", err);
+
+                    continue;
+                }
+            };
+
+            if config.write_html_to_file {
+                if let Err(err) = save_html_to_file(&config.html_file_path, &html) {
+                    ::log::error!("Couldn't save statistics to file: {:#}", err)
+                }
+            }
+
+            if let Some(pages) = opt_pages.as_ref() {
+                match report.to_json(&config) {
+                    Ok(json) => pages.update(html, json),
+                    Err(err) => ::log::error!("Couldn't render statistics json: {:#}", err),
+                }
+            }
+        }
+    }
+}
+
+/// Write HTML to temporary file and move it into place, so that readers
+/// never see a partially written file
+pub fn save_html_to_file(path: &Path, html: &str) -> anyhow::Result<()> {
+    let tmp_path = path.with_extension("html.tmp");
+
+    let mut file = File::create(&tmp_path)
+        .with_context(|| format!("File path: {}", tmp_path.to_string_lossy()))?;
+
+    file.write_all(html.as_bytes())?;
+
+    ::std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("File path: {}", path.to_string_lossy()))?;
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Value {
+    Count(usize),
+    PerSecond(f64),
+}
+
+impl Value {
+    fn formatted(&self) -> String {
+        match self {
+            Self::Count(n) => n.to_formatted_string(&Locale::en),
+            Self::PerSecond(n) => (*n as usize).to_formatted_string(&Locale::en),
+        }
+    }
+
+    fn to_json(self) -> serde_json::Value {
+        match self {
+            Self::Count(n) => n.into(),
+            Self::PerSecond(n) => ((n * 100.0).round() / 100.0).into(),
+        }
+    }
+}
+
+struct Row {
+    key: &'static str,
+    label: &'static str,
+    value: Value,
+}
+
+struct Section {
+    key: &'static str,
+    title: &'static str,
+    rows: Vec<Row>,
+    opt_top_torrents: Option<TopTorrents>,
+}
+
+struct Report {
+    last_updated: String,
+    sections: Vec<Section>,
+    peer_clients: Vec<(PeerClient, usize)>,
+}
+
+impl Report {
+    fn collect(
+        config: &StatisticsConfig,
+        tracker_info: &TrackerInfo,
+        statistics: &Statistics,
+        elapsed: f64,
+    ) -> Self {
+        let mut sections = Vec::new();
+
+        // Always collect for both IP versions so that counters are reset
+        for ipv4 in [true, false] {
+            let section = Self::collect_section(config, tracker_info, statistics, ipv4, elapsed);
+
+            if (ipv4 & tracker_info.ipv4_active) | (!ipv4 & tracker_info.ipv6_active) {
+                sections.push(section);
+            }
+        }
+
+        let peer_clients = if config.peer_clients {
+            statistics.peer_clients.client_counts()
+        } else {
+            Vec::new()
+        };
+
+        Self {
+            last_updated: OffsetDateTime::now_utc()
+                .format(&Rfc2822)
+                .unwrap_or("(formatting error)".into()),
+            sections,
+            peer_clients,
+        }
+    }
+
+    fn collect_section(
+        config: &StatisticsConfig,
+        tracker_info: &TrackerInfo,
+        statistics: &Statistics,
+        ipv4: bool,
+        elapsed: f64,
+    ) -> Section {
+        let mut connections = 0;
+        let mut requests_announce = 0;
+        let mut requests_scrape = 0;
+        let mut responses_announce = 0;
+        let mut responses_scrape = 0;
+        let mut responses_offer = 0;
+        let mut responses_answer = 0;
+        let mut responses_error = 0;
+
+        for statistics in statistics.socket.iter().map(|s| s.get(ipv4)) {
+            connections += statistics.connections.load(Ordering::Relaxed);
+            requests_announce += statistics.requests_announce.fetch_and(0, Ordering::Relaxed);
+            requests_scrape += statistics.requests_scrape.fetch_and(0, Ordering::Relaxed);
+            responses_announce += statistics
+                .responses_announce
+                .fetch_and(0, Ordering::Relaxed);
+            responses_scrape += statistics.responses_scrape.fetch_and(0, Ordering::Relaxed);
+            responses_offer += statistics.responses_offer.fetch_and(0, Ordering::Relaxed);
+            responses_answer += statistics.responses_answer.fetch_and(0, Ordering::Relaxed);
+            responses_error += statistics.responses_error.fetch_and(0, Ordering::Relaxed);
+        }
+
+        let mut torrents = 0;
+        let mut peers = 0;
+        let mut rejected_torrents = 0;
+        let mut rejected_peers = 0;
+        let mut evicted_torrents = 0;
+        let mut rejected_ip_peers = 0;
+        let mut opt_top_torrents =
+            (config.top_torrents > 0).then(|| TopTorrents::new(config.top_torrents));
+
+        for statistics in statistics.swarm.iter().map(|s| s.get(ipv4)) {
+            torrents += statistics.torrents.load(Ordering::Relaxed);
+            peers += statistics.peers.load(Ordering::Relaxed);
+            rejected_torrents += statistics.rejected_torrents.load(Ordering::Relaxed);
+            rejected_peers += statistics.rejected_peers.load(Ordering::Relaxed);
+            evicted_torrents += statistics.evicted_torrents.load(Ordering::Relaxed);
+            rejected_ip_peers += statistics.rejected_ip_peers.load(Ordering::Relaxed);
+
+            if let Some(top_torrents) = opt_top_torrents.as_mut() {
+                top_torrents.merge(&statistics.top_torrents.lock().unwrap());
+            }
+        }
+
+        let per_second = |n: usize| Value::PerSecond(n as f64 / elapsed);
+
+        let mut rows = vec![
+            Row {
+                key: "torrents",
+                label: "Number of torrents",
+                value: Value::Count(torrents),
+            },
+            Row {
+                key: "peers",
+                label: "Number of peers",
+                value: Value::Count(peers),
+            },
+            Row {
+                key: "connections",
+                label: "Open connections",
+                value: Value::Count(connections),
+            },
+            Row {
+                key: "announce_requests_per_second",
+                label: "Announce requests / second",
+                value: per_second(requests_announce),
+            },
+            Row {
+                key: "scrape_requests_per_second",
+                label: "Scrape requests / second",
+                value: per_second(requests_scrape),
+            },
+            Row {
+                key: "announce_responses_per_second",
+                label: "Announce responses / second",
+                value: per_second(responses_announce),
+            },
+            Row {
+                key: "scrape_responses_per_second",
+                label: "Scrape responses / second",
+                value: per_second(responses_scrape),
+            },
+        ];
+
+        if tracker_info.torrent_limits {
+            rows.push(Row {
+                key: "rejected_torrents",
+                label: "Rejected torrents (total)",
+                value: Value::Count(rejected_torrents),
+            });
+            rows.push(Row {
+                key: "rejected_peers",
+                label: "Rejected peers (total)",
+                value: Value::Count(rejected_peers),
+            });
+            rows.push(Row {
+                key: "evicted_torrents",
+                label: "Evicted torrents (total)",
+                value: Value::Count(evicted_torrents),
+            });
+        }
+
+        if tracker_info.peer_ip_limits {
+            rows.push(Row {
+                key: "rejected_ip_peers",
+                label: "Peers rejected by per-address limit (total)",
+                value: Value::Count(rejected_ip_peers),
+            });
+        }
+
+        if tracker_info.webtorrent {
+            rows.push(Row {
+                key: "offers_per_second",
+                label: "Offers / second",
+                value: per_second(responses_offer),
+            });
+            rows.push(Row {
+                key: "answers_per_second",
+                label: "Answers / second",
+                value: per_second(responses_answer),
+            });
+        }
+
+        rows.push(Row {
+            key: "error_responses_per_second",
+            label: "Error responses / second",
+            value: per_second(responses_error),
+        });
+
+        if ipv4 {
+            Section {
+                key: "ipv4",
+                title: "IPv4",
+                rows,
+                opt_top_torrents,
+            }
+        } else {
+            Section {
+                key: "ipv6",
+                title: "IPv6",
+                rows,
+                opt_top_torrents,
+            }
+        }
+    }
+
+    fn print_to_stdout(&self) {
+        for section in self.sections.iter() {
+            println!("{}:", section.title);
+
+            for row in section.rows.iter() {
+                println!(
+                    "  {:<30} {:>12}",
+                    format!("{}:", row.label),
+                    row.value.formatted()
+                );
+            }
+        }
+
+        println!();
+    }
+
+    fn render_html(
+        &self,
+        config: &StatisticsConfig,
+        tracker_info: &TrackerInfo,
+        template: &PageTemplate,
+    ) -> anyhow::Result<String> {
+        let peer_clients = self
+            .peer_clients
+            .iter()
+            .map(|(client, count)| (client.to_string(), count.to_formatted_string(&Locale::en)))
+            .collect::<Vec<_>>();
+
+        let page = Page {
+            title: tracker_info.title,
+            homepage_url: &config.homepage_url,
+            tracker_url: &config.tracker_url,
+            last_updated: &self.last_updated,
+            peer_update_interval: tracker_info.peer_update_interval,
+            sections: self
+                .sections
+                .iter()
+                .map(|section| PageSection {
+                    title: section.title,
+                    rows: section
+                        .rows
+                        .iter()
+                        .map(|row| (row.label, row.value.formatted()))
+                        .collect(),
+                    top_torrents: section.opt_top_torrents.as_ref(),
+                    tables: Vec::new(),
+                })
+                .collect(),
+            peer_clients_active: config.peer_clients,
+            peer_clients: &peer_clients,
+        };
+
+        template.render(&page)
+    }
+
+    fn to_json(&self, config: &StatisticsConfig) -> anyhow::Result<String> {
+        let mut data = serde_json::Map::new();
+
+        data.insert("last_updated".into(), self.last_updated.clone().into());
+
+        for section in self.sections.iter() {
+            let mut rows = section
+                .rows
+                .iter()
+                .map(|row| (row.key.to_string(), row.value.to_json()))
+                .collect::<serde_json::Map<_, _>>();
+
+            if let Some(top_torrents) = section.opt_top_torrents.as_ref() {
+                rows.insert("top_torrents".into(), serde_json::to_value(top_torrents)?);
+            }
+
+            data.insert(section.key.into(), rows.into());
+        }
+
+        if config.peer_clients {
+            let peer_clients = self
+                .peer_clients
+                .iter()
+                .map(|(client, count)| {
+                    serde_json::json!({
+                        "client": client.to_string(),
+                        "count": count,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            data.insert("peer_clients".into(), peer_clients.into());
+        }
+
+        Ok(serde_json::to_string(&data)?)
+    }
+}
+
+/// Statistics page template, shared by all trackers
+pub struct PageTemplate(TinyTemplate<'static>);
+
+impl PageTemplate {
+    pub fn new() -> anyhow::Result<Self> {
+        let mut tt = TinyTemplate::new();
+
+        tt.add_template(TEMPLATE_KEY, TEMPLATE_CONTENTS)
+            .context("parse statistics html template")?;
+
+        Ok(Self(tt))
+    }
+
+    pub fn render(&self, page: &Page) -> anyhow::Result<String> {
+        #[derive(Serialize)]
+        struct TemplateData<'a> {
+            stylesheet: &'static str,
+            #[serde(flatten)]
+            page: &'a Page<'a>,
+        }
+
+        let template_data = TemplateData {
+            stylesheet: STYLESHEET,
+            page,
+        };
+
+        Ok(self.0.render(TEMPLATE_KEY, &template_data)?)
+    }
+}
+
+/// Contents of statistics page
+#[derive(Serialize)]
+pub struct Page<'a> {
+    pub title: &'static str,
+    pub homepage_url: &'a str,
+    pub tracker_url: &'a str,
+    pub last_updated: &'a str,
+    /// How often torrent and peer counts are updated (seconds)
+    pub peer_update_interval: u64,
+    pub sections: Vec<PageSection<'a>>,
+    pub peer_clients_active: bool,
+    /// Formatted count per peer client
+    pub peer_clients: &'a [(String, String)],
+}
+
+/// Statistics for one IP version
+#[derive(Serialize)]
+pub struct PageSection<'a> {
+    pub title: &'static str,
+    /// Label and formatted value of each row in main table
+    pub rows: Vec<(&'static str, String)>,
+    pub top_torrents: Option<&'a TopTorrents>,
+    /// Tracker specific tables, shown after the others
+    pub tables: Vec<PageTable>,
+}
+
+#[derive(Serialize)]
+pub struct PageTable {
+    pub title: &'static str,
+    pub caption: String,
+    /// Column headers, including one for row labels (leave empty for none)
+    pub columns: Vec<&'static str>,
+    /// Label and formatted values of each row
+    pub rows: Vec<(&'static str, Vec<String>)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_client_counter() {
+        let counter = PeerClientCounter::default();
+
+        let mut a = [0u8; 20];
+        let mut b = [0u8; 20];
+
+        a[..8].copy_from_slice(b"-lt0D80-");
+        b[..8].copy_from_slice(b"-qB4250-");
+
+        counter.add(PeerId(a));
+        counter.add(PeerId(a));
+        counter.add(PeerId(b));
+
+        let counts = counter.client_counts();
+
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[0].1, 1);
+        assert_eq!(counts[1].1, 1);
+
+        counter.remove(PeerId(a));
+
+        assert_eq!(counter.client_counts().len(), 2);
+
+        counter.remove(PeerId(a));
+        counter.remove(PeerId(b));
+
+        assert!(counter.client_counts().is_empty());
+    }
+
+    #[test]
+    fn test_peer_client_updates() {
+        let counter = PeerClientCounter::default();
+
+        let mut a = [0u8; 20];
+        let mut b = [0u8; 20];
+
+        a[..8].copy_from_slice(b"-lt0D80-");
+        b[..8].copy_from_slice(b"-qB4250-");
+
+        let mut worker_1 = PeerClientUpdates::default();
+        let mut worker_2 = PeerClientUpdates::default();
+
+        worker_1.add(PeerId(a));
+        worker_1.add(PeerId(b));
+        worker_1.remove(PeerId(b));
+        worker_2.add(PeerId(a));
+
+        counter.apply(&mut worker_1);
+
+        assert_eq!(counter.client_counts().len(), 1);
+        assert!(worker_1.0.is_empty());
+
+        // Removal applied before addition of same peer id by other worker
+        worker_1.remove(PeerId(a));
+        worker_1.remove(PeerId(a));
+        counter.apply(&mut worker_1);
+
+        assert!(counter.client_counts().is_empty());
+
+        counter.apply(&mut worker_2);
+
+        assert!(counter.client_counts().is_empty());
+        assert!(counter.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_render_page() {
+        let peer_clients = vec![("qBittorrent 4.2.5".to_string(), "1".to_string())];
+
+        let page = Page {
+            title: "Tracker statistics",
+            homepage_url: "",
+            tracker_url: "",
+            last_updated: "",
+            peer_update_interval: 60,
+            sections: vec![PageSection {
+                title: "IPv4",
+                rows: vec![("Number of peers", "10".into())],
+                top_torrents: None,
+                tables: vec![PageTable {
+                    title: "Request latency",
+                    caption: String::new(),
+                    columns: vec!["Response type", "p50"],
+                    rows: vec![("Announce", vec!["123".into()])],
+                }],
+            }],
+            peer_clients_active: true,
+            peer_clients: &peer_clients,
+        };
+
+        let html = PageTemplate::new().unwrap().render(&page).unwrap();
+
+        for expected in [
+            "<title>Tracker statistics</title>",
+            "<td>10</td>",
+            "<h3>Request latency</h3>",
+            "<th>p50</th>",
+            "<td>123</td>",
+            "<td>qBittorrent 4.2.5</td>",
+        ] {
+            assert!(html.contains(expected), "{} not in html", expected);
+        }
+    }
+
+    #[test]
+    fn test_top_torrents() {
+        let torrent = |i: u8, peers, announces| TopTorrent {
+            info_hash: [i; 20],
+            peers,
+            announces,
+        };
+
+        let mut a = TopTorrents::new(2);
+
+        a.add(torrent(0, 5, 0));
+        a.add(torrent(1, 1, 10));
+        a.add(torrent(2, 3, 2));
+        a.add(torrent(3, 0, 0));
+
+        assert_eq!(a.by_peers, vec![torrent(0, 5, 0), torrent(2, 3, 2)]);
+        assert_eq!(a.by_announces, vec![torrent(1, 1, 10), torrent(2, 3, 2)]);
+
+        let mut b = TopTorrents::new(2);
+
+        b.add(torrent(4, 4, 20));
+
+        a.merge(&b);
+
+        assert_eq!(a.by_peers, vec![torrent(0, 5, 0), torrent(4, 4, 20)]);
+        assert_eq!(a.by_announces, vec![torrent(4, 4, 20), torrent(1, 1, 10)]);
+    }
+}
@@ -0,0 +1,309 @@
+//! Push metrics to a StatsD (or DogStatsD) server over UDP
+//!
+//! Metrics are recorded through the `metrics` facade, just like for the
+//! prometheus endpoint. Counters are sent as the increase since the previous
+//! push and gauges as their current value.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use metrics::Key;
+use metrics_util::MetricKindMask;
+use serde::{Deserialize, Serialize};
+
+use crate::push_recorder::PushRecorder;
+
+/// Keep datagrams small enough to not be fragmented on common networks
+const MAX_PACKET_SIZE: usize = 1432;
+
+/// StatsD line format. Available formats are statsd and dogstatsd.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsdFormat {
+    /// Plain StatsD without tags. Metric labels are appended to the name as
+    /// `.key.value` and configured tags are ignored.
+    Statsd,
+    /// DogStatsD, sending metric labels and configured tags as tags
+    Dogstatsd,
+}
+
+/// Exporter settings, built from tracker configuration
+#[derive(Clone, Debug)]
+pub struct StatsdConfig {
+    /// Address of StatsD server
+    pub address: SocketAddr,
+    /// Prepended to all metric names
+    pub prefix: String,
+    /// Comma-separated tags added to all metrics, e.g. `env:prod,region:eu`
+    pub tags: String,
+    pub format: StatsdFormat,
+    /// Push metrics this often (seconds)
+    pub push_interval: u64,
+}
+
+/// Spawn thread regularly pushing metrics to StatsD server
+///
+/// The returned recorder needs to be installed, either with
+/// `install_push_recorders` or through `spawn_prometheus_endpoint`.
+pub fn spawn_statsd_exporter(
+    config: StatsdConfig,
+    idle_timeout: Option<Duration>,
+    idle_timeout_mask: Option<MetricKindMask>,
+) -> anyhow::Result<(PushRecorder, JoinHandle<anyhow::Result<()>>)> {
+    let bind_addr = if config.address.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+
+    let socket = UdpSocket::bind(bind_addr).context("bind statsd socket")?;
+
+    socket
+        .connect(config.address)
+        .with_context(|| format!("connect statsd socket to {}", config.address))?;
+
+    let recorder = PushRecorder::new(idle_timeout, idle_timeout_mask);
+
+    let mut exporter = Exporter {
+        socket,
+        recorder: recorder.clone(),
+        formatter: Formatter::new(&config),
+        previous_counter_values: Default::default(),
+    };
+    let interval = Duration::from_secs(config.push_interval.max(1));
+
+    let handle = Builder::new()
+        .name("statsd".into())
+        .spawn(move || loop {
+            ::std::thread::sleep(interval);
+
+            if let Err(err) = exporter.push() {
+                ::log::warn!("couldn't push metrics to statsd server: {:#}", err);
+            }
+        })
+        .context("spawn statsd exporter")?;
+
+    Ok((recorder, handle))
+}
+
+struct Exporter {
+    socket: UdpSocket,
+    recorder: PushRecorder,
+    formatter: Formatter,
+    previous_counter_values: HashMap<Key, u64>,
+}
+
+impl Exporter {
+    fn push(&mut self) -> anyhow::Result<()> {
+        for packet in self.render_packets() {
+            self.socket
+                .send(packet.as_bytes())
+                .context("send statsd packet")?;
+        }
+
+        Ok(())
+    }
+
+    /// Render metric lines, joined into packets of at most MAX_PACKET_SIZE
+    /// bytes (unless a single line is longer)
+    // Key only uses interior mutability to cache its hash
+    #[allow(clippy::mutable_key_type)]
+    fn render_packets(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut gauge_lines = Vec::new();
+        let mut counter_values = HashMap::with_capacity(self.previous_counter_values.len());
+
+        let formatter = &self.formatter;
+        let previous_counter_values = &self.previous_counter_values;
+
+        self.recorder.visit(
+            |key, value| {
+                let previous = previous_counter_values.get(key).copied().unwrap_or(0);
+                let delta = value.saturating_sub(previous);
+
+                if delta != 0 {
+                    lines.push(formatter.line(key, &delta.to_string(), "c"));
+                }
+
+                counter_values.insert(key.clone(), value);
+            },
+            |key, value| {
+                // Values with a sign are interpreted as changes to the current
+                // value, so reset gauge before sending negative values
+                if value < 0.0 {
+                    gauge_lines.push(formatter.line(key, "0", "g"));
+                }
+
+                gauge_lines.push(formatter.line(key, &value.to_string(), "g"));
+            },
+        );
+
+        lines.append(&mut gauge_lines);
+
+        // Only keep values for metrics that are still present
+        self.previous_counter_values = counter_values;
+
+        let mut packets = Vec::new();
+        let mut packet = String::new();
+
+        for line in lines {
+            if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_SIZE {
+                packets.push(::std::mem::take(&mut packet));
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+
+            packet.push_str(&line);
+        }
+
+        if !packet.is_empty() {
+            packets.push(packet);
+        }
+
+        packets
+    }
+}
+
+struct Formatter {
+    prefix: String,
+    tags: Vec<String>,
+    format: StatsdFormat,
+}
+
+impl Formatter {
+    fn new(config: &StatsdConfig) -> Self {
+        let tags = config
+            .tags
+            .split(',')
+            .map(|tag| tag.trim())
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| tag.to_string())
+            .collect();
+
+        Self {
+            prefix: config.prefix.clone(),
+            tags,
+            format: config.format,
+        }
+    }
+
+    fn line(&self, key: &Key, value: &str, metric_type: &str) -> String {
+        let mut line = format!("{}{}", self.prefix, key.name());
+
+        match self.format {
+            StatsdFormat::Statsd => {
+                for label in key.labels() {
+                    write!(line, ".{}.{}", label.key(), label.value()).unwrap();
+                }
+
+                write!(line, ":{}|{}", value, metric_type).unwrap();
+            }
+            StatsdFormat::Dogstatsd => {
+                write!(line, ":{}|{}", value, metric_type).unwrap();
+
+                let mut tags = self
+                    .tags
+                    .iter()
+                    .cloned()
+                    .chain(
+                        key.labels()
+                            .map(|label| format!("{}:{}", label.key(), label.value())),
+                    )
+                    .peekable();
+
+                if tags.peek().is_some() {
+                    line.push_str("|#");
+                    line.push_str(&tags.collect::<Vec<_>>().join(","));
+                }
+            }
+        }
+
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(format: StatsdFormat) -> StatsdConfig {
+        StatsdConfig {
+            address: SocketAddr::from(([127, 0, 0, 1], 8125)),
+            prefix: "tracker.".into(),
+            tags: "env:test, region:eu".into(),
+            format,
+            push_interval: 10,
+        }
+    }
+
+    #[test]
+    fn test_push_to_stub_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        socket.connect(server.local_addr().unwrap()).unwrap();
+
+        let recorder = PushRecorder::new(None, None);
+
+        let mut exporter = Exporter {
+            socket,
+            recorder: recorder.clone(),
+            formatter: Formatter::new(&config(StatsdFormat::Dogstatsd)),
+            previous_counter_values: Default::default(),
+        };
+
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+
+        let mut push_and_receive = |exporter: &mut Exporter| {
+            exporter.push().unwrap();
+
+            let len = server.recv(&mut buf).unwrap();
+
+            String::from_utf8(buf[..len].to_vec()).unwrap()
+        };
+
+        ::metrics::with_local_recorder(&recorder, || {
+            ::metrics::counter!("aquatic_requests_total", "ip_version" => "4").increment(3);
+            ::metrics::gauge!("aquatic_peers", "ip_version" => "6").set(-5.0);
+        });
+
+        assert_eq!(
+            push_and_receive(&mut exporter),
+            "tracker.aquatic_requests_total:3|c|#env:test,region:eu,ip_version:4\n\
+             tracker.aquatic_peers:0|g|#env:test,region:eu,ip_version:6\n\
+             tracker.aquatic_peers:-5|g|#env:test,region:eu,ip_version:6"
+        );
+
+        ::metrics::with_local_recorder(&recorder, || {
+            ::metrics::counter!("aquatic_requests_total", "ip_version" => "4").increment(2);
+            ::metrics::gauge!("aquatic_peers", "ip_version" => "6").set(5.0);
+        });
+
+        // Counters are sent as increase since previous push
+        assert_eq!(
+            push_and_receive(&mut exporter),
+            "tracker.aquatic_requests_total:2|c|#env:test,region:eu,ip_version:4\n\
+             tracker.aquatic_peers:5|g|#env:test,region:eu,ip_version:6"
+        );
+    }
+
+    #[test]
+    fn test_plain_statsd_line() {
+        let formatter = Formatter::new(&config(StatsdFormat::Statsd));
+        let key = Key::from_parts(
+            "aquatic_peers",
+            vec![metrics::Label::new("ip_version", "4")],
+        );
+
+        assert_eq!(
+            formatter.line(&key, "10", "g"),
+            "tracker.aquatic_peers.ip_version.4:10|g"
+        );
+    }
+}
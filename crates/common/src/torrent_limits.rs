@@ -0,0 +1,327 @@
+//! Limits on the number of torrents and peers stored by trackers
+
+use aquatic_toml_config::TomlConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::ValidUntil;
+
+/// Number of torrents to consider when choosing one to evict in the
+/// announce path
+pub const EVICTION_SAMPLE_SIZE: usize = 8;
+
+/// What to do when a torrent or peer limit is reached
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// Don't store new torrents or peers
+    RejectNew,
+    /// Remove torrents that were announced to least recently
+    LeastRecentlyAnnounced,
+    /// Remove torrents with the fewest peers
+    SmallestSwarm,
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TorrentLimitConfig {
+    /// Maximum number of torrents per IP version (0 for no limit)
+    ///
+    /// If the tracker has multiple swarm workers, the limit is divided
+    /// between them and they enforce their share separately. If it is lower
+    /// than the number of workers, some of them don't store any torrents.
+    pub max_torrents: usize,
+    /// Maximum number of peers per IP version (0 for no limit)
+    ///
+    /// Divided in the same way as the torrent limit.
+    pub max_peers: usize,
+    /// What to do when a limit is reached: reject_new,
+    /// least_recently_announced or smallest_swarm
+    ///
+    /// With the eviction policies, a small random sample of torrents is
+    /// considered when making room in the announce path, while torrent
+    /// cleaning evicts torrents in exact policy order until all limits are
+    /// met.
+    pub eviction_policy: EvictionPolicy,
+}
+
+impl TorrentLimitConfig {
+    pub fn is_active(&self) -> bool {
+        (self.max_torrents != 0) | (self.max_peers != 0)
+    }
+
+    /// Limits for part `part_index` of `num_parts` torrent maps sharing the
+    /// configured limits
+    ///
+    /// Limits are divided so that the parts together never exceed them, with
+    /// the remainder going to the parts with the lowest indices.
+    pub fn split(&self, num_parts: usize, part_index: usize) -> TorrentLimits {
+        let num_parts = num_parts.max(1);
+
+        let split = |limit: usize| {
+            if limit == 0 {
+                usize::MAX
+            } else {
+                limit / num_parts + usize::from(part_index < limit % num_parts)
+            }
+        };
+
+        TorrentLimits {
+            max_torrents: split(self.max_torrents),
+            max_peers: split(self.max_peers),
+            eviction_policy: self.eviction_policy,
+        }
+    }
+}
+
+impl Default for TorrentLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_torrents: 0,
+            max_peers: 0,
+            eviction_policy: EvictionPolicy::RejectNew,
+        }
+    }
+}
+
+/// Limits enforced by a single torrent map
+#[derive(Clone, Copy, Debug)]
+pub struct TorrentLimits {
+    pub max_torrents: usize,
+    pub max_peers: usize,
+    pub eviction_policy: EvictionPolicy,
+}
+
+impl TorrentLimits {
+    pub fn limits_peers(&self) -> bool {
+        self.max_peers != usize::MAX
+    }
+
+    pub fn torrent_limit_reached(&self, num_torrents: usize) -> bool {
+        num_torrents >= self.max_torrents
+    }
+
+    pub fn peer_limit_reached(&self, num_peers: usize) -> bool {
+        num_peers >= self.max_peers
+    }
+
+    pub fn rejects_new(&self) -> bool {
+        self.eviction_policy == EvictionPolicy::RejectNew
+    }
+
+    pub fn exceeded(&self, num_torrents: usize, num_peers: usize) -> bool {
+        (num_torrents > self.max_torrents) | (num_peers > self.max_peers)
+    }
+
+    /// Choose torrent to evict among sampled candidates
+    ///
+    /// Returns None if policy is to reject new torrents and peers.
+    pub fn select_eviction<K>(
+        &self,
+        candidates: impl IntoIterator<Item = EvictionCandidate<K>>,
+    ) -> Option<K> {
+        match self.eviction_policy {
+            EvictionPolicy::RejectNew => None,
+            EvictionPolicy::LeastRecentlyAnnounced => candidates
+                .into_iter()
+                .min_by_key(|c| c.last_announce)
+                .map(|c| c.key),
+            EvictionPolicy::SmallestSwarm => candidates
+                .into_iter()
+                .min_by_key(|c| (c.num_peers, c.last_announce))
+                .map(|c| c.key),
+        }
+    }
+
+    /// Choose torrents to evict so that limits are met after removing them
+    ///
+    /// Torrents are chosen in policy order until limits are met. Chosen
+    /// torrents that can be kept without exceeding limits again (such as
+    /// small ones picked before a large one that made room on its own) are
+    /// then dropped from the selection, latest first.
+    ///
+    /// Returns an empty list if policy is to reject new torrents and peers.
+    pub fn select_evictions<K>(&self, mut candidates: Vec<EvictionCandidate<K>>) -> Vec<K> {
+        match self.eviction_policy {
+            EvictionPolicy::RejectNew => return Vec::new(),
+            EvictionPolicy::LeastRecentlyAnnounced => {
+                candidates.sort_unstable_by_key(|c| c.last_announce)
+            }
+            EvictionPolicy::SmallestSwarm => {
+                candidates.sort_unstable_by_key(|c| (c.num_peers, c.last_announce))
+            }
+        }
+
+        let mut num_torrents = candidates.len();
+        let mut num_peers: usize = candidates.iter().map(|c| c.num_peers).sum();
+        let mut num_selected = 0;
+
+        for candidate in candidates.iter() {
+            if !self.exceeded(num_torrents, num_peers) {
+                break;
+            }
+
+            num_torrents -= 1;
+            num_peers -= candidate.num_peers;
+            num_selected += 1;
+        }
+
+        candidates.truncate(num_selected);
+
+        let mut evictions = Vec::with_capacity(num_selected);
+
+        for candidate in candidates.into_iter().rev() {
+            if self.exceeded(num_torrents + 1, num_peers + candidate.num_peers) {
+                evictions.push(candidate.key);
+            } else {
+                num_torrents += 1;
+                num_peers += candidate.num_peers;
+            }
+        }
+
+        evictions.reverse();
+
+        evictions
+    }
+}
+
+/// Effect of an announce on the number of stored peers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerCountChange {
+    Unchanged,
+    Added,
+    Removed,
+    /// New peer was not stored because of peer limit
+    Rejected,
+}
+
+/// Torrents and peers rejected or evicted because of limits
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TorrentLimitCounters {
+    pub rejected_torrents: usize,
+    pub rejected_peers: usize,
+    pub evicted_torrents: usize,
+}
+
+impl TorrentLimitCounters {
+    pub fn add(&mut self, other: Self) {
+        self.rejected_torrents += other.rejected_torrents;
+        self.rejected_peers += other.rejected_peers;
+        self.evicted_torrents += other.evicted_torrents;
+    }
+}
+
+/// Torrent properties considered when choosing torrents to evict
+#[derive(Clone, Copy, Debug)]
+pub struct EvictionCandidate<K> {
+    pub key: K,
+    /// Peer validity set on latest announce, which follows announce time
+    pub last_announce: ValidUntil,
+    pub num_peers: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ServerStartInstant;
+
+    use super::*;
+
+    #[test]
+    fn test_select_evictions() {
+        let server_start_instant = ServerStartInstant::new();
+
+        let candidates = vec![
+            EvictionCandidate {
+                key: 0,
+                last_announce: ValidUntil::new(server_start_instant, 10),
+                num_peers: 5,
+            },
+            EvictionCandidate {
+                key: 1,
+                last_announce: ValidUntil::new(server_start_instant, 20),
+                num_peers: 1,
+            },
+            EvictionCandidate {
+                key: 2,
+                last_announce: ValidUntil::new(server_start_instant, 30),
+                num_peers: 2,
+            },
+        ];
+
+        let limits = |max_torrents, max_peers, eviction_policy| TorrentLimits {
+            max_torrents,
+            max_peers,
+            eviction_policy,
+        };
+
+        assert!(limits(1, 1, EvictionPolicy::RejectNew)
+            .select_evictions(candidates.clone())
+            .is_empty());
+        assert_eq!(
+            limits(2, usize::MAX, EvictionPolicy::LeastRecentlyAnnounced)
+                .select_evictions(candidates.clone()),
+            vec![0]
+        );
+        assert_eq!(
+            limits(2, usize::MAX, EvictionPolicy::SmallestSwarm)
+                .select_evictions(candidates.clone()),
+            vec![1]
+        );
+        // Evicting the largest torrent is enough, so the smaller ones
+        // chosen before it are kept
+        assert_eq!(
+            limits(usize::MAX, 3, EvictionPolicy::SmallestSwarm)
+                .select_evictions(candidates.clone()),
+            vec![0]
+        );
+        assert_eq!(
+            limits(usize::MAX, 6, EvictionPolicy::SmallestSwarm)
+                .select_evictions(candidates.clone()),
+            vec![2]
+        );
+        assert_eq!(
+            limits(2, 6, EvictionPolicy::SmallestSwarm).select_evictions(candidates.clone()),
+            vec![2]
+        );
+        assert_eq!(
+            limits(2, 7, EvictionPolicy::SmallestSwarm).select_evictions(candidates.clone()),
+            vec![1]
+        );
+        assert_eq!(
+            limits(usize::MAX, 3, EvictionPolicy::LeastRecentlyAnnounced)
+                .select_evictions(candidates.clone()),
+            vec![0]
+        );
+        assert_eq!(
+            limits(1, 1, EvictionPolicy::SmallestSwarm).select_eviction(candidates),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_split() {
+        let config = TorrentLimitConfig {
+            max_torrents: 10,
+            max_peers: 0,
+            eviction_policy: EvictionPolicy::RejectNew,
+        };
+
+        let limits = config.split(4, 0);
+
+        assert_eq!(limits.max_torrents, 3);
+        assert_eq!(limits.max_peers, usize::MAX);
+        assert!(!limits.limits_peers());
+
+        for num_parts in [1, 3, 4, 10, 16] {
+            let parts = (0..num_parts)
+                .map(|i| config.split(num_parts, i).max_torrents)
+                .collect::<Vec<_>>();
+
+            assert_eq!(parts.iter().sum::<usize>(), 10);
+            assert!(parts.iter().max().unwrap() - parts.iter().min().unwrap() <= 1);
+        }
+
+        assert_eq!(config.split(4, 3).max_torrents, 2);
+        assert_eq!(config.split(16, 9).max_torrents, 1);
+        assert_eq!(config.split(16, 10).max_torrents, 0);
+    }
+}
@@ -1,13 +1,14 @@
 use std::sync::Arc;
 
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::client_filter::ClientFilterArcSwap;
 use aquatic_common::CanonicalSocketAddr;
 
 pub use aquatic_common::ValidUntil;
 
 use aquatic_http_protocol::{
     request::{AnnounceRequest, ScrapeRequest},
-    response::{AnnounceResponse, ScrapeResponse},
+    response::{Response, ScrapeResponse},
 };
 use glommio::channels::shared_channel::SharedSender;
 use slotmap::new_key_type;
@@ -25,7 +26,9 @@ pub enum ChannelRequest {
     Announce {
         request: AnnounceRequest,
         peer_addr: CanonicalSocketAddr,
-        response_sender: SharedSender<AnnounceResponse>,
+        /// Receives announce response, or failure response if peer
+        /// could not be stored
+        response_sender: SharedSender<Response>,
     },
     Scrape {
         request: ScrapeRequest,
@@ -37,4 +40,5 @@ pub enum ChannelRequest {
 #[derive(Default, Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub client_filter: Arc<ClientFilterArcSwap>,
 }
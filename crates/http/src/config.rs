@@ -3,11 +3,20 @@ use std::{
     path::PathBuf,
 };
 
-use aquatic_common::{access_list::AccessListConfig, privileges::PrivilegeConfig};
+#[cfg(feature = "otlp")]
+use aquatic_common::otlp::OtlpConfig;
+#[cfg(feature = "statsd")]
+use aquatic_common::statsd::{StatsdConfig, StatsdFormat};
+use aquatic_common::{
+    access_list::AccessListConfig, announce_interval::AnnounceIntervalConfig,
+    client_filter::ClientFilterConfig, peer_ip_limits::PeerIpLimitConfig,
+    peer_selection::PeerSelectionConfig, privileges::PrivilegeConfig, statistics::StatisticsConfig,
+    torrent_limits::TorrentLimitConfig,
+};
 use aquatic_toml_config::TomlConfig;
 use serde::{Deserialize, Serialize};
 
-use aquatic_common::cli::LogLevel;
+use aquatic_common::cli::{LogFormat, LogLevel};
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, TomlConfig, Deserialize, Default)]
 #[serde(rename_all = "snake_case")]
@@ -32,6 +41,10 @@ pub struct Config {
     /// generate responses and send them back to the socket workers.
     pub swarm_workers: usize,
     pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    /// Comma-separated log level overrides for specific modules, e.g.
+    /// `aquatic_http::workers::socket=debug,glommio=off`
+    pub log_module_levels: String,
     pub network: NetworkConfig,
     pub protocol: ProtocolConfig,
     pub cleaning: CleaningConfig,
@@ -43,6 +56,21 @@ pub struct Config {
     /// emitting of an error-level log message, while successful updates of the
     /// access list result in emitting of an info-level log message.
     pub access_list: AccessListConfig,
+    /// Reject announces from peer clients matching (or, in allow mode, not
+    /// matching) entries in a file
+    ///
+    /// The file is read on start and reloaded along with the access list,
+    /// with failures handled in the same way.
+    pub client_filter: ClientFilterConfig,
+    /// Limits on the number of stored torrents and peers
+    pub torrent_limits: TorrentLimitConfig,
+    /// Limit on the number of peers stored for a single source address
+    pub peer_ip_limits: PeerIpLimitConfig,
+    /// Strategies for selecting peers to include in announce responses
+    pub peer_selection: PeerSelectionConfig,
+    /// Announce intervals scaled by swarm size and tracker load
+    pub announce_interval: AnnounceIntervalConfig,
+    pub statistics: StatisticsConfig,
     #[cfg(feature = "metrics")]
     pub metrics: MetricsConfig,
 }
@@ -53,11 +81,19 @@ impl Default for Config {
             socket_workers: 1,
             swarm_workers: 1,
             log_level: LogLevel::default(),
+            log_format: LogFormat::default(),
+            log_module_levels: String::new(),
             network: NetworkConfig::default(),
             protocol: ProtocolConfig::default(),
             cleaning: CleaningConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            client_filter: ClientFilterConfig::default(),
+            torrent_limits: TorrentLimitConfig::default(),
+            peer_ip_limits: PeerIpLimitConfig::default(),
+            peer_selection: PeerSelectionConfig::default(),
+            announce_interval: AnnounceIntervalConfig::default(),
+            statistics: StatisticsConfig::default(),
             #[cfg(feature = "metrics")]
             metrics: Default::default(),
         }
@@ -68,6 +104,12 @@ impl aquatic_common::cli::Config for Config {
     fn get_log_level(&self) -> Option<LogLevel> {
         Some(self.log_level)
     }
+    fn get_log_format(&self) -> LogFormat {
+        self.log_format
+    }
+    fn get_log_module_levels(&self) -> &str {
+        &self.log_module_levels
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
@@ -164,6 +206,11 @@ pub struct ProtocolConfig {
     pub max_peers: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: usize,
+    /// Ask peers not to announce more often than this (seconds)
+    ///
+    /// Sent as `min interval` in announce responses. When announce intervals
+    /// are scaled, this value is scaled by the same factor. 0 = don't send.
+    pub peer_min_announce_interval: usize,
 }
 
 impl Default for ProtocolConfig {
@@ -172,6 +219,7 @@ impl Default for ProtocolConfig {
             max_scrape_torrents: 100,
             max_peers: 50,
             peer_announce_interval: 120,
+            peer_min_announce_interval: 0,
         }
     }
 }
@@ -210,6 +258,96 @@ pub struct MetricsConfig {
     pub prometheus_endpoint_address: SocketAddr,
     /// Update metrics for torrent count this often (seconds)
     pub torrent_count_update_interval: u64,
+    /// Serve information on peer clients
+    ///
+    /// Expect a certain CPU hit
+    pub peer_clients: bool,
+    /// Serve information on all peer id prefixes
+    ///
+    /// Requires `peer_clients` to be activated.
+    ///
+    /// Expect a certain CPU hit
+    pub peer_id_prefixes: bool,
+    /// Export metrics to an OpenTelemetry collector over OTLP/HTTP
+    #[cfg(feature = "otlp")]
+    pub otlp_export_metrics: bool,
+    /// Export spans for this share of announce and scrape requests to the
+    /// OpenTelemetry collector (0.0 to disable, 1.0 for all requests)
+    #[cfg(feature = "otlp")]
+    pub otlp_span_sample_ratio: f64,
+    /// OTLP/HTTP collector base URL
+    ///
+    /// Data is posted as JSON to `/v1/metrics` and `/v1/traces` below it.
+    /// Only plain HTTP is supported.
+    #[cfg(feature = "otlp")]
+    pub otlp_endpoint: String,
+    /// Send data to OTLP collector this often (seconds)
+    #[cfg(feature = "otlp")]
+    pub otlp_export_interval: u64,
+    /// Push metrics to a StatsD or DogStatsD server over UDP
+    #[cfg(feature = "statsd")]
+    pub statsd_push_metrics: bool,
+    /// Address of StatsD server
+    #[cfg(feature = "statsd")]
+    pub statsd_address: SocketAddr,
+    /// Prepended to all metric names, e.g. `tracker.`
+    #[cfg(feature = "statsd")]
+    pub statsd_prefix: String,
+    /// Comma-separated tags to add to all metrics, e.g. `env:prod,region:eu`
+    ///
+    /// Only sent in dogstatsd format
+    #[cfg(feature = "statsd")]
+    pub statsd_tags: String,
+    /// Line format (statsd or dogstatsd)
+    ///
+    /// With plain statsd, metric labels are appended to metric names as
+    /// `.key.value`. With dogstatsd, they are sent as tags.
+    #[cfg(feature = "statsd")]
+    pub statsd_format: StatsdFormat,
+    /// Push metrics to StatsD server this often (seconds)
+    #[cfg(feature = "statsd")]
+    pub statsd_push_interval: u64,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsConfig {
+    /// Metrics are served on prometheus endpoint or pushed by an exporter
+    pub fn active(&self) -> bool {
+        #[allow(unused_mut)]
+        let mut active = self.run_prometheus_endpoint;
+
+        #[cfg(feature = "otlp")]
+        {
+            active |= self.otlp_export_metrics;
+        }
+        #[cfg(feature = "statsd")]
+        {
+            active |= self.statsd_push_metrics;
+        }
+
+        active
+    }
+
+    #[cfg(feature = "otlp")]
+    pub fn otlp_config(&self) -> OtlpConfig {
+        OtlpConfig {
+            export_metrics: self.otlp_export_metrics,
+            span_sample_ratio: self.otlp_span_sample_ratio,
+            endpoint: self.otlp_endpoint.clone(),
+            export_interval: self.otlp_export_interval,
+        }
+    }
+
+    #[cfg(feature = "statsd")]
+    pub fn statsd_config(&self) -> StatsdConfig {
+        StatsdConfig {
+            address: self.statsd_address,
+            prefix: self.statsd_prefix.clone(),
+            tags: self.statsd_tags.clone(),
+            format: self.statsd_format,
+            push_interval: self.statsd_push_interval,
+        }
+    }
 }
 
 #[cfg(feature = "metrics")]
@@ -219,6 +357,28 @@ impl Default for MetricsConfig {
             run_prometheus_endpoint: false,
             prometheus_endpoint_address: SocketAddr::from(([0, 0, 0, 0], 9000)),
             torrent_count_update_interval: 10,
+            peer_clients: false,
+            peer_id_prefixes: false,
+            #[cfg(feature = "otlp")]
+            otlp_export_metrics: false,
+            #[cfg(feature = "otlp")]
+            otlp_span_sample_ratio: 0.0,
+            #[cfg(feature = "otlp")]
+            otlp_endpoint: "http://127.0.0.1:4318".into(),
+            #[cfg(feature = "otlp")]
+            otlp_export_interval: 10,
+            #[cfg(feature = "statsd")]
+            statsd_push_metrics: false,
+            #[cfg(feature = "statsd")]
+            statsd_address: SocketAddr::from(([127, 0, 0, 1], 8125)),
+            #[cfg(feature = "statsd")]
+            statsd_prefix: String::new(),
+            #[cfg(feature = "statsd")]
+            statsd_tags: String::new(),
+            #[cfg(feature = "statsd")]
+            statsd_format: StatsdFormat::Dogstatsd,
+            #[cfg(feature = "statsd")]
+            statsd_push_interval: 10,
         }
     }
 }
@@ -1,7 +1,14 @@
 use anyhow::Context;
+#[cfg(feature = "metrics")]
+use aquatic_common::statistics::SwarmTopTorrentMetrics;
 use aquatic_common::{
-    access_list::update_access_list, privileges::PrivilegeDropper,
-    rustls_config::create_rustls_config, ServerStartInstant, WorkerType,
+    access_list::update_access_list,
+    client_filter::update_client_filter,
+    peer_ip_limits::PeerIpCounter,
+    privileges::PrivilegeDropper,
+    rustls_config::create_rustls_config,
+    statistics::{load_peer_client_definitions, run_statistics_worker, Statistics, TrackerInfo},
+    ServerStartInstant, WorkerType,
 };
 use arc_swap::ArcSwap;
 use common::State;
@@ -33,9 +40,16 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         ));
     }
 
+    config
+        .announce_interval
+        .validate(config.cleaning.max_peer_age)?;
+
+    load_peer_client_definitions(&config.statistics.peer_client_definitions_path)?;
+
     let state = State::default();
 
     update_access_list(&config.access_list, &state.access_list)?;
+    update_client_filter(&config.client_filter, &state.client_filter)?;
 
     let request_mesh_builder = MeshBuilder::partial(
         config.socket_workers + config.swarm_workers,
@@ -60,6 +74,7 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
     };
 
     let server_start_instant = ServerStartInstant::new();
+    let statistics = Statistics::new(config.socket_workers, config.swarm_workers);
 
     let mut join_handles = Vec::new();
 
@@ -68,6 +83,7 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         let state = state.clone();
         let opt_tls_config = opt_tls_config.clone();
         let request_mesh_builder = request_mesh_builder.clone();
+        let statistics = statistics.socket[i].clone();
 
         let mut priv_droppers = Vec::new();
 
@@ -78,6 +94,8 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         let handle = Builder::new()
             .name(format!("socket-{:02}", i + 1))
             .spawn(move || {
+                aquatic_common::logging::set_worker("socket", i);
+
                 LocalExecutorBuilder::default()
                     .make()
                     .map_err(|err| anyhow::anyhow!("Spawning executor failed: {:#}", err))?
@@ -88,6 +106,7 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                         request_mesh_builder,
                         priv_droppers,
                         server_start_instant,
+                        statistics,
                         i,
                     ))
             })
@@ -96,14 +115,25 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         join_handles.push((WorkerType::Socket(i), handle));
     }
 
+    let peer_ip_counter = Arc::new(PeerIpCounter::new(&config.peer_ip_limits));
+    #[cfg(feature = "metrics")]
+    let top_torrent_metrics = SwarmTopTorrentMetrics::new(&config.statistics, &statistics);
+
     for i in 0..(config.swarm_workers) {
         let config = config.clone();
         let state = state.clone();
         let request_mesh_builder = request_mesh_builder.clone();
+        let swarm_statistics = statistics.swarm[i].clone();
+        let peer_clients = statistics.peer_clients.clone();
+        let peer_ip_counter = peer_ip_counter.clone();
+        #[cfg(feature = "metrics")]
+        let top_torrent_metrics = top_torrent_metrics.clone();
 
         let handle = Builder::new()
             .name(format!("swarm-{:02}", i + 1))
             .spawn(move || {
+                aquatic_common::logging::set_worker("swarm", i);
+
                 LocalExecutorBuilder::default()
                     .make()
                     .map_err(|err| anyhow::anyhow!("Spawning executor failed: {:#}", err))?
@@ -112,6 +142,11 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                         state,
                         request_mesh_builder,
                         server_start_instant,
+                        swarm_statistics,
+                        peer_clients,
+                        peer_ip_counter,
+                        #[cfg(feature = "metrics")]
+                        top_torrent_metrics,
                         i,
                     ))
             })
@@ -120,24 +155,82 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         join_handles.push((WorkerType::Swarm(i), handle));
     }
 
+    if config.statistics.active() {
+        let statistics_config = config.statistics.clone();
+        let tracker_info = TrackerInfo {
+            title: "HTTP BitTorrent tracker statistics",
+            ipv4_active: config.network.use_ipv4,
+            ipv6_active: config.network.use_ipv6,
+            webtorrent: false,
+            peer_update_interval: config.cleaning.torrent_cleaning_interval,
+            torrent_limits: config.torrent_limits.is_active(),
+            peer_ip_limits: config.peer_ip_limits.is_active(),
+        };
+
+        let handle = Builder::new()
+            .name("statistics".into())
+            .spawn(move || run_statistics_worker(statistics_config, tracker_info, statistics))
+            .context("spawn statistics worker")?;
+
+        join_handles.push((WorkerType::Statistics, handle));
+    }
+
+    #[cfg(any(feature = "prometheus", feature = "otlp", feature = "statsd"))]
+    let idle_timeout = config
+        .cleaning
+        .connection_cleaning_interval
+        .max(config.cleaning.torrent_cleaning_interval)
+        .max(config.metrics.torrent_count_update_interval)
+        * 2;
+
+    // Recorders of exporters pushing metrics elsewhere
+    #[cfg(any(feature = "otlp", feature = "statsd"))]
+    let mut push_recorders = Vec::new();
+
+    #[cfg(feature = "otlp")]
+    if config.metrics.otlp_config().active() {
+        let (opt_recorder, handle) = aquatic_common::otlp::spawn_otlp_exporter(
+            config.metrics.otlp_config(),
+            "aquatic_http",
+            Some(Duration::from_secs(idle_timeout)),
+            Some(metrics_util::MetricKindMask::GAUGE),
+        )?;
+
+        push_recorders.extend(opt_recorder);
+        join_handles.push((WorkerType::Otlp, handle));
+    }
+
+    #[cfg(feature = "statsd")]
+    if config.metrics.statsd_push_metrics {
+        let (recorder, handle) = aquatic_common::statsd::spawn_statsd_exporter(
+            config.metrics.statsd_config(),
+            Some(Duration::from_secs(idle_timeout)),
+            Some(metrics_util::MetricKindMask::GAUGE),
+        )?;
+
+        push_recorders.push(recorder);
+        join_handles.push((WorkerType::Statsd, handle));
+    }
+
     #[cfg(feature = "prometheus")]
     if config.metrics.run_prometheus_endpoint {
-        let idle_timeout = config
-            .cleaning
-            .connection_cleaning_interval
-            .max(config.cleaning.torrent_cleaning_interval)
-            .max(config.metrics.torrent_count_update_interval)
-            * 2;
-
         let handle = aquatic_common::spawn_prometheus_endpoint(
             config.metrics.prometheus_endpoint_address,
             Some(Duration::from_secs(idle_timeout)),
             Some(metrics_util::MetricKindMask::GAUGE),
+            #[cfg(any(feature = "otlp", feature = "statsd"))]
+            ::std::mem::take(&mut push_recorders),
         )?;
 
         join_handles.push((WorkerType::Prometheus, handle));
     }
 
+    // Install push recorders directly if prometheus endpoint isn't running
+    #[cfg(any(feature = "otlp", feature = "statsd"))]
+    if !push_recorders.is_empty() {
+        aquatic_common::push_recorder::install_push_recorders(push_recorders)?;
+    }
+
     // Spawn signal handler thread
     {
         let handle: JoinHandle<anyhow::Result<()>> = Builder::new()
@@ -147,6 +240,8 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                     match signal {
                         SIGUSR1 => {
                             let _ = update_access_list(&config.access_list, &state.access_list);
+                            let _ =
+                                update_client_filter(&config.client_filter, &state.client_filter);
 
                             if let Some(tls_config) = opt_tls_config.as_ref() {
                                 match create_rustls_config(
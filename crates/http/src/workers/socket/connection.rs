@@ -2,11 +2,18 @@ use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use anyhow::Context;
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+#[cfg(feature = "metrics")]
+use aquatic_common::client_filter::rejected_client_label;
+use aquatic_common::client_filter::ClientFilterArcSwap;
+#[cfg(feature = "otlp")]
+use aquatic_common::otlp::Span;
 use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::statistics::{CachePaddedArc, IpVersionStatistics, SocketWorkerStatistics};
 use aquatic_common::{CanonicalSocketAddr, ServerStartInstant};
 use aquatic_http_protocol::common::InfoHash;
 use aquatic_http_protocol::request::{Request, ScrapeRequest};
@@ -70,11 +77,13 @@ pub enum ConnectionError {
 pub(super) async fn run_connection(
     config: Rc<Config>,
     access_list: Arc<AccessListArcSwap>,
+    client_filter: Arc<ClientFilterArcSwap>,
     request_senders: Rc<Senders<ChannelRequest>>,
     server_start_instant: ServerStartInstant,
     opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
     valid_until: Rc<RefCell<ValidUntil>>,
     stream: TcpStream,
+    statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
     worker_index: usize,
 ) -> Result<(), ConnectionError> {
     let access_list_cache = create_access_list_cache(&access_list);
@@ -106,6 +115,7 @@ pub(super) async fn run_connection(
         let mut conn = Connection {
             config,
             access_list_cache,
+            client_filter,
             request_senders,
             valid_until,
             server_start_instant,
@@ -114,6 +124,7 @@ pub(super) async fn run_connection(
             request_buffer_position: 0,
             response_buffer,
             stream,
+            statistics,
             worker_index_string: worker_index.to_string(),
         };
 
@@ -122,6 +133,7 @@ pub(super) async fn run_connection(
         let mut conn = Connection {
             config,
             access_list_cache,
+            client_filter,
             request_senders,
             valid_until,
             server_start_instant,
@@ -130,6 +142,7 @@ pub(super) async fn run_connection(
             request_buffer_position: 0,
             response_buffer,
             stream,
+            statistics,
             worker_index_string: worker_index.to_string(),
         };
 
@@ -140,6 +153,7 @@ pub(super) async fn run_connection(
 struct Connection<S> {
     config: Rc<Config>,
     access_list_cache: AccessListCache,
+    client_filter: Arc<ClientFilterArcSwap>,
     request_senders: Rc<Senders<ChannelRequest>>,
     valid_until: Rc<RefCell<ValidUntil>>,
     server_start_instant: ServerStartInstant,
@@ -148,6 +162,7 @@ struct Connection<S> {
     request_buffer_position: usize,
     response_buffer: Box<[u8; RESPONSE_BUFFER_SIZE]>,
     stream: S,
+    statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
     worker_index_string: String,
 }
 
@@ -167,10 +182,24 @@ where
                 .or(opt_peer_addr)
                 .ok_or(anyhow::anyhow!("Could not extract peer addr"))?;
 
+            #[cfg(feature = "otlp")]
+            let opt_span = Span::start_sampled(match request {
+                Request::Announce(_) => "announce",
+                Request::Scrape(_) => "scrape",
+            });
+
             let response = self.handle_request(request, peer_addr).await?;
 
             self.write_response(&response, peer_addr).await?;
 
+            #[cfg(feature = "otlp")]
+            if let Some(mut span) = opt_span {
+                span.set_attribute("ip_version", peer_addr_to_ip_version_str(&peer_addr));
+                span.set_attribute("worker_index", self.worker_index_string.clone());
+                span.set_attribute("response_type", response_type_str(&response));
+                span.finish();
+            }
+
             if !self.config.network.keep_alive {
                 break;
             }
@@ -247,8 +276,12 @@ where
             self.config.cleaning.max_connection_idle,
         );
 
+        let statistics = self.statistics.get(peer_addr.is_ipv4());
+
         match request {
             Request::Announce(request) => {
+                statistics.requests_announce.fetch_add(1, Ordering::Relaxed);
+
                 #[cfg(feature = "metrics")]
                 ::metrics::counter!(
                     "aquatic_requests_total",
@@ -260,7 +293,24 @@ where
 
                 let info_hash = request.info_hash;
 
-                if self
+                if !self
+                    .client_filter
+                    .load()
+                    .allows(self.config.client_filter.mode, &request.peer_id.0)
+                {
+                    #[cfg(feature = "metrics")]
+                    ::metrics::counter!(
+                        "aquatic_client_filter_rejections_total",
+                        "client" => rejected_client_label(&request.peer_id.0),
+                    )
+                    .increment(1);
+
+                    let response = Response::Failure(FailureResponse {
+                        failure_reason: "Client not allowed".into(),
+                    });
+
+                    Ok(response)
+                } else if self
                     .access_list_cache
                     .load()
                     .allows(self.config.access_list.mode, &info_hash.0)
@@ -287,7 +337,6 @@ where
                         .recv()
                         .await
                         .ok_or(ConnectionError::ResponseSenderClosed)
-                        .map(Response::Announce)
                 } else {
                     let response = Response::Failure(FailureResponse {
                         failure_reason: "Info hash not allowed".into(),
@@ -297,6 +346,8 @@ where
                 }
             }
             Request::Scrape(ScrapeRequest { info_hashes }) => {
+                statistics.requests_scrape.fetch_add(1, Ordering::Relaxed);
+
                 #[cfg(feature = "metrics")]
                 ::metrics::counter!(
                     "aquatic_requests_total",
@@ -438,19 +489,25 @@ where
             .with_context(|| "write")?;
         self.stream.flush().await.with_context(|| "flush")?;
 
-        #[cfg(feature = "metrics")]
         {
-            let response_type = match response {
-                Response::Announce(_) => "announce",
-                Response::Scrape(_) => "scrape",
-                Response::Failure(_) => "error",
+            let statistics = self.statistics.get(peer_addr.is_ipv4());
+
+            let counter = match response {
+                Response::Announce(_) => &statistics.responses_announce,
+                Response::Scrape(_) => &statistics.responses_scrape,
+                Response::Failure(_) => &statistics.responses_error,
             };
 
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+
+        #[cfg(feature = "metrics")]
+        {
             let ip_version_str = peer_addr_to_ip_version_str(&peer_addr);
 
             ::metrics::counter!(
                 "aquatic_responses_total",
-                "type" => response_type,
+                "type" => response_type_str(response),
                 "ip_version" => ip_version_str,
                 "worker_index" => self.worker_index_string.clone(),
             )
@@ -461,6 +518,15 @@ where
     }
 }
 
+#[cfg(feature = "metrics")]
+fn response_type_str(response: &Response) -> &'static str {
+    match response {
+        Response::Announce(_) => "announce",
+        Response::Scrape(_) => "scrape",
+        Response::Failure(_) => "error",
+    }
+}
+
 fn calculate_request_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
     (info_hash.0[0] as usize) % config.swarm_workers
 }
@@ -5,13 +5,16 @@ use std::cell::RefCell;
 use std::net::SocketAddr;
 use std::os::unix::prelude::{FromRawFd, IntoRawFd};
 use std::rc::Rc;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
 use aquatic_common::access_list::AccessList;
+use aquatic_common::client_filter::ClientFilterArcSwap;
 use aquatic_common::privileges::PrivilegeDropper;
 use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::statistics::{CachePaddedArc, IpVersionStatistics, SocketWorkerStatistics};
 use aquatic_common::{CanonicalSocketAddr, ServerStartInstant};
 use arc_swap::{ArcSwap, ArcSwapAny};
 use futures_lite::future::race;
@@ -40,6 +43,7 @@ pub async fn run_socket_worker(
     request_mesh_builder: MeshBuilder<ChannelRequest, Partial>,
     mut priv_droppers: Vec<PrivilegeDropper>,
     server_start_instant: ServerStartInstant,
+    statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
     worker_index: usize,
 ) -> anyhow::Result<()> {
     let config = Rc::new(config);
@@ -98,10 +102,12 @@ pub async fn run_socket_worker(
             let listener_state = ListenerState {
                 config: config.clone(),
                 access_list: state.access_list.clone(),
+                client_filter: state.client_filter.clone(),
                 opt_tls_config: opt_tls_config.clone(),
                 server_start_instant,
                 connection_handles: connection_handles.clone(),
                 request_senders: request_senders.clone(),
+                statistics: statistics.clone(),
                 worker_index,
             };
 
@@ -120,10 +126,12 @@ pub async fn run_socket_worker(
 struct ListenerState {
     config: Rc<Config>,
     access_list: Arc<ArcSwapAny<Arc<AccessList>>>,
+    client_filter: Arc<ClientFilterArcSwap>,
     opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
     server_start_instant: ServerStartInstant,
     connection_handles: Rc<RefCell<HopSlotMap<ConnectionId, ConnectionHandle>>>,
     request_senders: Rc<Senders<ChannelRequest>>,
+    statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
     worker_index: usize,
 }
 
@@ -180,15 +188,26 @@ impl ListenerState {
         #[cfg(feature = "metrics")]
         active_connections_gauge.increment(1.0);
 
+        let opt_peer_addr = stream.peer_addr().ok().map(CanonicalSocketAddr::new);
+
+        let opt_connection_statistics =
+            opt_peer_addr.map(|addr| self.statistics.get(addr.is_ipv4()));
+
+        if let Some(statistics) = opt_connection_statistics {
+            statistics.connections.fetch_add(1, Ordering::Relaxed);
+        }
+
         let f1 = async {
             run_connection(
                 self.config,
                 self.access_list,
+                self.client_filter,
                 self.request_senders,
                 self.server_start_instant,
                 self.opt_tls_config,
                 valid_until.clone(),
                 stream,
+                self.statistics.clone(),
                 self.worker_index,
             )
             .await
@@ -204,6 +223,10 @@ impl ListenerState {
         #[cfg(feature = "metrics")]
         active_connections_gauge.decrement(1.0);
 
+        if let Some(statistics) = opt_connection_statistics {
+            statistics.connections.fetch_sub(1, Ordering::Relaxed);
+        }
+
         match result {
             Ok(()) => (),
             Err(
@@ -212,7 +235,12 @@ impl ListenerState {
                 | ConnectionError::ScrapeChannelError(_)
                 | ConnectionError::ResponseSenderClosed),
             ) => {
-                ::log::error!("connection closed: {:#}", err);
+                ::log::error!(
+                    peer_addr:% = opt_peer_addr
+                        .map_or_else(|| "unknown".into(), |addr| addr.get().to_string());
+                    "connection closed: {:#}",
+                    err
+                );
             }
             Err(err @ ConnectionError::RequestBufferFull) => {
                 ::log::info!("connection closed: {:#}", err);
@@ -2,6 +2,7 @@ mod storage;
 
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::Duration;
 
 use futures_lite::{Stream, StreamExt};
@@ -11,6 +12,13 @@ use glommio::{enclose, prelude::*};
 use rand::prelude::SmallRng;
 use rand::SeedableRng;
 
+use aquatic_common::logging::InfoHashPrefix;
+use aquatic_common::peer_ip_limits::PeerIpCounter;
+#[cfg(feature = "metrics")]
+use aquatic_common::statistics::SwarmTopTorrentMetrics;
+use aquatic_common::statistics::{
+    CachePaddedArc, IpVersionStatistics, PeerClientCounter, SwarmWorkerStatistics,
+};
 use aquatic_common::{ServerStartInstant, ValidUntil};
 
 use crate::common::*;
@@ -18,11 +26,16 @@ use crate::config::Config;
 
 use self::storage::TorrentMaps;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn run_swarm_worker(
     config: Config,
     state: State,
     request_mesh_builder: MeshBuilder<ChannelRequest, Partial>,
     server_start_instant: ServerStartInstant,
+    statistics: CachePaddedArc<IpVersionStatistics<SwarmWorkerStatistics>>,
+    peer_clients: PeerClientCounter,
+    peer_ip_counter: Arc<PeerIpCounter>,
+    #[cfg(feature = "metrics")] top_torrent_metrics: SwarmTopTorrentMetrics,
     worker_index: usize,
 ) -> anyhow::Result<()> {
     let (_, mut request_receivers) = request_mesh_builder
@@ -30,7 +43,15 @@ pub async fn run_swarm_worker(
         .await
         .map_err(|err| anyhow::anyhow!("join request mesh: {:#}", err))?;
 
-    let torrents = Rc::new(RefCell::new(TorrentMaps::new(worker_index)));
+    let torrents = Rc::new(RefCell::new(TorrentMaps::new(
+        &config,
+        worker_index,
+        statistics,
+        peer_clients,
+        peer_ip_counter,
+        #[cfg(feature = "metrics")]
+        top_torrent_metrics,
+    )));
     let access_list = state.access_list;
 
     // Periodically clean torrents
@@ -105,6 +126,8 @@ async fn handle_request_stream<S>(
                 peer_addr,
                 response_sender,
             } => {
+                let info_hash = request.info_hash;
+
                 let response = torrents.borrow_mut().handle_announce_request(
                     &config,
                     &mut rng,
@@ -114,7 +137,12 @@ async fn handle_request_stream<S>(
                 );
 
                 if let Err(err) = response_sender.connect().await.send(response).await {
-                    ::log::error!("swarm worker could not send announce response: {:#}", err);
+                    ::log::error!(
+                        peer_addr:% = peer_addr.get(),
+                        info_hash:% = InfoHashPrefix(&info_hash.0);
+                        "swarm worker could not send announce response: {:#}",
+                        err
+                    );
                 }
             }
             ChannelRequest::Scrape {
@@ -127,7 +155,11 @@ async fn handle_request_stream<S>(
                     .handle_scrape_request(&config, peer_addr, request);
 
                 if let Err(err) = response_sender.connect().await.send(response).await {
-                    ::log::error!("swarm worker could not send scrape response: {:#}", err);
+                    ::log::error!(
+                        peer_addr:% = peer_addr.get();
+                        "swarm worker could not send scrape response: {:#}",
+                        err
+                    );
                 }
             }
         };
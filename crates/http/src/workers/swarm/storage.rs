@@ -1,11 +1,23 @@
 use std::collections::BTreeMap;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use arrayvec::ArrayVec;
 use rand::Rng;
 
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::peer_ip_limits::PeerIpCounter;
+use aquatic_common::peer_selection::{PeerCandidate, PeerSelectionConfig};
+#[cfg(feature = "metrics")]
+use aquatic_common::statistics::SwarmTopTorrentMetrics;
+use aquatic_common::statistics::{
+    CachePaddedArc, IpVersionStatistics, PeerClientCounter, PeerClientUpdates,
+    SwarmWorkerStatistics, TopTorrent, TopTorrents,
+};
+use aquatic_common::torrent_limits::{
+    EvictionCandidate, PeerCountChange, TorrentLimitCounters, TorrentLimits, EVICTION_SAMPLE_SIZE,
+};
 use aquatic_common::{
     CanonicalSocketAddr, IndexMap, SecondsSinceServerStart, ServerStartInstant, ValidUntil,
 };
@@ -18,7 +30,7 @@ use crate::config::Config;
 
 const SMALL_PEER_MAP_CAPACITY: usize = 4;
 
-pub trait Ip: ::std::fmt::Debug + Copy + Eq + ::std::hash::Hash {}
+pub trait Ip: ::std::fmt::Debug + Copy + Eq + ::std::hash::Hash + Into<IpAddr> {}
 
 impl Ip for Ipv4Addr {}
 impl Ip for Ipv6Addr {}
@@ -26,13 +38,38 @@ impl Ip for Ipv6Addr {}
 pub struct TorrentMaps {
     pub ipv4: TorrentMap<Ipv4Addr>,
     pub ipv6: TorrentMap<Ipv6Addr>,
+    statistics: CachePaddedArc<IpVersionStatistics<SwarmWorkerStatistics>>,
+    peer_clients: PeerClientCounter,
+    /// Changes to peer client counts, applied to shared counter when
+    /// cleaning to avoid locking it on every announce
+    peer_client_updates: PeerClientUpdates,
+    peer_ip_counter: Arc<PeerIpCounter>,
+    #[cfg(feature = "metrics")]
+    top_torrent_metrics: SwarmTopTorrentMetrics,
 }
 
 impl TorrentMaps {
-    pub fn new(worker_index: usize) -> Self {
+    pub fn new(
+        config: &Config,
+        worker_index: usize,
+        statistics: CachePaddedArc<IpVersionStatistics<SwarmWorkerStatistics>>,
+        peer_clients: PeerClientCounter,
+        peer_ip_counter: Arc<PeerIpCounter>,
+        #[cfg(feature = "metrics")] top_torrent_metrics: SwarmTopTorrentMetrics,
+    ) -> Self {
+        let limits = config
+            .torrent_limits
+            .split(config.swarm_workers, worker_index);
+
         Self {
-            ipv4: TorrentMap::new(worker_index, true),
-            ipv6: TorrentMap::new(worker_index, false),
+            ipv4: TorrentMap::new(limits, worker_index, true),
+            ipv6: TorrentMap::new(limits, worker_index, false),
+            statistics,
+            peer_clients,
+            peer_client_updates: Default::default(),
+            peer_ip_counter,
+            #[cfg(feature = "metrics")]
+            top_torrent_metrics,
         }
     }
 
@@ -43,46 +80,60 @@ impl TorrentMaps {
         valid_until: ValidUntil,
         peer_addr: CanonicalSocketAddr,
         request: AnnounceRequest,
-    ) -> AnnounceResponse {
-        match peer_addr.get().ip() {
-            IpAddr::V4(peer_ip_address) => {
-                let (seeders, leechers, response_peers) =
-                    self.ipv4.upsert_peer_and_get_response_peers(
-                        config,
-                        rng,
-                        valid_until,
-                        peer_ip_address,
-                        request,
-                    );
-
-                AnnounceResponse {
-                    complete: seeders,
-                    incomplete: leechers,
-                    announce_interval: config.protocol.peer_announce_interval,
-                    peers: ResponsePeerListV4(response_peers),
-                    peers6: ResponsePeerListV6(vec![]),
-                    warning_message: None,
-                }
-            }
-            IpAddr::V6(peer_ip_address) => {
-                let (seeders, leechers, response_peers) =
-                    self.ipv6.upsert_peer_and_get_response_peers(
-                        config,
-                        rng,
-                        valid_until,
-                        peer_ip_address,
-                        request,
-                    );
+    ) -> Response {
+        let opt_response = match peer_addr.get().ip() {
+            IpAddr::V4(peer_ip_address) => self
+                .ipv4
+                .upsert_peer_and_get_response_peers(
+                    config,
+                    rng,
+                    valid_until,
+                    &mut self.peer_client_updates,
+                    &self.peer_ip_counter,
+                    peer_ip_address,
+                    request,
+                )
+                .map(
+                    |(seeders, leechers, response_peers, intervals)| AnnounceResponse {
+                        complete: seeders,
+                        incomplete: leechers,
+                        announce_interval: intervals.interval,
+                        min_announce_interval: intervals.min_interval,
+                        peers: ResponsePeerListV4(response_peers),
+                        peers6: ResponsePeerListV6(vec![]),
+                        warning_message: None,
+                    },
+                ),
+
+            IpAddr::V6(peer_ip_address) => self
+                .ipv6
+                .upsert_peer_and_get_response_peers(
+                    config,
+                    rng,
+                    valid_until,
+                    &mut self.peer_client_updates,
+                    &self.peer_ip_counter,
+                    peer_ip_address,
+                    request,
+                )
+                .map(
+                    |(seeders, leechers, response_peers, intervals)| AnnounceResponse {
+                        complete: seeders,
+                        incomplete: leechers,
+                        announce_interval: intervals.interval,
+                        min_announce_interval: intervals.min_interval,
+                        peers: ResponsePeerListV4(vec![]),
+                        peers6: ResponsePeerListV6(response_peers),
+                        warning_message: None,
+                    },
+                ),
+        };
 
-                AnnounceResponse {
-                    complete: seeders,
-                    incomplete: leechers,
-                    announce_interval: config.protocol.peer_announce_interval,
-                    peers: ResponsePeerListV4(vec![]),
-                    peers6: ResponsePeerListV6(response_peers),
-                    warning_message: None,
-                }
-            }
+        match opt_response {
+            Some(response) => Response::Announce(response),
+            None => Response::Failure(FailureResponse {
+                failure_reason: "Too many peers from your address".into(),
+            }),
         }
     }
 
@@ -115,21 +166,77 @@ impl TorrentMaps {
 
         let now = server_start_instant.seconds_elapsed();
 
-        self.ipv4.clean(config, &mut access_list_cache, now);
-        self.ipv6.clean(config, &mut access_list_cache, now);
+        self.ipv4.clean(
+            config,
+            &mut access_list_cache,
+            &self.statistics.ipv4,
+            &mut self.peer_client_updates,
+            &self.peer_ip_counter,
+            now,
+        );
+        self.ipv6.clean(
+            config,
+            &mut access_list_cache,
+            &self.statistics.ipv6,
+            &mut self.peer_client_updates,
+            &self.peer_ip_counter,
+            now,
+        );
+
+        if config.statistics.peer_clients {
+            self.peer_clients.apply(&mut self.peer_client_updates);
+        }
+
+        #[cfg(feature = "metrics")]
+        if config.metrics.active() && config.statistics.top_torrents > 0 {
+            self.top_torrent_metrics.update(true);
+            self.top_torrent_metrics.update(false);
+        }
+
+        // Counter is shared by all swarm workers, so rejections are moved to
+        // the statistics of the one that happens to clean first
+        if self.peer_ip_counter.is_active() {
+            for (ipv4, statistics) in [
+                (true, &self.statistics.ipv4),
+                (false, &self.statistics.ipv6),
+            ] {
+                let rejected = self.peer_ip_counter.take_rejected(ipv4);
+
+                statistics
+                    .rejected_ip_peers
+                    .fetch_add(rejected, Ordering::Relaxed);
+
+                #[cfg(feature = "metrics")]
+                if config.metrics.active() {
+                    ::metrics::counter!(
+                        "aquatic_peer_ip_limit_rejections_total",
+                        "ip_version" => if ipv4 { "4" } else { "6" },
+                    )
+                    .increment(rejected as u64);
+                }
+            }
+        }
     }
 }
 
 pub struct TorrentMap<I: Ip> {
     torrents: IndexMap<InfoHash, TorrentData<I>>,
+    limits: TorrentLimits,
+    num_peers: usize,
+    /// Rejections and evictions since previous cleaning
+    limit_counters: TorrentLimitCounters,
     #[cfg(feature = "metrics")]
     peer_gauge: ::metrics::Gauge,
     #[cfg(feature = "metrics")]
     torrent_gauge: ::metrics::Gauge,
+    #[cfg(feature = "metrics")]
+    ip_version: &'static str,
+    #[cfg(feature = "metrics")]
+    worker_index: usize,
 }
 
 impl<I: Ip> TorrentMap<I> {
-    fn new(worker_index: usize, ipv4: bool) -> Self {
+    fn new(limits: TorrentLimits, worker_index: usize, ipv4: bool) -> Self {
         #[cfg(feature = "metrics")]
         let peer_gauge = if ipv4 {
             ::metrics::gauge!(
@@ -161,33 +268,192 @@ impl<I: Ip> TorrentMap<I> {
 
         Self {
             torrents: Default::default(),
+            limits,
+            num_peers: 0,
+            limit_counters: Default::default(),
             #[cfg(feature = "metrics")]
             peer_gauge,
             #[cfg(feature = "metrics")]
             torrent_gauge,
+            #[cfg(feature = "metrics")]
+            ip_version: if ipv4 { "4" } else { "6" },
+            #[cfg(feature = "metrics")]
+            worker_index,
         }
     }
 
+    /// Returns None if peer could not be stored because of per-address
+    /// peer limit
+    #[allow(clippy::too_many_arguments)]
     fn upsert_peer_and_get_response_peers(
         &mut self,
         config: &Config,
         rng: &mut impl Rng,
         valid_until: ValidUntil,
+        peer_clients: &mut PeerClientUpdates,
+        peer_ip_counter: &PeerIpCounter,
         peer_ip_address: I,
         request: AnnounceRequest,
-    ) -> (usize, usize, Vec<ResponsePeer<I>>) {
-        self.torrents
-            .entry(request.info_hash)
-            .or_default()
-            .upsert_peer_and_get_response_peers(
+    ) -> Option<AnnounceResponseData<I>> {
+        let info_hash = request.info_hash;
+
+        // Count peer for its address if it would be added
+        let peer_ip_counted = peer_ip_counter.is_active()
+            && request.event != AnnounceEvent::Stopped
+            && !self.torrents.get(&info_hash).map_or(false, |torrent_data| {
+                torrent_data.peer_map.contains(&ResponsePeer {
+                    ip_address: peer_ip_address,
+                    port: request.port,
+                })
+            });
+
+        if peer_ip_counted && !peer_ip_counter.try_add(peer_ip_address.into()) {
+            return None;
+        }
+
+        if !self.torrents.contains_key(&info_hash)
+            && self.limits.torrent_limit_reached(self.torrents.len())
+            && !self.evict_sampled_torrent(config, rng, peer_clients, peer_ip_counter, &info_hash)
+        {
+            self.limit_counters.rejected_torrents += 1;
+
+            if peer_ip_counted {
+                peer_ip_counter.remove(peer_ip_address.into());
+            }
+
+            return Some((0, 0, Vec::new(), AnnounceIntervals::fixed(config)));
+        }
+
+        let peer_limit_reached = self.limits.peer_limit_reached(self.num_peers);
+
+        let torrent_data = self
+            .torrents
+            .entry(info_hash)
+            .or_insert_with(|| TorrentData {
+                peer_map: Default::default(),
+                last_announce: valid_until,
+                num_announces: 0,
+            });
+
+        torrent_data.last_announce = valid_until;
+        torrent_data.num_announces += 1;
+
+        let (response_data, peer_count_change) =
+            torrent_data.peer_map.upsert_peer_and_get_response_peers(
                 config,
                 rng,
                 request,
                 peer_ip_address,
                 valid_until,
+                peer_clients,
+                !(peer_limit_reached && self.limits.rejects_new()),
+                // Each swarm worker stores a share of peers
+                self.num_peers.saturating_mul(config.swarm_workers),
                 #[cfg(feature = "metrics")]
                 &self.peer_gauge,
-            )
+            );
+
+        match peer_count_change {
+            PeerCountChange::Added => {
+                self.num_peers += 1;
+
+                if peer_limit_reached {
+                    self.evict_sampled_torrent(
+                        config,
+                        rng,
+                        peer_clients,
+                        peer_ip_counter,
+                        &info_hash,
+                    );
+                }
+            }
+            PeerCountChange::Removed => {
+                self.num_peers = self.num_peers.saturating_sub(1);
+
+                peer_ip_counter.remove(peer_ip_address.into());
+            }
+            PeerCountChange::Rejected => {
+                self.limit_counters.rejected_peers += 1;
+
+                if peer_ip_counted {
+                    peer_ip_counter.remove(peer_ip_address.into());
+                }
+            }
+            PeerCountChange::Unchanged => (),
+        }
+
+        Some(response_data)
+    }
+
+    /// Evict a torrent chosen among a random sample according to eviction
+    /// policy, never the one with `protected_info_hash`
+    ///
+    /// Returns false if no torrent was evicted.
+    fn evict_sampled_torrent(
+        &mut self,
+        config: &Config,
+        rng: &mut impl Rng,
+        peer_clients: &mut PeerClientUpdates,
+        peer_ip_counter: &PeerIpCounter,
+        protected_info_hash: &InfoHash,
+    ) -> bool {
+        if self.limits.rejects_new() || self.torrents.is_empty() {
+            return false;
+        }
+
+        let num_torrents = self.torrents.len();
+        let torrents = &self.torrents;
+
+        let candidates = (0..EVICTION_SAMPLE_SIZE).filter_map(|_| {
+            let index = rng.gen_range(0..num_torrents);
+            let (info_hash, torrent_data) = torrents.get_index(index)?;
+
+            (info_hash != protected_info_hash).then(|| EvictionCandidate {
+                key: index,
+                last_announce: torrent_data.last_announce,
+                num_peers: torrent_data.peer_map.num_peers(),
+            })
+        });
+
+        if let Some(index) = self.limits.select_eviction(candidates) {
+            if let Some((_, torrent_data)) = self.torrents.swap_remove_index(index) {
+                self.on_torrent_evicted(config, peer_clients, peer_ip_counter, torrent_data);
+
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn on_torrent_evicted(
+        &mut self,
+        config: &Config,
+        peer_clients: &mut PeerClientUpdates,
+        peer_ip_counter: &PeerIpCounter,
+        torrent_data: TorrentData<I>,
+    ) {
+        let num_peers = torrent_data.peer_map.num_peers();
+
+        torrent_data
+            .peer_map
+            .remove_from_peer_ip_counter(peer_ip_counter);
+
+        self.num_peers = self.num_peers.saturating_sub(num_peers);
+        self.limit_counters.evicted_torrents += 1;
+
+        if tracks_peer_clients(config) {
+            let mut peer_ids = Vec::new();
+
+            torrent_data.peer_map.extend_with_peer_ids(&mut peer_ids);
+
+            for peer_id in peer_ids {
+                on_peer_removed(config, peer_clients, peer_id);
+            }
+        }
+
+        #[cfg(feature = "metrics")]
+        self.peer_gauge.decrement(num_peers as f64);
     }
 
     fn handle_scrape_request(&mut self, config: &Config, request: ScrapeRequest) -> ScrapeResponse {
@@ -204,7 +470,7 @@ impl<I: Ip> TorrentMap<I> {
             let stats = self
                 .torrents
                 .get(&info_hash)
-                .map(|torrent_data| torrent_data.scrape_statistics())
+                .map(|torrent_data| torrent_data.peer_map.scrape_statistics())
                 .unwrap_or(ScrapeStatistics {
                     complete: 0,
                     incomplete: 0,
@@ -221,41 +487,221 @@ impl<I: Ip> TorrentMap<I> {
         &mut self,
         config: &Config,
         access_list_cache: &mut AccessListCache,
+        statistics: &SwarmWorkerStatistics,
+        peer_clients: &mut PeerClientUpdates,
+        peer_ip_counter: &PeerIpCounter,
         now: SecondsSinceServerStart,
     ) {
         let mut total_num_peers = 0;
+        let mut removed_peer_ids = Vec::new();
+
+        // Only collect removed peer ids if needed
+        let mut opt_removed_peer_ids = tracks_peer_clients(config).then_some(&mut removed_peer_ids);
+        let mut opt_top_torrents = (config.statistics.top_torrents > 0)
+            .then(|| TopTorrents::new(config.statistics.top_torrents));
 
         self.torrents.retain(|info_hash, torrent_data| {
             if !access_list_cache
                 .load()
                 .allows(config.access_list.mode, &info_hash.0)
             {
+                if let Some(removed_peer_ids) = opt_removed_peer_ids.as_mut() {
+                    torrent_data.peer_map.extend_with_peer_ids(removed_peer_ids);
+                }
+
+                torrent_data
+                    .peer_map
+                    .remove_from_peer_ip_counter(peer_ip_counter);
+
                 return false;
             }
 
-            let num_peers = match torrent_data {
-                TorrentData::Small(t) => t.clean_and_get_num_peers(now),
-                TorrentData::Large(t) => t.clean_and_get_num_peers(now),
+            let num_peers = match &mut torrent_data.peer_map {
+                PeerMap::Small(t) => t.clean_and_get_num_peers(
+                    opt_removed_peer_ids.as_deref_mut(),
+                    peer_ip_counter,
+                    now,
+                ),
+                PeerMap::Large(t) => t.clean_and_get_num_peers(
+                    opt_removed_peer_ids.as_deref_mut(),
+                    peer_ip_counter,
+                    now,
+                ),
             };
 
             total_num_peers += num_peers as u64;
 
+            let num_announces = ::std::mem::take(&mut torrent_data.num_announces);
+
+            if let Some(top_torrents) = opt_top_torrents.as_mut() {
+                top_torrents.add(TopTorrent {
+                    info_hash: info_hash.0,
+                    peers: num_peers,
+                    announces: num_announces,
+                });
+            }
+
             num_peers > 0
         });
 
+        for peer_id in removed_peer_ids {
+            on_peer_removed(config, peer_clients, peer_id);
+        }
+
+        self.num_peers = total_num_peers as usize;
+
+        self.enforce_limits(config, peer_clients, peer_ip_counter);
+
         self.torrents.shrink_to_fit();
 
+        statistics
+            .torrents
+            .store(self.torrents.len(), Ordering::Relaxed);
+        statistics.peers.store(self.num_peers, Ordering::Relaxed);
+
+        let limit_counters = ::std::mem::take(&mut self.limit_counters);
+
+        statistics.add_torrent_limit_counters(limit_counters);
+
         #[cfg(feature = "metrics")]
-        self.peer_gauge.set(total_num_peers as f64);
+        {
+            self.peer_gauge.set(self.num_peers as f64);
+
+            if config.metrics.active() {
+                self.update_torrent_limit_metrics(limit_counters);
+            }
+        }
+
+        if let Some(top_torrents) = opt_top_torrents {
+            *statistics.top_torrents.lock().unwrap() = top_torrents;
+        }
+    }
+
+    /// Evict torrents in policy order until limits are met
+    fn enforce_limits(
+        &mut self,
+        config: &Config,
+        peer_clients: &mut PeerClientUpdates,
+        peer_ip_counter: &PeerIpCounter,
+    ) {
+        if self.limits.rejects_new() || !self.limits.exceeded(self.torrents.len(), self.num_peers) {
+            return;
+        }
+
+        let candidates = self
+            .torrents
+            .iter()
+            .map(|(info_hash, torrent_data)| EvictionCandidate {
+                key: *info_hash,
+                last_announce: torrent_data.last_announce,
+                num_peers: torrent_data.peer_map.num_peers(),
+            })
+            .collect();
+
+        for info_hash in self.limits.select_evictions(candidates) {
+            if let Some(torrent_data) = self.torrents.swap_remove(&info_hash) {
+                self.on_torrent_evicted(config, peer_clients, peer_ip_counter, torrent_data);
+            }
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn update_torrent_limit_metrics(&self, limit_counters: TorrentLimitCounters) {
+        let worker_index = self.worker_index.to_string();
+
+        ::metrics::counter!(
+            "aquatic_torrent_limit_rejections_total",
+            "type" => "torrent",
+            "ip_version" => self.ip_version,
+            "worker_index" => worker_index.clone(),
+        )
+        .increment(limit_counters.rejected_torrents as u64);
+        ::metrics::counter!(
+            "aquatic_torrent_limit_rejections_total",
+            "type" => "peer",
+            "ip_version" => self.ip_version,
+            "worker_index" => worker_index.clone(),
+        )
+        .increment(limit_counters.rejected_peers as u64);
+        ::metrics::counter!(
+            "aquatic_torrent_evictions_total",
+            "ip_version" => self.ip_version,
+            "worker_index" => worker_index,
+        )
+        .increment(limit_counters.evicted_torrents as u64);
+    }
+}
+
+/// Number of seeders and leechers, response peers and announce intervals
+type AnnounceResponseData<I> = (usize, usize, Vec<ResponsePeer<I>>, AnnounceIntervals);
+
+/// Announce interval and optional minimum announce interval (seconds)
+pub struct AnnounceIntervals {
+    interval: usize,
+    min_interval: Option<usize>,
+}
+
+impl AnnounceIntervals {
+    fn new(config: &Config, swarm_peers: usize, tracker_peers: usize) -> Self {
+        let interval = config
+            .announce_interval
+            .interval(
+                config.protocol.peer_announce_interval as u64,
+                swarm_peers,
+                tracker_peers,
+            )
+            .try_into()
+            .unwrap_or(usize::MAX);
+
+        Self::with_interval(config, interval)
+    }
+
+    /// Intervals from protocol settings, without scaling
+    fn fixed(config: &Config) -> Self {
+        Self::with_interval(config, config.protocol.peer_announce_interval)
+    }
+
+    fn with_interval(config: &Config, interval: usize) -> Self {
+        // Scale minimum interval by the same factor as interval
+        let min_interval = match config.protocol.peer_min_announce_interval {
+            0 => None,
+            min_interval => Some(
+                min_interval
+                    .saturating_mul(interval)
+                    .checked_div(config.protocol.peer_announce_interval)
+                    .unwrap_or(min_interval)
+                    .min(interval),
+            ),
+        };
+
+        Self {
+            interval,
+            min_interval,
+        }
     }
 }
 
-pub enum TorrentData<I: Ip> {
+pub struct TorrentData<I: Ip> {
+    peer_map: PeerMap<I>,
+    /// Peer validity set on latest announce
+    last_announce: ValidUntil,
+    /// Announces since previous cleaning
+    num_announces: usize,
+}
+
+pub enum PeerMap<I: Ip> {
     Small(SmallPeerMap<I>),
     Large(LargePeerMap<I>),
 }
 
-impl<I: Ip> TorrentData<I> {
+impl<I: Ip> PeerMap<I> {
+    /// Insert, update or remove peer and get response data
+    ///
+    /// Peers that are not already present are only inserted if
+    /// `allow_new_peer` is true. `tracker_peers` is the number of peers
+    /// stored by the tracker for this IP version, used for scaling the
+    /// announce interval.
+    #[allow(clippy::too_many_arguments)]
     fn upsert_peer_and_get_response_peers(
         &mut self,
         config: &Config,
@@ -263,8 +709,11 @@ impl<I: Ip> TorrentData<I> {
         request: AnnounceRequest,
         ip_address: I,
         valid_until: ValidUntil,
+        peer_clients: &mut PeerClientUpdates,
+        allow_new_peer: bool,
+        tracker_peers: usize,
         #[cfg(feature = "metrics")] peer_gauge: &::metrics::Gauge,
-    ) -> (usize, usize, Vec<ResponsePeer<I>>) {
+    ) -> (AnnounceResponseData<I>, PeerCountChange) {
         let max_num_peers_to_take = match request.numwant {
             Some(0) | None => config.protocol.max_peers,
             Some(numwant) => numwant.min(config.protocol.max_peers),
@@ -277,6 +726,8 @@ impl<I: Ip> TorrentData<I> {
             port: request.port,
         };
 
+        let intervals = AnnounceIntervals::new(config, self.num_peers(), tracker_peers);
+
         // Create the response before inserting the peer. This means that we
         // don't have to filter it out from the response peers, and that the
         // reported number of seeders/leechers will not include it
@@ -285,7 +736,12 @@ impl<I: Ip> TorrentData<I> {
                 let opt_removed_peer = peer_map.remove(&peer_map_key);
 
                 let (seeders, leechers) = peer_map.num_seeders_leechers();
-                let response_peers = peer_map.extract_response_peers(max_num_peers_to_take);
+                let response_peers = peer_map.extract_response_peers(
+                    &config.peer_selection,
+                    ip_address.into(),
+                    status == PeerStatus::Seeding,
+                    max_num_peers_to_take,
+                );
 
                 // Convert peer map to large variant if it is full and
                 // announcing peer is not stopped and will therefore be
@@ -294,13 +750,22 @@ impl<I: Ip> TorrentData<I> {
                     *self = Self::Large(peer_map.to_large());
                 }
 
-                ((seeders, leechers, response_peers), opt_removed_peer)
+                (
+                    (seeders, leechers, response_peers, intervals),
+                    opt_removed_peer,
+                )
             }
             Self::Large(peer_map) => {
                 let opt_removed_peer = peer_map.remove_peer(&peer_map_key);
 
                 let (seeders, leechers) = peer_map.num_seeders_leechers();
-                let response_peers = peer_map.extract_response_peers(rng, max_num_peers_to_take);
+                let response_peers = peer_map.extract_response_peers(
+                    rng,
+                    &config.peer_selection,
+                    ip_address.into(),
+                    status == PeerStatus::Seeding,
+                    max_num_peers_to_take,
+                );
 
                 // Try shrinking the map if announcing peer is stopped and
                 // will therefore not be inserted
@@ -310,11 +775,19 @@ impl<I: Ip> TorrentData<I> {
                     }
                 }
 
-                ((seeders, leechers, response_peers), opt_removed_peer)
+                (
+                    (seeders, leechers, response_peers, intervals),
+                    opt_removed_peer,
+                )
             }
         };
 
-        match status {
+        let peer_count_change = match status {
+            PeerStatus::Leeching | PeerStatus::Seeding
+                if opt_removed_peer.is_none() && !allow_new_peer =>
+            {
+                PeerCountChange::Rejected
+            }
             PeerStatus::Leeching | PeerStatus::Seeding => {
                 #[cfg(feature = "metrics")]
                 if opt_removed_peer.is_none() {
@@ -322,6 +795,7 @@ impl<I: Ip> TorrentData<I> {
                 }
 
                 let peer = Peer {
+                    peer_id: request.peer_id,
                     is_seeder: status == PeerStatus::Seeding,
                     valid_until,
                 };
@@ -330,17 +804,81 @@ impl<I: Ip> TorrentData<I> {
                     Self::Small(peer_map) => peer_map.insert(peer_map_key, peer),
                     Self::Large(peer_map) => peer_map.insert(peer_map_key, peer),
                 }
+
+                match opt_removed_peer {
+                    Some(removed_peer) if removed_peer.peer_id == request.peer_id => {
+                        PeerCountChange::Unchanged
+                    }
+                    Some(removed_peer) => {
+                        on_peer_removed(config, peer_clients, removed_peer.peer_id);
+                        on_peer_added(config, peer_clients, request.peer_id);
+
+                        PeerCountChange::Unchanged
+                    }
+                    None => {
+                        on_peer_added(config, peer_clients, request.peer_id);
+
+                        PeerCountChange::Added
+                    }
+                }
             }
-            PeerStatus::Stopped =>
-            {
+            PeerStatus::Stopped => {
                 #[cfg(feature = "metrics")]
                 if opt_removed_peer.is_some() {
                     peer_gauge.decrement(1.0);
                 }
+
+                if let Some(removed_peer) = opt_removed_peer {
+                    on_peer_removed(config, peer_clients, removed_peer.peer_id);
+
+                    PeerCountChange::Removed
+                } else {
+                    PeerCountChange::Unchanged
+                }
             }
         };
 
-        response_data
+        (response_data, peer_count_change)
+    }
+
+    fn num_peers(&self) -> usize {
+        match self {
+            Self::Small(peer_map) => peer_map.0.len(),
+            Self::Large(peer_map) => peer_map.peers.len(),
+        }
+    }
+
+    fn contains(&self, key: &ResponsePeer<I>) -> bool {
+        match self {
+            Self::Small(peer_map) => peer_map.0.iter().any(|(k, _)| k == key),
+            Self::Large(peer_map) => peer_map.peers.contains_key(key),
+        }
+    }
+
+    fn remove_from_peer_ip_counter(&self, peer_ip_counter: &PeerIpCounter) {
+        if !peer_ip_counter.is_active() {
+            return;
+        }
+
+        match self {
+            Self::Small(peer_map) => {
+                for (key, _) in peer_map.0.iter() {
+                    peer_ip_counter.remove(key.ip_address.into());
+                }
+            }
+            Self::Large(peer_map) => {
+                for key in peer_map.peers.keys() {
+                    peer_ip_counter.remove(key.ip_address.into());
+                }
+            }
+        }
+    }
+
+    fn extend_with_peer_ids(&self, peer_ids: &mut Vec<PeerId>) {
+        match self {
+            Self::Small(peer_map) => peer_ids.extend(peer_map.0.iter().map(|(_, p)| p.peer_id)),
+            Self::Large(peer_map) => peer_ids.extend(peer_map.peers.values().map(|p| p.peer_id)),
+        }
     }
 
     fn scrape_statistics(&self) -> ScrapeStatistics {
@@ -357,7 +895,7 @@ impl<I: Ip> TorrentData<I> {
     }
 }
 
-impl<I: Ip> Default for TorrentData<I> {
+impl<I: Ip> Default for PeerMap<I> {
     fn default() -> Self {
         Self::Small(SmallPeerMap(ArrayVec::default()))
     }
@@ -395,12 +933,44 @@ impl<I: Ip> SmallPeerMap<I> {
         None
     }
 
-    fn extract_response_peers(&self, max_num_peers_to_take: usize) -> Vec<ResponsePeer<I>> {
-        Vec::from_iter(self.0.iter().take(max_num_peers_to_take).map(|(k, _)| *k))
+    fn extract_response_peers(
+        &self,
+        selection: &PeerSelectionConfig,
+        requester_ip: IpAddr,
+        requester_is_seeder: bool,
+        max_num_peers_to_take: usize,
+    ) -> Vec<ResponsePeer<I>> {
+        if selection.is_active() {
+            selection.select(
+                requester_ip,
+                requester_is_seeder,
+                self.0.iter().map(|(k, p)| p.to_candidate(*k)),
+                max_num_peers_to_take,
+            )
+        } else {
+            Vec::from_iter(self.0.iter().take(max_num_peers_to_take).map(|(k, _)| *k))
+        }
     }
 
-    fn clean_and_get_num_peers(&mut self, now: SecondsSinceServerStart) -> usize {
-        self.0.retain(|(_, peer)| peer.valid_until.valid(now));
+    fn clean_and_get_num_peers(
+        &mut self,
+        mut opt_removed_peer_ids: Option<&mut Vec<PeerId>>,
+        peer_ip_counter: &PeerIpCounter,
+        now: SecondsSinceServerStart,
+    ) -> usize {
+        self.0.retain(|(key, peer)| {
+            let keep = peer.valid_until.valid(now);
+
+            if !keep {
+                if let Some(removed_peer_ids) = opt_removed_peer_ids.as_mut() {
+                    removed_peer_ids.push(peer.peer_id);
+                }
+
+                peer_ip_counter.remove(key.ip_address.into());
+            }
+
+            keep
+        });
 
         self.0.len()
     }
@@ -451,13 +1021,29 @@ impl<I: Ip> LargePeerMap<I> {
     /// selection of peers from first and second halves of map in order to avoid
     /// returning too homogeneous peers.
     ///
+    /// If peer selection strategies are active, peers are instead selected
+    /// from up to `max_candidates` consecutive peers starting at a random
+    /// position.
+    ///
     /// Does NOT filter out announcing peer.
     pub fn extract_response_peers(
         &self,
         rng: &mut impl Rng,
+        selection: &PeerSelectionConfig,
+        requester_ip: IpAddr,
+        requester_is_seeder: bool,
         max_num_peers_to_take: usize,
     ) -> Vec<ResponsePeer<I>> {
-        if self.peers.len() <= max_num_peers_to_take {
+        if selection.is_active() {
+            selection.select_from_map(
+                rng,
+                &self.peers,
+                |key, peer| peer.to_candidate(key),
+                requester_ip,
+                requester_is_seeder,
+                max_num_peers_to_take,
+            )
+        } else if self.peers.len() <= max_num_peers_to_take {
             self.peers.keys().copied().collect()
         } else {
             let middle_index = self.peers.len() / 2;
@@ -492,12 +1078,24 @@ impl<I: Ip> LargePeerMap<I> {
         }
     }
 
-    fn clean_and_get_num_peers(&mut self, now: SecondsSinceServerStart) -> usize {
-        self.peers.retain(|_, peer| {
+    fn clean_and_get_num_peers(
+        &mut self,
+        mut opt_removed_peer_ids: Option<&mut Vec<PeerId>>,
+        peer_ip_counter: &PeerIpCounter,
+        now: SecondsSinceServerStart,
+    ) -> usize {
+        self.peers.retain(|key, peer| {
             let keep = peer.valid_until.valid(now);
 
-            if (!keep) & peer.is_seeder {
-                self.num_seeders -= 1;
+            if !keep {
+                if peer.is_seeder {
+                    self.num_seeders -= 1;
+                }
+                if let Some(removed_peer_ids) = opt_removed_peer_ids.as_mut() {
+                    removed_peer_ids.push(peer.peer_id);
+                }
+
+                peer_ip_counter.remove(key.ip_address.into());
             }
 
             keep
@@ -517,12 +1115,80 @@ impl<I: Ip> LargePeerMap<I> {
     }
 }
 
+/// Peer clients are counted for statistics or metrics
+fn tracks_peer_clients(config: &Config) -> bool {
+    #[allow(unused_mut)]
+    let mut tracks = config.statistics.peer_clients;
+
+    #[cfg(feature = "metrics")]
+    {
+        tracks |= config.metrics.active() && config.metrics.peer_clients;
+    }
+
+    tracks
+}
+
+fn on_peer_added(config: &Config, peer_clients: &mut PeerClientUpdates, peer_id: PeerId) {
+    if config.statistics.peer_clients {
+        peer_clients.add(aquatic_peer_id::PeerId(peer_id.0));
+    }
+
+    #[cfg(feature = "metrics")]
+    update_peer_client_gauges(config, peer_id, 1.0);
+}
+
+fn on_peer_removed(config: &Config, peer_clients: &mut PeerClientUpdates, peer_id: PeerId) {
+    if config.statistics.peer_clients {
+        peer_clients.remove(aquatic_peer_id::PeerId(peer_id.0));
+    }
+
+    #[cfg(feature = "metrics")]
+    update_peer_client_gauges(config, peer_id, -1.0);
+}
+
+/// Gauges are shared by all swarm workers, so they are updated by delta
+/// rather than set
+#[cfg(feature = "metrics")]
+fn update_peer_client_gauges(config: &Config, peer_id: PeerId, delta: f64) {
+    if !(config.metrics.active() && config.metrics.peer_clients) {
+        return;
+    }
+
+    let peer_id = aquatic_peer_id::PeerId(peer_id.0);
+
+    ::metrics::gauge!(
+        "aquatic_peer_clients",
+        "client" => peer_id.client().to_string(),
+    )
+    .increment(delta);
+
+    if config.metrics.peer_id_prefixes {
+        ::metrics::gauge!(
+            "aquatic_peer_id_prefixes",
+            "prefix_hex" => peer_id.first_8_bytes_hex().to_string(),
+        )
+        .increment(delta);
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 struct Peer {
+    pub peer_id: PeerId,
     pub valid_until: ValidUntil,
     pub is_seeder: bool,
 }
 
+impl Peer {
+    fn to_candidate<I: Ip>(self, key: ResponsePeer<I>) -> PeerCandidate<ResponsePeer<I>> {
+        PeerCandidate {
+            key,
+            ip: key.ip_address.into(),
+            is_seeder: self.is_seeder,
+            valid_until: self.valid_until,
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 enum PeerStatus {
     Seeding,
@@ -541,3 +1207,37 @@ impl PeerStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announce_intervals() {
+        let mut config = Config::default();
+
+        config.protocol.peer_announce_interval = 1000;
+
+        assert_eq!(AnnounceIntervals::new(&config, 0, 0).min_interval, None);
+
+        config.protocol.peer_min_announce_interval = 500;
+        config.announce_interval.dynamic = true;
+        config.announce_interval.min_interval = 100;
+        config.announce_interval.max_interval = 2000;
+
+        let intervals = AnnounceIntervals::new(&config, 0, 0);
+
+        assert_eq!(intervals.interval, 100);
+        assert_eq!(intervals.min_interval, Some(50));
+
+        let intervals = AnnounceIntervals::new(&config, 1_000_000, 0);
+
+        assert_eq!(intervals.interval, 2000);
+        assert_eq!(intervals.min_interval, Some(1000));
+
+        let intervals = AnnounceIntervals::fixed(&config);
+
+        assert_eq!(intervals.interval, 1000);
+        assert_eq!(intervals.min_interval, Some(500));
+    }
+}
@@ -1,5 +1,8 @@
-use std::sync::{atomic::AtomicUsize, Arc};
+use std::sync::{atomic::AtomicUsize, Arc, Mutex};
+use std::time::Instant;
 
+use aquatic_common::peer_lifecycle::LifecycleStatistics;
+use hdrhistogram::Histogram;
 use rand_distr::Gamma;
 
 pub use aquatic_http_protocol::common::*;
@@ -22,6 +25,9 @@ pub struct Statistics {
     pub responses_failure: AtomicUsize,
     pub bytes_sent: AtomicUsize,
     pub bytes_received: AtomicUsize,
+    pub connections: AtomicUsize,
+    pub connections_opened: AtomicUsize,
+    pub lifecycle: LifecycleStatistics,
 }
 
 #[derive(Clone)]
@@ -29,6 +35,12 @@ pub struct LoadTestState {
     pub info_hashes: Arc<Vec<InfoHash>>,
     pub statistics: Arc<Statistics>,
     pub gamma: Arc<Gamma<f64>>,
+    pub start_instant: Instant,
+    /// Response latencies in microseconds (open-loop mode only)
+    pub response_latencies: Arc<Mutex<Histogram<u64>>>,
+    /// Time until tracker closed connection in milliseconds (slowloris and
+    /// no-read scenarios only)
+    pub connection_lifetimes: Arc<Mutex<Histogram<u64>>>,
 }
 
 #[derive(PartialEq, Eq, Clone, Copy)]
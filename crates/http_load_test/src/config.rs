@@ -1,8 +1,10 @@
 use std::net::SocketAddr;
 
 use aquatic_common::cli::LogLevel;
+use aquatic_common::open_loop::OpenLoopConfig;
+use aquatic_common::peer_lifecycle::LifecycleConfig;
 use aquatic_toml_config::TomlConfig;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// aquatic_http_load_test configuration
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
@@ -23,7 +25,37 @@ pub struct Config {
     pub duration: usize,
     pub keep_alive: bool,
     pub enable_tls: bool,
+    /// Connection behaviour. Available values are normal, churn, slowloris
+    /// and no-read.
+    ///
+    /// - normal: send requests back to back (or according to open-loop
+    ///   schedule), reading each response before sending the next request
+    /// - churn: open connection, send one request and close connection
+    ///   without waiting for the response
+    /// - slowloris: send requests a few bytes at a time as configured in
+    ///   the slowloris section, keeping connection open until the tracker
+    ///   closes it
+    /// - no-read: send requests back to back without ever reading responses,
+    ///   until the tracker closes the connection
+    ///
+    /// For slowloris and no-read, the time until the tracker closes
+    /// connections is reported. Running them with many connections is also
+    /// useful for measuring tracker memory use per idle connection.
+    pub scenario: Scenario,
     pub torrents: TorrentConfig,
+    /// Open-loop (fixed request rate) mode
+    ///
+    /// Requests are scheduled per connection. Requires `keep_alive` to be
+    /// set.
+    pub open_loop: OpenLoopConfig,
+    pub slowloris: SlowlorisConfig,
+    /// Peer lifecycle simulation
+    ///
+    /// Arrivals are divided between workers. Connections send announce
+    /// requests on behalf of the simulated peers of their worker, using
+    /// `torrents.peer_seeder_probability` for arriving peers. Can only be
+    /// used with the normal scenario.
+    pub lifecycle: LifecycleConfig,
 }
 
 impl aquatic_common::cli::Config for Config {
@@ -44,11 +76,25 @@ impl Default for Config {
             duration: 0,
             keep_alive: true,
             enable_tls: true,
+            scenario: Scenario::Normal,
             torrents: TorrentConfig::default(),
+            open_loop: OpenLoopConfig::default(),
+            slowloris: SlowlorisConfig::default(),
+            lifecycle: LifecycleConfig::default(),
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scenario {
+    #[default]
+    Normal,
+    Churn,
+    Slowloris,
+    NoRead,
+}
+
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct TorrentConfig {
@@ -80,6 +126,24 @@ impl Default for TorrentConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlowlorisConfig {
+    /// Number of request bytes to send at a time
+    pub bytes_per_write: usize,
+    /// Wait this many milliseconds between writes
+    pub write_interval_ms: u64,
+}
+
+impl Default for SlowlorisConfig {
+    fn default() -> Self {
+        Self {
+            bytes_per_write: 1,
+            write_interval_ms: 1000,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Config;
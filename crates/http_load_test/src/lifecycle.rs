@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use aquatic_common::peer_lifecycle::{LifecycleEvent, PeerEvent, PeerPopulation};
+use rand::Rng;
+
+use crate::common::*;
+use crate::config::Config;
+use crate::utils::select_info_hash_index;
+
+/// Simulated peers of a worker, shared by its connections
+pub struct SimulatedPeers {
+    population: PeerPopulation,
+    /// Torrent and port of each active peer
+    peers: HashMap<usize, (InfoHash, u16)>,
+}
+
+impl SimulatedPeers {
+    pub fn new(config: &Config, rng: &mut impl Rng) -> anyhow::Result<Self> {
+        let population = PeerPopulation::new(
+            &config.lifecycle,
+            config.torrents.peer_seeder_probability,
+            config.num_workers,
+            usize::MAX,
+            rng,
+            Instant::now(),
+        )?;
+
+        Ok(Self {
+            population,
+            peers: Default::default(),
+        })
+    }
+
+    /// Return next announce request that is due along with index of peer
+    /// sending it, if any
+    pub fn next_request(
+        &mut self,
+        config: &Config,
+        state: &LoadTestState,
+        rng: &mut impl Rng,
+        now: Instant,
+    ) -> Option<(usize, Request)> {
+        loop {
+            let event = self.population.poll(rng, now)?;
+
+            state.statistics.lifecycle.record(&event);
+
+            let (peer_index, peer_id, event, seeding) = match event {
+                LifecycleEvent::Announce {
+                    peer_index,
+                    peer_id,
+                    event,
+                    seeding,
+                } => (peer_index, peer_id, event, seeding),
+                LifecycleEvent::Abandon { peer_index } => {
+                    self.peers.remove(&peer_index);
+
+                    continue;
+                }
+                LifecycleEvent::ArrivalDropped => continue,
+            };
+
+            // Peer indices are reused, so pick new torrent and port
+            if event == PeerEvent::Started {
+                let info_hash = state.info_hashes[select_info_hash_index(config, state, rng)];
+
+                self.peers.insert(peer_index, (info_hash, rng.gen()));
+            }
+
+            let (info_hash, port) = match self.peers.get(&peer_index) {
+                Some(peer) => *peer,
+                None => continue,
+            };
+
+            let event = match event {
+                PeerEvent::Started => AnnounceEvent::Started,
+                PeerEvent::Completed => AnnounceEvent::Completed,
+                PeerEvent::Stopped => {
+                    self.peers.remove(&peer_index);
+
+                    AnnounceEvent::Stopped
+                }
+                PeerEvent::None => AnnounceEvent::Empty,
+            };
+
+            let request = Request::Announce(AnnounceRequest {
+                info_hash,
+                peer_id: PeerId(peer_id),
+                bytes_left: if seeding { 0 } else { 50 },
+                event,
+                key: None,
+                numwant: None,
+                port,
+                bytes_uploaded: 0,
+                bytes_downloaded: 0,
+            });
+
+            return Some((peer_index, request));
+        }
+    }
+
+    pub fn population(&mut self) -> &mut PeerPopulation {
+        &mut self.population
+    }
+}
@@ -1,13 +1,16 @@
-use std::sync::{atomic::Ordering, Arc};
+use std::sync::{atomic::Ordering, Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use ::glommio::LocalExecutorBuilder;
+use aquatic_common::open_loop::RateSchedule;
+use hdrhistogram::Histogram;
 use rand::prelude::*;
 use rand_distr::Gamma;
 
 mod common;
 mod config;
+mod lifecycle;
 mod network;
 mod utils;
 
@@ -21,6 +24,8 @@ static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 /// Multiply bytes during a second with this to get Mbit/s
 const MBITS_FACTOR: f64 = 1.0 / ((1024.0 * 1024.0) / 8.0);
 
+const PERCENTILES: &[f64] = &[10.0, 25.0, 50.0, 75.0, 90.0, 95.0, 99.0, 99.9, 100.0];
+
 pub fn main() {
     aquatic_common::cli::run_app_with_cli_and_config::<Config>(
         "aquatic_http_load_test: BitTorrent load tester",
@@ -34,6 +39,18 @@ fn run(config: Config) -> ::anyhow::Result<()> {
     if config.torrents.weight_announce + config.torrents.weight_scrape == 0 {
         panic!("Error: at least one weight must be larger than zero.");
     }
+    if config.open_loop.active() && !config.keep_alive {
+        panic!("Error: open-loop mode requires keep_alive to be set.");
+    }
+    if config.open_loop.active() && config.scenario != Scenario::Normal {
+        panic!("Error: open-loop mode can only be used with normal scenario.");
+    }
+    if config.lifecycle.active() && config.open_loop.active() {
+        panic!("Error: open-loop mode and lifecycle simulation can't be combined.");
+    }
+    if config.lifecycle.active() && config.scenario != Scenario::Normal {
+        panic!("Error: lifecycle simulation can only be used with normal scenario.");
+    }
 
     println!("Starting client with config: {:#?}", config);
 
@@ -55,6 +72,9 @@ fn run(config: Config) -> ::anyhow::Result<()> {
         info_hashes: Arc::new(info_hashes),
         statistics: Arc::new(Statistics::default()),
         gamma: Arc::new(gamma),
+        start_instant: Instant::now(),
+        response_latencies: Arc::new(Mutex::new(Histogram::new(3).unwrap())),
+        connection_lifetimes: Arc::new(Mutex::new(Histogram::new(3).unwrap())),
     };
 
     let opt_tls_config = if config.enable_tls {
@@ -84,8 +104,17 @@ fn run(config: Config) -> ::anyhow::Result<()> {
 }
 
 fn monitor_statistics(state: LoadTestState, config: &Config) {
-    let start_time = Instant::now();
+    let start_time = state.start_instant;
     let mut report_avg_response_vec: Vec<f64> = Vec::new();
+    let mut report_avg_request_vec: Vec<f64> = Vec::new();
+    let mut report_avg_connections_opened_vec: Vec<f64> = Vec::new();
+    let mut report_latencies = Histogram::<u64>::new(3).unwrap();
+
+    let open_loop_schedule = config
+        .open_loop
+        .active()
+        .then(|| RateSchedule::new(&config.open_loop, 1));
+    let mut last = start_time;
 
     let interval = 5;
     let interval_f64 = interval as f64;
@@ -112,6 +141,12 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
             statistics.bytes_sent.fetch_and(0, Ordering::Relaxed) as f64 / interval_f64;
         let bytes_received_per_second =
             statistics.bytes_received.fetch_and(0, Ordering::Relaxed) as f64 / interval_f64;
+        let connections_opened_per_second = statistics
+            .connections_opened
+            .fetch_and(0, Ordering::Relaxed) as f64
+            / interval_f64;
+
+        let connections = statistics.connections.load(Ordering::Relaxed);
 
         let responses_announce_per_second = responses_announce / interval_f64;
 
@@ -120,8 +155,23 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
             + responses_failure_per_second;
 
         report_avg_response_vec.push(responses_per_second);
+        report_avg_request_vec.push(requests_per_second);
+        report_avg_connections_opened_vec.push(connections_opened_per_second);
 
         println!();
+
+        if let Some(schedule) = open_loop_schedule.as_ref() {
+            let now = Instant::now();
+
+            let target_per_second = (schedule.requests_due(now - start_time)
+                - schedule.requests_due(last - start_time))
+                / (now - last).as_secs_f64();
+
+            last = now;
+
+            println!("Target requests: {:.2}/second", target_per_second);
+        }
+
         println!("Requests out: {:.2}/second", requests_per_second);
         println!("Responses in: {:.2}/second", responses_per_second);
         println!(
@@ -142,6 +192,32 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
             "Bandwidth in:  {:.2}Mbit/s",
             bytes_received_per_second * MBITS_FACTOR
         );
+        println!("Active connections: {}", connections);
+        println!(
+            "Connections opened: {:.2}/second",
+            connections_opened_per_second
+        );
+
+        if config.lifecycle.active() {
+            statistics.lifecycle.print_and_reset(interval_f64);
+        }
+
+        if let Scenario::Slowloris | Scenario::NoRead = config.scenario {
+            print_connection_lifetime_percentiles(&state.connection_lifetimes.lock().unwrap());
+        }
+
+        if open_loop_schedule.is_some() {
+            let latencies = ::std::mem::replace(
+                &mut *state.response_latencies.lock().unwrap(),
+                Histogram::new(3).unwrap(),
+            );
+
+            print_latency_percentiles(&latencies);
+
+            if let Err(err) = report_latencies.add(latencies) {
+                eprintln!("Couldn't merge latency histograms: {:#}", err);
+            }
+        }
 
         let time_elapsed = start_time.elapsed();
         let duration = Duration::from_secs(config.duration as u64);
@@ -155,18 +231,76 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
                 concat!(
                     "\n# aquatic load test report\n\n",
                     "Test ran for {} seconds.\n",
-                    "Average responses per second: {:.2}\n\nConfig: {:#?}\n"
+                    "Average responses per second: {:.2}",
                 ),
                 time_elapsed.as_secs(),
                 report_avg,
-                config
             );
 
+            if let Some(schedule) = open_loop_schedule.as_ref() {
+                let report_avg_requests: f64 =
+                    report_avg_request_vec.into_iter().sum::<f64>() / report_len;
+
+                println!(
+                    "Average target requests per second: {:.2}",
+                    schedule.requests_due(time_elapsed) / time_elapsed.as_secs_f64()
+                );
+                println!("Average requests per second: {:.2}", report_avg_requests);
+                print_latency_percentiles(&report_latencies);
+            }
+
+            match config.scenario {
+                Scenario::Normal => (),
+                Scenario::Churn => {
+                    let report_avg_connections_opened: f64 =
+                        report_avg_connections_opened_vec.into_iter().sum::<f64>() / report_len;
+
+                    println!(
+                        "Average connections opened per second: {:.2}",
+                        report_avg_connections_opened
+                    );
+                }
+                Scenario::Slowloris | Scenario::NoRead => {
+                    print_connection_lifetime_percentiles(
+                        &state.connection_lifetimes.lock().unwrap(),
+                    );
+                }
+            }
+
+            println!("\nConfig: {:#?}\n", config);
+
             break;
         }
     }
 }
 
+fn print_latency_percentiles(latencies: &Histogram<u64>) {
+    println!("Response latency (milliseconds):");
+
+    for p in PERCENTILES {
+        println!(
+            "  - p{}: {:.2}",
+            p,
+            latencies.value_at_percentile(*p) as f64 / 1000.0
+        );
+    }
+}
+
+fn print_connection_lifetime_percentiles(lifetimes: &Histogram<u64>) {
+    println!(
+        "Time until tracker closed connection (seconds, {} connections):",
+        lifetimes.len()
+    );
+
+    for p in PERCENTILES {
+        println!(
+            "  - p{}: {:.2}",
+            p,
+            lifetimes.value_at_percentile(*p) as f64 / 1000.0
+        );
+    }
+}
+
 #[derive(Debug)]
 struct FakeCertificateVerifier;
 
@@ -4,17 +4,34 @@ use std::{
     io::Cursor,
     rc::Rc,
     sync::{atomic::Ordering, Arc},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use aquatic_common::open_loop::RateSchedule;
 use aquatic_http_protocol::response::Response;
 use futures_lite::{AsyncReadExt, AsyncWriteExt};
 use futures_rustls::TlsConnector;
 use glommio::net::TcpStream;
 use glommio::{prelude::*, timer::TimerActionRepeat};
+use hdrhistogram::Histogram;
 use rand::{prelude::SmallRng, SeedableRng};
 
-use crate::{common::LoadTestState, config::Config, utils::create_random_request};
+use crate::{
+    common::{LoadTestState, Request},
+    config::{Config, Scenario},
+    lifecycle::SimulatedPeers,
+    utils::create_random_request,
+};
+
+/// Wait at most this long before checking for due lifecycle events again
+const MAX_LIFECYCLE_WAIT: Duration = Duration::from_millis(100);
+
+/// Response latencies recorded by connections on current thread, in
+/// microseconds
+type LocalLatencies = Rc<RefCell<Histogram<u64>>>;
+
+/// Simulated peers of current thread (lifecycle mode only)
+type LocalPeers = Option<Rc<RefCell<SimulatedPeers>>>;
 
 pub async fn run_socket_thread(
     config: Config,
@@ -24,6 +41,23 @@ pub async fn run_socket_thread(
     let config = Rc::new(config);
     let num_active_connections = Rc::new(RefCell::new(0usize));
     let rng = Rc::new(RefCell::new(SmallRng::from_entropy()));
+    let latencies: LocalLatencies = Rc::new(RefCell::new(Histogram::new(3).unwrap()));
+    let peers: LocalPeers = if config.lifecycle.active() {
+        let peers = SimulatedPeers::new(&config, &mut *rng.borrow_mut())?;
+
+        Some(Rc::new(RefCell::new(peers)))
+    } else {
+        None
+    };
+
+    if config.open_loop.active() {
+        let latencies = latencies.clone();
+        let load_test_state = load_test_state.clone();
+
+        TimerActionRepeat::repeat(move || {
+            flush_latencies(load_test_state.clone(), latencies.clone())
+        });
+    }
 
     let interval = config.connection_creation_interval_ms;
 
@@ -36,6 +70,8 @@ pub async fn run_socket_thread(
                     load_test_state.clone(),
                     num_active_connections.clone(),
                     rng.clone(),
+                    latencies.clone(),
+                    peers.clone(),
                 )
                 .await
                 {
@@ -54,6 +90,8 @@ pub async fn run_socket_thread(
                 load_test_state.clone(),
                 num_active_connections.clone(),
                 rng.clone(),
+                latencies.clone(),
+                peers.clone(),
             )
         });
     }
@@ -63,6 +101,7 @@ pub async fn run_socket_thread(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn periodically_open_connections(
     config: Rc<Config>,
     interval: Duration,
@@ -70,6 +109,8 @@ async fn periodically_open_connections(
     load_test_state: LoadTestState,
     num_active_connections: Rc<RefCell<usize>>,
     rng: Rc<RefCell<SmallRng>>,
+    latencies: LocalLatencies,
+    peers: LocalPeers,
 ) -> Option<Duration> {
     if *num_active_connections.borrow() < config.num_connections {
         spawn_local(async move {
@@ -79,6 +120,8 @@ async fn periodically_open_connections(
                 load_test_state,
                 num_active_connections,
                 rng.clone(),
+                latencies,
+                peers,
             )
             .await
             {
@@ -91,17 +134,50 @@ async fn periodically_open_connections(
     Some(interval)
 }
 
+/// Move response latencies recorded on this thread to shared histogram
+async fn flush_latencies(
+    load_test_state: LoadTestState,
+    latencies: LocalLatencies,
+) -> Option<Duration> {
+    let local_latencies = latencies.replace(Histogram::new(3).unwrap());
+
+    if let Err(err) = load_test_state
+        .response_latencies
+        .lock()
+        .unwrap()
+        .add(local_latencies)
+    {
+        ::log::error!("couldn't merge latency histograms: {:#}", err);
+    }
+
+    Some(Duration::from_secs(1))
+}
+
 async fn run_connection(
     config: Rc<Config>,
     opt_tls_config: Option<Arc<rustls::ClientConfig>>,
     load_test_state: LoadTestState,
     num_active_connections: Rc<RefCell<usize>>,
     rng: Rc<RefCell<SmallRng>>,
+    latencies: LocalLatencies,
+    peers: LocalPeers,
 ) -> anyhow::Result<()> {
     let stream = TcpStream::connect(config.server_address)
         .await
         .map_err(|err| anyhow::anyhow!("connect: {:?}", err))?;
 
+    let opt_schedule = config.open_loop.active().then(|| {
+        RateSchedule::new(
+            &config.open_loop,
+            config.num_workers * config.num_connections,
+        )
+    });
+    // Don't try to catch up on requests that would have been sent before
+    // connection was opened
+    let requests_scheduled = opt_schedule
+        .map(|schedule| schedule.requests_due(load_test_state.start_instant.elapsed()) as u64)
+        .unwrap_or(0);
+
     if let Some(tls_config) = opt_tls_config {
         let stream = TlsConnector::from(tls_config)
             .connect("example.com".try_into().unwrap(), stream)
@@ -113,6 +189,10 @@ async fn run_connection(
             rng,
             stream,
             buffer: Box::new([0; 2048]),
+            opt_schedule,
+            requests_scheduled,
+            latencies,
+            peers,
         };
 
         connection.run(num_active_connections).await?;
@@ -123,6 +203,10 @@ async fn run_connection(
             rng,
             stream,
             buffer: Box::new([0; 2048]),
+            opt_schedule,
+            requests_scheduled,
+            latencies,
+            peers,
         };
 
         connection.run(num_active_connections).await?;
@@ -137,6 +221,10 @@ struct Connection<S> {
     rng: Rc<RefCell<SmallRng>>,
     stream: S,
     buffer: Box<[u8; 2048]>,
+    opt_schedule: Option<RateSchedule>,
+    requests_scheduled: u64,
+    latencies: LocalLatencies,
+    peers: LocalPeers,
 }
 
 impl<S> Connection<S>
@@ -144,23 +232,76 @@ where
     S: futures::AsyncRead + futures::AsyncWrite + Unpin + 'static,
 {
     async fn run(&mut self, num_active_connections: Rc<RefCell<usize>>) -> anyhow::Result<()> {
+        let statistics = self.load_test_state.statistics.clone();
+
         *num_active_connections.borrow_mut() += 1;
+        statistics.connections.fetch_add(1, Ordering::Relaxed);
+        statistics
+            .connections_opened
+            .fetch_add(1, Ordering::Relaxed);
+
+        let opened_at = Instant::now();
+
+        let result = match self.config.scenario {
+            Scenario::Normal => match self.peers.clone() {
+                Some(peers) => self.run_lifecycle_loop(peers).await,
+                None => self.run_connection_loop().await,
+            },
+            Scenario::Churn => self.send_random_request().await,
+            Scenario::Slowloris => self.run_slowloris_loop().await,
+            Scenario::NoRead => self.run_no_read_loop().await,
+        };
 
-        let result = self.run_connection_loop().await;
+        if let Scenario::Slowloris | Scenario::NoRead = self.config.scenario {
+            let lifetime = opened_at.elapsed();
+
+            self.load_test_state
+                .connection_lifetimes
+                .lock()
+                .unwrap()
+                .record(lifetime.as_millis() as u64)
+                .unwrap();
+        }
 
         if let Err(err) = &result {
             ::log::info!("connection error: {:?}", err);
         }
 
         *num_active_connections.borrow_mut() -= 1;
+        statistics.connections.fetch_sub(1, Ordering::Relaxed);
 
         result
     }
 
     async fn run_connection_loop(&mut self) -> anyhow::Result<()> {
         loop {
-            self.send_request().await?;
-            self.read_response().await?;
+            if let Some(schedule) = self.opt_schedule {
+                let scheduled_at = self.load_test_state.start_instant
+                    + schedule.send_time(self.requests_scheduled);
+
+                self.requests_scheduled += 1;
+
+                let now = Instant::now();
+
+                if scheduled_at > now {
+                    glommio::timer::sleep(scheduled_at - now).await;
+                }
+
+                self.send_random_request().await?;
+                self.read_response().await?;
+
+                // Measure from scheduled time so that requests delayed by
+                // slow responses to previous ones are accounted for
+                let latency = scheduled_at.elapsed();
+
+                self.latencies
+                    .borrow_mut()
+                    .record(latency.as_micros() as u64)
+                    .unwrap();
+            } else {
+                self.send_random_request().await?;
+                self.read_response().await?;
+            }
 
             if !self.config.keep_alive {
                 break Ok(());
@@ -168,23 +309,123 @@ where
         }
     }
 
-    async fn send_request(&mut self) -> anyhow::Result<()> {
-        let request = create_random_request(
+    /// Send announce requests on behalf of simulated peers as their events
+    /// become due
+    async fn run_lifecycle_loop(
+        &mut self,
+        peers: Rc<RefCell<SimulatedPeers>>,
+    ) -> anyhow::Result<()> {
+        loop {
+            let now = Instant::now();
+
+            let opt_request = peers.borrow_mut().next_request(
+                &self.config,
+                &self.load_test_state,
+                &mut *self.rng.borrow_mut(),
+                now,
+            );
+
+            let (peer_index, request) = match opt_request {
+                Some(peer_request) => peer_request,
+                None => {
+                    let next_event_at = peers.borrow_mut().population().next_event_at();
+                    let wait = next_event_at
+                        .saturating_duration_since(now)
+                        .clamp(Duration::from_millis(1), MAX_LIFECYCLE_WAIT);
+
+                    glommio::timer::sleep(wait).await;
+
+                    continue;
+                }
+            };
+
+            self.send_request(&request).await?;
+
+            match self.read_response().await? {
+                Some(Response::Announce(response)) => {
+                    peers.borrow_mut().population().handle_announce_response(
+                        peer_index,
+                        Duration::from_secs(response.announce_interval as u64),
+                        Instant::now(),
+                    );
+                }
+                Some(_) => (),
+                None => return Ok(()),
+            }
+
+            if !self.config.keep_alive {
+                break Ok(());
+            }
+        }
+    }
+
+    /// Send requests a few bytes at a time until tracker closes connection
+    async fn run_slowloris_loop(&mut self) -> anyhow::Result<()> {
+        let bytes_per_write = self.config.slowloris.bytes_per_write.max(1);
+        let write_interval = Duration::from_millis(self.config.slowloris.write_interval_ms);
+
+        loop {
+            let request = self.create_random_request();
+            let request_len = self.write_request_to_buffer(&request)?;
+
+            for chunk in self.buffer[..request_len].chunks(bytes_per_write) {
+                self.stream.write_all(chunk).await?;
+                self.stream.flush().await?;
+
+                self.load_test_state
+                    .statistics
+                    .bytes_sent
+                    .fetch_add(chunk.len(), Ordering::Relaxed);
+
+                glommio::timer::sleep(write_interval).await;
+            }
+
+            self.load_test_state
+                .statistics
+                .requests
+                .fetch_add(1, Ordering::Relaxed);
+
+            if self.read_response().await?.is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Send requests without reading responses until writing fails, which
+    /// happens when the tracker has closed the connection
+    async fn run_no_read_loop(&mut self) -> anyhow::Result<()> {
+        loop {
+            self.send_random_request().await?;
+        }
+    }
+
+    fn create_random_request(&self) -> Request {
+        create_random_request(
             &self.config,
             &self.load_test_state,
             &mut self.rng.borrow_mut(),
-        );
+        )
+    }
 
+    /// Write request to buffer, returning its length
+    fn write_request_to_buffer(&mut self, request: &Request) -> anyhow::Result<usize> {
         let mut cursor = Cursor::new(&mut self.buffer[..]);
 
         request.write(&mut cursor, self.config.url_suffix.as_bytes())?;
 
-        let cursor_position = cursor.position() as usize;
+        Ok(cursor.position() as usize)
+    }
 
-        let bytes_sent = self
-            .stream
-            .write(&cursor.into_inner()[..cursor_position])
-            .await?;
+    async fn send_random_request(&mut self) -> anyhow::Result<()> {
+        let request = self.create_random_request();
+
+        self.send_request(&request).await
+    }
+
+    async fn send_request(&mut self, request: &Request) -> anyhow::Result<()> {
+        let request_len = self.write_request_to_buffer(request)?;
+
+        let bytes_sent = self.stream.write(&self.buffer[..request_len]).await?;
 
         self.stream.flush().await?;
 
@@ -201,7 +442,10 @@ where
         Ok(())
     }
 
-    async fn read_response(&mut self) -> anyhow::Result<()> {
+    /// Read response and update statistics
+    ///
+    /// Returns None if connection was closed by tracker
+    async fn read_response(&mut self) -> anyhow::Result<Option<Response>> {
         let mut buffer_position = 0;
 
         loop {
@@ -211,7 +455,7 @@ where
                 .await?;
 
             if bytes_read == 0 {
-                break;
+                return Ok(None);
             }
 
             buffer_position += bytes_read;
@@ -231,7 +475,7 @@ where
             if let Some(body_start_index) = opt_body_start_index {
                 match Response::parse_bytes(&interesting_bytes[body_start_index..]) {
                     Ok(response) => {
-                        match response {
+                        match &response {
                             Response::Announce(_) => {
                                 self.load_test_state
                                     .statistics
@@ -258,7 +502,7 @@ where
                             .bytes_received
                             .fetch_add(interesting_bytes.len(), Ordering::Relaxed);
 
-                        break;
+                        return Ok(Some(response));
                     }
                     Err(err) => {
                         ::log::warn!(
@@ -271,7 +515,5 @@ where
                 }
             }
         }
-
-        Ok(())
     }
 }
@@ -68,7 +68,7 @@ fn create_scrape_request(config: &Config, state: &LoadTestState, rng: &mut impl
 }
 
 #[inline]
-fn select_info_hash_index(config: &Config, state: &LoadTestState, rng: &mut impl Rng) -> usize {
+pub fn select_info_hash_index(config: &Config, state: &LoadTestState, rng: &mut impl Rng) -> usize {
     gamma_usize(rng, &state.gamma, config.torrents.number_of_torrents - 1)
 }
 
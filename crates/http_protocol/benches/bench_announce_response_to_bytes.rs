@@ -17,6 +17,7 @@ pub fn bench(c: &mut Criterion) {
 
     let announce_response = AnnounceResponse {
         announce_interval: 120,
+        min_announce_interval: None,
         complete: 100,
         incomplete: 500,
         peers: ResponsePeerListV4(peers),
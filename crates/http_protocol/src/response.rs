@@ -45,6 +45,14 @@ pub struct ScrapeStatistics {
 pub struct AnnounceResponse {
     #[serde(rename = "interval")]
     pub announce_interval: usize,
+    // Serialize as integer if Some, otherwise skip
+    #[serde(
+        rename = "min interval",
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_usize"
+    )]
+    pub min_announce_interval: Option<usize>,
     pub complete: usize,
     pub incomplete: usize,
     #[serde(default)]
@@ -77,6 +85,12 @@ impl AnnounceResponse {
                 .as_bytes(),
         )?;
 
+        if let Some(min_announce_interval) = self.min_announce_interval {
+            bytes_written += output.write(b"e12:min intervali")?;
+            bytes_written +=
+                output.write(itoa::Buffer::new().format(min_announce_interval).as_bytes())?;
+        }
+
         bytes_written += output.write(b"e5:peers")?;
         bytes_written += output.write(
             itoa::Buffer::new()
@@ -246,6 +260,7 @@ impl quickcheck::Arbitrary for AnnounceResponse {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
         Self {
             announce_interval: usize::arbitrary(g),
+            min_announce_interval: Option::arbitrary(g),
             complete: usize::arbitrary(g),
             incomplete: usize::arbitrary(g),
             peers: ResponsePeerListV4::arbitrary(g),
@@ -68,6 +68,17 @@ where
     }
 }
 
+#[inline]
+pub fn serialize_optional_usize<S>(v: &Option<usize>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match v {
+        Some(n) => serializer.serialize_u64(*n as u64),
+        None => Err(serde::ser::Error::custom("use skip_serializing_if")),
+    }
+}
+
 #[inline]
 pub fn serialize_20_bytes<S>(bytes: &[u8; 20], serializer: S) -> Result<S::Ok, S::Error>
 where
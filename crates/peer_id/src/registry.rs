@@ -0,0 +1,519 @@
+use std::{collections::HashMap, fmt::Display, sync::OnceLock};
+
+use compact_str::{format_compact, CompactString};
+use serde::{Deserialize, Serialize};
+
+use crate::{PeerClient, PeerId};
+
+/// Built-in client definitions
+///
+/// Mostly based on the lists at <https://wiki.theory.org/BitTorrentSpecification#peer_id>
+/// and in BEP 20. Display names of clients that were supported before the
+/// registry was introduced are kept as they were.
+#[rustfmt::skip]
+const BUILTIN_CLIENTS: &[(PeerIdStyle, &str, &str, VersionFormat)] = {
+    use PeerIdStyle::{Azureus, Mainline};
+    use VersionFormat::*;
+
+    &[
+        (Azureus, "7T", "aTorrent", Raw),
+        (Azureus, "AB", "AnyEvent::BitTorrent", Raw),
+        (Azureus, "AG", "Ares", Raw),
+        (Azureus, "A~", "Ares", Raw),
+        (Azureus, "AR", "Arctic", Raw),
+        (Azureus, "AT", "Artemis", Raw),
+        (Azureus, "AV", "Avicora", Raw),
+        (Azureus, "AX", "BitPump", Raw),
+        (Azureus, "AZ", "Vuze", Dotted),
+        (Azureus, "BB", "BitBuddy", Raw),
+        (Azureus, "BC", "BitComet", Raw),
+        (Azureus, "BE", "Baretorrent", Raw),
+        (Azureus, "BF", "Bitflu", Raw),
+        (Azureus, "BG", "BTG", Dotted),
+        (Azureus, "BI", "BiglyBT", Dotted),
+        (Azureus, "BL", "BitBlinder", Raw),
+        (Azureus, "BP", "BitTorrent Pro", Raw),
+        (Azureus, "BR", "BitRocket", Raw),
+        (Azureus, "BS", "BTSlave", Raw),
+        (Azureus, "BT", "BitTorrent", ThreeDottedPrerelease),
+        (Azureus, "BW", "BitWombat", Raw),
+        (Azureus, "BX", "BittorrentX", Raw),
+        (Azureus, "CD", "Enhanced CTorrent", Raw),
+        (Azureus, "CT", "CTorrent", Raw),
+        (Azureus, "DE", "Deluge", ThreeDottedPrerelease),
+        (Azureus, "DP", "Propagate Data Client", Raw),
+        (Azureus, "EB", "EBit", Raw),
+        (Azureus, "ES", "electric sheep", Raw),
+        (Azureus, "FC", "FileCroc", Raw),
+        (Azureus, "FD", "Free Download Manager", Raw),
+        (Azureus, "FT", "FoxTorrent", Raw),
+        (Azureus, "FX", "Freebox BitTorrent", Raw),
+        (Azureus, "GS", "GSTorrent", Raw),
+        (Azureus, "HK", "Hekate", Raw),
+        (Azureus, "HL", "Halite", Raw),
+        (Azureus, "HM", "hMule", Raw),
+        (Azureus, "HN", "Hydranode", Raw),
+        (Azureus, "IL", "iLivid", Raw),
+        (Azureus, "JS", "Justseed.it client", Raw),
+        (Azureus, "JT", "JavaTorrent", Raw),
+        (Azureus, "KG", "KGet", Raw),
+        (Azureus, "KT", "KTorrent", Raw),
+        (Azureus, "LC", "LeechCraft", Raw),
+        (Azureus, "LH", "LH-ABC", Raw),
+        (Azureus, "LP", "Lphant", Raw),
+        (Azureus, "lt", "lt (rakshasa)", TwoDigitMinor),
+        (Azureus, "LT", "lt (rasterbar)", TwoDigitMinor),
+        (Azureus, "LW", "LimeWire", Raw),
+        (Azureus, "MK", "Meerkat", Raw),
+        (Azureus, "MO", "MonoTorrent", Raw),
+        (Azureus, "MP", "MooPolice", Raw),
+        (Azureus, "MR", "Miro", Raw),
+        (Azureus, "MT", "MoonlightTorrent", Raw),
+        (Azureus, "NB", "Net::BitTorrent", Raw),
+        (Azureus, "NX", "Net Transport", Raw),
+        (Azureus, "OS", "OneSwarm", Raw),
+        (Azureus, "OT", "OmegaTorrent", Raw),
+        (Azureus, "PB", "Protocol::BitTorrent", Raw),
+        (Azureus, "PD", "Pando", Raw),
+        (Azureus, "PI", "PicoTorrent", Raw),
+        (Azureus, "PT", "PHPTracker", Raw),
+        (Azureus, "qB", "QBitTorrent", ThreeDotted),
+        (Azureus, "QD", "QQDownload", Raw),
+        (Azureus, "QT", "Qt 4 Torrent example", Raw),
+        (Azureus, "rQ", "rqbit", Raw),
+        (Azureus, "RT", "Retriever", Raw),
+        (Azureus, "RZ", "RezTorrent", Raw),
+        (Azureus, "S~", "Shareaza alpha/beta", Raw),
+        (Azureus, "SB", "SwiftBit", Raw),
+        (Azureus, "SD", "Thunder", Raw),
+        (Azureus, "SM", "SoMud", Raw),
+        (Azureus, "SP", "BitSpirit", Raw),
+        (Azureus, "SS", "SwarmScope", Raw),
+        (Azureus, "ST", "SymTorrent", Raw),
+        (Azureus, "st", "sharktorrent", Raw),
+        (Azureus, "SZ", "Shareaza", Dotted),
+        (Azureus, "TB", "Torch", Raw),
+        (Azureus, "TE", "terasaur Seed Bank", Raw),
+        (Azureus, "TL", "Tribler", Raw),
+        (Azureus, "TN", "TorrentDotNET", Raw),
+        (Azureus, "TR", "Transmission", Transmission),
+        (Azureus, "TS", "Torrentstorm", Raw),
+        (Azureus, "TT", "TuoTu", Raw),
+        (Azureus, "TX", "Tixati", TwoDigitPairs),
+        (Azureus, "UE", "µTorrent Emb.", ThreeDottedPrerelease),
+        (Azureus, "UL", "uLeecher!", Raw),
+        (Azureus, "UM", "µTorrent Mac", ThreeDottedPrerelease),
+        (Azureus, "UT", "µTorrent", ThreeDottedPrerelease),
+        (Azureus, "UW", "µTorrent Web", ThreeDottedPrerelease),
+        (Azureus, "VG", "Vagaa", Raw),
+        (Azureus, "WD", "WebTorrent Desktop", TwoDigitPairs),
+        (Azureus, "WT", "BitLet", Raw),
+        (Azureus, "WW", "WebTorrent", TwoDigitPairs),
+        (Azureus, "WY", "FireTorrent", Raw),
+        (Azureus, "XF", "Xfplay", Raw),
+        (Azureus, "XL", "Xunlei", Raw),
+        (Azureus, "XS", "XSwifter", Raw),
+        (Azureus, "XT", "XanTorrent", Raw),
+        (Azureus, "XX", "Xtorrent", Raw),
+        (Azureus, "ZT", "ZipTorrent", Raw),
+        (PeerIdStyle::Shadow, "A", "ABC", Shadow),
+        (PeerIdStyle::Shadow, "O", "Osprey Permaseed", Shadow),
+        (PeerIdStyle::Shadow, "Q", "BTQueue", Shadow),
+        (PeerIdStyle::Shadow, "R", "Tribler", Shadow),
+        (PeerIdStyle::Shadow, "S", "Shadow", Shadow),
+        (PeerIdStyle::Shadow, "T", "BitTornado", Shadow),
+        (PeerIdStyle::Shadow, "U", "UPnP NAT Bit Torrent", Shadow),
+        (Mainline, "M", "Mainline", DashSeparated),
+        (Mainline, "A2", "aria2", DashSeparated),
+    ]
+};
+
+static CLIENT_REGISTRY: OnceLock<ClientRegistry> = OnceLock::new();
+
+/// Set registry used by [`PeerId::client`]
+///
+/// Needs to be called before the first peer client lookup. Returns the
+/// registry as error if a registry is already in use.
+pub fn set_client_registry(registry: ClientRegistry) -> Result<(), ClientRegistry> {
+    CLIENT_REGISTRY.set(registry)
+}
+
+/// Registry used by [`PeerId::client`], with built-in definitions only
+/// unless another one has been set
+pub fn client_registry() -> &'static ClientRegistry {
+    CLIENT_REGISTRY.get_or_init(ClientRegistry::builtin)
+}
+
+/// Peer id layout of a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerIdStyle {
+    /// Dash, two-character prefix and four version characters, e.g.
+    /// `-TR2940-`
+    Azureus,
+    /// One-character prefix and up to five version characters padded with
+    /// dashes, followed by `---`, e.g. `S58B-----`
+    Shadow,
+    /// Prefix and dash-separated version numbers, e.g. `M7-2-1--` or
+    /// `A2-1-37-0-`
+    Mainline,
+}
+
+/// How to turn the version characters of a peer id into a version string
+///
+/// Formats expecting four characters fall back to `raw` for other lengths.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionFormat {
+    /// Keep characters as they are
+    #[default]
+    Raw,
+    /// One number per character, e.g. `5700` -> `5.7.0.0`
+    Dotted,
+    /// One number per each of first three characters, e.g. `4250` -> `4.2.5`
+    ThreeDotted,
+    /// Like `three_dotted`, with fourth character denoting release type,
+    /// e.g. `355B` -> `3.5.5 beta`
+    ThreeDottedPrerelease,
+    /// One-digit major, two-digit minor and one-digit patch version, e.g.
+    /// `2003` -> `2.00.3`
+    TwoDigitMinor,
+    /// Transmission scheme, e.g. `0084` -> `0.84` and `2940` -> `2.94`
+    Transmission,
+    /// Two-digit major and minor versions without leading zeros, e.g.
+    /// `0219` -> `2.19`
+    TwoDigitPairs,
+    /// Shadow-style base 64 characters, e.g. `58B` -> `5.8.11`
+    Shadow,
+    /// Dash-separated numbers, e.g. `1-23-4` -> `1.23.4`
+    DashSeparated,
+}
+
+impl VersionFormat {
+    pub fn decode(self, version: &[u8]) -> CompactString {
+        match (self, version) {
+            (Self::Raw, _) => CompactString::from_utf8_lossy(version),
+            (Self::Dotted, _) => join(
+                version.iter().map(|c| format_compact!("{}", *c as char)),
+                '.',
+            ),
+            (Self::ThreeDotted, &[v1, v2, v3, _]) => {
+                format_compact!("{}.{}.{}", v1 as char, v2 as char, v3 as char)
+            }
+            (Self::ThreeDottedPrerelease, &[v1, v2, v3, v4]) => {
+                let prerelease = match v4 {
+                    b'd' | b'D' => " dev".into(),
+                    b'a' | b'A' => " alpha".into(),
+                    b'b' | b'B' => " beta".into(),
+                    b'r' | b'R' => " rc".into(),
+                    b's' | b'S' => " stable".into(),
+                    other => format_compact!("{}", other as char),
+                };
+
+                format_compact!("{}.{}.{}{}", v1 as char, v2 as char, v3 as char, prerelease)
+            }
+            (Self::TwoDigitMinor, &[v1, v2, v3, v4]) => {
+                format_compact!("{}.{}{}.{}", v1 as char, v2 as char, v3 as char, v4 as char)
+            }
+            (Self::Transmission, &[v1, v2, v3, v4]) => {
+                let (v1, v2, v3, v4) = (v1 as char, v2 as char, v3 as char, v4 as char);
+
+                match (v1, v2, v3, v4) {
+                    ('0', '0', '0', v4) => format_compact!("0.{}", v4),
+                    ('0', '0', v3, v4) => format_compact!("0.{}{}", v3, v4),
+                    _ => format_compact!("{}.{}{}", v1, v2, v3),
+                }
+            }
+            (Self::TwoDigitPairs, &[v1, v2, v3, v4]) => {
+                let (v1, v2, v3, v4) = (v1 as char, v2 as char, v3 as char, v4 as char);
+
+                let major = if v1 == '0' {
+                    format_compact!("{}", v2)
+                } else {
+                    format_compact!("{}{}", v1, v2)
+                };
+
+                let minor = if v3 == '0' {
+                    format_compact!("{}", v4)
+                } else {
+                    format_compact!("{}{}", v3, v4)
+                };
+
+                format_compact!("{}.{}", major, minor)
+            }
+            (Self::Shadow, _) => join(
+                version
+                    .iter()
+                    .map_while(|c| shadow_value(*c))
+                    .map(|value| format_compact!("{}", value)),
+                '.',
+            ),
+            (Self::DashSeparated, _) => join(
+                version
+                    .split(|c| *c == b'-')
+                    .filter(|part| !part.is_empty())
+                    .map(CompactString::from_utf8_lossy),
+                '.',
+            ),
+            (_, _) => CompactString::from_utf8_lossy(version),
+        }
+    }
+}
+
+/// Client definition, e.g. from a TOML file:
+///
+/// ```toml
+/// [[clients]]
+/// style = "azureus"
+/// prefix = "XY"
+/// name = "Example client"
+/// version = "dotted"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientDefinition {
+    pub style: PeerIdStyle,
+    /// Two characters for azureus style, one for shadow style and one or
+    /// more letters or digits for mainline style
+    pub prefix: String,
+    pub name: String,
+    #[serde(default)]
+    pub version: VersionFormat,
+}
+
+/// List of client definitions, the format of peer client definition files
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientDefinitions {
+    pub clients: Vec<ClientDefinition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidClientDefinition(pub ClientDefinition);
+
+impl Display for InvalidClientDefinition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid prefix {:?} for {:?} style peer client {:?}",
+            self.0.prefix, self.0.style, self.0.name
+        )
+    }
+}
+
+impl std::error::Error for InvalidClientDefinition {}
+
+#[derive(Debug, Clone)]
+struct Client {
+    name: CompactString,
+    version_format: VersionFormat,
+}
+
+impl Client {
+    fn peer_client(&self, version: &[u8]) -> PeerClient {
+        PeerClient::Known {
+            name: self.name.clone(),
+            version: self.version_format.decode(version),
+        }
+    }
+}
+
+/// Mapping from peer id prefixes to clients
+#[derive(Debug, Clone, Default)]
+pub struct ClientRegistry {
+    azureus: HashMap<[u8; 2], Client>,
+    shadow: HashMap<u8, Client>,
+    /// Sorted by prefix length, longest first
+    mainline: Vec<(Vec<u8>, Client)>,
+}
+
+impl ClientRegistry {
+    pub fn builtin() -> Self {
+        let mut registry = Self::default();
+
+        for (style, prefix, name, version) in BUILTIN_CLIENTS.iter().copied() {
+            registry
+                .add(ClientDefinition {
+                    style,
+                    prefix: prefix.into(),
+                    name: name.into(),
+                    version,
+                })
+                .expect("builtin peer client definitions are valid");
+        }
+
+        registry
+    }
+
+    /// Add client, replacing any existing one with same style and prefix
+    pub fn add(&mut self, definition: ClientDefinition) -> Result<(), InvalidClientDefinition> {
+        let client = Client {
+            name: definition.name.as_str().into(),
+            version_format: definition.version,
+        };
+
+        match (definition.style, definition.prefix.as_bytes()) {
+            (PeerIdStyle::Azureus, &[a, b])
+                if is_azureus_prefix_char(a) && is_azureus_prefix_char(b) =>
+            {
+                self.azureus.insert([a, b], client);
+            }
+            (PeerIdStyle::Shadow, &[a]) if a.is_ascii_alphanumeric() => {
+                self.shadow.insert(a, client);
+            }
+            (PeerIdStyle::Mainline, prefix)
+                if !prefix.is_empty()
+                    && prefix[0].is_ascii_alphabetic()
+                    && prefix.iter().all(u8::is_ascii_alphanumeric) =>
+            {
+                self.mainline.retain(|(p, _)| p != prefix);
+                self.mainline.push((prefix.to_vec(), client));
+                self.mainline
+                    .sort_by_key(|(prefix, _)| ::std::cmp::Reverse(prefix.len()));
+            }
+            _ => return Err(InvalidClientDefinition(definition)),
+        }
+
+        Ok(())
+    }
+
+    pub fn identify(&self, peer_id: &PeerId) -> PeerClient {
+        let bytes = &peer_id.0;
+
+        if let Some((prefix, version)) = azureus_parts(bytes) {
+            return match self.azureus.get(&prefix) {
+                Some(client) => client.peer_client(version),
+                None => PeerClient::OtherWithPrefixAndVersion {
+                    prefix: CompactString::from_utf8_lossy(&prefix),
+                    version: CompactString::from_utf8_lossy(version),
+                },
+            };
+        }
+
+        for (prefix, client) in self.mainline.iter() {
+            if let Some(version) = bytes
+                .strip_prefix(prefix.as_slice())
+                .and_then(mainline_version)
+            {
+                return client.peer_client(version);
+            }
+        }
+
+        if let Some(client) = self.shadow.get(&bytes[0]) {
+            if let Some(version) = shadow_version(bytes) {
+                return client.peer_client(version);
+            }
+        }
+
+        // Unknown mainline style client with one-character prefix
+        if bytes[0].is_ascii_alphabetic()
+            && bytes[1..7].iter().all(|c| c.is_ascii_digit() || *c == b'-')
+            && bytes[7] == b'-'
+        {
+            return PeerClient::OtherWithPrefixAndVersion {
+                prefix: CompactString::from_utf8_lossy(&bytes[..1]),
+                version: CompactString::from_utf8_lossy(&bytes[1..7]),
+            };
+        }
+
+        // Prefix followed by dash
+        let prefix_len = bytes
+            .iter()
+            .take_while(|c| c.is_ascii_alphanumeric() || **c == b'-')
+            .count();
+
+        if let Some(dash_index) = bytes[..prefix_len].iter().rposition(|c| *c == b'-') {
+            if dash_index > 0 {
+                return PeerClient::OtherWithPrefix(CompactString::from_utf8_lossy(
+                    &bytes[..dash_index],
+                ));
+            }
+        }
+
+        PeerClient::Other
+    }
+}
+
+fn is_azureus_prefix_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'~'
+}
+
+fn azureus_parts(bytes: &[u8; 20]) -> Option<([u8; 2], &[u8])> {
+    let version = &bytes[3..7];
+
+    (bytes[0] == b'-'
+        && is_azureus_prefix_char(bytes[1])
+        && is_azureus_prefix_char(bytes[2])
+        && version.iter().all(u8::is_ascii_alphanumeric))
+    .then_some(([bytes[1], bytes[2]], version))
+}
+
+/// Parse version such as `1-23-4` from bytes such as `1-23-4-abc` or
+/// `-1-23-4-abc`
+fn mainline_version(bytes: &[u8]) -> Option<&[u8]> {
+    let start = usize::from(bytes.first() == Some(&b'-'));
+
+    let mut end = start;
+    let mut num_numbers = 0;
+
+    loop {
+        let num_digits = bytes[end..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+
+        if num_digits == 0 || bytes.get(end + num_digits) != Some(&b'-') {
+            return None;
+        }
+
+        end += num_digits;
+        num_numbers += 1;
+
+        // Dash is followed by another number
+        if bytes.get(end + 1).map_or(false, u8::is_ascii_digit) {
+            end += 1;
+        } else {
+            break;
+        }
+    }
+
+    (num_numbers > 1).then(|| &bytes[start..end])
+}
+
+/// Return version characters if peer id is in shadow style
+fn shadow_version(bytes: &[u8; 20]) -> Option<&[u8]> {
+    let version = &bytes[1..6];
+    let version_len = version.iter().take_while(|c| **c != b'-').count();
+
+    (version_len > 0
+        && version[..version_len]
+            .iter()
+            .all(|c| shadow_value(*c).is_some())
+        && version[version_len..].iter().all(|c| *c == b'-')
+        && &bytes[6..9] == b"---")
+        .then(|| &version[..version_len])
+}
+
+fn shadow_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'A'..=b'Z' => Some(c - b'A' + 10),
+        b'a'..=b'z' => Some(c - b'a' + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+fn join<T: AsRef<str>>(parts: impl Iterator<Item = T>, separator: char) -> CompactString {
+    let mut output = CompactString::default();
+
+    for (i, part) in parts.enumerate() {
+        if i != 0 {
+            output.push(separator);
+        }
+
+        output.push_str(part.as_ref());
+    }
+
+    output
+}
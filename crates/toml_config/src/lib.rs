@@ -75,7 +75,7 @@ pub trait TomlConfig: Default {
 }
 
 pub mod __private {
-    use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
     use std::path::PathBuf;
 
     pub trait Private {
@@ -122,6 +122,9 @@ pub mod __private {
     impl_trait!(String);
 
     impl_trait!(PathBuf);
+    impl_trait!(IpAddr);
+    impl_trait!(Ipv4Addr);
+    impl_trait!(Ipv6Addr);
     impl_trait!(SocketAddr);
     impl_trait!(SocketAddrV4);
     impl_trait!(SocketAddrV6);
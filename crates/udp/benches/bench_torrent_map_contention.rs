@@ -0,0 +1,124 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::num::NonZeroU16;
+use std::sync::{Arc, Barrier};
+use std::time::{Duration, Instant};
+
+use aquatic_common::peer_ip_limits::PeerIpCounter;
+use aquatic_common::{CanonicalSocketAddr, ServerStartInstant, ValidUntil};
+use aquatic_udp::config::Config;
+use aquatic_udp::swarm::TorrentMaps;
+use aquatic_udp_protocol::*;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+/// Few torrents receiving most requests, as on public trackers
+const NUM_POPULAR_TORRENTS: u8 = 4;
+const NUM_PEERS_PER_THREAD: u16 = 256;
+/// Every n-th request is a scrape
+const SCRAPE_EVERY: u64 = 4;
+
+fn run_workers(config: &Config, torrent_maps: &TorrentMaps, iters: u64) -> Duration {
+    let num_threads = config.socket_workers;
+    let barrier = Barrier::new(num_threads + 1);
+    let valid_until = ValidUntil::new(ServerStartInstant::new(), 60 * 60);
+
+    ::std::thread::scope(|scope| {
+        for thread_index in 0..num_threads {
+            let barrier = &barrier;
+
+            scope.spawn(move || {
+                let (statistics_sender, _statistics_receiver) = crossbeam_channel::unbounded();
+                let mut rng = SmallRng::seed_from_u64(thread_index as u64);
+                let src = CanonicalSocketAddr::new(SocketAddr::new(
+                    Ipv4Addr::new(10, 0, thread_index as u8, 1).into(),
+                    1,
+                ));
+
+                barrier.wait();
+
+                for i in 0..iters {
+                    let info_hash = InfoHash([rng.gen_range(0..NUM_POPULAR_TORRENTS); 20]);
+
+                    if i % SCRAPE_EVERY == 0 {
+                        let request = ScrapeRequest {
+                            connection_id: ConnectionId::new(0),
+                            transaction_id: TransactionId::new(0),
+                            info_hashes: vec![info_hash],
+                        };
+
+                        criterion::black_box(torrent_maps.scrape(request, src));
+                    } else {
+                        let port = rng.gen_range(1..=NUM_PEERS_PER_THREAD);
+
+                        let request = AnnounceRequest {
+                            connection_id: ConnectionId::new(0),
+                            action_placeholder: Default::default(),
+                            transaction_id: TransactionId::new(0),
+                            info_hash,
+                            peer_id: PeerId([0; 20]),
+                            bytes_downloaded: NumberOfBytes::new(0),
+                            bytes_uploaded: NumberOfBytes::new(0),
+                            bytes_left: NumberOfBytes::new(rng.gen_range(0..2)),
+                            event: AnnounceEvent::None.into(),
+                            ip_address: Ipv4AddrBytes([0; 4]),
+                            key: PeerKey::new(0),
+                            peers_wanted: NumberOfPeers::new(-1),
+                            port: Port::new(NonZeroU16::new(port).unwrap()),
+                        };
+
+                        criterion::black_box(torrent_maps.announce(
+                            config,
+                            &statistics_sender,
+                            &mut rng,
+                            &request,
+                            src,
+                            valid_until,
+                        ));
+                    }
+                }
+
+                barrier.wait();
+            });
+        }
+
+        barrier.wait();
+
+        let start = Instant::now();
+
+        barrier.wait();
+
+        start.elapsed()
+    })
+}
+
+pub fn bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("torrent-map-contention");
+
+    for num_threads in [1, 2, 4, 8] {
+        let config = Config {
+            socket_workers: num_threads,
+            ..Default::default()
+        };
+
+        let torrent_maps = TorrentMaps::new(
+            &config,
+            Arc::new(PeerIpCounter::new(&config.peer_ip_limits)),
+        );
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_threads),
+            &num_threads,
+            |b, _| b.iter_custom(|iters| run_workers(&config, &torrent_maps, iters)),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .measurement_time(Duration::from_secs(10));
+    targets = bench
+}
+criterion_main!(benches);
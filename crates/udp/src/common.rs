@@ -1,15 +1,22 @@
 use std::iter::repeat_with;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use aquatic_common::access_list::AccessListArcSwap;
-use aquatic_common::ServerStartInstant;
+use aquatic_common::client_filter::ClientFilterArcSwap;
+use aquatic_common::peer_ip_limits::PeerIpCounter;
+use aquatic_common::statistics::TopTorrents;
+use aquatic_common::torrent_limits::TorrentLimitCounters;
+use aquatic_common::{CanonicalSocketAddr, ServerStartInstant};
 use aquatic_udp_protocol::*;
+use crossbeam_channel::Sender;
 use crossbeam_utils::CachePadded;
 use hdrhistogram::Histogram;
 
 use crate::config::Config;
-use crate::swarm::TorrentMaps;
+use crate::swarm::{CleaningStatistics, TorrentMaps};
+use crate::workers::socket::ConnectFloodGuard;
 
 pub const BUFFER_SIZE: usize = 8192;
 
@@ -70,6 +77,8 @@ pub struct SocketWorkerStatistics {
     pub responses_error: AtomicUsize,
     pub bytes_received: AtomicUsize,
     pub bytes_sent: AtomicUsize,
+    /// Connect requests dropped by flood protection
+    pub dropped_connects: AtomicUsize,
 }
 
 pub type CachePaddedArc<T> = CachePadded<Arc<CachePadded<T>>>;
@@ -78,27 +87,196 @@ pub type CachePaddedArc<T> = CachePadded<Arc<CachePadded<T>>>;
 pub struct SwarmWorkerStatistics {
     pub torrents: AtomicUsize,
     pub peers: AtomicUsize,
+    /// New torrents not stored because of torrent limit (total)
+    pub rejected_torrents: AtomicUsize,
+    /// New peers not stored because of peer limit (total)
+    pub rejected_peers: AtomicUsize,
+    /// Torrents removed to stay within limits (total)
+    pub evicted_torrents: AtomicUsize,
+    /// New peers not stored because of per-address peer limit (total)
+    pub rejected_ip_peers: AtomicUsize,
+}
+
+impl SwarmWorkerStatistics {
+    pub fn add_torrent_limit_counters(&self, counters: TorrentLimitCounters) {
+        self.rejected_torrents
+            .fetch_add(counters.rejected_torrents, Ordering::Relaxed);
+        self.rejected_peers
+            .fetch_add(counters.rejected_peers, Ordering::Relaxed);
+        self.evicted_torrents
+            .fetch_add(counters.evicted_torrents, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ResponseType {
+    #[default]
+    Connect,
+    Announce,
+    Scrape,
+    Error,
+}
+
+impl ResponseType {
+    pub fn from_response(response: &Response) -> Self {
+        match response {
+            Response::Connect(_) => Self::Connect,
+            Response::AnnounceIpv4(_) | Response::AnnounceIpv6(_) => Self::Announce,
+            Response::Scrape(_) => Self::Scrape,
+            Response::Error(_) => Self::Error,
+        }
+    }
+}
+
+/// Time from receiving requests until sending responses, in microseconds
+///
+/// Values above one minute are recorded as one minute
+pub struct RequestLatencyHistograms {
+    pub connect: Histogram<u64>,
+    pub announce: Histogram<u64>,
+    pub scrape: Histogram<u64>,
+    pub error: Histogram<u64>,
+}
+
+impl Default for RequestLatencyHistograms {
+    fn default() -> Self {
+        let new_histogram =
+            || Histogram::new_with_bounds(1, 60_000_000, 3).expect("create latency histogram");
+
+        Self {
+            connect: new_histogram(),
+            announce: new_histogram(),
+            scrape: new_histogram(),
+            error: new_histogram(),
+        }
+    }
+}
+
+impl RequestLatencyHistograms {
+    pub fn record(&mut self, response_type: ResponseType, latency: Duration) {
+        let histogram = match response_type {
+            ResponseType::Connect => &mut self.connect,
+            ResponseType::Announce => &mut self.announce,
+            ResponseType::Scrape => &mut self.scrape,
+            ResponseType::Error => &mut self.error,
+        };
+
+        histogram.saturating_record(latency.as_micros().try_into().unwrap_or(u64::MAX));
+    }
+
+    pub fn add(&mut self, other: &Self) {
+        for (histogram, other) in [
+            (&mut self.connect, &other.connect),
+            (&mut self.announce, &other.announce),
+            (&mut self.scrape, &other.scrape),
+            (&mut self.error, &other.error),
+        ] {
+            if let Err(err) = histogram.add(other) {
+                ::log::error!("couldn't merge request latency histograms: {:#}", err);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connect.is_empty()
+            && self.announce.is_empty()
+            && self.scrape.is_empty()
+            && self.error.is_empty()
+    }
 }
 
 pub enum StatisticsMessage {
     Ipv4PeerHistogram(Histogram<u64>),
     Ipv6PeerHistogram(Histogram<u64>),
+    Ipv4TopTorrents(TopTorrents),
+    Ipv6TopTorrents(TopTorrents),
+    Ipv4RequestLatencies(RequestLatencyHistograms),
+    Ipv6RequestLatencies(RequestLatencyHistograms),
     PeerAdded(PeerId),
     PeerRemoved(PeerId),
+    /// Announce was rejected by client filter
+    ClientRejected(PeerId),
+}
+
+/// Message sent to swarm worker
+pub enum SwarmWorkerMessage {
+    Request(SwarmRequest),
+    /// Clean torrents and send back statistics, including any statistics
+    /// messages generated
+    Clean(
+        Sender<(
+            IpVersionStatistics<CleaningStatistics>,
+            Vec<StatisticsMessage>,
+        )>,
+    ),
+}
+
+/// Request with valid connection id, sent from socket worker to swarm worker
+pub struct SwarmRequest {
+    pub socket_worker_index: usize,
+    /// Request was received on IPv4 socket
+    pub ipv4_socket: bool,
+    pub src: CanonicalSocketAddr,
+    pub request: ConnectedRequest,
+    pub opt_received_at: Option<Instant>,
+}
+
+pub enum ConnectedRequest {
+    Announce(AnnounceRequest),
+    Scrape(PendingScrapeRequest),
+}
+
+/// Part of scrape request concerning torrents handled by a single swarm worker
+pub struct PendingScrapeRequest {
+    pub slab_key: usize,
+    /// Identifies scrape request, since slab keys are reused
+    pub scrape_id: u64,
+    /// Info hashes along with their indices in the original request
+    pub info_hashes: Vec<(usize, InfoHash)>,
+}
+
+/// Response sent from swarm worker to socket worker
+pub struct SwarmResponse {
+    /// Response should be sent on IPv4 socket
+    pub ipv4_socket: bool,
+    pub src: CanonicalSocketAddr,
+    pub response: ConnectedResponse,
+    pub opt_received_at: Option<Instant>,
+}
+
+pub enum ConnectedResponse {
+    Announce(Response),
+    Scrape(PendingScrapeResponse),
+}
+
+pub struct PendingScrapeResponse {
+    pub slab_key: usize,
+    pub scrape_id: u64,
+    /// Statistics along with their indices in the original request
+    pub torrent_stats: Vec<(usize, TorrentScrapeStatistics)>,
 }
 
 #[derive(Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub client_filter: Arc<ClientFilterArcSwap>,
     pub torrent_maps: TorrentMaps,
+    /// Shared with swarm workers if there are any
+    pub peer_ip_counter: Arc<PeerIpCounter>,
+    pub connect_flood_guard: Arc<ConnectFloodGuard>,
     pub server_start_instant: ServerStartInstant,
 }
 
-impl Default for State {
-    fn default() -> Self {
+impl State {
+    pub fn new(config: &Config) -> Self {
+        let peer_ip_counter = Arc::new(PeerIpCounter::new(&config.peer_ip_limits));
+
         Self {
             access_list: Arc::new(AccessListArcSwap::default()),
-            torrent_maps: TorrentMaps::default(),
+            client_filter: Arc::new(ClientFilterArcSwap::default()),
+            torrent_maps: TorrentMaps::new(config, peer_ip_counter.clone()),
+            peer_ip_counter,
+            connect_flood_guard: Arc::new(ConnectFloodGuard::new(config)),
             server_start_instant: ServerStartInstant::new(),
         }
     }
@@ -145,4 +323,24 @@ mod tests {
 
         assert!(buf.len() <= BUFFER_SIZE);
     }
+
+    #[test]
+    fn test_request_latency_histograms() {
+        let mut a = RequestLatencyHistograms::default();
+        let mut b = RequestLatencyHistograms::default();
+
+        assert!(a.is_empty());
+
+        a.record(ResponseType::Connect, Duration::from_micros(10));
+        b.record(ResponseType::Announce, Duration::from_micros(500));
+        b.record(ResponseType::Announce, Duration::from_secs(1));
+
+        a.add(&b);
+
+        assert!(!a.is_empty());
+        assert_eq!(a.connect.len(), 1);
+        assert_eq!(a.announce.len(), 2);
+        assert!(a.scrape.is_empty());
+        assert!(a.announce.max() >= 999_000);
+    }
 }
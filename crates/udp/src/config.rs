@@ -3,11 +3,20 @@ use std::{
     path::PathBuf,
 };
 
-use aquatic_common::{access_list::AccessListConfig, privileges::PrivilegeConfig};
+#[cfg(feature = "otlp")]
+use aquatic_common::otlp::OtlpConfig;
+#[cfg(feature = "statsd")]
+use aquatic_common::statsd::{StatsdConfig, StatsdFormat};
+use aquatic_common::{
+    access_list::AccessListConfig, announce_interval::AnnounceIntervalConfig,
+    client_filter::ClientFilterConfig, peer_ip_limits::PeerIpLimitConfig,
+    peer_selection::PeerSelectionConfig, privileges::PrivilegeConfig,
+    torrent_limits::TorrentLimitConfig,
+};
 use cfg_if::cfg_if;
 use serde::{Deserialize, Serialize};
 
-use aquatic_common::cli::LogLevel;
+use aquatic_common::cli::{LogFormat, LogLevel};
 use aquatic_toml_config::TomlConfig;
 
 /// aquatic_udp configuration
@@ -18,11 +27,35 @@ pub struct Config {
     ///
     /// 0 = automatically set to number of available virtual CPUs
     pub socket_workers: usize,
+    /// Number of torrent map shards per IP version
+    ///
+    /// More shards reduce lock contention between socket workers at the cost
+    /// of some memory. 0 = automatically set to four times the number of
+    /// socket workers, rounded up to a power of two (minimum 16).
+    pub torrent_map_shards: usize,
+    /// Number of swarm workers
+    ///
+    /// 0 = handle announce and scrape requests in socket workers, using
+    /// torrent maps shared between them. Otherwise, socket workers send these
+    /// requests to swarm workers chosen by info hash, each of which has
+    /// exclusive access to its own torrent maps. This reduces lock contention
+    /// for workloads dominated by a few very large swarms.
+    ///
+    /// Not supported by the io_uring and AF_XDP backends.
+    pub swarm_workers: usize,
+    /// Maximum number of items in each channel passing requests and
+    /// responses between socket and swarm workers
+    pub worker_channel_size: usize,
     pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    /// Comma-separated log level overrides for specific modules, e.g.
+    /// `aquatic_udp::workers::socket=debug,mio=off`
+    pub log_module_levels: String,
     pub network: NetworkConfig,
     pub protocol: ProtocolConfig,
     pub statistics: StatisticsConfig,
     pub cleaning: CleaningConfig,
+    pub connection_ids: ConnectionIdConfig,
     pub privileges: PrivilegeConfig,
     /// Access list configuration
     ///
@@ -31,19 +64,59 @@ pub struct Config {
     /// emitting of an error-level log message, while successful updates of the
     /// access list result in emitting of an info-level log message.
     pub access_list: AccessListConfig,
+    /// Reject announces from peer clients matching (or, in allow mode, not
+    /// matching) entries in a file
+    ///
+    /// The file is read on start and reloaded along with the access list,
+    /// with failures handled in the same way.
+    pub client_filter: ClientFilterConfig,
+    /// Limits on the number of stored torrents and peers
+    pub torrent_limits: TorrentLimitConfig,
+    /// Limit on the number of peers stored for a single source address
+    pub peer_ip_limits: PeerIpLimitConfig,
+    /// Strategies for selecting peers to include in announce responses
+    pub peer_selection: PeerSelectionConfig,
+    /// Announce intervals scaled by swarm size and tracker load
+    pub announce_interval: AnnounceIntervalConfig,
+    /// Protection against floods of connect requests
+    pub connect_flood: ConnectFloodConfig,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             socket_workers: 1,
+            torrent_map_shards: 0,
+            swarm_workers: 0,
+            worker_channel_size: 1024 * 16,
             log_level: LogLevel::Error,
+            log_format: LogFormat::default(),
+            log_module_levels: String::new(),
             network: NetworkConfig::default(),
             protocol: ProtocolConfig::default(),
             statistics: StatisticsConfig::default(),
             cleaning: CleaningConfig::default(),
+            connection_ids: ConnectionIdConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            client_filter: ClientFilterConfig::default(),
+            torrent_limits: TorrentLimitConfig::default(),
+            peer_ip_limits: PeerIpLimitConfig::default(),
+            peer_selection: PeerSelectionConfig::default(),
+            announce_interval: AnnounceIntervalConfig::default(),
+            connect_flood: ConnectFloodConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Number of torrent map shards per IP version, with automatic value
+    /// resolved
+    pub fn num_torrent_map_shards(&self) -> usize {
+        if self.torrent_map_shards == 0 {
+            (self.socket_workers * 4).next_power_of_two().max(16)
+        } else {
+            self.torrent_map_shards
         }
     }
 }
@@ -52,6 +125,12 @@ impl aquatic_common::cli::Config for Config {
     fn get_log_level(&self) -> Option<LogLevel> {
         Some(self.log_level)
     }
+    fn get_log_format(&self) -> LogFormat {
+        self.log_format
+    }
+    fn get_log_module_levels(&self) -> &str {
+        &self.log_module_levels
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
@@ -97,6 +176,19 @@ pub struct NetworkConfig {
     /// such as FreeBSD. Setting the value to zero disables resending
     /// functionality.
     pub resend_buffer_max_len: usize,
+    /// Receive and send up to this many packets per syscall using recvmmsg
+    /// and sendmmsg (mio backend on Linux only)
+    ///
+    /// Setting the value to 1 disables batching.
+    pub batch_size: usize,
+    /// Send batched responses to the same address as a single packet
+    /// train using UDP generic segmentation offload (mio backend on Linux
+    /// 4.18 or later only)
+    ///
+    /// Only consecutive responses of equal size (apart from the last one)
+    /// are combined. Ignored if batching is disabled or the kernel doesn't
+    /// support it.
+    pub use_gso: bool,
     /// Set flag on IPv6 socket to only accept IPv6 traffic.
     ///
     /// This should typically be set to true unless your OS does not support
@@ -110,6 +202,31 @@ pub struct NetworkConfig {
     /// Will be rounded to next power of two if not already one.
     #[cfg(feature = "io-uring")]
     pub ring_size: u16,
+    /// Receive and send packets using AF_XDP sockets (af-xdp backend only)
+    ///
+    /// Requires Linux 5.9 or later and CAP_NET_ADMIN, CAP_NET_RAW and
+    /// CAP_BPF (or CAP_SYS_ADMIN). An XDP program is attached to
+    /// `af_xdp_interface`, redirecting UDP packets with `address_ipv4` or
+    /// `address_ipv6` as destination (any IP if unspecified) to the AF_XDP
+    /// sockets. Packets with IP options, extension headers, fragmentation
+    /// or VLAN tags are passed on to the kernel network stack, as are
+    /// packets arriving on other queues. Socket workers handle these with
+    /// regular sockets alongside their AF_XDP sockets.
+    #[cfg(feature = "af-xdp")]
+    pub use_af_xdp: bool,
+    /// Network interface to receive packets on (af-xdp backend only)
+    #[cfg(feature = "af-xdp")]
+    pub af_xdp_interface: String,
+    /// Comma-separated list of interface queues to receive packets on
+    /// (af-xdp backend only)
+    ///
+    /// Queues are distributed over socket workers. Workers without a queue
+    /// only use the regular backend.
+    #[cfg(feature = "af-xdp")]
+    pub af_xdp_queues: String,
+    /// Use regular backend if setting up AF_XDP fails (af-xdp backend only)
+    #[cfg(feature = "af-xdp")]
+    pub af_xdp_fallback: bool,
 }
 
 impl NetworkConfig {
@@ -131,11 +248,21 @@ impl Default for NetworkConfig {
             socket_recv_buffer_size: 8_000_000,
             poll_timeout_ms: 50,
             resend_buffer_max_len: 0,
+            batch_size: 32,
+            use_gso: false,
             set_only_ipv6: true,
             #[cfg(feature = "io-uring")]
             use_io_uring: true,
             #[cfg(feature = "io-uring")]
             ring_size: 128,
+            #[cfg(feature = "af-xdp")]
+            use_af_xdp: false,
+            #[cfg(feature = "af-xdp")]
+            af_xdp_interface: "eth0".into(),
+            #[cfg(feature = "af-xdp")]
+            af_xdp_queues: "0".into(),
+            #[cfg(feature = "af-xdp")]
+            af_xdp_fallback: true,
         }
     }
 }
@@ -177,12 +304,42 @@ pub struct StatisticsConfig {
     /// Expect a certain CPU hit (maybe 5% higher consumption) and a bit higher
     /// memory use
     pub peer_clients: bool,
+    /// Path to TOML file with additional peer client definitions (leave
+    /// empty for none)
+    ///
+    /// Definitions are added to the built-in ones, replacing any with the
+    /// same style and prefix.
+    pub peer_client_definitions_path: PathBuf,
+    /// Report this many torrents with the most peers and this many with the
+    /// most announces since the previous torrent cleaning (0 to disable)
+    ///
+    /// Also exported as metrics when they are enabled. Will increase time
+    /// taken for torrent cleaning.
+    pub top_torrents: usize,
+    /// Collect statistics on time taken from receiving requests until
+    /// sending responses, per response type
+    ///
+    /// With io_uring, responses are counted as sent once the kernel reports
+    /// send completion, so batching delays are included.
+    pub request_latency_histograms: bool,
     /// Print statistics to standard output
     pub print_to_stdout: bool,
     /// Save statistics as HTML to a file
     pub write_html_to_file: bool,
     /// Path to save HTML file to
     pub html_file_path: PathBuf,
+    /// Serve statistics as HTML and JSON over HTTP
+    ///
+    /// The HTML page is available at `/` and the JSON version at
+    /// `/statistics.json`
+    pub run_http_server: bool,
+    /// Address to serve statistics on
+    pub http_server_address: SocketAddr,
+    /// Homepage to link to on the statistics page (leave empty for none)
+    pub homepage_url: String,
+    /// Tracker announce URL to show on the statistics page (leave empty for
+    /// none)
+    pub tracker_url: String,
     /// Run a prometheus endpoint
     #[cfg(feature = "prometheus")]
     pub run_prometheus_endpoint: bool,
@@ -197,6 +354,47 @@ pub struct StatisticsConfig {
     /// client will be reported continuously on the endpoint
     #[cfg(feature = "prometheus")]
     pub prometheus_peer_id_prefixes: bool,
+    /// Export metrics to an OpenTelemetry collector over OTLP/HTTP
+    ///
+    /// Metrics are the same as the ones served on the prometheus endpoint,
+    /// so settings such as `prometheus_peer_id_prefixes` apply here too.
+    #[cfg(feature = "otlp")]
+    pub otlp_export_metrics: bool,
+    /// OTLP/HTTP collector base URL
+    ///
+    /// Data is posted as JSON to `/v1/metrics` below it. Only plain HTTP is
+    /// supported.
+    #[cfg(feature = "otlp")]
+    pub otlp_endpoint: String,
+    /// Send metrics to OTLP collector this often (seconds)
+    #[cfg(feature = "otlp")]
+    pub otlp_export_interval: u64,
+    /// Push metrics to a StatsD or DogStatsD server over UDP
+    ///
+    /// Metrics are the same as the ones served on the prometheus endpoint,
+    /// so settings such as `prometheus_peer_id_prefixes` apply here too.
+    #[cfg(feature = "statsd")]
+    pub statsd_push_metrics: bool,
+    /// Address of StatsD server
+    #[cfg(feature = "statsd")]
+    pub statsd_address: SocketAddr,
+    /// Prepended to all metric names, e.g. `tracker.`
+    #[cfg(feature = "statsd")]
+    pub statsd_prefix: String,
+    /// Comma-separated tags to add to all metrics, e.g. `env:prod,region:eu`
+    ///
+    /// Only sent in dogstatsd format
+    #[cfg(feature = "statsd")]
+    pub statsd_tags: String,
+    /// Line format (statsd or dogstatsd)
+    ///
+    /// With plain statsd, metric labels are appended to metric names as
+    /// `.key.value`. With dogstatsd, they are sent as tags.
+    #[cfg(feature = "statsd")]
+    pub statsd_format: StatsdFormat,
+    /// Push metrics to StatsD server this often (seconds)
+    #[cfg(feature = "statsd")]
+    pub statsd_push_interval: u64,
 }
 
 impl StatisticsConfig {
@@ -204,14 +402,55 @@ impl StatisticsConfig {
         if #[cfg(feature = "prometheus")] {
             pub fn active(&self) -> bool {
                 (self.interval != 0) &
-                    (self.print_to_stdout | self.write_html_to_file | self.run_prometheus_endpoint)
+                    (self.print_to_stdout | self.write_html_to_file | self.run_http_server |
+                        self.metrics_active())
+            }
+
+            /// Metrics are served on prometheus endpoint or pushed by an
+            /// exporter
+            pub fn metrics_active(&self) -> bool {
+                #[allow(unused_mut)]
+                let mut active = self.run_prometheus_endpoint;
+
+                #[cfg(feature = "otlp")]
+                {
+                    active |= self.otlp_export_metrics;
+                }
+                #[cfg(feature = "statsd")]
+                {
+                    active |= self.statsd_push_metrics;
+                }
+
+                active
             }
         } else {
             pub fn active(&self) -> bool {
-                (self.interval != 0) & (self.print_to_stdout | self.write_html_to_file)
+                (self.interval != 0) &
+                    (self.print_to_stdout | self.write_html_to_file | self.run_http_server)
             }
         }
     }
+
+    #[cfg(feature = "otlp")]
+    pub fn otlp_config(&self) -> OtlpConfig {
+        OtlpConfig {
+            export_metrics: self.otlp_export_metrics,
+            span_sample_ratio: 0.0,
+            endpoint: self.otlp_endpoint.clone(),
+            export_interval: self.otlp_export_interval,
+        }
+    }
+
+    #[cfg(feature = "statsd")]
+    pub fn statsd_config(&self) -> StatsdConfig {
+        StatsdConfig {
+            address: self.statsd_address,
+            prefix: self.statsd_prefix.clone(),
+            tags: self.statsd_tags.clone(),
+            format: self.statsd_format,
+            push_interval: self.statsd_push_interval,
+        }
+    }
 }
 
 impl Default for StatisticsConfig {
@@ -220,15 +459,40 @@ impl Default for StatisticsConfig {
             interval: 5,
             torrent_peer_histograms: false,
             peer_clients: false,
+            peer_client_definitions_path: PathBuf::new(),
+            top_torrents: 0,
+            request_latency_histograms: false,
             print_to_stdout: false,
             write_html_to_file: false,
             html_file_path: "tmp/statistics.html".into(),
+            run_http_server: false,
+            http_server_address: SocketAddr::from(([127, 0, 0, 1], 9001)),
+            homepage_url: String::new(),
+            tracker_url: String::new(),
             #[cfg(feature = "prometheus")]
             run_prometheus_endpoint: false,
             #[cfg(feature = "prometheus")]
             prometheus_endpoint_address: SocketAddr::from(([0, 0, 0, 0], 9000)),
             #[cfg(feature = "prometheus")]
             prometheus_peer_id_prefixes: false,
+            #[cfg(feature = "otlp")]
+            otlp_export_metrics: false,
+            #[cfg(feature = "otlp")]
+            otlp_endpoint: "http://127.0.0.1:4318".into(),
+            #[cfg(feature = "otlp")]
+            otlp_export_interval: 10,
+            #[cfg(feature = "statsd")]
+            statsd_push_metrics: false,
+            #[cfg(feature = "statsd")]
+            statsd_address: SocketAddr::from(([127, 0, 0, 1], 8125)),
+            #[cfg(feature = "statsd")]
+            statsd_prefix: String::new(),
+            #[cfg(feature = "statsd")]
+            statsd_tags: String::new(),
+            #[cfg(feature = "statsd")]
+            statsd_format: StatsdFormat::Dogstatsd,
+            #[cfg(feature = "statsd")]
+            statsd_push_interval: 10,
         }
     }
 }
@@ -242,6 +506,9 @@ pub struct CleaningConfig {
     pub max_connection_age: u32,
     /// Remove peers who have not announced for this long (seconds)
     pub max_peer_age: u32,
+    /// Drop scrape requests split between swarm workers if all partial
+    /// responses have not been received after this long (seconds)
+    pub max_pending_scrape_age: u32,
 }
 
 impl Default for CleaningConfig {
@@ -250,6 +517,78 @@ impl Default for CleaningConfig {
             torrent_cleaning_interval: 60 * 2,
             max_connection_age: 60 * 2,
             max_peer_age: 60 * 20,
+            max_pending_scrape_age: 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionIdConfig {
+    /// Path to file containing secret used for creating connection ids
+    ///
+    /// Leave empty to use a random secret, which means that connection ids
+    /// are no longer accepted after a restart. Instances using the same
+    /// secret accept each other's connection ids, provided that their clocks
+    /// are synchronized. Leading and trailing whitespace is ignored. The
+    /// secret must be at least 16 bytes long.
+    pub shared_secret_path: PathBuf,
+    /// Derive a new connection id key from the secret this often (seconds,
+    /// 0 = never)
+    ///
+    /// Connection ids created with the previous key are accepted until they
+    /// expire. Must be at least `cleaning.max_connection_age` unless 0.
+    pub key_rotation_interval: u64,
+}
+
+impl Default for ConnectionIdConfig {
+    fn default() -> Self {
+        Self {
+            shared_secret_path: PathBuf::new(),
+            key_rotation_interval: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectFloodConfig {
+    /// Drop connect requests from source addresses that have sent more than
+    /// this many during the current window (0 = don't track sources)
+    ///
+    /// Counts are estimated using a count-min sketch shared by all socket
+    /// workers. Estimates may be too high, but never too low.
+    pub max_connects_per_source: u32,
+    /// Only drop connect requests from sources above the limit while the
+    /// total number of connect requests during the current window exceeds
+    /// this value (0 = always drop them)
+    pub min_total_connects: u64,
+    /// Length of window (seconds)
+    pub window_length: u32,
+    /// Number of counters in each of the four rows of the sketch
+    ///
+    /// More counters make estimates more accurate when many addresses send
+    /// connect requests.
+    pub sketch_width: usize,
+    /// IPv6 addresses sharing this number of leading bits count as a single
+    /// source address
+    pub ipv6_prefix_length: u8,
+}
+
+impl ConnectFloodConfig {
+    pub fn is_active(&self) -> bool {
+        self.max_connects_per_source != 0
+    }
+}
+
+impl Default for ConnectFloodConfig {
+    fn default() -> Self {
+        Self {
+            max_connects_per_source: 0,
+            min_total_connects: 0,
+            window_length: 10,
+            sketch_width: 1 << 16,
+            ipv6_prefix_length: 64,
         }
     }
 }
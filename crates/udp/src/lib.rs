@@ -3,6 +3,7 @@ pub mod config;
 pub mod swarm;
 pub mod workers;
 
+use std::iter::repeat_with;
 use std::thread::{available_parallelism, sleep, Builder, JoinHandle};
 use std::time::Duration;
 
@@ -13,7 +14,9 @@ use signal_hook::consts::SIGUSR1;
 use signal_hook::iterator::Signals;
 
 use aquatic_common::access_list::update_access_list;
+use aquatic_common::client_filter::update_client_filter;
 use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::statistics::load_peer_client_definitions;
 
 use common::{State, Statistics};
 use config::Config;
@@ -31,14 +34,35 @@ pub fn run(mut config: Config) -> ::anyhow::Result<()> {
         ));
     }
 
+    config
+        .announce_interval
+        .validate(config.cleaning.max_peer_age)?;
+
     if config.socket_workers == 0 {
         config.socket_workers = available_parallelism().map(Into::into).unwrap_or(1);
     };
 
+    if config.swarm_workers > 0 {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if config.network.use_io_uring {
+            return Err(anyhow::anyhow!(
+                "swarm workers are not supported by the io_uring backend"
+            ));
+        }
+        #[cfg(all(target_os = "linux", feature = "af-xdp"))]
+        if config.network.use_af_xdp {
+            return Err(anyhow::anyhow!(
+                "swarm workers are not supported by the AF_XDP backend"
+            ));
+        }
+    }
+
     let num_sockets_per_worker =
         if config.network.use_ipv4 { 1 } else { 0 } + if config.network.use_ipv6 { 1 } else { 0 };
 
-    let state = State::default();
+    load_peer_client_definitions(&config.statistics.peer_client_definitions_path)?;
+
+    let state = State::new(&config);
     let statistics = Statistics::new(&config);
     let connection_validator = ConnectionValidator::new(&config)?;
     let priv_dropper = PrivilegeDropper::new(
@@ -48,16 +72,59 @@ pub fn run(mut config: Config) -> ::anyhow::Result<()> {
     let (statistics_sender, statistics_receiver) = unbounded();
 
     update_access_list(&config.access_list, &state.access_list)?;
+    update_client_filter(&config.client_filter, &state.client_filter)?;
+
+    #[cfg(all(target_os = "linux", feature = "af-xdp"))]
+    let opt_xdp_program = workers::socket::af_xdp::attach_program(&config)?;
+
+    let (socket_worker_swarm_channels, swarm_worker_channels, swarm_request_senders) =
+        if config.swarm_workers > 0 {
+            let channels = workers::swarm::create_channels(&config)?;
+
+            (
+                channels.socket_workers.into_iter().map(Some).collect(),
+                channels.swarm_workers,
+                channels.request_senders,
+            )
+        } else {
+            (
+                repeat_with(|| None)
+                    .take(config.socket_workers)
+                    .collect::<Vec<_>>(),
+                Vec::new(),
+                Vec::new(),
+            )
+        };
 
     let mut join_handles = Vec::new();
 
+    // Spawn swarm worker threads
+    for (i, channels) in swarm_worker_channels.into_iter().enumerate() {
+        let state = state.clone();
+        let config = config.clone();
+        let statistics_sender = statistics_sender.clone();
+
+        let handle = Builder::new()
+            .name(format!("swarm-{:02}", i + 1))
+            .spawn(move || {
+                aquatic_common::logging::set_worker("swarm", i);
+
+                workers::swarm::run_swarm_worker(config, state, statistics_sender, channels)
+            })
+            .with_context(|| "spawn swarm worker")?;
+
+        join_handles.push((WorkerType::Swarm(i), handle));
+    }
+
     // Spawn socket worker threads
-    for i in 0..config.socket_workers {
+    for (i, opt_swarm_channels) in socket_worker_swarm_channels.into_iter().enumerate() {
         let state = state.clone();
         let config = config.clone();
         let connection_validator = connection_validator.clone();
         let statistics = statistics.socket[i].clone();
         let statistics_sender = statistics_sender.clone();
+        #[cfg(all(target_os = "linux", feature = "af-xdp"))]
+        let opt_xdp_program = opt_xdp_program.clone();
 
         let mut priv_droppers = Vec::new();
 
@@ -68,6 +135,22 @@ pub fn run(mut config: Config) -> ::anyhow::Result<()> {
         let handle = Builder::new()
             .name(format!("socket-{:02}", i + 1))
             .spawn(move || {
+                aquatic_common::logging::set_worker("socket", i);
+
+                #[cfg(all(target_os = "linux", feature = "af-xdp"))]
+                if let Some(program) = opt_xdp_program {
+                    return workers::socket::af_xdp::run_socket_worker(
+                        config,
+                        state,
+                        statistics,
+                        statistics_sender,
+                        connection_validator,
+                        priv_droppers,
+                        program,
+                        i,
+                    );
+                }
+
                 workers::socket::run_socket_worker(
                     config,
                     state,
@@ -75,6 +158,7 @@ pub fn run(mut config: Config) -> ::anyhow::Result<()> {
                     statistics_sender,
                     connection_validator,
                     priv_droppers,
+                    opt_swarm_channels,
                 )
             })
             .with_context(|| "spawn socket worker")?;
@@ -94,13 +178,22 @@ pub fn run(mut config: Config) -> ::anyhow::Result<()> {
                 config.cleaning.torrent_cleaning_interval,
             ));
 
-            state.torrent_maps.clean_and_update_statistics(
-                &config,
-                &statistics,
-                &statistics_sender,
-                &state.access_list,
-                state.server_start_instant,
-            );
+            if swarm_request_senders.is_empty() {
+                state.torrent_maps.clean_and_update_statistics(
+                    &config,
+                    &statistics,
+                    &statistics_sender,
+                    &state.access_list,
+                    state.server_start_instant,
+                );
+            } else {
+                workers::swarm::clean_torrents(
+                    &config,
+                    &statistics,
+                    &statistics_sender,
+                    &swarm_request_senders,
+                );
+            }
         })?;
 
         join_handles.push((WorkerType::Cleaning, handle));
@@ -126,6 +219,41 @@ pub fn run(mut config: Config) -> ::anyhow::Result<()> {
         join_handles.push((WorkerType::Statistics, handle));
     }
 
+    // Recorders of exporters pushing metrics elsewhere
+    #[cfg(any(feature = "otlp", feature = "statsd"))]
+    let mut push_recorders = Vec::new();
+
+    // Spawn OTLP exporter thread
+    #[cfg(feature = "otlp")]
+    if config.statistics.active() && config.statistics.otlp_export_metrics {
+        let (opt_recorder, handle) = aquatic_common::otlp::spawn_otlp_exporter(
+            config.statistics.otlp_config(),
+            "aquatic_udp",
+            Some(Duration::from_secs(
+                config.cleaning.torrent_cleaning_interval * 2,
+            )),
+            None,
+        )?;
+
+        push_recorders.extend(opt_recorder);
+        join_handles.push((WorkerType::Otlp, handle));
+    }
+
+    // Spawn StatsD exporter thread
+    #[cfg(feature = "statsd")]
+    if config.statistics.active() && config.statistics.statsd_push_metrics {
+        let (recorder, handle) = aquatic_common::statsd::spawn_statsd_exporter(
+            config.statistics.statsd_config(),
+            Some(Duration::from_secs(
+                config.cleaning.torrent_cleaning_interval * 2,
+            )),
+            None,
+        )?;
+
+        push_recorders.push(recorder);
+        join_handles.push((WorkerType::Statsd, handle));
+    }
+
     // Spawn prometheus endpoint thread
     #[cfg(feature = "prometheus")]
     if config.statistics.active() && config.statistics.run_prometheus_endpoint {
@@ -135,11 +263,19 @@ pub fn run(mut config: Config) -> ::anyhow::Result<()> {
                 config.cleaning.torrent_cleaning_interval * 2,
             )),
             None,
+            #[cfg(any(feature = "otlp", feature = "statsd"))]
+            ::std::mem::take(&mut push_recorders),
         )?;
 
         join_handles.push((WorkerType::Prometheus, handle));
     }
 
+    // Install push recorders directly if prometheus endpoint isn't running
+    #[cfg(any(feature = "otlp", feature = "statsd"))]
+    if !push_recorders.is_empty() {
+        aquatic_common::push_recorder::install_push_recorders(push_recorders)?;
+    }
+
     // Spawn signal handler thread
     {
         let config = config.clone();
@@ -151,6 +287,8 @@ pub fn run(mut config: Config) -> ::anyhow::Result<()> {
                     match signal {
                         SIGUSR1 => {
                             let _ = update_access_list(&config.access_list, &state.access_list);
+                            let _ =
+                                update_client_filter(&config.client_filter, &state.client_filter);
                         }
                         _ => unreachable!(),
                     }
@@ -1,24 +1,30 @@
 use std::iter::repeat_with;
 use std::net::IpAddr;
-use std::ops::DerefMut;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
+use aquatic_common::peer_ip_limits::PeerIpCounter;
+use aquatic_common::peer_selection::{PeerCandidate, PeerSelectionConfig};
+use aquatic_common::statistics::{TopTorrent, TopTorrents};
+use aquatic_common::torrent_limits::{
+    EvictionCandidate, PeerCountChange, TorrentLimitCounters, TorrentLimits, EVICTION_SAMPLE_SIZE,
+};
 use aquatic_common::SecondsSinceServerStart;
 use aquatic_common::ServerStartInstant;
 use aquatic_common::{
     access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache, AccessListMode},
     ValidUntil,
 };
-use aquatic_common::{CanonicalSocketAddr, IndexMap};
+use aquatic_common::{AtomicValidUntil, CanonicalSocketAddr, IndexMap};
 
 use aquatic_udp_protocol::*;
 use arrayvec::ArrayVec;
 use crossbeam_channel::Sender;
 use hashbrown::HashMap;
 use hdrhistogram::Histogram;
-use parking_lot::RwLockUpgradableReadGuard;
+use parking_lot::{RwLockUpgradableReadGuard, RwLockWriteGuard};
 use rand::prelude::SmallRng;
 use rand::Rng;
 
@@ -36,18 +42,16 @@ pub struct TorrentMaps {
     ipv6: TorrentMapShards<Ipv6AddrBytes>,
 }
 
-impl Default for TorrentMaps {
-    fn default() -> Self {
-        const NUM_SHARDS: usize = 16;
+impl TorrentMaps {
+    pub fn new(config: &Config, peer_ip_counter: Arc<PeerIpCounter>) -> Self {
+        let num_shards = config.num_torrent_map_shards();
 
         Self {
-            ipv4: TorrentMapShards::new(NUM_SHARDS),
-            ipv6: TorrentMapShards::new(NUM_SHARDS),
+            ipv4: TorrentMapShards::new(config, num_shards, peer_ip_counter.clone()),
+            ipv6: TorrentMapShards::new(config, num_shards, peer_ip_counter),
         }
     }
-}
 
-impl TorrentMaps {
     pub fn announce(
         &self,
         config: &Config,
@@ -57,24 +61,32 @@ impl TorrentMaps {
         src: CanonicalSocketAddr,
         valid_until: ValidUntil,
     ) -> Response {
-        match src.get().ip() {
-            IpAddr::V4(ip_address) => Response::AnnounceIpv4(self.ipv4.announce(
-                config,
-                statistics_sender,
-                rng,
-                request,
-                ip_address.into(),
-                valid_until,
-            )),
-            IpAddr::V6(ip_address) => Response::AnnounceIpv6(self.ipv6.announce(
-                config,
-                statistics_sender,
-                rng,
-                request,
-                ip_address.into(),
-                valid_until,
-            )),
-        }
+        let opt_response = match src.get().ip() {
+            IpAddr::V4(ip_address) => self
+                .ipv4
+                .announce(
+                    config,
+                    statistics_sender,
+                    rng,
+                    request,
+                    ip_address.into(),
+                    valid_until,
+                )
+                .map(Response::AnnounceIpv4),
+            IpAddr::V6(ip_address) => self
+                .ipv6
+                .announce(
+                    config,
+                    statistics_sender,
+                    rng,
+                    request,
+                    ip_address.into(),
+                    valid_until,
+                )
+                .map(Response::AnnounceIpv6),
+        };
+
+        opt_response.unwrap_or_else(|| create_peer_ip_limit_error_response(request))
     }
 
     pub fn scrape(&self, request: ScrapeRequest, src: CanonicalSocketAddr) -> ScrapeResponse {
@@ -100,227 +112,1304 @@ impl TorrentMaps {
 
         let mut statistics_messages = Vec::new();
 
-        let ipv4 = self.ipv4.clean_and_get_statistics(
+        let mut cleaning_statistics = IpVersionStatistics {
+            ipv4: self.ipv4.clean_and_get_statistics(
+                config,
+                &mut statistics_messages,
+                &mut cache,
+                mode,
+                now,
+            ),
+            ipv6: self.ipv6.clean_and_get_statistics(
+                config,
+                &mut statistics_messages,
+                &mut cache,
+                mode,
+                now,
+            ),
+        };
+
+        cleaning_statistics.take_peer_ip_rejections(&self.ipv4.peer_ip_counter);
+
+        update_statistics(
             config,
-            &mut statistics_messages,
-            &mut cache,
-            mode,
-            now,
+            statistics,
+            statistics_sender,
+            cleaning_statistics,
+            statistics_messages,
         );
-        let ipv6 = self.ipv6.clean_and_get_statistics(
+    }
+}
+
+/// Store torrent and peer counts and send statistics messages collected
+/// while cleaning torrents
+pub fn update_statistics(
+    config: &Config,
+    statistics: &CachePaddedArc<IpVersionStatistics<SwarmWorkerStatistics>>,
+    statistics_sender: &Sender<StatisticsMessage>,
+    cleaning_statistics: IpVersionStatistics<CleaningStatistics>,
+    mut statistics_messages: Vec<StatisticsMessage>,
+) {
+    if !config.statistics.active() {
+        return;
+    }
+
+    let IpVersionStatistics { ipv4, ipv6 } = cleaning_statistics;
+
+    statistics
+        .ipv4
+        .torrents
+        .store(ipv4.num_torrents, Ordering::Relaxed);
+    statistics
+        .ipv6
+        .torrents
+        .store(ipv6.num_torrents, Ordering::Relaxed);
+    statistics
+        .ipv4
+        .peers
+        .store(ipv4.num_peers, Ordering::Relaxed);
+    statistics
+        .ipv6
+        .peers
+        .store(ipv6.num_peers, Ordering::Relaxed);
+    statistics
+        .ipv4
+        .add_torrent_limit_counters(ipv4.limit_counters);
+    statistics
+        .ipv6
+        .add_torrent_limit_counters(ipv6.limit_counters);
+    statistics
+        .ipv4
+        .rejected_ip_peers
+        .fetch_add(ipv4.rejected_ip_peers, Ordering::Relaxed);
+    statistics
+        .ipv6
+        .rejected_ip_peers
+        .fetch_add(ipv6.rejected_ip_peers, Ordering::Relaxed);
+
+    if let Some(message) = ipv4.opt_histogram {
+        statistics_messages.push(StatisticsMessage::Ipv4PeerHistogram(message));
+    }
+    if let Some(message) = ipv6.opt_histogram {
+        statistics_messages.push(StatisticsMessage::Ipv6PeerHistogram(message));
+    }
+    if let Some(message) = ipv4.opt_top_torrents {
+        statistics_messages.push(StatisticsMessage::Ipv4TopTorrents(message));
+    }
+    if let Some(message) = ipv6.opt_top_torrents {
+        statistics_messages.push(StatisticsMessage::Ipv6TopTorrents(message));
+    }
+
+    for message in statistics_messages {
+        if let Err(err) = statistics_sender.try_send(message) {
+            ::log::error!("couldn't send statistics message: {:#}", err);
+        }
+    }
+}
+
+/// Torrent and peer counts, and optionally peer count histogram and top
+/// torrents, collected while cleaning torrents
+pub struct CleaningStatistics {
+    pub num_torrents: usize,
+    pub num_peers: usize,
+    pub opt_histogram: Option<Histogram<u64>>,
+    pub opt_top_torrents: Option<TopTorrents>,
+    /// Rejections and evictions since previous cleaning
+    pub limit_counters: TorrentLimitCounters,
+    /// Peers rejected because of per-address peer limit since previous
+    /// cleaning
+    pub rejected_ip_peers: usize,
+}
+
+impl IpVersionStatistics<CleaningStatistics> {
+    /// Counter is shared by all torrent maps, so rejections are taken once
+    /// after cleaning all of them
+    pub fn take_peer_ip_rejections(&mut self, peer_ip_counter: &PeerIpCounter) {
+        self.ipv4.rejected_ip_peers += peer_ip_counter.take_rejected(true);
+        self.ipv6.rejected_ip_peers += peer_ip_counter.take_rejected(false);
+    }
+}
+
+impl CleaningStatistics {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            num_torrents: 0,
+            num_peers: 0,
+            opt_histogram: config
+                .statistics
+                .torrent_peer_histograms
+                .then(|| Histogram::new(3).expect("create peer histogram")),
+            opt_top_torrents: (config.statistics.top_torrents > 0)
+                .then(|| TopTorrents::new(config.statistics.top_torrents)),
+            limit_counters: Default::default(),
+            rejected_ip_peers: 0,
+        }
+    }
+
+    fn record_torrent(&mut self, info_hash: &InfoHash, num_peers: usize, num_announces: usize) {
+        match self.opt_histogram.as_mut() {
+            Some(histogram) if num_peers > 0 => {
+                if let Err(err) = histogram.record(num_peers as u64) {
+                    ::log::error!("Couldn't record {} to histogram: {:#}", num_peers, err);
+                }
+            }
+            _ => (),
+        }
+
+        if let Some(top_torrents) = self.opt_top_torrents.as_mut() {
+            top_torrents.add(TopTorrent {
+                info_hash: info_hash.0,
+                peers: num_peers,
+                announces: num_announces,
+            });
+        }
+
+        self.num_peers += num_peers;
+    }
+
+    /// Merge with statistics collected from a disjoint set of torrents
+    pub fn merge(&mut self, other: Self) {
+        self.num_torrents += other.num_torrents;
+        self.num_peers += other.num_peers;
+        self.limit_counters.add(other.limit_counters);
+        self.rejected_ip_peers += other.rejected_ip_peers;
+
+        if let (Some(histogram), Some(other)) =
+            (self.opt_histogram.as_mut(), other.opt_histogram.as_ref())
+        {
+            if let Err(err) = histogram.add(other) {
+                ::log::error!("Couldn't merge peer histograms: {:#}", err);
+            }
+        }
+        if let (Some(top_torrents), Some(other)) = (
+            self.opt_top_torrents.as_mut(),
+            other.opt_top_torrents.as_ref(),
+        ) {
+            top_torrents.merge(other);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TorrentMapShards<I: Ip> {
+    shards: Arc<[RwLock<TorrentMapShard<I>>]>,
+    /// Limits for all shards together
+    limits: TorrentLimits,
+    limit_state: Arc<LimitState>,
+    peer_ip_counter: Arc<PeerIpCounter>,
+}
+
+impl<I: Ip> TorrentMapShards<I> {
+    fn new(config: &Config, num_shards: usize, peer_ip_counter: Arc<PeerIpCounter>) -> Self {
+        Self {
+            shards: repeat_with(Default::default)
+                .take(num_shards)
+                .collect::<Vec<_>>()
+                .into_boxed_slice()
+                .into(),
+            limits: config.torrent_limits.split(1, 0),
+            limit_state: Default::default(),
+            peer_ip_counter,
+        }
+    }
+
+    /// Returns None if peer could not be stored because of per-address
+    /// peer limit
+    fn announce(
+        &self,
+        config: &Config,
+        statistics_sender: &Sender<StatisticsMessage>,
+        rng: &mut SmallRng,
+        request: &AnnounceRequest,
+        ip_address: I,
+        valid_until: ValidUntil,
+    ) -> Option<AnnounceResponse<I>> {
+        let mut statistics_messages = Vec::new();
+
+        let opt_response = loop {
+            let torrent_data = match self.get_or_insert_torrent(
+                config,
+                &mut statistics_messages,
+                &request.info_hash,
+                valid_until,
+            ) {
+                Some(torrent_data) => torrent_data,
+                None => break Some(create_rejected_announce_response(config, request)),
+            };
+
+            if let Some(opt_response) = self.announce_to_torrent(
+                config,
+                statistics_sender,
+                &mut statistics_messages,
+                rng,
+                request,
+                ip_address,
+                valid_until,
+                &torrent_data,
+            ) {
+                break opt_response;
+            }
+
+            // Torrent was removed after it was fetched, so try again
+        };
+
+        for message in statistics_messages {
+            statistics_sender
+                .try_send(message)
+                .expect("statistics channel should be unbounded");
+        }
+
+        opt_response
+    }
+
+    /// Returns None if torrent is new and could not be stored because of
+    /// torrent limit
+    fn get_or_insert_torrent(
+        &self,
+        config: &Config,
+        statistics_messages: &mut Vec<StatisticsMessage>,
+        info_hash: &InfoHash,
+        valid_until: ValidUntil,
+    ) -> Option<Arc<TorrentData<I>>> {
+        let torrent_map_shard = self.get_shard(info_hash).upgradable_read();
+
+        // Clone Arc here to avoid keeping lock on whole shard
+        if let Some(torrent_data) = torrent_map_shard.get(info_hash) {
+            return Some(torrent_data.clone());
+        }
+
+        let mut torrent_map_shard = RwLockUpgradableReadGuard::upgrade(torrent_map_shard);
+
+        // Torrents are counted for all shards together, but room is only
+        // made in the shard the torrent is inserted into. If that shard is
+        // empty, the torrent is stored anyway, so the limit can be exceeded
+        // by at most the number of shards until the next cleaning.
+        if self
+            .limits
+            .torrent_limit_reached(self.limit_state.num_torrents.load(Ordering::Relaxed))
+        {
+            if self.limits.rejects_new() {
+                self.limit_state
+                    .rejected_torrents
+                    .fetch_add(1, Ordering::Relaxed);
+
+                return None;
+            }
+
+            self.evict_sampled_torrent(
+                config,
+                statistics_messages,
+                &mut torrent_map_shard,
+                info_hash,
+            );
+        }
+
+        let torrent_data = Arc::new(TorrentData::new(valid_until));
+
+        // Shard was locked since checking for the torrent, so entry can't
+        // have been created in the meantime
+        torrent_map_shard.insert(*info_hash, torrent_data.clone());
+
+        self.limit_state
+            .num_torrents
+            .fetch_add(1, Ordering::Relaxed);
+
+        Some(torrent_data)
+    }
+
+    /// Returns None without doing anything if torrent has been removed from
+    /// its shard, since peers stored in it would never be cleaned or
+    /// uncounted. Otherwise, returns None as the response if peer could not
+    /// be stored because of per-address peer limit.
+    #[allow(clippy::too_many_arguments)]
+    fn announce_to_torrent(
+        &self,
+        config: &Config,
+        statistics_sender: &Sender<StatisticsMessage>,
+        statistics_messages: &mut Vec<StatisticsMessage>,
+        rng: &mut SmallRng,
+        request: &AnnounceRequest,
+        ip_address: I,
+        valid_until: ValidUntil,
+        torrent_data: &TorrentData<I>,
+    ) -> Option<Option<AnnounceResponse<I>>> {
+        let peer_limit_reached = self.limits.limits_peers()
+            && self
+                .limits
+                .peer_limit_reached(self.limit_state.num_peers.load(Ordering::Relaxed));
+
+        let mut peer_map = torrent_data.peer_map.write();
+
+        // Flag is only set while holding write lock on peer map
+        if torrent_data.removed.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        if config.statistics.top_torrents > 0 {
+            torrent_data.num_announces.fetch_add(1, Ordering::Relaxed);
+        }
+
+        torrent_data.last_announce.store_max(valid_until);
+
+        let peer_ip_counted =
+            counts_peer_ip(&self.peer_ip_counter, Some(&peer_map), request, ip_address);
+
+        if peer_ip_counted && !self.peer_ip_counter.try_add(ip_address.into()) {
+            return Some(None);
+        }
+
+        let (response, peer_count_change) = peer_map.announce(
             config,
-            &mut statistics_messages,
-            &mut cache,
-            mode,
-            now,
+            statistics_sender,
+            rng,
+            request,
+            ip_address,
+            valid_until,
+            !(peer_limit_reached && self.limits.rejects_new()),
+            self.limit_state.num_peers.load(Ordering::Relaxed),
+        );
+
+        torrent_data.update_scrape_statistics(&peer_map);
+
+        drop(peer_map);
+
+        let evict = update_counts_after_announce(
+            &self.limits,
+            &mut &*self.limit_state,
+            &self.peer_ip_counter,
+            ip_address,
+            peer_ip_counted,
+            peer_limit_reached,
+            peer_count_change,
         );
 
-        if config.statistics.active() {
-            statistics.ipv4.torrents.store(ipv4.0, Ordering::Relaxed);
-            statistics.ipv6.torrents.store(ipv6.0, Ordering::Relaxed);
-            statistics.ipv4.peers.store(ipv4.1, Ordering::Relaxed);
-            statistics.ipv6.peers.store(ipv6.1, Ordering::Relaxed);
+        if evict {
+            self.evict_sampled_torrent(
+                config,
+                statistics_messages,
+                &mut self.get_shard(&request.info_hash).write(),
+                &request.info_hash,
+            );
+        }
+
+        Some(Some(response))
+    }
+
+    /// Evict a torrent in shard according to eviction policy, never the one
+    /// with `protected_info_hash`
+    fn evict_sampled_torrent(
+        &self,
+        config: &Config,
+        statistics_messages: &mut Vec<StatisticsMessage>,
+        torrent_map_shard: &mut TorrentMapShard<I>,
+        protected_info_hash: &InfoHash,
+    ) {
+        if let Some(info_hash) =
+            select_sampled_eviction(&self.limits, torrent_map_shard, protected_info_hash)
+        {
+            if let Some(torrent_data) = torrent_map_shard.remove(&info_hash) {
+                self.on_torrent_evicted(config, statistics_messages, &torrent_data);
+            }
+        }
+    }
+
+    /// Returns number of peers in evicted torrent
+    fn on_torrent_evicted(
+        &self,
+        config: &Config,
+        statistics_messages: &mut Vec<StatisticsMessage>,
+        torrent_data: &TorrentData<I>,
+    ) -> usize {
+        self.limit_state
+            .num_torrents
+            .fetch_sub(1, Ordering::Relaxed);
+
+        on_torrent_evicted(
+            config,
+            statistics_messages,
+            &self.limits,
+            &mut &*self.limit_state,
+            &self.peer_ip_counter,
+            &torrent_data.mark_removed(),
+        )
+    }
+
+    fn scrape(&self, request: ScrapeRequest) -> ScrapeResponse {
+        let mut response = ScrapeResponse {
+            transaction_id: request.transaction_id,
+            torrent_stats: Vec::with_capacity(request.info_hashes.len()),
+        };
+
+        for info_hash in request.info_hashes {
+            let torrent_map_shard = self.get_shard(&info_hash);
+
+            let statistics = if let Some(torrent_data) = torrent_map_shard.read().get(&info_hash) {
+                torrent_data.scrape_statistics()
+            } else {
+                TorrentScrapeStatistics {
+                    seeders: NumberOfPeers::new(0),
+                    leechers: NumberOfPeers::new(0),
+                    completed: NumberOfDownloads::new(0),
+                }
+            };
+
+            response.torrent_stats.push(statistics);
+        }
+
+        response
+    }
+
+    fn clean_and_get_statistics(
+        &self,
+        config: &Config,
+        statistics_messages: &mut Vec<StatisticsMessage>,
+        access_list_cache: &mut AccessListCache,
+        access_list_mode: AccessListMode,
+        now: SecondsSinceServerStart,
+    ) -> CleaningStatistics {
+        let mut cleaning_statistics = CleaningStatistics::new(config);
+
+        for torrent_map_shard in self.shards.iter() {
+            for (info_hash, torrent_data) in torrent_map_shard.read().iter() {
+                let mut peer_map = torrent_data.peer_map.write();
+
+                let num_peers = peer_map.clean_and_get_num_peers(
+                    config,
+                    statistics_messages,
+                    &self.peer_ip_counter,
+                    now,
+                );
+
+                torrent_data.update_scrape_statistics(&peer_map);
 
-            if let Some(message) = ipv4.2 {
-                statistics_messages.push(StatisticsMessage::Ipv4PeerHistogram(message));
+                drop(peer_map);
+
+                cleaning_statistics.record_torrent(
+                    info_hash,
+                    num_peers,
+                    torrent_data.num_announces.swap(0, Ordering::Relaxed),
+                );
+
+                torrent_data
+                    .pending_removal
+                    .store(num_peers == 0, Ordering::Release);
             }
-            if let Some(message) = ipv6.2 {
-                statistics_messages.push(StatisticsMessage::Ipv6PeerHistogram(message));
+
+            let mut torrent_map_shard = torrent_map_shard.write();
+            let num_torrents_before = torrent_map_shard.len();
+
+            torrent_map_shard.retain(|info_hash, torrent_data| {
+                if !access_list_cache
+                    .load()
+                    .allows(access_list_mode, &info_hash.0)
+                {
+                    torrent_data
+                        .mark_removed()
+                        .remove_from_peer_ip_counter(&self.peer_ip_counter);
+
+                    return false;
+                }
+
+                // Check pending_removal flag set in previous cleaning step. This
+                // prevents us from removing TorrentData entries that were just
+                // added but do not yet contain any peers. Also double-check that
+                // no peers have been added since we last checked.
+                if torrent_data
+                    .pending_removal
+                    .fetch_and(false, Ordering::Acquire)
+                {
+                    let peer_map = torrent_data.peer_map.write();
+
+                    if peer_map.is_empty() {
+                        torrent_data.removed.store(true, Ordering::Relaxed);
+
+                        return false;
+                    }
+                }
+
+                true
+            });
+
+            self.limit_state.num_torrents.fetch_sub(
+                num_torrents_before - torrent_map_shard.len(),
+                Ordering::Relaxed,
+            );
+
+            torrent_map_shard.shrink_to_fit();
+
+            cleaning_statistics.num_torrents += torrent_map_shard.len();
+        }
+
+        self.limit_state
+            .num_peers
+            .store(cleaning_statistics.num_peers, Ordering::Relaxed);
+
+        self.enforce_limits(config, statistics_messages, &mut cleaning_statistics);
+
+        cleaning_statistics.limit_counters = self.limit_state.take_counters();
+
+        cleaning_statistics
+    }
+
+    /// Evict torrents from all shards in policy order until limits are met
+    fn enforce_limits(
+        &self,
+        config: &Config,
+        statistics_messages: &mut Vec<StatisticsMessage>,
+        cleaning_statistics: &mut CleaningStatistics,
+    ) {
+        let limits = self.limits;
+
+        if limits.rejects_new()
+            || !limits.exceeded(
+                cleaning_statistics.num_torrents,
+                cleaning_statistics.num_peers,
+            )
+        {
+            return;
+        }
+
+        let mut candidates = Vec::new();
+
+        for (shard_index, torrent_map_shard) in self.shards.iter().enumerate() {
+            for (info_hash, torrent_data) in torrent_map_shard.read().iter() {
+                let candidate = torrent_data.eviction_candidate(*info_hash);
+
+                candidates.push(EvictionCandidate {
+                    key: (shard_index, candidate.key),
+                    last_announce: candidate.last_announce,
+                    num_peers: candidate.num_peers,
+                });
+            }
+        }
+
+        let mut evictions = limits.select_evictions(candidates);
+
+        evictions.sort_unstable_by_key(|(shard_index, _)| *shard_index);
+
+        let mut evictions = evictions.into_iter().peekable();
+
+        while let Some((shard_index, info_hash)) = evictions.next() {
+            let mut torrent_map_shard = self.shards[shard_index].write();
+            let mut info_hash = info_hash;
+
+            loop {
+                if let Some(torrent_data) = torrent_map_shard.remove(&info_hash) {
+                    cleaning_statistics.num_torrents -= 1;
+                    cleaning_statistics.num_peers -=
+                        self.on_torrent_evicted(config, statistics_messages, &torrent_data);
+                }
+
+                // Keep shard locked while evicting its remaining torrents
+                match evictions.next_if(|(i, _)| *i == shard_index) {
+                    Some((_, next_info_hash)) => info_hash = next_info_hash,
+                    None => break,
+                }
+            }
+        }
+    }
+
+    fn get_shard(&self, info_hash: &InfoHash) -> &RwLock<TorrentMapShard<I>> {
+        &self.shards[self.get_shard_index(info_hash)]
+    }
+
+    fn get_shard_index(&self, info_hash: &InfoHash) -> usize {
+        // Use two bytes so that more than 256 shards can be put to use, in
+        // an order that doesn't depend on host byte order
+        let key = u16::from_le_bytes([info_hash.0[0], info_hash.0[1]]);
+
+        key as usize % self.shards.len()
+    }
+}
+
+/// Torrent and peer counts and limit counters shared by users of torrent
+/// map shards
+#[derive(Default)]
+struct LimitState {
+    /// Updated whenever torrents are inserted or removed
+    num_torrents: AtomicUsize,
+    /// Set to the counted number when cleaning. If there is a peer limit,
+    /// also updated when peers are added or removed in the announce path.
+    num_peers: AtomicUsize,
+    rejected_torrents: AtomicUsize,
+    rejected_peers: AtomicUsize,
+    evicted_torrents: AtomicUsize,
+}
+
+impl LimitState {
+    fn take_counters(&self) -> TorrentLimitCounters {
+        TorrentLimitCounters {
+            rejected_torrents: self.rejected_torrents.swap(0, Ordering::Relaxed),
+            rejected_peers: self.rejected_peers.swap(0, Ordering::Relaxed),
+            evicted_torrents: self.evicted_torrents.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+impl LimitCounts for &LimitState {
+    fn add_peer(&mut self) {
+        self.num_peers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn subtract_peers(&mut self, n: usize) {
+        // Count may have been set just before peers that were not counted
+        // were removed, so avoid wrapping around
+        let _ = self
+            .num_peers
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |num_peers| {
+                Some(num_peers.saturating_sub(n))
+            });
+    }
+
+    fn count_rejected_peer(&mut self) {
+        self.rejected_peers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count_evicted_torrent(&mut self) {
+        self.evicted_torrents.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Use HashMap instead of IndexMap for better lookup performance
+type TorrentMapShard<T> = HashMap<InfoHash, Arc<TorrentData<T>>>;
+
+pub struct TorrentData<T: Ip> {
+    peer_map: RwLock<PeerMap<T>>,
+    pending_removal: AtomicBool,
+    /// Announces since previous cleaning, if top torrents are reported
+    num_announces: AtomicUsize,
+    /// Number of seeders, updated while holding write lock on peer map so
+    /// that scrape requests don't need to take the lock
+    num_seeders: AtomicUsize,
+    /// Number of leechers, updated along with `num_seeders`
+    num_leechers: AtomicUsize,
+    /// Peer validity set on latest announce
+    last_announce: AtomicValidUntil,
+    /// Set when torrent is removed from its shard, while holding write lock
+    /// on peer map. Announces may still hold a clone of the Arc, and must
+    /// not store peers after this has been set.
+    removed: AtomicBool,
+}
+
+impl<I: Ip> TorrentData<I> {
+    fn new(last_announce: ValidUntil) -> Self {
+        Self {
+            peer_map: Default::default(),
+            pending_removal: Default::default(),
+            removed: Default::default(),
+            num_announces: Default::default(),
+            num_seeders: Default::default(),
+            num_leechers: Default::default(),
+            last_announce: AtomicValidUntil::new(last_announce),
+        }
+    }
+
+    /// Flag torrent as removed from its shard and return write lock on peer
+    /// map, which was taken before setting flag
+    fn mark_removed(&self) -> RwLockWriteGuard<'_, PeerMap<I>> {
+        let peer_map = self.peer_map.write();
+
+        self.removed.store(true, Ordering::Relaxed);
+
+        peer_map
+    }
+
+    fn update_scrape_statistics(&self, peer_map: &PeerMap<I>) {
+        let (seeders, leechers) = peer_map.num_seeders_leechers();
+
+        self.num_seeders.store(seeders, Ordering::Relaxed);
+        self.num_leechers.store(leechers, Ordering::Relaxed);
+    }
+
+    fn scrape_statistics(&self) -> TorrentScrapeStatistics {
+        create_torrent_scrape_statistics(
+            self.num_seeders.load(Ordering::Relaxed),
+            self.num_leechers.load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl<I: Ip> TorrentEntry for TorrentData<I> {
+    fn eviction_candidate(&self, info_hash: InfoHash) -> EvictionCandidate<InfoHash> {
+        EvictionCandidate {
+            key: info_hash,
+            last_announce: self.last_announce.load(),
+            num_peers: self.num_seeders.load(Ordering::Relaxed)
+                + self.num_leechers.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Torrent maps owned by a single swarm worker, which can access them
+/// without taking any locks
+pub struct WorkerTorrentMaps {
+    ipv4: WorkerTorrentMap<Ipv4AddrBytes>,
+    ipv6: WorkerTorrentMap<Ipv6AddrBytes>,
+}
+
+impl WorkerTorrentMaps {
+    pub fn new(config: &Config, worker_index: usize, peer_ip_counter: Arc<PeerIpCounter>) -> Self {
+        let limits = config
+            .torrent_limits
+            .split(config.swarm_workers, worker_index);
+
+        Self {
+            ipv4: WorkerTorrentMap::new(limits, peer_ip_counter.clone()),
+            ipv6: WorkerTorrentMap::new(limits, peer_ip_counter),
+        }
+    }
+
+    pub fn announce(
+        &mut self,
+        config: &Config,
+        statistics_sender: &Sender<StatisticsMessage>,
+        rng: &mut SmallRng,
+        request: &AnnounceRequest,
+        src: CanonicalSocketAddr,
+        valid_until: ValidUntil,
+    ) -> Response {
+        let opt_response = match src.get().ip() {
+            IpAddr::V4(ip_address) => self
+                .ipv4
+                .announce(
+                    config,
+                    statistics_sender,
+                    rng,
+                    request,
+                    ip_address.into(),
+                    valid_until,
+                )
+                .map(Response::AnnounceIpv4),
+            IpAddr::V6(ip_address) => self
+                .ipv6
+                .announce(
+                    config,
+                    statistics_sender,
+                    rng,
+                    request,
+                    ip_address.into(),
+                    valid_until,
+                )
+                .map(Response::AnnounceIpv6),
+        };
+
+        opt_response.unwrap_or_else(|| create_peer_ip_limit_error_response(request))
+    }
+
+    pub fn scrape(
+        &self,
+        request: PendingScrapeRequest,
+        src: CanonicalSocketAddr,
+    ) -> PendingScrapeResponse {
+        let torrent_stats = if src.is_ipv4() {
+            self.ipv4.scrape(request.info_hashes)
+        } else {
+            self.ipv6.scrape(request.info_hashes)
+        };
+
+        PendingScrapeResponse {
+            slab_key: request.slab_key,
+            scrape_id: request.scrape_id,
+            torrent_stats,
+        }
+    }
+
+    /// Remove forbidden or inactive torrents and return statistics
+    pub fn clean(
+        &mut self,
+        config: &Config,
+        statistics_messages: &mut Vec<StatisticsMessage>,
+        access_list: &Arc<AccessListArcSwap>,
+        server_start_instant: ServerStartInstant,
+    ) -> IpVersionStatistics<CleaningStatistics> {
+        let mut cache = create_access_list_cache(access_list);
+        let mode = config.access_list.mode;
+        let now = server_start_instant.seconds_elapsed();
+
+        let mut cleaning_statistics = IpVersionStatistics {
+            ipv4: self.ipv4.clean_and_get_statistics(
+                config,
+                statistics_messages,
+                &mut cache,
+                mode,
+                now,
+            ),
+            ipv6: self.ipv6.clean_and_get_statistics(
+                config,
+                statistics_messages,
+                &mut cache,
+                mode,
+                now,
+            ),
+        };
+
+        cleaning_statistics.take_peer_ip_rejections(&self.ipv4.peer_ip_counter);
+
+        cleaning_statistics
+    }
+}
+
+struct WorkerTorrentMap<I: Ip> {
+    torrents: HashMap<InfoHash, WorkerTorrentData<I>>,
+    limits: TorrentLimits,
+    limit_state: WorkerLimitState,
+    peer_ip_counter: Arc<PeerIpCounter>,
+}
+
+impl<I: Ip> WorkerTorrentMap<I> {
+    fn new(limits: TorrentLimits, peer_ip_counter: Arc<PeerIpCounter>) -> Self {
+        Self {
+            torrents: Default::default(),
+            limits,
+            limit_state: Default::default(),
+            peer_ip_counter,
+        }
+    }
+
+    /// Returns None if peer could not be stored because of per-address
+    /// peer limit
+    fn announce(
+        &mut self,
+        config: &Config,
+        statistics_sender: &Sender<StatisticsMessage>,
+        rng: &mut SmallRng,
+        request: &AnnounceRequest,
+        ip_address: I,
+        valid_until: ValidUntil,
+    ) -> Option<AnnounceResponse<I>> {
+        let mut statistics_messages = Vec::new();
+
+        let peer_ip_counted = counts_peer_ip(
+            &self.peer_ip_counter,
+            self.torrents
+                .get(&request.info_hash)
+                .map(|torrent_data| &torrent_data.peer_map),
+            request,
+            ip_address,
+        );
+
+        if peer_ip_counted && !self.peer_ip_counter.try_add(ip_address.into()) {
+            return None;
+        }
+
+        if !self.torrents.contains_key(&request.info_hash)
+            && self.limits.torrent_limit_reached(self.torrents.len())
+            && !self.evict_sampled_torrent(config, &mut statistics_messages, &request.info_hash)
+        {
+            self.limit_state.counters.rejected_torrents += 1;
+
+            if peer_ip_counted {
+                self.peer_ip_counter.remove(ip_address.into());
+            }
+
+            return Some(create_rejected_announce_response(config, request));
+        }
+
+        let peer_limit_reached = self.limits.limits_peers()
+            && self.limits.peer_limit_reached(self.limit_state.num_peers);
+
+        let torrent_data =
+            self.torrents
+                .entry(request.info_hash)
+                .or_insert_with(|| WorkerTorrentData {
+                    peer_map: Default::default(),
+                    num_announces: 0,
+                    last_announce: valid_until,
+                });
+
+        if config.statistics.top_torrents > 0 {
+            torrent_data.num_announces += 1;
+        }
+
+        torrent_data.last_announce = valid_until;
+
+        // Each swarm worker stores a share of peers
+        let tracker_peers = self
+            .limit_state
+            .num_peers
+            .saturating_mul(config.swarm_workers);
+
+        let (response, peer_count_change) = torrent_data.peer_map.announce(
+            config,
+            statistics_sender,
+            rng,
+            request,
+            ip_address,
+            valid_until,
+            !(peer_limit_reached && self.limits.rejects_new()),
+            tracker_peers,
+        );
+
+        let evict = update_counts_after_announce(
+            &self.limits,
+            &mut self.limit_state,
+            &self.peer_ip_counter,
+            ip_address,
+            peer_ip_counted,
+            peer_limit_reached,
+            peer_count_change,
+        );
+
+        if evict {
+            self.evict_sampled_torrent(config, &mut statistics_messages, &request.info_hash);
+        }
+
+        for message in statistics_messages {
+            statistics_sender
+                .try_send(message)
+                .expect("statistics channel should be unbounded");
+        }
+
+        Some(response)
+    }
+
+    /// Evict a torrent according to eviction policy, never the one with
+    /// `protected_info_hash`
+    ///
+    /// Returns false if no torrent was evicted.
+    fn evict_sampled_torrent(
+        &mut self,
+        config: &Config,
+        statistics_messages: &mut Vec<StatisticsMessage>,
+        protected_info_hash: &InfoHash,
+    ) -> bool {
+        if let Some(info_hash) =
+            select_sampled_eviction(&self.limits, &self.torrents, protected_info_hash)
+        {
+            if let Some(torrent_data) = self.torrents.remove(&info_hash) {
+                self.on_torrent_evicted(config, statistics_messages, &torrent_data);
+
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Returns number of peers in evicted torrent
+    fn on_torrent_evicted(
+        &mut self,
+        config: &Config,
+        statistics_messages: &mut Vec<StatisticsMessage>,
+        torrent_data: &WorkerTorrentData<I>,
+    ) -> usize {
+        on_torrent_evicted(
+            config,
+            statistics_messages,
+            &self.limits,
+            &mut self.limit_state,
+            &self.peer_ip_counter,
+            &torrent_data.peer_map,
+        )
+    }
+
+    fn scrape(&self, info_hashes: Vec<(usize, InfoHash)>) -> Vec<(usize, TorrentScrapeStatistics)> {
+        info_hashes
+            .into_iter()
+            .map(|(i, info_hash)| {
+                let (seeders, leechers) = self
+                    .torrents
+                    .get(&info_hash)
+                    .map(|torrent_data| torrent_data.peer_map.num_seeders_leechers())
+                    .unwrap_or((0, 0));
+
+                (i, create_torrent_scrape_statistics(seeders, leechers))
+            })
+            .collect()
+    }
+
+    fn clean_and_get_statistics(
+        &mut self,
+        config: &Config,
+        statistics_messages: &mut Vec<StatisticsMessage>,
+        access_list_cache: &mut AccessListCache,
+        access_list_mode: AccessListMode,
+        now: SecondsSinceServerStart,
+    ) -> CleaningStatistics {
+        let mut cleaning_statistics = CleaningStatistics::new(config);
+
+        let peer_ip_counter = &self.peer_ip_counter;
+
+        self.torrents.retain(|info_hash, torrent_data| {
+            if !access_list_cache
+                .load()
+                .allows(access_list_mode, &info_hash.0)
+            {
+                torrent_data
+                    .peer_map
+                    .remove_from_peer_ip_counter(peer_ip_counter);
+
+                return false;
             }
 
-            for message in statistics_messages {
-                if let Err(err) = statistics_sender.try_send(message) {
-                    ::log::error!("couldn't send statistics message: {:#}", err);
-                }
+            let num_peers = torrent_data.peer_map.clean_and_get_num_peers(
+                config,
+                statistics_messages,
+                peer_ip_counter,
+                now,
+            );
+
+            cleaning_statistics.record_torrent(
+                info_hash,
+                num_peers,
+                ::std::mem::take(&mut torrent_data.num_announces),
+            );
+
+            // No other thread can be about to add peers, so empty torrents
+            // can be removed immediately
+            num_peers > 0
+        });
+
+        for info_hash in
+            select_evictions(&self.limits, &self.torrents, cleaning_statistics.num_peers)
+        {
+            if let Some(torrent_data) = self.torrents.remove(&info_hash) {
+                cleaning_statistics.num_peers -=
+                    self.on_torrent_evicted(config, statistics_messages, &torrent_data);
             }
         }
+
+        self.torrents.shrink_to_fit();
+
+        self.limit_state.num_peers = cleaning_statistics.num_peers;
+
+        cleaning_statistics.num_torrents = self.torrents.len();
+        cleaning_statistics.limit_counters = ::std::mem::take(&mut self.limit_state.counters);
+
+        cleaning_statistics
     }
 }
 
-#[derive(Clone)]
-pub struct TorrentMapShards<I: Ip>(Arc<[RwLock<TorrentMapShard<I>>]>);
+struct WorkerTorrentData<I: Ip> {
+    peer_map: PeerMap<I>,
+    /// Announces since previous cleaning, if top torrents are reported
+    num_announces: usize,
+    /// Peer validity set on latest announce
+    last_announce: ValidUntil,
+}
 
-impl<I: Ip> TorrentMapShards<I> {
-    fn new(num_shards: usize) -> Self {
-        Self(
-            repeat_with(Default::default)
-                .take(num_shards)
-                .collect::<Vec<_>>()
-                .into_boxed_slice()
-                .into(),
-        )
+impl<I: Ip> TorrentEntry for WorkerTorrentData<I> {
+    fn eviction_candidate(&self, info_hash: InfoHash) -> EvictionCandidate<InfoHash> {
+        EvictionCandidate {
+            key: info_hash,
+            last_announce: self.last_announce,
+            num_peers: self.peer_map.num_peers(),
+        }
     }
+}
 
-    fn announce(
-        &self,
-        config: &Config,
-        statistics_sender: &Sender<StatisticsMessage>,
-        rng: &mut SmallRng,
-        request: &AnnounceRequest,
-        ip_address: I,
-        valid_until: ValidUntil,
-    ) -> AnnounceResponse<I> {
-        let torrent_data = {
-            let torrent_map_shard = self.get_shard(&request.info_hash).upgradable_read();
-
-            // Clone Arc here to avoid keeping lock on whole shard
-            if let Some(torrent_data) = torrent_map_shard.get(&request.info_hash) {
-                torrent_data.clone()
-            } else {
-                // Don't overwrite entry if created in the meantime
-                RwLockUpgradableReadGuard::upgrade(torrent_map_shard)
-                    .entry(request.info_hash)
-                    .or_default()
-                    .clone()
-            }
-        };
+/// Peer count and limit counters of a torrent map owned by a swarm worker
+#[derive(Default)]
+struct WorkerLimitState {
+    /// Set to the counted number when cleaning. If there is a peer limit,
+    /// also updated when peers are added or removed in the announce path.
+    num_peers: usize,
+    /// Rejections and evictions since previous cleaning
+    counters: TorrentLimitCounters,
+}
 
-        let mut peer_map = torrent_data.peer_map.write();
+impl LimitCounts for WorkerLimitState {
+    fn add_peer(&mut self) {
+        self.num_peers += 1;
+    }
 
-        peer_map.announce(
-            config,
-            statistics_sender,
-            rng,
-            request,
-            ip_address,
-            valid_until,
-        )
+    fn subtract_peers(&mut self, n: usize) {
+        self.num_peers = self.num_peers.saturating_sub(n);
     }
 
-    fn scrape(&self, request: ScrapeRequest) -> ScrapeResponse {
-        let mut response = ScrapeResponse {
-            transaction_id: request.transaction_id,
-            torrent_stats: Vec::with_capacity(request.info_hashes.len()),
-        };
+    fn count_rejected_peer(&mut self) {
+        self.counters.rejected_peers += 1;
+    }
 
-        for info_hash in request.info_hashes {
-            let torrent_map_shard = self.get_shard(&info_hash);
+    fn count_evicted_torrent(&mut self) {
+        self.counters.evicted_torrents += 1;
+    }
+}
 
-            let statistics = if let Some(torrent_data) = torrent_map_shard.read().get(&info_hash) {
-                torrent_data.peer_map.read().scrape_statistics()
-            } else {
-                TorrentScrapeStatistics {
-                    seeders: NumberOfPeers::new(0),
-                    leechers: NumberOfPeers::new(0),
-                    completed: NumberOfDownloads::new(0),
-                }
-            };
+/// Peer count and limit counters, shared by torrent map shards or owned by
+/// a swarm worker
+trait LimitCounts {
+    fn add_peer(&mut self);
+    fn subtract_peers(&mut self, n: usize);
+    fn count_rejected_peer(&mut self);
+    fn count_evicted_torrent(&mut self);
+}
 
-            response.torrent_stats.push(statistics);
-        }
+/// Torrent as stored in torrent map shards or swarm worker torrent maps
+trait TorrentEntry {
+    fn eviction_candidate(&self, info_hash: InfoHash) -> EvictionCandidate<InfoHash>;
+}
 
-        response
+impl<T: TorrentEntry> TorrentEntry for Arc<T> {
+    fn eviction_candidate(&self, info_hash: InfoHash) -> EvictionCandidate<InfoHash> {
+        (**self).eviction_candidate(info_hash)
     }
+}
 
-    fn clean_and_get_statistics(
-        &self,
-        config: &Config,
-        statistics_messages: &mut Vec<StatisticsMessage>,
-        access_list_cache: &mut AccessListCache,
-        access_list_mode: AccessListMode,
-        now: SecondsSinceServerStart,
-    ) -> (usize, usize, Option<Histogram<u64>>) {
-        let mut total_num_torrents = 0;
-        let mut total_num_peers = 0;
+/// Choose torrent to evict among a sample of torrents, never the one with
+/// `protected_info_hash`
+///
+/// HashMap doesn't support picking random entries, so the first torrents
+/// in iteration order are used as the sample. Since that order depends
+/// on info hash hashes, they are unrelated to torrent age and size.
+fn select_sampled_eviction<T: TorrentEntry>(
+    limits: &TorrentLimits,
+    torrents: &HashMap<InfoHash, T>,
+    protected_info_hash: &InfoHash,
+) -> Option<InfoHash> {
+    if limits.rejects_new() {
+        return None;
+    }
 
-        let mut opt_histogram: Option<Histogram<u64>> = config
-            .statistics
-            .torrent_peer_histograms
-            .then(|| Histogram::new(3).expect("create peer histogram"));
+    let candidates = torrents
+        .iter()
+        .filter(|(info_hash, _)| *info_hash != protected_info_hash)
+        .take(EVICTION_SAMPLE_SIZE)
+        .map(|(info_hash, torrent_data)| torrent_data.eviction_candidate(*info_hash));
 
-        for torrent_map_shard in self.0.iter() {
-            for torrent_data in torrent_map_shard.read().values() {
-                let mut peer_map = torrent_data.peer_map.write();
+    limits.select_eviction(candidates)
+}
 
-                let num_peers = match peer_map.deref_mut() {
-                    PeerMap::Small(small_peer_map) => {
-                        small_peer_map.clean_and_get_num_peers(config, statistics_messages, now)
-                    }
-                    PeerMap::Large(large_peer_map) => {
-                        let num_peers = large_peer_map.clean_and_get_num_peers(
-                            config,
-                            statistics_messages,
-                            now,
-                        );
-
-                        if let Some(small_peer_map) = large_peer_map.try_shrink() {
-                            *peer_map = PeerMap::Small(small_peer_map);
-                        }
-
-                        num_peers
-                    }
-                };
+/// Choose torrents to evict so that limits are met, given that `num_peers`
+/// peers are stored
+fn select_evictions<T: TorrentEntry>(
+    limits: &TorrentLimits,
+    torrents: &HashMap<InfoHash, T>,
+    num_peers: usize,
+) -> Vec<InfoHash> {
+    if limits.rejects_new() || !limits.exceeded(torrents.len(), num_peers) {
+        return Vec::new();
+    }
 
-                drop(peer_map);
+    let candidates = torrents
+        .iter()
+        .map(|(info_hash, torrent_data)| torrent_data.eviction_candidate(*info_hash))
+        .collect();
 
-                match opt_histogram.as_mut() {
-                    Some(histogram) if num_peers > 0 => {
-                        if let Err(err) = histogram.record(num_peers as u64) {
-                            ::log::error!("Couldn't record {} to histogram: {:#}", num_peers, err);
-                        }
-                    }
-                    _ => (),
-                }
+    limits.select_evictions(candidates)
+}
 
-                total_num_peers += num_peers;
+/// Uncount peers of a torrent that was evicted and report them as removed
+///
+/// Returns number of peers in torrent.
+fn on_torrent_evicted<I: Ip>(
+    config: &Config,
+    statistics_messages: &mut Vec<StatisticsMessage>,
+    limits: &TorrentLimits,
+    limit_counts: &mut impl LimitCounts,
+    peer_ip_counter: &PeerIpCounter,
+    peer_map: &PeerMap<I>,
+) -> usize {
+    let num_peers = peer_map.num_peers();
+
+    peer_map.remove_from_peer_ip_counter(peer_ip_counter);
+
+    if config.statistics.peer_clients {
+        statistics_messages.extend(
+            peer_map
+                .peer_ids()
+                .into_iter()
+                .map(StatisticsMessage::PeerRemoved),
+        );
+    }
 
-                torrent_data
-                    .pending_removal
-                    .store(num_peers == 0, Ordering::Release);
-            }
+    if limits.limits_peers() {
+        limit_counts.subtract_peers(num_peers);
+    }
 
-            let mut torrent_map_shard = torrent_map_shard.write();
+    limit_counts.count_evicted_torrent();
 
-            torrent_map_shard.retain(|info_hash, torrent_data| {
-                if !access_list_cache
-                    .load()
-                    .allows(access_list_mode, &info_hash.0)
-                {
-                    return false;
-                }
+    num_peers
+}
 
-                // Check pending_removal flag set in previous cleaning step. This
-                // prevents us from removing TorrentData entries that were just
-                // added but do not yet contain any peers. Also double-check that
-                // no peers have been added since we last checked.
-                if torrent_data
-                    .pending_removal
-                    .fetch_and(false, Ordering::Acquire)
-                    && torrent_data.peer_map.read().is_empty()
-                {
-                    return false;
-                }
+/// Update address and limit counts after an announce changed the peer map
+///
+/// Returns true if a torrent should be evicted to make room for an added
+/// peer.
+fn update_counts_after_announce<I: Ip>(
+    limits: &TorrentLimits,
+    limit_counts: &mut impl LimitCounts,
+    peer_ip_counter: &PeerIpCounter,
+    ip_address: I,
+    peer_ip_counted: bool,
+    peer_limit_reached: bool,
+    peer_count_change: PeerCountChange,
+) -> bool {
+    match peer_count_change {
+        PeerCountChange::Removed => peer_ip_counter.remove(ip_address.into()),
+        PeerCountChange::Rejected if peer_ip_counted => peer_ip_counter.remove(ip_address.into()),
+        _ => (),
+    }
 
-                true
-            });
+    if !limits.limits_peers() {
+        return false;
+    }
 
-            torrent_map_shard.shrink_to_fit();
+    match peer_count_change {
+        PeerCountChange::Added => {
+            limit_counts.add_peer();
 
-            total_num_torrents += torrent_map_shard.len();
+            return peer_limit_reached;
         }
-
-        (total_num_torrents, total_num_peers, opt_histogram)
+        PeerCountChange::Removed => limit_counts.subtract_peers(1),
+        PeerCountChange::Rejected => limit_counts.count_rejected_peer(),
+        PeerCountChange::Unchanged => (),
     }
 
-    fn get_shard(&self, info_hash: &InfoHash) -> &RwLock<TorrentMapShard<I>> {
-        self.0.get(info_hash.0[0] as usize % self.0.len()).unwrap()
+    false
+}
+
+/// Peer is to be counted for its address if it would be added to the
+/// (possibly nonexistent) peer map
+fn counts_peer_ip<I: Ip>(
+    peer_ip_counter: &PeerIpCounter,
+    opt_peer_map: Option<&PeerMap<I>>,
+    request: &AnnounceRequest,
+    ip_address: I,
+) -> bool {
+    if !peer_ip_counter.is_active()
+        || PeerStatus::from_event_and_bytes_left(request.event.into(), request.bytes_left)
+            == PeerStatus::Stopped
+    {
+        return false;
     }
+
+    let key = ResponsePeer {
+        ip_address,
+        port: request.port,
+    };
+
+    !opt_peer_map.map_or(false, |peer_map| peer_map.contains(&key))
 }
 
-/// Use HashMap instead of IndexMap for better lookup performance
-type TorrentMapShard<T> = HashMap<InfoHash, Arc<TorrentData<T>>>;
+fn create_peer_ip_limit_error_response(request: &AnnounceRequest) -> Response {
+    Response::Error(ErrorResponse {
+        transaction_id: request.transaction_id,
+        message: "Too many peers from your address".into(),
+    })
+}
 
-pub struct TorrentData<T: Ip> {
-    peer_map: RwLock<PeerMap<T>>,
-    pending_removal: AtomicBool,
+/// Response for announce requests to torrents that could not be stored
+fn create_rejected_announce_response<I: Ip>(
+    config: &Config,
+    request: &AnnounceRequest,
+) -> AnnounceResponse<I> {
+    AnnounceResponse {
+        fixed: AnnounceResponseFixedData {
+            transaction_id: request.transaction_id,
+            announce_interval: AnnounceInterval::new(config.protocol.peer_announce_interval),
+            leechers: NumberOfPeers::new(0),
+            seeders: NumberOfPeers::new(0),
+        },
+        peers: Vec::new(),
+    }
 }
 
-impl<I: Ip> Default for TorrentData<I> {
-    fn default() -> Self {
-        Self {
-            peer_map: Default::default(),
-            pending_removal: Default::default(),
-        }
+fn create_torrent_scrape_statistics(seeders: usize, leechers: usize) -> TorrentScrapeStatistics {
+    TorrentScrapeStatistics {
+        seeders: NumberOfPeers::new(seeders.try_into().unwrap_or(i32::MAX)),
+        leechers: NumberOfPeers::new(leechers.try_into().unwrap_or(i32::MAX)),
+        completed: NumberOfDownloads::new(0),
     }
 }
 
@@ -330,6 +1419,12 @@ pub enum PeerMap<I: Ip> {
 }
 
 impl<I: Ip> PeerMap<I> {
+    /// Handle announce request, only inserting a peer that isn't already
+    /// stored if `allow_new_peer` is true
+    ///
+    /// `tracker_peers` is the number of peers stored by the tracker for this
+    /// IP version, used for scaling the announce interval.
+    #[allow(clippy::too_many_arguments)]
     fn announce(
         &mut self,
         config: &Config,
@@ -338,7 +1433,9 @@ impl<I: Ip> PeerMap<I> {
         request: &AnnounceRequest,
         ip_address: I,
         valid_until: ValidUntil,
-    ) -> AnnounceResponse<I> {
+        allow_new_peer: bool,
+        tracker_peers: usize,
+    ) -> (AnnounceResponse<I>, PeerCountChange) {
         let max_num_peers_to_take: usize = if request.peers_wanted.0.get() <= 0 {
             config.protocol.max_response_peers
         } else {
@@ -356,6 +1453,18 @@ impl<I: Ip> PeerMap<I> {
             port: request.port,
         };
 
+        let announce_interval = AnnounceInterval::new(
+            config
+                .announce_interval
+                .interval(
+                    config.protocol.peer_announce_interval.max(0) as u64,
+                    self.num_peers(),
+                    tracker_peers,
+                )
+                .try_into()
+                .unwrap_or(i32::MAX),
+        );
+
         // Create the response before inserting the peer. This means that we
         // don't have to filter it out from the response peers, and that the
         // reported number of seeders/leechers will not include it
@@ -368,19 +1477,25 @@ impl<I: Ip> PeerMap<I> {
                 let response = AnnounceResponse {
                     fixed: AnnounceResponseFixedData {
                         transaction_id: request.transaction_id,
-                        announce_interval: AnnounceInterval::new(
-                            config.protocol.peer_announce_interval,
-                        ),
+                        announce_interval,
                         leechers: NumberOfPeers::new(leechers.try_into().unwrap_or(i32::MAX)),
                         seeders: NumberOfPeers::new(seeders.try_into().unwrap_or(i32::MAX)),
                     },
-                    peers: peer_map.extract_response_peers(max_num_peers_to_take),
+                    peers: peer_map.extract_response_peers(
+                        &config.peer_selection,
+                        ip_address.into(),
+                        status == PeerStatus::Seeding,
+                        max_num_peers_to_take,
+                    ),
                 };
 
                 // Convert peer map to large variant if it is full and
                 // announcing peer is not stopped and will therefore be
                 // inserted
-                if peer_map.is_full() && status != PeerStatus::Stopped {
+                if peer_map.is_full()
+                    && status != PeerStatus::Stopped
+                    && (allow_new_peer || opt_removed_peer.is_some())
+                {
                     *self = Self::Large(peer_map.to_large());
                 }
 
@@ -394,13 +1509,17 @@ impl<I: Ip> PeerMap<I> {
                 let response = AnnounceResponse {
                     fixed: AnnounceResponseFixedData {
                         transaction_id: request.transaction_id,
-                        announce_interval: AnnounceInterval::new(
-                            config.protocol.peer_announce_interval,
-                        ),
+                        announce_interval,
                         leechers: NumberOfPeers::new(leechers.try_into().unwrap_or(i32::MAX)),
                         seeders: NumberOfPeers::new(seeders.try_into().unwrap_or(i32::MAX)),
                     },
-                    peers: peer_map.extract_response_peers(rng, max_num_peers_to_take),
+                    peers: peer_map.extract_response_peers(
+                        rng,
+                        &config.peer_selection,
+                        ip_address.into(),
+                        status == PeerStatus::Seeding,
+                        max_num_peers_to_take,
+                    ),
                 };
 
                 // Try shrinking the map if announcing peer is stopped and
@@ -415,7 +1534,12 @@ impl<I: Ip> PeerMap<I> {
             }
         };
 
-        match status {
+        let peer_count_change = match status {
+            PeerStatus::Leeching | PeerStatus::Seeding
+                if !allow_new_peer && opt_removed_peer.is_none() =>
+            {
+                PeerCountChange::Rejected
+            }
             PeerStatus::Leeching | PeerStatus::Seeding => {
                 let peer = Peer {
                     peer_id: request.peer_id,
@@ -428,34 +1552,70 @@ impl<I: Ip> PeerMap<I> {
                     Self::Large(peer_map) => peer_map.insert(peer_map_key, peer),
                 }
 
-                if config.statistics.peer_clients && opt_removed_peer.is_none() {
-                    statistics_sender
-                        .try_send(StatisticsMessage::PeerAdded(request.peer_id))
-                        .expect("statistics channel should be unbounded");
+                if opt_removed_peer.is_none() {
+                    if config.statistics.peer_clients {
+                        statistics_sender
+                            .try_send(StatisticsMessage::PeerAdded(request.peer_id))
+                            .expect("statistics channel should be unbounded");
+                    }
+
+                    PeerCountChange::Added
+                } else {
+                    PeerCountChange::Unchanged
                 }
             }
             PeerStatus::Stopped => {
-                if config.statistics.peer_clients && opt_removed_peer.is_some() {
-                    statistics_sender
-                        .try_send(StatisticsMessage::PeerRemoved(request.peer_id))
-                        .expect("statistics channel should be unbounded");
+                if opt_removed_peer.is_some() {
+                    if config.statistics.peer_clients {
+                        statistics_sender
+                            .try_send(StatisticsMessage::PeerRemoved(request.peer_id))
+                            .expect("statistics channel should be unbounded");
+                    }
+
+                    PeerCountChange::Removed
+                } else {
+                    PeerCountChange::Unchanged
                 }
             }
         };
 
-        response
+        (response, peer_count_change)
     }
 
-    fn scrape_statistics(&self) -> TorrentScrapeStatistics {
-        let (seeders, leechers) = match self {
+    fn num_seeders_leechers(&self) -> (usize, usize) {
+        match self {
             Self::Small(peer_map) => peer_map.num_seeders_leechers(),
             Self::Large(peer_map) => peer_map.num_seeders_leechers(),
-        };
+        }
+    }
+
+    /// Remove inactive peers, shrinking map if possible, and return number
+    /// of remaining peers
+    fn clean_and_get_num_peers(
+        &mut self,
+        config: &Config,
+        statistics_messages: &mut Vec<StatisticsMessage>,
+        peer_ip_counter: &PeerIpCounter,
+        now: SecondsSinceServerStart,
+    ) -> usize {
+        match self {
+            Self::Small(peer_map) => {
+                peer_map.clean_and_get_num_peers(config, statistics_messages, peer_ip_counter, now)
+            }
+            Self::Large(peer_map) => {
+                let num_peers = peer_map.clean_and_get_num_peers(
+                    config,
+                    statistics_messages,
+                    peer_ip_counter,
+                    now,
+                );
+
+                if let Some(peer_map) = peer_map.try_shrink() {
+                    *self = Self::Small(peer_map);
+                }
 
-        TorrentScrapeStatistics {
-            seeders: NumberOfPeers::new(seeders.try_into().unwrap_or(i32::MAX)),
-            leechers: NumberOfPeers::new(leechers.try_into().unwrap_or(i32::MAX)),
-            completed: NumberOfDownloads::new(0),
+                num_peers
+            }
         }
     }
 
@@ -465,6 +1625,46 @@ impl<I: Ip> PeerMap<I> {
             Self::Large(peer_map) => peer_map.peers.is_empty(),
         }
     }
+
+    fn num_peers(&self) -> usize {
+        match self {
+            Self::Small(peer_map) => peer_map.0.len(),
+            Self::Large(peer_map) => peer_map.peers.len(),
+        }
+    }
+
+    fn contains(&self, key: &ResponsePeer<I>) -> bool {
+        match self {
+            Self::Small(peer_map) => peer_map.0.iter().any(|(k, _)| k == key),
+            Self::Large(peer_map) => peer_map.peers.contains_key(key),
+        }
+    }
+
+    fn remove_from_peer_ip_counter(&self, peer_ip_counter: &PeerIpCounter) {
+        if !peer_ip_counter.is_active() {
+            return;
+        }
+
+        match self {
+            Self::Small(peer_map) => {
+                for (key, _) in peer_map.0.iter() {
+                    peer_ip_counter.remove(key.ip_address.into());
+                }
+            }
+            Self::Large(peer_map) => {
+                for key in peer_map.peers.keys() {
+                    peer_ip_counter.remove(key.ip_address.into());
+                }
+            }
+        }
+    }
+
+    fn peer_ids(&self) -> Vec<PeerId> {
+        match self {
+            Self::Small(peer_map) => peer_map.0.iter().map(|(_, p)| p.peer_id).collect(),
+            Self::Large(peer_map) => peer_map.peers.values().map(|p| p.peer_id).collect(),
+        }
+    }
 }
 
 impl<I: Ip> Default for PeerMap<I> {
@@ -505,21 +1705,41 @@ impl<I: Ip> SmallPeerMap<I> {
         None
     }
 
-    fn extract_response_peers(&self, max_num_peers_to_take: usize) -> Vec<ResponsePeer<I>> {
-        Vec::from_iter(self.0.iter().take(max_num_peers_to_take).map(|(k, _)| *k))
+    fn extract_response_peers(
+        &self,
+        selection: &PeerSelectionConfig,
+        requester_ip: IpAddr,
+        requester_is_seeder: bool,
+        max_num_peers_to_take: usize,
+    ) -> Vec<ResponsePeer<I>> {
+        if selection.is_active() {
+            selection.select(
+                requester_ip,
+                requester_is_seeder,
+                self.0.iter().map(|(k, p)| p.to_candidate(*k)),
+                max_num_peers_to_take,
+            )
+        } else {
+            Vec::from_iter(self.0.iter().take(max_num_peers_to_take).map(|(k, _)| *k))
+        }
     }
 
     fn clean_and_get_num_peers(
         &mut self,
         config: &Config,
         statistics_messages: &mut Vec<StatisticsMessage>,
+        peer_ip_counter: &PeerIpCounter,
         now: SecondsSinceServerStart,
     ) -> usize {
-        self.0.retain(|(_, peer)| {
+        self.0.retain(|(key, peer)| {
             let keep = peer.valid_until.valid(now);
 
-            if !keep && config.statistics.peer_clients {
-                statistics_messages.push(StatisticsMessage::PeerRemoved(peer.peer_id));
+            if !keep {
+                if config.statistics.peer_clients {
+                    statistics_messages.push(StatisticsMessage::PeerRemoved(peer.peer_id));
+                }
+
+                peer_ip_counter.remove(key.ip_address.into());
             }
 
             keep
@@ -574,12 +1794,28 @@ impl<I: Ip> LargePeerMap<I> {
     /// random selection of peers from first and second halves of map in
     /// order to avoid returning too homogeneous peers. This is a lot more
     /// cache-friendly than doing a fully random selection.
+    ///
+    /// If peer selection strategies are active, peers are instead selected
+    /// from up to `max_candidates` consecutive peers starting at a random
+    /// position.
     fn extract_response_peers(
         &self,
         rng: &mut impl Rng,
+        selection: &PeerSelectionConfig,
+        requester_ip: IpAddr,
+        requester_is_seeder: bool,
         max_num_peers_to_take: usize,
     ) -> Vec<ResponsePeer<I>> {
-        if self.peers.len() <= max_num_peers_to_take {
+        if selection.is_active() {
+            selection.select_from_map(
+                rng,
+                &self.peers,
+                |key, peer| peer.to_candidate(key),
+                requester_ip,
+                requester_is_seeder,
+                max_num_peers_to_take,
+            )
+        } else if self.peers.len() <= max_num_peers_to_take {
             self.peers.keys().copied().collect()
         } else {
             let middle_index = self.peers.len() / 2;
@@ -618,9 +1854,10 @@ impl<I: Ip> LargePeerMap<I> {
         &mut self,
         config: &Config,
         statistics_messages: &mut Vec<StatisticsMessage>,
+        peer_ip_counter: &PeerIpCounter,
         now: SecondsSinceServerStart,
     ) -> usize {
-        self.peers.retain(|_, peer| {
+        self.peers.retain(|key, peer| {
             let keep = peer.valid_until.valid(now);
 
             if !keep {
@@ -630,6 +1867,8 @@ impl<I: Ip> LargePeerMap<I> {
                 if config.statistics.peer_clients {
                     statistics_messages.push(StatisticsMessage::PeerRemoved(peer.peer_id));
                 }
+
+                peer_ip_counter.remove(key.ip_address.into());
             }
 
             keep
@@ -658,6 +1897,17 @@ struct Peer {
     valid_until: ValidUntil,
 }
 
+impl Peer {
+    fn to_candidate<I: Ip>(self, key: ResponsePeer<I>) -> PeerCandidate<ResponsePeer<I>> {
+        PeerCandidate {
+            key,
+            ip: key.ip_address.into(),
+            is_seeder: self.is_seeder,
+            valid_until: self.valid_until,
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub enum PeerStatus {
     Seeding,
@@ -683,8 +1933,29 @@ impl PeerStatus {
 
 #[cfg(test)]
 mod tests {
+    use std::num::NonZeroU16;
+
     use super::*;
 
+    /// Announce request for seeding peer with port 1
+    fn announce_request(info_hash: InfoHash, peer_id: PeerId) -> AnnounceRequest {
+        AnnounceRequest {
+            connection_id: ConnectionId::new(0),
+            action_placeholder: Default::default(),
+            transaction_id: TransactionId::new(0),
+            info_hash,
+            peer_id,
+            bytes_downloaded: NumberOfBytes::new(0),
+            bytes_uploaded: NumberOfBytes::new(0),
+            bytes_left: NumberOfBytes::new(0),
+            event: AnnounceEvent::Started.into(),
+            ip_address: Ipv4AddrBytes([0; 4]),
+            key: PeerKey::new(0),
+            peers_wanted: NumberOfPeers::new(-1),
+            port: Port::new(NonZeroU16::new(1).unwrap()),
+        }
+    }
+
     #[test]
     fn test_peer_status_from_event_and_bytes_left() {
         use PeerStatus::*;
@@ -703,4 +1974,216 @@ mod tests {
         assert_eq!(Seeding, f(AnnounceEvent::None, NumberOfBytes::new(0)));
         assert_eq!(Leeching, f(AnnounceEvent::None, NumberOfBytes::new(1)));
     }
+
+    #[test]
+    fn test_scrape_statistics_follow_announces() {
+        use std::net::{Ipv4Addr, SocketAddr};
+
+        use rand::SeedableRng;
+
+        let config = Config::default();
+        let torrent_maps = TorrentMaps::new(
+            &config,
+            Arc::new(PeerIpCounter::new(&config.peer_ip_limits)),
+        );
+        let (statistics_sender, _statistics_receiver) = crossbeam_channel::unbounded();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let valid_until = ValidUntil::new(ServerStartInstant::new(), 60);
+        let info_hash = InfoHash([1; 20]);
+
+        let scrape = || {
+            let request = ScrapeRequest {
+                connection_id: ConnectionId::new(0),
+                transaction_id: TransactionId::new(0),
+                info_hashes: vec![info_hash],
+            };
+            let src = CanonicalSocketAddr::new(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1));
+
+            let statistics = torrent_maps.scrape(request, src).torrent_stats[0];
+
+            (statistics.seeders.0.get(), statistics.leechers.0.get())
+        };
+
+        assert_eq!(scrape(), (0, 0));
+
+        // Enough peers to convert peer map to large variant and back
+        for (i, event, bytes_left) in [
+            (1, AnnounceEvent::Started, 0),
+            (2, AnnounceEvent::Started, 1),
+            (3, AnnounceEvent::Started, 1),
+            (4, AnnounceEvent::Started, 0),
+            (2, AnnounceEvent::Completed, 0),
+            (1, AnnounceEvent::Stopped, 0),
+            (3, AnnounceEvent::Stopped, 1),
+        ] {
+            let request = AnnounceRequest {
+                bytes_left: NumberOfBytes::new(bytes_left),
+                event: event.into(),
+                port: Port::new(NonZeroU16::new(i.into()).unwrap()),
+                ..announce_request(info_hash, PeerId([i; 20]))
+            };
+            let src = CanonicalSocketAddr::new(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1));
+
+            torrent_maps.announce(
+                &config,
+                &statistics_sender,
+                &mut rng,
+                &request,
+                src,
+                valid_until,
+            );
+        }
+
+        assert_eq!(scrape(), (2, 0));
+    }
+
+    #[test]
+    fn test_torrent_limit() {
+        use std::net::{Ipv4Addr, SocketAddr};
+
+        use aquatic_common::torrent_limits::EvictionPolicy;
+        use rand::SeedableRng;
+
+        let (statistics_sender, _statistics_receiver) = crossbeam_channel::unbounded();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let valid_until = ValidUntil::new(ServerStartInstant::new(), 60);
+        let src = CanonicalSocketAddr::new(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1));
+
+        // Torrents A and C end up in the same shard and torrent B in
+        // another one. The limit is lower than the number of shards, but
+        // applies to all of them together.
+        let info_hash_a = InfoHash([0; 20]);
+        let info_hash_b = InfoHash([1; 20]);
+        let info_hash_c = InfoHash([[0; 10], [2; 10]].concat().try_into().unwrap());
+
+        for (eviction_policy, expected_peers) in [
+            (EvictionPolicy::RejectNew, (1, 1, 0)),
+            (EvictionPolicy::LeastRecentlyAnnounced, (0, 1, 1)),
+        ] {
+            let mut config = Config::default();
+
+            config.torrent_limits.max_torrents = 2;
+            config.torrent_limits.eviction_policy = eviction_policy;
+
+            let torrent_maps = TorrentMaps::new(
+                &config,
+                Arc::new(PeerIpCounter::new(&config.peer_ip_limits)),
+            );
+
+            let num_seeders = |info_hash| {
+                let request = ScrapeRequest {
+                    connection_id: ConnectionId::new(0),
+                    transaction_id: TransactionId::new(0),
+                    info_hashes: vec![info_hash],
+                };
+
+                torrent_maps.scrape(request, src).torrent_stats[0]
+                    .seeders
+                    .0
+                    .get()
+            };
+
+            assert_ne!(
+                torrent_maps.ipv4.get_shard_index(&info_hash_a),
+                torrent_maps.ipv4.get_shard_index(&info_hash_b)
+            );
+            assert_eq!(
+                torrent_maps.ipv4.get_shard_index(&info_hash_a),
+                torrent_maps.ipv4.get_shard_index(&info_hash_c)
+            );
+
+            for info_hash in [info_hash_a, info_hash_b, info_hash_c] {
+                torrent_maps.announce(
+                    &config,
+                    &statistics_sender,
+                    &mut rng,
+                    &announce_request(info_hash, PeerId([1; 20])),
+                    src,
+                    valid_until,
+                );
+            }
+
+            assert_eq!(
+                (
+                    num_seeders(info_hash_a),
+                    num_seeders(info_hash_b),
+                    num_seeders(info_hash_c)
+                ),
+                expected_peers
+            );
+        }
+    }
+
+    #[test]
+    fn test_peer_ip_counter_with_removed_torrent() {
+        use rand::SeedableRng;
+
+        let mut config = Config::default();
+
+        config.peer_ip_limits.max_peers = 1;
+
+        let peer_ip_counter = Arc::new(PeerIpCounter::new(&config.peer_ip_limits));
+        let torrent_map_shards =
+            TorrentMapShards::<Ipv4AddrBytes>::new(&config, 1, peer_ip_counter.clone());
+
+        let (statistics_sender, _statistics_receiver) = crossbeam_channel::unbounded();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let valid_until = ValidUntil::new(ServerStartInstant::new(), 60);
+        let ip_address = Ipv4AddrBytes([127, 0, 0, 1]);
+
+        let info_hash_a = InfoHash([1; 20]);
+        let info_hash_b = InfoHash([2; 20]);
+
+        let request = |info_hash, peer_id| announce_request(info_hash, PeerId([peer_id; 20]));
+
+        let mut announce = |request: AnnounceRequest| {
+            torrent_map_shards
+                .announce(
+                    &config,
+                    &statistics_sender,
+                    &mut rng,
+                    &request,
+                    ip_address,
+                    valid_until,
+                )
+                .is_some()
+        };
+
+        assert!(announce(request(info_hash_a, 1)));
+        assert!(!announce(request(info_hash_b, 2)));
+
+        // Torrent is evicted after an announce request has fetched it
+        let torrent_data = torrent_map_shards
+            .get_shard(&info_hash_a)
+            .read()
+            .get(&info_hash_a)
+            .unwrap()
+            .clone();
+
+        let evicted = torrent_map_shards
+            .get_shard(&info_hash_a)
+            .write()
+            .remove(&info_hash_a)
+            .unwrap();
+
+        torrent_map_shards.on_torrent_evicted(&config, &mut Vec::new(), &evicted);
+
+        // Request must not store peer in the removed torrent or count it
+        assert!(torrent_map_shards
+            .announce_to_torrent(
+                &config,
+                &statistics_sender,
+                &mut Vec::new(),
+                &mut SmallRng::seed_from_u64(0),
+                &request(info_hash_a, 3),
+                ip_address,
+                valid_until,
+                &torrent_data,
+            )
+            .is_none());
+        assert_eq!(torrent_data.peer_map.read().num_peers(), 1);
+
+        assert!(announce(request(info_hash_b, 2)));
+        assert!(!peer_ip_counter.try_add(ip_address.into()));
+    }
 }
@@ -0,0 +1,332 @@
+//! Socket worker receiving and sending packets using AF_XDP sockets
+
+mod packet;
+mod program;
+mod xsk;
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Context;
+use aquatic_common::{privileges::PrivilegeDropper, CanonicalSocketAddr};
+use aquatic_udp_protocol::*;
+use crossbeam_channel::Sender;
+
+use crate::common::*;
+use crate::config::Config;
+
+use self::packet::{parse_frame, write_response_frame, FrameAddrs};
+use self::xsk::{XskSocket, FRAME_SIZE};
+
+use super::mio::{handle_packet, record_response_sent, WorkerSharedData};
+use super::validator::ConnectionValidator;
+
+pub use self::program::XdpProgram;
+
+/// Handle at most this many packets per socket before doing other work
+const MAX_PACKETS_PER_ITERATION: usize = 256;
+
+/// Attach XDP program if AF_XDP is enabled in config
+///
+/// Returns None if AF_XDP is disabled, or if setting it up failed and
+/// falling back to the regular backend is allowed.
+pub fn attach_program(config: &Config) -> anyhow::Result<Option<Arc<XdpProgram>>> {
+    if !config.network.use_af_xdp {
+        return Ok(None);
+    }
+
+    match XdpProgram::attach(config) {
+        Ok(program) => Ok(Some(Arc::new(program))),
+        Err(err) if config.network.af_xdp_fallback => {
+            ::log::warn!("Setting up AF_XDP failed, using regular backend: {:#}", err);
+
+            Ok(None)
+        }
+        Err(err) => Err(err.context("set up AF_XDP")),
+    }
+}
+
+/// Parse comma-separated list of queue ids
+fn parse_queues(queues: &str) -> anyhow::Result<Vec<u32>> {
+    let mut parsed = Vec::new();
+
+    for queue in queues.split(',').map(str::trim).filter(|q| !q.is_empty()) {
+        let queue_id = queue
+            .parse::<u32>()
+            .with_context(|| format!("invalid AF_XDP queue: {}", queue))?;
+
+        if !parsed.contains(&queue_id) {
+            parsed.push(queue_id);
+        }
+    }
+
+    if parsed.is_empty() {
+        return Err(anyhow::anyhow!("no AF_XDP queues configured"));
+    }
+
+    Ok(parsed)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run_socket_worker(
+    config: Config,
+    shared_state: State,
+    statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
+    statistics_sender: Sender<StatisticsMessage>,
+    validator: ConnectionValidator,
+    priv_droppers: Vec<PrivilegeDropper>,
+    program: Arc<XdpProgram>,
+    worker_index: usize,
+) -> anyhow::Result<()> {
+    let queues = program
+        .queues
+        .iter()
+        .copied()
+        .skip(worker_index)
+        .step_by(config.socket_workers)
+        .collect::<Vec<_>>();
+
+    let opt_sockets = if queues.is_empty() {
+        ::log::info!("No AF_XDP queue assigned to socket worker, using regular backend");
+
+        None
+    } else {
+        match create_sockets(&program, &queues) {
+            Ok(sockets) => Some(sockets),
+            Err(err) if config.network.af_xdp_fallback => {
+                ::log::warn!(
+                    "Creating AF_XDP sockets failed, using regular backend: {:#}",
+                    err
+                );
+
+                None
+            }
+            Err(err) => return Err(err),
+        }
+    };
+
+    let mut sockets = if let Some(sockets) = opt_sockets {
+        sockets
+    } else {
+        return super::run_socket_worker(
+            config,
+            shared_state,
+            statistics,
+            statistics_sender,
+            validator,
+            priv_droppers,
+            None,
+        );
+    };
+
+    // The XDP program only redirects packets arriving on the configured
+    // queues, so the regular sockets handle the ones the NIC puts in other
+    // queues (as well as packets passed on to the kernel network stack)
+    let (mut opt_socket_ipv4, mut opt_socket_ipv6) =
+        super::mio::create_sockets(&config, priv_droppers)?;
+
+    let mut shared = WorkerSharedData::new(
+        config,
+        shared_state,
+        statistics,
+        statistics_sender,
+        validator,
+    );
+
+    let mut poll_fds = sockets
+        .iter()
+        .map(|socket| libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+
+    for fd in opt_socket_ipv4
+        .iter()
+        .map(|socket| socket.socket.as_raw_fd())
+        .chain(
+            opt_socket_ipv6
+                .iter()
+                .map(|socket| socket.socket.as_raw_fd()),
+        )
+    {
+        poll_fds.push(libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+    }
+
+    let poll_timeout = shared.config.network.poll_timeout_ms as libc::c_int;
+    let max_frame_len = program.max_frame_len.min(FRAME_SIZE);
+
+    let mut iter_counter = 0u64;
+
+    loop {
+        let result = unsafe {
+            libc::poll(
+                poll_fds.as_mut_ptr(),
+                poll_fds.len() as libc::nfds_t,
+                poll_timeout,
+            )
+        };
+
+        if result < 0 {
+            let err = io::Error::last_os_error();
+
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err).context("poll");
+            }
+        }
+
+        for socket in sockets.iter_mut() {
+            handle_packets(&mut shared, socket, max_frame_len);
+        }
+
+        let mut regular_poll_fds = poll_fds[sockets.len()..].iter();
+
+        if let Some(socket) = opt_socket_ipv4.as_mut() {
+            if regular_poll_fds.next().map_or(false, is_readable) {
+                socket.read_and_handle_requests(&mut shared);
+            }
+
+            socket.resend_failed(&mut shared);
+        }
+        if let Some(socket) = opt_socket_ipv6.as_mut() {
+            if regular_poll_fds.next().map_or(false, is_readable) {
+                socket.read_and_handle_requests(&mut shared);
+            }
+
+            socket.resend_failed(&mut shared);
+        }
+
+        shared.maintain(iter_counter);
+
+        iter_counter = iter_counter.wrapping_add(1);
+    }
+}
+
+fn is_readable(poll_fd: &libc::pollfd) -> bool {
+    poll_fd.revents & libc::POLLIN != 0
+}
+
+fn create_sockets(program: &XdpProgram, queues: &[u32]) -> anyhow::Result<Vec<XskSocket>> {
+    let mut sockets = Vec::new();
+
+    for queue_id in queues.iter().copied() {
+        let socket = XskSocket::new(program.ifindex, queue_id)?;
+
+        program
+            .register_socket(queue_id, socket.as_raw_fd())
+            .with_context(|| format!("add AF_XDP socket for queue {} to map", queue_id))?;
+
+        ::log::info!("Receiving packets on queue {} with AF_XDP", queue_id);
+
+        sockets.push(socket);
+    }
+
+    Ok(sockets)
+}
+
+fn handle_packets(shared: &mut WorkerSharedData, socket: &mut XskSocket, max_frame_len: usize) {
+    let max_scrape_torrents = shared.config.protocol.max_scrape_torrents;
+
+    socket.reclaim_tx_frames();
+
+    for _ in 0..MAX_PACKETS_PER_ITERATION {
+        let (rx_addr, frame) = if let Some(received) = socket.next_received() {
+            received
+        } else {
+            break;
+        };
+
+        let opt_received_at = shared.opt_request_latencies.is_some().then(Instant::now);
+
+        let opt_response = parse_frame(frame).and_then(|(addrs, payload)| {
+            let parse_result = Request::parse_bytes(payload, max_scrape_torrents);
+
+            handle_packet(
+                shared,
+                addrs.src,
+                payload.len(),
+                parse_result,
+                addrs.src.is_ipv4(),
+                opt_received_at,
+            )
+            .map(|(canonical_addr, response)| (addrs, canonical_addr, response))
+        });
+
+        socket.recycle_rx_frame(rx_addr);
+
+        if let Some((addrs, canonical_addr, response)) = opt_response {
+            send_response(
+                shared,
+                socket,
+                &addrs,
+                canonical_addr,
+                response,
+                opt_received_at,
+                max_frame_len,
+            );
+        }
+    }
+
+    socket.flush();
+}
+
+fn send_response(
+    shared: &mut WorkerSharedData,
+    socket: &mut XskSocket,
+    request_addrs: &FrameAddrs,
+    canonical_addr: CanonicalSocketAddr,
+    response: Response,
+    opt_received_at: Option<Instant>,
+    max_frame_len: usize,
+) {
+    let (tx_addr, tx_frame) = if let Some(frame) = socket.free_tx_frame() {
+        frame
+    } else {
+        ::log::debug!("No free AF_XDP frame, dropping response");
+
+        return;
+    };
+
+    match write_response_frame(&mut tx_frame[..max_frame_len], request_addrs, &response) {
+        Ok((frame_len, payload_len)) => {
+            socket.send(tx_addr, frame_len);
+
+            record_response_sent(
+                shared,
+                canonical_addr,
+                &response,
+                payload_len,
+                opt_received_at,
+            );
+        }
+        Err(err) => {
+            socket.return_tx_frame(tx_addr);
+
+            ::log::warn!(
+                peer_addr:% = canonical_addr.get();
+                "failed writing response frame: {:#}",
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_queues() {
+        assert_eq!(parse_queues("0").unwrap(), vec![0]);
+        assert_eq!(parse_queues("2, 0,2,1,").unwrap(), vec![2, 0, 1]);
+        assert!(parse_queues("").is_err());
+        assert!(parse_queues("0,a").is_err());
+    }
+}
@@ -0,0 +1,357 @@
+//! Ethernet, IP and UDP header handling
+
+use std::io::Cursor;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use aquatic_udp_protocol::Response;
+
+const ETH_HEADER_LEN: usize = 14;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+
+const ETH_P_IPV4: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86DD;
+const IPPROTO_UDP: u8 = 17;
+
+const TTL: u8 = 64;
+
+/// Addresses of a received packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameAddrs {
+    pub src_mac: [u8; 6],
+    pub dst_mac: [u8; 6],
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+/// Parse Ethernet frame containing an UDP packet, returning its addresses
+/// and payload
+///
+/// Packets with IP options, IPv6 extension headers or fragmentation are
+/// not supported. Checksums are not verified.
+pub fn parse_frame(frame: &[u8]) -> Option<(FrameAddrs, &[u8])> {
+    if frame.len() < ETH_HEADER_LEN {
+        return None;
+    }
+
+    let mut dst_mac = [0; 6];
+    let mut src_mac = [0; 6];
+
+    dst_mac.copy_from_slice(&frame[0..6]);
+    src_mac.copy_from_slice(&frame[6..12]);
+
+    let ip_packet = &frame[ETH_HEADER_LEN..];
+
+    let (src_ip, dst_ip, udp_packet) = match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETH_P_IPV4 => {
+            if ip_packet.len() < IPV4_HEADER_LEN
+                || ip_packet[0] != 0x45
+                || ip_packet[9] != IPPROTO_UDP
+                // More fragments flag or fragment offset set
+                || u16::from_be_bytes([ip_packet[6], ip_packet[7]]) & 0x3fff != 0
+            {
+                return None;
+            }
+
+            let total_len = u16::from_be_bytes([ip_packet[2], ip_packet[3]]) as usize;
+
+            if total_len < IPV4_HEADER_LEN || total_len > ip_packet.len() {
+                return None;
+            }
+
+            let src: [u8; 4] = ip_packet[12..16].try_into().unwrap();
+            let dst: [u8; 4] = ip_packet[16..20].try_into().unwrap();
+
+            (
+                Ipv4Addr::from(src).into(),
+                Ipv4Addr::from(dst).into(),
+                &ip_packet[IPV4_HEADER_LEN..total_len],
+            )
+        }
+        ETH_P_IPV6 => {
+            if ip_packet.len() < IPV6_HEADER_LEN || ip_packet[6] != IPPROTO_UDP {
+                return None;
+            }
+
+            let payload_len = u16::from_be_bytes([ip_packet[4], ip_packet[5]]) as usize;
+
+            if IPV6_HEADER_LEN + payload_len > ip_packet.len() {
+                return None;
+            }
+
+            let src: [u8; 16] = ip_packet[8..24].try_into().unwrap();
+            let dst: [u8; 16] = ip_packet[24..40].try_into().unwrap();
+
+            (
+                Ipv6Addr::from(src).into(),
+                Ipv6Addr::from(dst).into(),
+                &ip_packet[IPV6_HEADER_LEN..IPV6_HEADER_LEN + payload_len],
+            )
+        }
+        _ => return None,
+    };
+
+    if udp_packet.len() < UDP_HEADER_LEN {
+        return None;
+    }
+
+    let src_port = u16::from_be_bytes([udp_packet[0], udp_packet[1]]);
+    let dst_port = u16::from_be_bytes([udp_packet[2], udp_packet[3]]);
+    let udp_len = u16::from_be_bytes([udp_packet[4], udp_packet[5]]) as usize;
+
+    if udp_len < UDP_HEADER_LEN || udp_len > udp_packet.len() {
+        return None;
+    }
+
+    let addrs = FrameAddrs {
+        src_mac,
+        dst_mac,
+        src: SocketAddr::new(src_ip, src_port),
+        dst: SocketAddr::new(dst_ip, dst_port),
+    };
+
+    Some((addrs, &udp_packet[UDP_HEADER_LEN..udp_len]))
+}
+
+/// Write Ethernet frame with response to received packet, returning frame
+/// length and payload length
+pub fn write_response_frame(
+    frame: &mut [u8],
+    request_addrs: &FrameAddrs,
+    response: &Response,
+) -> anyhow::Result<(usize, usize)> {
+    let (ethertype, ip_header_len) = match request_addrs.src {
+        SocketAddr::V4(_) => (ETH_P_IPV4, IPV4_HEADER_LEN),
+        SocketAddr::V6(_) => (ETH_P_IPV6, IPV6_HEADER_LEN),
+    };
+    let headers_len = ETH_HEADER_LEN + ip_header_len + UDP_HEADER_LEN;
+
+    if frame.len() < headers_len {
+        return Err(anyhow::anyhow!("frame too small for headers"));
+    }
+
+    let payload_len = {
+        let mut cursor = Cursor::new(&mut frame[headers_len..]);
+
+        response.write_bytes(&mut cursor)?;
+
+        cursor.position() as usize
+    };
+
+    let udp_len = UDP_HEADER_LEN + payload_len;
+
+    // Ethernet header
+    frame[0..6].copy_from_slice(&request_addrs.src_mac);
+    frame[6..12].copy_from_slice(&request_addrs.dst_mac);
+    frame[12..14].copy_from_slice(&ethertype.to_be_bytes());
+
+    let (ip_header, udp_packet) =
+        frame[ETH_HEADER_LEN..headers_len + payload_len].split_at_mut(ip_header_len);
+
+    // UDP header, with checksum zeroed for calculation
+    udp_packet[0..2].copy_from_slice(&request_addrs.dst.port().to_be_bytes());
+    udp_packet[2..4].copy_from_slice(&request_addrs.src.port().to_be_bytes());
+    udp_packet[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    udp_packet[6..8].copy_from_slice(&[0, 0]);
+
+    let mut checksum = Checksum::default();
+
+    match (request_addrs.dst, request_addrs.src) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            write_ipv4_header(ip_header, src, dst, udp_len);
+
+            checksum.add(&src.ip().octets());
+            checksum.add(&dst.ip().octets());
+            checksum.add(&[0, IPPROTO_UDP]);
+            checksum.add(&(udp_len as u16).to_be_bytes());
+        }
+        (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+            write_ipv6_header(ip_header, src, dst, udp_len);
+
+            checksum.add(&src.ip().octets());
+            checksum.add(&dst.ip().octets());
+            checksum.add(&(udp_len as u32).to_be_bytes());
+            checksum.add(&[0, 0, 0, IPPROTO_UDP]);
+        }
+        _ => unreachable!("address families of source and destination differ"),
+    }
+
+    checksum.add(udp_packet);
+
+    // Zero means no checksum, so send all ones instead
+    let udp_checksum = match checksum.finish() {
+        0 => 0xffff,
+        n => n,
+    };
+
+    udp_packet[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+    Ok((headers_len + payload_len, payload_len))
+}
+
+fn write_ipv4_header(header: &mut [u8], src: SocketAddrV4, dst: SocketAddrV4, udp_len: usize) {
+    let total_len = (IPV4_HEADER_LEN + udp_len) as u16;
+
+    header[0] = 0x45;
+    header[1] = 0;
+    header[2..4].copy_from_slice(&total_len.to_be_bytes());
+    // Identification
+    header[4..6].copy_from_slice(&[0, 0]);
+    // Don't fragment
+    header[6..8].copy_from_slice(&0x4000u16.to_be_bytes());
+    header[8] = TTL;
+    header[9] = IPPROTO_UDP;
+    header[10..12].copy_from_slice(&[0, 0]);
+    header[12..16].copy_from_slice(&src.ip().octets());
+    header[16..20].copy_from_slice(&dst.ip().octets());
+
+    let mut checksum = Checksum::default();
+
+    checksum.add(&header[..IPV4_HEADER_LEN]);
+
+    header[10..12].copy_from_slice(&checksum.finish().to_be_bytes());
+}
+
+fn write_ipv6_header(header: &mut [u8], src: SocketAddrV6, dst: SocketAddrV6, udp_len: usize) {
+    // Version, traffic class and flow label
+    header[0..4].copy_from_slice(&[0x60, 0, 0, 0]);
+    header[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    header[6] = IPPROTO_UDP;
+    header[7] = TTL;
+    header[8..24].copy_from_slice(&src.ip().octets());
+    header[24..40].copy_from_slice(&dst.ip().octets());
+}
+
+/// Internet checksum (RFC 1071)
+#[derive(Default)]
+struct Checksum {
+    sum: u32,
+}
+
+impl Checksum {
+    /// Add data. All chunks except the last one must have an even length.
+    fn add(&mut self, data: &[u8]) {
+        let mut chunks = data.chunks_exact(2);
+
+        for chunk in &mut chunks {
+            self.sum += u32::from(u16::from_be_bytes([chunk[0], chunk[1]]));
+        }
+
+        if let [last] = chunks.remainder() {
+            self.sum += u32::from(*last) << 8;
+        }
+
+        self.fold();
+    }
+
+    fn fold(&mut self) {
+        while self.sum > 0xffff {
+            self.sum = (self.sum & 0xffff) + (self.sum >> 16);
+        }
+    }
+
+    fn finish(self) -> u16 {
+        !(self.sum as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aquatic_udp_protocol::{ConnectResponse, ConnectionId, TransactionId};
+
+    use super::*;
+
+    fn checksum_valid(data: &[u8]) -> bool {
+        let mut checksum = Checksum::default();
+
+        checksum.add(data);
+
+        checksum.finish() == 0
+    }
+
+    #[test]
+    fn test_write_and_parse_response_frame() {
+        let response = Response::Connect(ConnectResponse {
+            connection_id: ConnectionId::new(1),
+            transaction_id: TransactionId::new(2),
+        });
+
+        for (client, tracker) in [
+            ("10.0.0.2:6881", "10.0.0.1:3000"),
+            ("[fd00::2]:6881", "[fd00::1]:3000"),
+        ] {
+            let request_addrs = FrameAddrs {
+                src_mac: [2, 0, 0, 0, 0, 2],
+                dst_mac: [2, 0, 0, 0, 0, 1],
+                src: client.parse().unwrap(),
+                dst: tracker.parse().unwrap(),
+            };
+
+            let mut frame = [0u8; 2048];
+
+            let (frame_len, payload_len) =
+                write_response_frame(&mut frame, &request_addrs, &response).unwrap();
+
+            assert_eq!(payload_len, 16);
+
+            let (addrs, payload) = parse_frame(&frame[..frame_len]).unwrap();
+
+            assert_eq!(addrs.src, request_addrs.dst);
+            assert_eq!(addrs.dst, request_addrs.src);
+            assert_eq!(addrs.src_mac, request_addrs.dst_mac);
+            assert_eq!(addrs.dst_mac, request_addrs.src_mac);
+            assert_eq!(Response::parse_bytes(payload, true).unwrap(), response);
+
+            let ip_packet = &frame[ETH_HEADER_LEN..frame_len];
+
+            // Checksum over pseudo header and UDP packet
+            let mut pseudo_header = Vec::new();
+
+            match addrs.src {
+                SocketAddr::V4(_) => {
+                    assert!(checksum_valid(&ip_packet[..IPV4_HEADER_LEN]));
+
+                    pseudo_header.extend_from_slice(&ip_packet[12..20]);
+                    pseudo_header.extend_from_slice(&[0, IPPROTO_UDP]);
+                    pseudo_header.extend_from_slice(&(payload_len as u16 + 8).to_be_bytes());
+                    pseudo_header.extend_from_slice(&ip_packet[IPV4_HEADER_LEN..]);
+                }
+                SocketAddr::V6(_) => {
+                    pseudo_header.extend_from_slice(&ip_packet[8..40]);
+                    pseudo_header.extend_from_slice(&(payload_len as u32 + 8).to_be_bytes());
+                    pseudo_header.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+                    pseudo_header.extend_from_slice(&ip_packet[IPV6_HEADER_LEN..]);
+                }
+            }
+
+            assert!(checksum_valid(&pseudo_header));
+        }
+    }
+
+    #[test]
+    fn test_parse_frame_rejects_fragments() {
+        let request_addrs = FrameAddrs {
+            src_mac: [0; 6],
+            dst_mac: [0; 6],
+            src: "10.0.0.2:6881".parse().unwrap(),
+            dst: "10.0.0.1:3000".parse().unwrap(),
+        };
+        let response = Response::Connect(ConnectResponse {
+            connection_id: ConnectionId::new(1),
+            transaction_id: TransactionId::new(2),
+        });
+
+        let mut frame = [0u8; 2048];
+
+        let (frame_len, _) = write_response_frame(&mut frame, &request_addrs, &response).unwrap();
+
+        assert!(parse_frame(&frame[..frame_len]).is_some());
+
+        // Set more fragments flag
+        frame[ETH_HEADER_LEN + 6] |= 0x20;
+
+        assert!(parse_frame(&frame[..frame_len]).is_none());
+        assert!(parse_frame(&frame[..ETH_HEADER_LEN + 4]).is_none());
+    }
+}
@@ -0,0 +1,531 @@
+//! XDP program redirecting tracker packets to AF_XDP sockets
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr::addr_of;
+
+use anyhow::Context;
+
+use crate::config::Config;
+
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_LINK_CREATE: libc::c_int = 28;
+
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+
+const VERIFIER_LOG_LEN: usize = 64 * 1024;
+
+/// XDP program attached to a network interface, along with the map of
+/// AF_XDP sockets to redirect packets to. The program is detached when
+/// this is dropped.
+pub struct XdpProgram {
+    map_fd: OwnedFd,
+    _program_fd: OwnedFd,
+    _link_fd: OwnedFd,
+    pub ifindex: u32,
+    /// Queues to receive packets on with AF_XDP sockets
+    pub queues: Vec<u32>,
+    /// Maximum frame length (excluding FCS) that can be sent
+    pub max_frame_len: usize,
+}
+
+impl XdpProgram {
+    pub fn attach(config: &Config) -> anyhow::Result<Self> {
+        let interface = &config.network.af_xdp_interface;
+        let queues = super::parse_queues(&config.network.af_xdp_queues)?;
+
+        let ifindex = {
+            let name = CString::new(interface.as_str()).context("invalid interface name")?;
+
+            match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+                0 => {
+                    return Err(io::Error::last_os_error())
+                        .with_context(|| format!("find interface {}", interface))
+                }
+                ifindex => ifindex,
+            }
+        };
+
+        let mtu = ::std::fs::read_to_string(format!("/sys/class/net/{}/mtu", interface))
+            .ok()
+            .and_then(|mtu| mtu.trim().parse::<usize>().ok())
+            .unwrap_or(1500);
+
+        let max_queue = queues.iter().copied().max().unwrap_or(0);
+
+        let map_fd = create_xsk_map(max_queue + 1).context("create xsk map")?;
+
+        let ipv4_address = &config.network.address_ipv4;
+        let ipv6_address = &config.network.address_ipv6;
+
+        let ipv4_port = if config.network.use_ipv4 {
+            ipv4_address.port()
+        } else {
+            0
+        };
+        let ipv6_port = if config.network.use_ipv6 {
+            ipv6_address.port()
+        } else {
+            0
+        };
+
+        let opt_ipv4_ip = Some(ipv4_address.ip())
+            .filter(|ip| !ip.is_unspecified())
+            .map(|ip| ip.octets());
+        let opt_ipv6_ip = Some(ipv6_address.ip())
+            .filter(|ip| !ip.is_unspecified())
+            .map(|ip| ip.octets());
+
+        let program_fd = load_program(&program_instructions(
+            map_fd.as_raw_fd(),
+            ipv4_port,
+            ipv6_port,
+            opt_ipv4_ip,
+            opt_ipv6_ip,
+        ))
+        .context("load xdp program")?;
+
+        let link_fd = attach_program(&program_fd, ifindex)
+            .with_context(|| format!("attach xdp program to interface {}", interface))?;
+
+        ::log::info!(
+            "Attached XDP program to interface {} (queues {:?})",
+            interface,
+            queues
+        );
+
+        Ok(Self {
+            map_fd,
+            _program_fd: program_fd,
+            _link_fd: link_fd,
+            ifindex,
+            queues,
+            max_frame_len: mtu + 14,
+        })
+    }
+
+    /// Redirect packets received on queue to socket
+    pub fn register_socket(&self, queue_id: u32, socket_fd: RawFd) -> io::Result<()> {
+        let key: u32 = queue_id;
+        let value: u32 = socket_fd as u32;
+
+        let attr = BpfMapUpdateAttr {
+            map_fd: self.map_fd.as_raw_fd() as u32,
+            _pad: 0,
+            key: addr_of!(key) as u64,
+            value: addr_of!(value) as u64,
+            flags: 0,
+        };
+
+        bpf(BPF_MAP_UPDATE_ELEM, &attr).map(|_| ())
+    }
+}
+
+#[repr(C)]
+struct BpfMapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+}
+
+#[repr(C)]
+struct BpfMapUpdateAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct BpfProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+}
+
+#[repr(C)]
+struct BpfLinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+fn bpf<T>(cmd: libc::c_int, attr: &T) -> io::Result<libc::c_long> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *const T,
+            ::std::mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+fn bpf_fd<T>(cmd: libc::c_int, attr: &T) -> io::Result<OwnedFd> {
+    bpf(cmd, attr).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+fn create_xsk_map(max_entries: u32) -> io::Result<OwnedFd> {
+    let attr = BpfMapCreateAttr {
+        map_type: BPF_MAP_TYPE_XSKMAP,
+        key_size: 4,
+        value_size: 4,
+        max_entries,
+    };
+
+    bpf_fd(BPF_MAP_CREATE, &attr)
+}
+
+fn load_program(instructions: &[Instruction]) -> anyhow::Result<OwnedFd> {
+    let license = CString::new("Apache-2.0").unwrap();
+    let mut log = vec![0u8; VERIFIER_LOG_LEN];
+
+    let attr = BpfProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_XDP,
+        insn_cnt: instructions.len() as u32,
+        insns: instructions.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 1,
+        log_size: log.len() as u32,
+        log_buf: log.as_mut_ptr() as u64,
+    };
+
+    bpf_fd(BPF_PROG_LOAD, &attr).map_err(|err| {
+        let log_len = log.iter().position(|b| *b == 0).unwrap_or(log.len());
+
+        anyhow::Error::new(err).context(format!(
+            "verifier log: {}",
+            String::from_utf8_lossy(&log[..log_len])
+        ))
+    })
+}
+
+fn attach_program(program_fd: &OwnedFd, ifindex: u32) -> io::Result<OwnedFd> {
+    let attr = BpfLinkCreateAttr {
+        prog_fd: program_fd.as_raw_fd() as u32,
+        target_ifindex: ifindex,
+        attach_type: BPF_XDP,
+        flags: 0,
+    };
+
+    bpf_fd(BPF_LINK_CREATE, &attr)
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Instruction {
+    code: u8,
+    /// Destination and source register, four bits each
+    registers: u8,
+    offset: i16,
+    immediate: i32,
+}
+
+impl Instruction {
+    const fn new(code: u8, dst: u8, src: u8, offset: i16, immediate: i32) -> Self {
+        Self {
+            code,
+            registers: if cfg!(target_endian = "little") {
+                (src << 4) | dst
+            } else {
+                (dst << 4) | src
+            },
+            offset,
+            immediate,
+        }
+    }
+}
+
+// Instruction classes, sizes, operations and sources
+const BPF_LD: u8 = 0x00;
+const BPF_LDX: u8 = 0x01;
+const BPF_JMP: u8 = 0x05;
+const BPF_JMP32: u8 = 0x06;
+const BPF_ALU64: u8 = 0x07;
+const BPF_W: u8 = 0x00;
+const BPF_H: u8 = 0x08;
+const BPF_B: u8 = 0x10;
+const BPF_DW: u8 = 0x18;
+const BPF_IMM: u8 = 0x00;
+const BPF_MEM: u8 = 0x60;
+const BPF_ADD: u8 = 0x00;
+const BPF_AND: u8 = 0x50;
+const BPF_MOV: u8 = 0xb0;
+const BPF_JA: u8 = 0x00;
+const BPF_JNE: u8 = 0x50;
+const BPF_JGT: u8 = 0x20;
+const BPF_CALL: u8 = 0x80;
+const BPF_EXIT: u8 = 0x90;
+const BPF_K: u8 = 0x00;
+const BPF_X: u8 = 0x08;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+
+/// Jump targets, resolved when assembling program
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Label {
+    Ipv6,
+    Redirect,
+    Pass,
+}
+
+enum Op {
+    Instruction(Instruction),
+    Jump(Label),
+    /// Jump to label if register doesn't equal immediate
+    JumpIfNotEqual(u8, i32, Label),
+    /// Jump to label if lower 32 bits of register don't equal immediate
+    JumpIfNotEqual32(u8, i32, Label),
+    /// Jump to label if first register is greater than second
+    JumpIfGreater(u8, u8, Label),
+    Label(Label),
+}
+
+/// Packet data is read as-is, so compare against values in network byte
+/// order
+fn network_u16(value: u16) -> i32 {
+    u16::from_ne_bytes(value.to_be_bytes()).into()
+}
+
+/// Jump to label unless the 32-bit words at offset match the address
+fn address_ops(address: &[u8], offset: i16, label: Label) -> Vec<Op> {
+    let mut ops = Vec::new();
+
+    for (i, word) in address.chunks_exact(4).enumerate() {
+        ops.push(Op::Instruction(Instruction::new(
+            BPF_LDX | BPF_W | BPF_MEM,
+            5,
+            2,
+            offset + 4 * i as i16,
+            0,
+        )));
+        ops.push(Op::JumpIfNotEqual32(
+            5,
+            i32::from_ne_bytes(word.try_into().unwrap()),
+            label,
+        ));
+    }
+
+    ops
+}
+
+/// Build program redirecting UDP packets to the given ports (without IP
+/// options or fragmentation) to the AF_XDP socket for the receiving queue,
+/// passing on other packets to the kernel network stack
+///
+/// A port of zero disables redirecting for that IP version. If a
+/// destination IP is given, packets to other addresses are passed on too.
+fn program_instructions(
+    map_fd: RawFd,
+    ipv4_port: u16,
+    ipv6_port: u16,
+    opt_ipv4_ip: Option<[u8; 4]>,
+    opt_ipv6_ip: Option<[u8; 16]>,
+) -> Vec<Instruction> {
+    use Label::*;
+    use Op::JumpIfNotEqual as Jne;
+
+    let ldx = |size, dst, src, offset| {
+        Op::Instruction(Instruction::new(
+            BPF_LDX | size | BPF_MEM,
+            dst,
+            src,
+            offset,
+            0,
+        ))
+    };
+    let mov_reg = |dst, src| {
+        Op::Instruction(Instruction::new(
+            BPF_ALU64 | BPF_MOV | BPF_X,
+            dst,
+            src,
+            0,
+            0,
+        ))
+    };
+    let mov_imm = |dst, imm| {
+        Op::Instruction(Instruction::new(
+            BPF_ALU64 | BPF_MOV | BPF_K,
+            dst,
+            0,
+            0,
+            imm,
+        ))
+    };
+    let add_imm = |dst, imm| {
+        Op::Instruction(Instruction::new(
+            BPF_ALU64 | BPF_ADD | BPF_K,
+            dst,
+            0,
+            0,
+            imm,
+        ))
+    };
+    let and_imm = |dst, imm| {
+        Op::Instruction(Instruction::new(
+            BPF_ALU64 | BPF_AND | BPF_K,
+            dst,
+            0,
+            0,
+            imm,
+        ))
+    };
+
+    // Registers: r1 = context, r2 = packet start, r3 = packet end,
+    // r4 = bounds check pointer, r5 = scratch, r6 = saved context
+    #[rustfmt::skip]
+    let mut ops = vec![
+        mov_reg(6, 1),
+        ldx(BPF_W, 2, 1, 0),
+        ldx(BPF_W, 3, 1, 4),
+        // Ethernet header
+        mov_reg(4, 2),
+        add_imm(4, 14),
+        Op::JumpIfGreater(4, 3, Pass),
+        ldx(BPF_H, 5, 2, 12),
+        Jne(5, network_u16(0x0800), Ipv6),
+        // IPv4 and UDP headers
+        mov_reg(4, 2),
+        add_imm(4, 14 + 20 + 8),
+        Op::JumpIfGreater(4, 3, Pass),
+        ldx(BPF_B, 5, 2, 14),
+        Jne(5, 0x45, Pass),
+        ldx(BPF_B, 5, 2, 14 + 9),
+        Jne(5, 17, Pass),
+        ldx(BPF_H, 5, 2, 14 + 6),
+        and_imm(5, network_u16(0x3fff)),
+        Jne(5, 0, Pass),
+        ldx(BPF_H, 5, 2, 14 + 20 + 2),
+        Jne(5, network_u16(ipv4_port), Pass),
+    ];
+
+    if let Some(ip) = opt_ipv4_ip {
+        ops.extend(address_ops(&ip, 14 + 16, Pass));
+    }
+
+    #[rustfmt::skip]
+    ops.extend(vec![
+        Op::Jump(Redirect),
+        Op::Label(Ipv6),
+        Jne(5, network_u16(0x86DD), Pass),
+        // IPv6 and UDP headers
+        mov_reg(4, 2),
+        add_imm(4, 14 + 40 + 8),
+        Op::JumpIfGreater(4, 3, Pass),
+        ldx(BPF_B, 5, 2, 14 + 6),
+        Jne(5, 17, Pass),
+        ldx(BPF_H, 5, 2, 14 + 40 + 2),
+        Jne(5, network_u16(ipv6_port), Pass),
+    ]);
+
+    if let Some(ip) = opt_ipv6_ip {
+        ops.extend(address_ops(&ip, 14 + 24, Pass));
+    }
+
+    #[rustfmt::skip]
+    ops.extend(vec![
+        Op::Label(Redirect),
+        // bpf_redirect_map(map, rx_queue_index, XDP_PASS)
+        ldx(BPF_W, 2, 6, 16),
+        Op::Instruction(Instruction::new(BPF_LD | BPF_DW | BPF_IMM, 1, BPF_PSEUDO_MAP_FD, 0, map_fd)),
+        Op::Instruction(Instruction::new(0, 0, 0, 0, 0)),
+        mov_imm(3, XDP_PASS),
+        Op::Instruction(Instruction::new(BPF_JMP | BPF_CALL, 0, 0, 0, BPF_FUNC_REDIRECT_MAP)),
+        Op::Instruction(Instruction::new(BPF_JMP | BPF_EXIT, 0, 0, 0, 0)),
+        Op::Label(Pass),
+        mov_imm(0, XDP_PASS),
+        Op::Instruction(Instruction::new(BPF_JMP | BPF_EXIT, 0, 0, 0, 0)),
+    ]);
+
+    assemble(ops)
+}
+
+fn assemble(ops: Vec<Op>) -> Vec<Instruction> {
+    let mut label_indices = Vec::new();
+    let mut index = 0i16;
+
+    for op in ops.iter() {
+        match op {
+            Op::Label(label) => label_indices.push((*label, index)),
+            _ => index += 1,
+        }
+    }
+
+    let target = |label: Label, index: i16| {
+        let (_, target_index) = label_indices
+            .iter()
+            .find(|(l, _)| *l == label)
+            .expect("label not defined");
+
+        target_index - index - 1
+    };
+
+    let mut instructions = Vec::new();
+
+    for op in ops {
+        let index = instructions.len() as i16;
+
+        match op {
+            Op::Instruction(instruction) => instructions.push(instruction),
+            Op::Jump(label) => {
+                instructions.push(Instruction::new(
+                    BPF_JMP | BPF_JA,
+                    0,
+                    0,
+                    target(label, index),
+                    0,
+                ));
+            }
+            Op::JumpIfNotEqual(register, immediate, label) => {
+                instructions.push(Instruction::new(
+                    BPF_JMP | BPF_JNE | BPF_K,
+                    register,
+                    0,
+                    target(label, index),
+                    immediate,
+                ));
+            }
+            Op::JumpIfNotEqual32(register, immediate, label) => {
+                instructions.push(Instruction::new(
+                    BPF_JMP32 | BPF_JNE | BPF_K,
+                    register,
+                    0,
+                    target(label, index),
+                    immediate,
+                ));
+            }
+            Op::JumpIfGreater(dst, src, label) => {
+                instructions.push(Instruction::new(
+                    BPF_JMP | BPF_JGT | BPF_X,
+                    dst,
+                    src,
+                    target(label, index),
+                    0,
+                ));
+            }
+            Op::Label(_) => (),
+        }
+    }
+
+    instructions
+}
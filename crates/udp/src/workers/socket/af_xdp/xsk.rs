@@ -0,0 +1,421 @@
+//! AF_XDP socket with its own UMEM (packet buffer area) and rings
+
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr::{addr_of, addr_of_mut, null_mut};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use anyhow::Context;
+
+/// Size of each frame in UMEM
+pub const FRAME_SIZE: usize = 2048;
+/// Number of frames in UMEM. Half are used for receiving, half for sending.
+const NUM_FRAMES: usize = 4096;
+/// Number of entries in each ring
+const RING_SIZE: u32 = (NUM_FRAMES / 2) as u32;
+
+// Fields are dropped in order, so rings and umem are unmapped before the
+// socket is closed
+pub struct XskSocket {
+    fill: Ring<u64>,
+    completion: Ring<u64>,
+    rx: Ring<libc::xdp_desc>,
+    tx: Ring<libc::xdp_desc>,
+    umem: Umem,
+    fd: OwnedFd,
+    free_tx_frames: Vec<u64>,
+}
+
+impl XskSocket {
+    pub fn new(ifindex: u32, queue_id: u32) -> anyhow::Result<Self> {
+        let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW, 0) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("create AF_XDP socket");
+        }
+
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let umem = Umem::new(NUM_FRAMES * FRAME_SIZE).context("allocate umem")?;
+
+        let mut umem_reg: libc::xdp_umem_reg = unsafe { MaybeUninit::zeroed().assume_init() };
+
+        umem_reg.addr = umem.area as u64;
+        umem_reg.len = umem.len as u64;
+        umem_reg.chunk_size = FRAME_SIZE as u32;
+
+        set_option(&fd, libc::XDP_UMEM_REG, &umem_reg).context("register umem")?;
+
+        for option in [
+            libc::XDP_UMEM_FILL_RING,
+            libc::XDP_UMEM_COMPLETION_RING,
+            libc::XDP_RX_RING,
+            libc::XDP_TX_RING,
+        ] {
+            set_option(&fd, option, &RING_SIZE).context("set ring size")?;
+        }
+
+        let offsets = {
+            let mut offsets: libc::xdp_mmap_offsets =
+                unsafe { MaybeUninit::zeroed().assume_init() };
+            let mut len = ::std::mem::size_of::<libc::xdp_mmap_offsets>() as libc::socklen_t;
+
+            let result = unsafe {
+                libc::getsockopt(
+                    fd.as_raw_fd(),
+                    libc::SOL_XDP,
+                    libc::XDP_MMAP_OFFSETS,
+                    addr_of_mut!(offsets) as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+
+            if result < 0 {
+                return Err(io::Error::last_os_error()).context("get ring mmap offsets");
+            }
+
+            offsets
+        };
+
+        let mut fill = unsafe {
+            Ring::map(
+                &fd,
+                &offsets.fr,
+                libc::XDP_UMEM_PGOFF_FILL_RING as libc::off_t,
+            )
+        }
+        .context("map fill ring")?;
+        let completion = unsafe {
+            Ring::map(
+                &fd,
+                &offsets.cr,
+                libc::XDP_UMEM_PGOFF_COMPLETION_RING as libc::off_t,
+            )
+        }
+        .context("map completion ring")?;
+        let rx = unsafe { Ring::map(&fd, &offsets.rx, libc::XDP_PGOFF_RX_RING) }
+            .context("map rx ring")?;
+        let tx = unsafe { Ring::map(&fd, &offsets.tx, libc::XDP_PGOFF_TX_RING) }
+            .context("map tx ring")?;
+
+        // Hand over receive frames to kernel
+        for i in 0..RING_SIZE as u64 {
+            fill.push(i * FRAME_SIZE as u64);
+        }
+
+        fill.commit();
+
+        let free_tx_frames = (RING_SIZE as u64..NUM_FRAMES as u64)
+            .map(|i| i * FRAME_SIZE as u64)
+            .collect();
+
+        let mut addr: libc::sockaddr_xdp = unsafe { MaybeUninit::zeroed().assume_init() };
+
+        addr.sxdp_family = libc::AF_XDP as u16;
+        addr.sxdp_flags = libc::XDP_USE_NEED_WAKEUP;
+        addr.sxdp_ifindex = ifindex;
+        addr.sxdp_queue_id = queue_id;
+
+        let result = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                addr_of!(addr) as *const libc::sockaddr,
+                ::std::mem::size_of::<libc::sockaddr_xdp>() as libc::socklen_t,
+            )
+        };
+
+        if result < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("bind AF_XDP socket to queue {}", queue_id));
+        }
+
+        Ok(Self {
+            fill,
+            completion,
+            rx,
+            tx,
+            umem,
+            fd,
+            free_tx_frames,
+        })
+    }
+
+    /// Reclaim frames of packets that have been sent
+    pub fn reclaim_tx_frames(&mut self) {
+        while let Some(addr) = self.completion.pop() {
+            self.free_tx_frames.push(addr);
+        }
+
+        self.completion.release();
+    }
+
+    /// Get next received frame, along with its UMEM address
+    pub fn next_received(&mut self) -> Option<(u64, &[u8])> {
+        let desc = self.rx.pop()?;
+
+        Some((desc.addr, self.umem.frame(desc.addr, desc.len as usize)))
+    }
+
+    /// Give frame of received packet back to kernel
+    pub fn recycle_rx_frame(&mut self, addr: u64) {
+        // Received packet might not start at beginning of frame
+        self.fill.push(addr - (addr % FRAME_SIZE as u64));
+    }
+
+    /// Get free frame to write packet to send into
+    pub fn free_tx_frame(&mut self) -> Option<(u64, &mut [u8])> {
+        let addr = self.free_tx_frames.pop()?;
+
+        Some((addr, self.umem.frame_mut(addr, FRAME_SIZE)))
+    }
+
+    /// Queue packet in frame for sending
+    pub fn send(&mut self, addr: u64, len: usize) {
+        let desc = libc::xdp_desc {
+            addr,
+            len: len as u32,
+            options: 0,
+        };
+
+        // Ring can hold all tx frames, so this shouldn't happen
+        if !self.tx.push(desc) {
+            self.return_tx_frame(addr);
+        }
+    }
+
+    /// Return frame that wasn't sent after all
+    pub fn return_tx_frame(&mut self, addr: u64) {
+        self.free_tx_frames.push(addr);
+    }
+
+    /// Make ring updates visible to kernel and wake it up if necessary
+    pub fn flush(&mut self) {
+        self.rx.release();
+        self.fill.commit();
+
+        self.tx.commit();
+
+        // Also retry if a previous wakeup failed
+        let tx_frames_in_use = self.free_tx_frames.len() < NUM_FRAMES - RING_SIZE as usize;
+
+        if tx_frames_in_use && self.tx.needs_wakeup() {
+            let result = unsafe {
+                libc::sendto(
+                    self.fd.as_raw_fd(),
+                    null_mut(),
+                    0,
+                    libc::MSG_DONTWAIT,
+                    null_mut(),
+                    0,
+                )
+            };
+
+            if result < 0 {
+                let err = io::Error::last_os_error();
+
+                match err.raw_os_error() {
+                    Some(libc::EAGAIN | libc::EBUSY | libc::ENOBUFS | libc::ENETDOWN) => (),
+                    _ => ::log::warn!("AF_XDP sendto error: {:#}", err),
+                }
+            }
+        }
+    }
+}
+
+impl AsRawFd for XskSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+struct Umem {
+    area: *mut u8,
+    len: usize,
+}
+
+impl Umem {
+    fn new(len: usize) -> io::Result<Self> {
+        let area = unsafe {
+            libc::mmap(
+                null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if area == libc::MAP_FAILED {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Self {
+                area: area as *mut u8,
+                len,
+            })
+        }
+    }
+
+    fn frame(&self, addr: u64, len: usize) -> &[u8] {
+        let (start, len) = self.checked_range(addr, len);
+
+        unsafe { ::std::slice::from_raw_parts(self.area.add(start), len) }
+    }
+
+    fn frame_mut(&mut self, addr: u64, len: usize) -> &mut [u8] {
+        let (start, len) = self.checked_range(addr, len);
+
+        unsafe { ::std::slice::from_raw_parts_mut(self.area.add(start), len) }
+    }
+
+    fn checked_range(&self, addr: u64, len: usize) -> (usize, usize) {
+        let start = addr as usize;
+
+        assert!(start + len <= self.len, "frame outside of umem");
+
+        (start, len)
+    }
+}
+
+impl Drop for Umem {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.area as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Single producer, single consumer ring shared with kernel. Depending on
+/// ring, either user space or the kernel is the producer.
+struct Ring<T> {
+    mmap_area: *mut libc::c_void,
+    mmap_len: usize,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    flags: *const AtomicU32,
+    descs: *mut T,
+    cached_producer: u32,
+    cached_consumer: u32,
+}
+
+impl<T: Copy> Ring<T> {
+    unsafe fn map(
+        fd: &OwnedFd,
+        offsets: &libc::xdp_ring_offset,
+        page_offset: libc::off_t,
+    ) -> io::Result<Self> {
+        let mmap_len = offsets.desc as usize + RING_SIZE as usize * ::std::mem::size_of::<T>();
+
+        let mmap_area = libc::mmap(
+            null_mut(),
+            mmap_len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_POPULATE,
+            fd.as_raw_fd(),
+            page_offset,
+        );
+
+        if mmap_area == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let base = mmap_area as *mut u8;
+
+        let producer = base.add(offsets.producer as usize) as *const AtomicU32;
+        let consumer = base.add(offsets.consumer as usize) as *const AtomicU32;
+
+        Ok(Self {
+            mmap_area,
+            mmap_len,
+            producer,
+            consumer,
+            flags: base.add(offsets.flags as usize) as *const AtomicU32,
+            descs: base.add(offsets.desc as usize) as *mut T,
+            cached_producer: (*producer).load(Ordering::Acquire),
+            cached_consumer: (*consumer).load(Ordering::Acquire),
+        })
+    }
+
+    fn index(&self, position: u32) -> usize {
+        (position & (RING_SIZE - 1)) as usize
+    }
+
+    /// Producer side: add entry, returning false if ring is full
+    fn push(&mut self, value: T) -> bool {
+        if self.cached_producer.wrapping_sub(self.cached_consumer) == RING_SIZE {
+            self.cached_consumer = unsafe { (*self.consumer).load(Ordering::Acquire) };
+
+            if self.cached_producer.wrapping_sub(self.cached_consumer) == RING_SIZE {
+                return false;
+            }
+        }
+
+        unsafe {
+            self.descs
+                .add(self.index(self.cached_producer))
+                .write(value);
+        }
+
+        self.cached_producer = self.cached_producer.wrapping_add(1);
+
+        true
+    }
+
+    /// Producer side: make added entries visible
+    fn commit(&mut self) {
+        unsafe { &*self.producer }.store(self.cached_producer, Ordering::Release);
+    }
+
+    /// Consumer side: take entry, if any
+    fn pop(&mut self) -> Option<T> {
+        if self.cached_consumer == self.cached_producer {
+            self.cached_producer = unsafe { (*self.producer).load(Ordering::Acquire) };
+
+            if self.cached_consumer == self.cached_producer {
+                return None;
+            }
+        }
+
+        let value = unsafe { self.descs.add(self.index(self.cached_consumer)).read() };
+
+        self.cached_consumer = self.cached_consumer.wrapping_add(1);
+
+        Some(value)
+    }
+
+    /// Consumer side: hand back taken entries
+    fn release(&mut self) {
+        unsafe { &*self.consumer }.store(self.cached_consumer, Ordering::Release);
+    }
+
+    fn needs_wakeup(&self) -> bool {
+        unsafe { &*self.flags }.load(Ordering::Relaxed) & libc::XDP_RING_NEED_WAKEUP != 0
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.mmap_area, self.mmap_len);
+        }
+    }
+}
+
+fn set_option<T>(fd: &OwnedFd, option: libc::c_int, value: &T) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_XDP,
+            option,
+            value as *const T as *const libc::c_void,
+            ::std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
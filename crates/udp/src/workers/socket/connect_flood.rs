@@ -0,0 +1,159 @@
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Instant;
+
+use ahash::RandomState;
+
+use crate::config::Config;
+
+/// Number of rows in count-min sketch
+const SKETCH_DEPTH: usize = 4;
+
+/// Connect request flood protection, shared by all socket workers
+///
+/// Creating a connection id is cheap compared to handling announce
+/// requests, but still costs a keyed BLAKE3 hash and a response packet.
+/// To avoid spending that on floods of (possibly spoofed) connect requests,
+/// the number of connect requests per source address during the current
+/// window is estimated with a count-min sketch. The estimate is never lower
+/// than the real count, but hash collisions may cause it to be higher.
+///
+/// Method update_window must be called regularly.
+pub struct ConnectFloodGuard {
+    max_connects_per_source: u32,
+    min_total_connects: u64,
+    window_length: u64,
+    ipv6_mask: u128,
+    hasher: RandomState,
+    sketch_width: usize,
+    sketch: Box<[AtomicU32]>,
+    total_connects: AtomicU64,
+    window: AtomicU64,
+    start_time: Instant,
+}
+
+impl ConnectFloodGuard {
+    pub fn new(config: &Config) -> Self {
+        let config = &config.connect_flood;
+
+        let ipv6_mask = match config.ipv6_prefix_length.min(128) {
+            0 => 0,
+            n => u128::MAX << (128 - u32::from(n)),
+        };
+        let sketch_width = config.sketch_width.max(1);
+        let sketch_len = if config.max_connects_per_source == 0 {
+            0
+        } else {
+            SKETCH_DEPTH * sketch_width
+        };
+
+        Self {
+            max_connects_per_source: config.max_connects_per_source,
+            min_total_connects: config.min_total_connects,
+            window_length: u64::from(config.window_length.max(1)),
+            ipv6_mask,
+            hasher: RandomState::new(),
+            sketch_width,
+            sketch: (0..sketch_len).map(|_| Default::default()).collect(),
+            total_connects: Default::default(),
+            window: Default::default(),
+            start_time: Instant::now(),
+        }
+    }
+
+    /// Count connect request and return false if it should be dropped
+    pub fn allow_connect(&self, ip: IpAddr) -> bool {
+        if self.max_connects_per_source == 0 {
+            return true;
+        }
+
+        let total_connects = self.total_connects.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let hash = self.hasher.hash_one(self.key(ip));
+        let h1 = hash & u64::from(u32::MAX);
+        let h2 = (hash >> 32) | 1;
+
+        let mut estimate = u32::MAX;
+
+        for row in 0..SKETCH_DEPTH {
+            let column = (h1.wrapping_add((row as u64).wrapping_mul(h2)) % self.sketch_width as u64)
+                as usize;
+            let count = self.sketch[row * self.sketch_width + column]
+                .fetch_add(1, Ordering::Relaxed)
+                .saturating_add(1);
+
+            estimate = estimate.min(count);
+        }
+
+        estimate <= self.max_connects_per_source || total_connects <= self.min_total_connects
+    }
+
+    /// Reset counts if a new window has started
+    pub fn update_window(&self) {
+        if self.max_connects_per_source != 0 {
+            self.start_window(self.start_time.elapsed().as_secs() / self.window_length);
+        }
+    }
+
+    fn start_window(&self, window: u64) {
+        let current = self.window.load(Ordering::Relaxed);
+
+        // Only one worker resets counts
+        if current == window
+            || self
+                .window
+                .compare_exchange(current, window, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+
+        for count in self.sketch.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+
+        self.total_connects.store(0, Ordering::Relaxed);
+    }
+
+    fn key(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(_) => ip,
+            IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & self.ipv6_mask)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_flood_guard() {
+        let mut config = Config::default();
+
+        config.connect_flood.max_connects_per_source = 2;
+
+        let guard = ConnectFloodGuard::new(&config);
+
+        let a: IpAddr = "2001:db8::1".parse().unwrap();
+        let b: IpAddr = "2001:db8::2".parse().unwrap();
+        let c: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert!(guard.allow_connect(a));
+        assert!(guard.allow_connect(b));
+        assert!(!guard.allow_connect(a));
+        assert!(guard.allow_connect(c));
+
+        guard.start_window(1);
+
+        assert!(guard.allow_connect(b));
+
+        // Only drop requests when there are many in total
+        config.connect_flood.min_total_connects = 3;
+
+        let guard = ConnectFloodGuard::new(&config);
+
+        assert!((0..3).all(|_| guard.allow_connect(c)));
+        assert!(!guard.allow_connect(c));
+    }
+}
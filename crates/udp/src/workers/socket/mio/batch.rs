@@ -0,0 +1,520 @@
+//! Batched receiving and sending using recvmmsg and sendmmsg
+
+use std::io::{self, Cursor, ErrorKind};
+use std::mem::MaybeUninit;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::{addr_of, addr_of_mut, null_mut};
+use std::time::Instant;
+
+use aquatic_common::CanonicalSocketAddr;
+use aquatic_udp_protocol::Response;
+
+use crate::common::BUFFER_SIZE;
+
+/// Maximum number of segments the kernel accepts in a single GSO send
+const MAX_GSO_SEGMENTS: usize = 64;
+/// Larger responses are never combined, to stay below common path MTUs
+const MAX_GSO_SEGMENT_SIZE: usize = 1200;
+/// Maximum UDP payload size
+const MAX_GSO_TOTAL_SIZE: usize = 65507;
+
+pub struct Batch {
+    pub recv: RecvBatch,
+    pub send: SendBatch,
+}
+
+impl Batch {
+    pub fn new(batch_size: usize, use_gso: bool) -> Self {
+        Self {
+            recv: RecvBatch::new(batch_size),
+            send: SendBatch::new(batch_size, use_gso),
+        }
+    }
+}
+
+/// Check if kernel supports UDP generic segmentation offload (Linux 4.18
+/// or later)
+pub fn gso_supported(socket: &impl AsRawFd) -> bool {
+    let mut value: libc::c_int = 0;
+    let mut len = core::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            addr_of_mut!(value) as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    result == 0
+}
+
+pub struct RecvBatch {
+    buffers: Vec<u8>,
+    names: Vec<libc::sockaddr_storage>,
+    iovecs: Vec<libc::iovec>,
+    headers: Vec<libc::mmsghdr>,
+}
+
+impl RecvBatch {
+    fn new(batch_size: usize) -> Self {
+        Self {
+            buffers: vec![0; batch_size * BUFFER_SIZE],
+            names: (0..batch_size).map(|_| zeroed_sockaddr_storage()).collect(),
+            iovecs: (0..batch_size)
+                .map(|_| libc::iovec {
+                    iov_base: null_mut(),
+                    iov_len: 0,
+                })
+                .collect(),
+            headers: (0..batch_size).map(|_| zeroed_mmsghdr()).collect(),
+        }
+    }
+
+    /// Receive as many packets as are available, up to the batch size
+    pub fn recv(&mut self, socket: &impl AsRawFd) -> io::Result<usize> {
+        // Pointers are set before each call so that moving self is fine
+        for (i, ((header, iovec), name)) in self
+            .headers
+            .iter_mut()
+            .zip(self.iovecs.iter_mut())
+            .zip(self.names.iter_mut())
+            .enumerate()
+        {
+            iovec.iov_base = self.buffers[i * BUFFER_SIZE..].as_mut_ptr() as *mut libc::c_void;
+            iovec.iov_len = BUFFER_SIZE;
+
+            header.msg_hdr.msg_name = name as *mut libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_hdr.msg_namelen =
+                core::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+            header.msg_len = 0;
+        }
+
+        let result = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                self.headers.as_mut_ptr(),
+                self.headers.len() as libc::c_uint,
+                0,
+                null_mut(),
+            )
+        };
+
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result as usize)
+        }
+    }
+
+    /// Source address and payload of received packet. Address is None if
+    /// it is of an unexpected family.
+    pub fn packet(&self, index: usize) -> (Option<SocketAddr>, &[u8]) {
+        let len = (self.headers[index].msg_len as usize).min(BUFFER_SIZE);
+        let payload = &self.buffers[index * BUFFER_SIZE..index * BUFFER_SIZE + len];
+
+        let name = &self.names[index];
+
+        let addr = match libc::c_int::from(name.ss_family) {
+            libc::AF_INET => {
+                let name = unsafe { &*(name as *const _ as *const libc::sockaddr_in) };
+
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    u32::from_be(name.sin_addr.s_addr).into(),
+                    u16::from_be(name.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                let name = unsafe { &*(name as *const _ as *const libc::sockaddr_in6) };
+
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    name.sin6_addr.s6_addr.into(),
+                    u16::from_be(name.sin6_port),
+                    name.sin6_flowinfo,
+                    name.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        };
+
+        (addr, payload)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum MessageStatus {
+    Pending,
+    Sent,
+    Failed(i32),
+}
+
+struct Message {
+    addr: SocketAddr,
+    start: usize,
+    len: usize,
+    segment_size: usize,
+    num_segments: usize,
+    status: MessageStatus,
+}
+
+impl Message {
+    fn can_append(&self, addr: SocketAddr, len: usize) -> bool {
+        self.addr == addr
+            // A shorter segment is only allowed last
+            && self.len == self.segment_size * self.num_segments
+            && len <= self.segment_size
+            && self.segment_size <= MAX_GSO_SEGMENT_SIZE
+            && self.num_segments < MAX_GSO_SEGMENTS
+            && self.len + len <= MAX_GSO_TOTAL_SIZE
+    }
+}
+
+struct QueuedResponse {
+    canonical_addr: CanonicalSocketAddr,
+    addr: SocketAddr,
+    response: Response,
+    opt_received_at: Option<Instant>,
+    /// Index of message containing response, unless serialization failed
+    opt_message_index: Option<usize>,
+    len: usize,
+}
+
+pub struct SendBatch {
+    batch_size: usize,
+    use_gso: bool,
+    responses: Vec<QueuedResponse>,
+    messages: Vec<Message>,
+    bytes: Vec<u8>,
+    names: Vec<libc::sockaddr_storage>,
+    iovecs: Vec<libc::iovec>,
+    /// Space for one UDP_SEGMENT control message per header
+    controls: Vec<[libc::cmsghdr; 2]>,
+    headers: Vec<libc::mmsghdr>,
+}
+
+impl SendBatch {
+    fn new(batch_size: usize, use_gso: bool) -> Self {
+        Self {
+            batch_size,
+            use_gso,
+            responses: Vec::with_capacity(batch_size),
+            messages: Vec::with_capacity(batch_size),
+            bytes: vec![0; batch_size * BUFFER_SIZE],
+            names: (0..batch_size).map(|_| zeroed_sockaddr_storage()).collect(),
+            iovecs: (0..batch_size)
+                .map(|_| libc::iovec {
+                    iov_base: null_mut(),
+                    iov_len: 0,
+                })
+                .collect(),
+            controls: (0..batch_size)
+                .map(|_| unsafe { MaybeUninit::zeroed().assume_init() })
+                .collect(),
+            headers: Vec::with_capacity(batch_size),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.responses.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.responses.len() >= self.batch_size
+    }
+
+    /// Queue response. Caller must make sure that batch isn't full.
+    pub fn push(
+        &mut self,
+        canonical_addr: CanonicalSocketAddr,
+        addr: SocketAddr,
+        response: Response,
+        opt_received_at: Option<Instant>,
+    ) {
+        debug_assert!(!self.is_full());
+
+        self.responses.push(QueuedResponse {
+            canonical_addr,
+            addr,
+            response,
+            opt_received_at,
+            opt_message_index: None,
+            len: 0,
+        });
+    }
+
+    /// Send queued responses, stopping early if socket send buffer is full
+    pub fn send(&mut self, socket: &impl AsRawFd) {
+        self.prepare();
+        self.send_prepared(socket.as_raw_fd());
+    }
+
+    /// Take queued responses along with number of bytes sent for each one
+    /// or the error that occurred when trying to send it
+    pub fn drain(
+        &mut self,
+    ) -> impl Iterator<
+        Item = (
+            CanonicalSocketAddr,
+            Response,
+            Option<Instant>,
+            io::Result<usize>,
+        ),
+    > + '_ {
+        let messages = &self.messages;
+
+        self.responses.drain(..).filter_map(move |queued| {
+            let result = match messages[queued.opt_message_index?].status {
+                MessageStatus::Sent => Ok(queued.len),
+                MessageStatus::Failed(errno) => Err(io::Error::from_raw_os_error(errno)),
+                // Only the case when sendmmsg reports fewer messages sent
+                // than requested, which it does on errors
+                MessageStatus::Pending => Err(ErrorKind::WouldBlock.into()),
+            };
+
+            Some((
+                queued.canonical_addr,
+                queued.response,
+                queued.opt_received_at,
+                result,
+            ))
+        })
+    }
+
+    fn prepare(&mut self) {
+        self.messages.clear();
+
+        let mut position = 0;
+
+        for queued in self.responses.iter_mut() {
+            let mut cursor = Cursor::new(&mut self.bytes[position..position + BUFFER_SIZE]);
+
+            if let Err(err) = queued.response.write_bytes(&mut cursor) {
+                ::log::error!(
+                    peer_addr:% = queued.canonical_addr.get();
+                    "failed writing response to buffer: {:#}",
+                    err
+                );
+
+                continue;
+            }
+
+            let len = cursor.position() as usize;
+
+            queued.len = len;
+
+            match self.messages.last_mut() {
+                Some(message) if self.use_gso && message.can_append(queued.addr, len) => {
+                    message.len += len;
+                    message.num_segments += 1;
+                }
+                _ => {
+                    self.messages.push(Message {
+                        addr: queued.addr,
+                        start: position,
+                        len,
+                        segment_size: len,
+                        num_segments: 1,
+                        status: MessageStatus::Pending,
+                    });
+                }
+            }
+
+            queued.opt_message_index = Some(self.messages.len() - 1);
+
+            position += len;
+        }
+
+        self.headers.clear();
+
+        for (((message, name), iovec), control) in self
+            .messages
+            .iter()
+            .zip(self.names.iter_mut())
+            .zip(self.iovecs.iter_mut())
+            .zip(self.controls.iter_mut())
+        {
+            let mut header = zeroed_mmsghdr();
+
+            header.msg_hdr.msg_name = name as *mut libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = write_sockaddr(message.addr, name);
+
+            iovec.iov_base = self.bytes[message.start..].as_mut_ptr() as *mut libc::c_void;
+            iovec.iov_len = message.len;
+
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+
+            if message.num_segments > 1 {
+                let segment_size = message.segment_size as u16;
+
+                unsafe {
+                    header.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                    header.msg_hdr.msg_controllen =
+                        libc::CMSG_SPACE(core::mem::size_of::<u16>() as u32) as _;
+
+                    let cmsg = libc::CMSG_FIRSTHDR(addr_of!(header.msg_hdr));
+
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(core::mem::size_of::<u16>() as u32) as _;
+
+                    (libc::CMSG_DATA(cmsg) as *mut u16).write_unaligned(segment_size);
+                }
+            }
+
+            self.headers.push(header);
+        }
+    }
+
+    fn send_prepared(&mut self, fd: RawFd) {
+        let mut num_sent = 0;
+
+        while num_sent < self.headers.len() {
+            let result = unsafe {
+                libc::sendmmsg(
+                    fd,
+                    self.headers[num_sent..].as_mut_ptr(),
+                    (self.headers.len() - num_sent) as libc::c_uint,
+                    0,
+                )
+            };
+
+            if result < 0 {
+                let err = io::Error::last_os_error();
+                let errno = err.raw_os_error().unwrap_or(libc::EIO);
+
+                self.messages[num_sent].status = MessageStatus::Failed(errno);
+
+                // Remaining messages are left pending and will be reported
+                // as blocked
+                if (errno == libc::ENOBUFS) || (err.kind() == ErrorKind::WouldBlock) {
+                    break;
+                }
+
+                num_sent += 1;
+            } else {
+                for message in self.messages[num_sent..num_sent + result as usize].iter_mut() {
+                    message.status = MessageStatus::Sent;
+                }
+
+                num_sent += result as usize;
+            }
+        }
+    }
+}
+
+fn write_sockaddr(addr: SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let name = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in) };
+
+            name.sin_family = libc::AF_INET as libc::sa_family_t;
+            name.sin_port = addr.port().to_be();
+            name.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+
+            core::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        }
+        SocketAddr::V6(addr) => {
+            let name = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in6) };
+
+            name.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            name.sin6_port = addr.port().to_be();
+            name.sin6_flowinfo = addr.flowinfo();
+            name.sin6_addr.s6_addr = addr.ip().octets();
+            name.sin6_scope_id = addr.scope_id();
+
+            core::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+        }
+    }
+}
+
+fn zeroed_sockaddr_storage() -> libc::sockaddr_storage {
+    unsafe { MaybeUninit::zeroed().assume_init() }
+}
+
+// On musl libc, msghdr contains private padding fields
+fn zeroed_mmsghdr() -> libc::mmsghdr {
+    unsafe { MaybeUninit::zeroed().assume_init() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    use aquatic_udp_protocol::{ConnectResponse, ConnectionId, TransactionId};
+
+    use super::*;
+
+    fn connect_response(n: i32) -> Response {
+        Response::Connect(ConnectResponse {
+            connection_id: ConnectionId::new(n.into()),
+            transaction_id: TransactionId::new(n),
+        })
+    }
+
+    #[test]
+    fn test_batch_send_and_recv() {
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+
+        receiver.set_nonblocking(true).unwrap();
+
+        let receiver_addr = receiver.local_addr().unwrap();
+
+        let mut send_batch = SendBatch::new(4, gso_supported(&sender));
+
+        for i in 0..4 {
+            send_batch.push(
+                CanonicalSocketAddr::new(receiver_addr),
+                receiver_addr,
+                connect_response(i),
+                None,
+            );
+        }
+
+        assert!(send_batch.is_full());
+
+        send_batch.send(&sender);
+
+        let results = send_batch.drain().collect::<Vec<_>>();
+
+        assert_eq!(results.len(), 4);
+        assert!(results
+            .iter()
+            .all(|(_, _, _, result)| matches!(result, Ok(16))));
+
+        let mut recv_batch = RecvBatch::new(8);
+        let mut payloads = Vec::new();
+
+        while payloads.len() < 4 {
+            match recv_batch.recv(&receiver) {
+                Ok(num_received) => {
+                    for i in 0..num_received {
+                        let (addr, payload) = recv_batch.packet(i);
+
+                        assert_eq!(addr, Some(sender.local_addr().unwrap()));
+
+                        payloads.push(payload.to_vec());
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => (),
+                Err(err) => panic!("recvmmsg: {:#}", err),
+            }
+        }
+
+        for (i, payload) in payloads.into_iter().enumerate() {
+            let mut expected = Vec::new();
+
+            connect_response(i as i32)
+                .write_bytes(&mut expected)
+                .unwrap();
+
+            assert_eq!(payload, expected);
+        }
+    }
+}
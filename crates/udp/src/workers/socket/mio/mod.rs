@@ -1,11 +1,15 @@
+#[cfg(target_os = "linux")]
+mod batch;
+mod router;
 mod socket;
 
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use aquatic_common::access_list::AccessListCache;
 use crossbeam_channel::Sender;
-use mio::{Events, Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Token, Waker};
 
 use aquatic_common::{
     access_list::create_access_list_cache, privileges::PrivilegeDropper, CanonicalSocketAddr,
@@ -17,14 +21,29 @@ use rand::SeedableRng;
 
 use crate::common::*;
 use crate::config::Config;
+use crate::workers::swarm::SocketWorkerSwarmChannels;
 
-use socket::Socket;
+use router::{SwarmResponseToSend, SwarmRouter};
+#[cfg(all(target_os = "linux", feature = "af-xdp"))]
+pub(super) use socket::{handle_packet, record_response_sent};
+use socket::{Ipv4, Ipv6, Socket};
 
 use super::validator::ConnectionValidator;
+use super::RequestLatencyRecorder;
 use super::{EXTRA_PACKET_SIZE_IPV4, EXTRA_PACKET_SIZE_IPV6};
 
 const TOKEN_V4: Token = Token(0);
 const TOKEN_V6: Token = Token(1);
+const TOKEN_WAKER: Token = Token(2);
+
+/// Create poll instance along with waker that swarm workers can use to
+/// notify socket worker of available responses
+pub fn create_poll_with_waker() -> anyhow::Result<(Poll, Arc<Waker>)> {
+    let poll = Poll::new().context("create poll")?;
+    let waker = Waker::new(poll.registry(), TOKEN_WAKER).context("create waker")?;
+
+    Ok((poll, Arc::new(waker)))
+}
 
 pub fn run(
     config: Config,
@@ -32,43 +51,35 @@ pub fn run(
     statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
     statistics_sender: Sender<StatisticsMessage>,
     validator: ConnectionValidator,
-    mut priv_droppers: Vec<PrivilegeDropper>,
+    priv_droppers: Vec<PrivilegeDropper>,
+    opt_swarm_channels: Option<SocketWorkerSwarmChannels>,
 ) -> anyhow::Result<()> {
-    let mut opt_socket_ipv4 = if config.network.use_ipv4 {
-        let priv_dropper = priv_droppers.pop().expect("not enough privilege droppers");
+    let (mut opt_socket_ipv4, mut opt_socket_ipv6) = create_sockets(&config, priv_droppers)?;
 
-        Some(Socket::<self::socket::Ipv4>::create(&config, priv_dropper)?)
-    } else {
-        None
-    };
-    let mut opt_socket_ipv6 = if config.network.use_ipv6 {
-        let priv_dropper = priv_droppers.pop().expect("not enough privilege droppers");
-
-        Some(Socket::<self::socket::Ipv6>::create(&config, priv_dropper)?)
-    } else {
-        None
-    };
-
-    let access_list_cache = create_access_list_cache(&shared_state.access_list);
-    let peer_valid_until = ValidUntil::new(
-        shared_state.server_start_instant,
-        config.cleaning.max_peer_age,
-    );
-
-    let mut shared = WorkerSharedData {
+    let mut shared = WorkerSharedData::new(
         config,
         shared_state,
         statistics,
         statistics_sender,
         validator,
-        access_list_cache,
-        buffer: [0; BUFFER_SIZE],
-        rng: SmallRng::from_entropy(),
-        peer_valid_until,
+    );
+
+    let mut poll = if let Some(channels) = opt_swarm_channels {
+        shared.opt_swarm_router = Some(SwarmRouter::new(
+            &shared.config,
+            shared.shared_state.server_start_instant,
+            channels.socket_worker_index,
+            channels.request_senders,
+            channels.response_receiver,
+        ));
+
+        channels.poll
+    } else {
+        Poll::new().context("create poll")?
     };
 
-    let mut events = Events::with_capacity(2);
-    let mut poll = Poll::new().context("create poll")?;
+    let mut events = Events::with_capacity(3);
+    let mut swarm_responses: Vec<SwarmResponseToSend> = Vec::new();
 
     if let Some(socket) = opt_socket_ipv4.as_mut() {
         poll.registry()
@@ -106,6 +117,37 @@ pub fn run(
             }
         }
 
+        if let Some(router) = shared.opt_swarm_router.as_mut() {
+            router.receive_responses(&mut swarm_responses);
+
+            for response in swarm_responses.drain(..) {
+                if response.ipv4_socket {
+                    if let Some(socket) = opt_socket_ipv4.as_mut() {
+                        socket.send_or_queue_response(
+                            &mut shared,
+                            response.src,
+                            response.response,
+                            response.opt_received_at,
+                        );
+                    }
+                } else if let Some(socket) = opt_socket_ipv6.as_mut() {
+                    socket.send_or_queue_response(
+                        &mut shared,
+                        response.src,
+                        response.response,
+                        response.opt_received_at,
+                    );
+                }
+            }
+
+            if let Some(socket) = opt_socket_ipv4.as_mut() {
+                socket.flush_responses(&mut shared);
+            }
+            if let Some(socket) = opt_socket_ipv6.as_mut() {
+                socket.flush_responses(&mut shared);
+            }
+        }
+
         if let Some(socket) = opt_socket_ipv4.as_mut() {
             socket.resend_failed(&mut shared);
         }
@@ -113,21 +155,40 @@ pub fn run(
             socket.resend_failed(&mut shared);
         }
 
-        if iter_counter % 256 == 0 {
-            shared.validator.update_elapsed();
-
-            shared.peer_valid_until = ValidUntil::new(
-                shared.shared_state.server_start_instant,
-                shared.config.cleaning.max_peer_age,
-            );
-        }
+        shared.maintain(iter_counter);
 
         iter_counter = iter_counter.wrapping_add(1);
     }
 }
 
+/// Sockets for enabled IP versions
+pub(super) type Sockets = (Option<Socket<Ipv4>>, Option<Socket<Ipv6>>);
+
+/// Create sockets for enabled IP versions, consuming privilege droppers
+pub(super) fn create_sockets(
+    config: &Config,
+    mut priv_droppers: Vec<PrivilegeDropper>,
+) -> anyhow::Result<Sockets> {
+    let opt_socket_ipv4 = if config.network.use_ipv4 {
+        let priv_dropper = priv_droppers.pop().expect("not enough privilege droppers");
+
+        Some(Socket::<Ipv4>::create(config, priv_dropper)?)
+    } else {
+        None
+    };
+    let opt_socket_ipv6 = if config.network.use_ipv6 {
+        let priv_dropper = priv_droppers.pop().expect("not enough privilege droppers");
+
+        Some(Socket::<Ipv6>::create(config, priv_dropper)?)
+    } else {
+        None
+    };
+
+    Ok((opt_socket_ipv4, opt_socket_ipv6))
+}
+
 pub struct WorkerSharedData {
-    config: Config,
+    pub(super) config: Config,
     shared_state: State,
     statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
     statistics_sender: Sender<StatisticsMessage>,
@@ -136,10 +197,74 @@ pub struct WorkerSharedData {
     buffer: [u8; BUFFER_SIZE],
     rng: SmallRng,
     peer_valid_until: ValidUntil,
+    pub(super) opt_request_latencies: Option<RequestLatencyRecorder>,
+    opt_swarm_router: Option<SwarmRouter>,
 }
 
 impl WorkerSharedData {
-    fn handle_request(&mut self, request: Request, src: CanonicalSocketAddr) -> Option<Response> {
+    pub(super) fn new(
+        config: Config,
+        shared_state: State,
+        statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
+        statistics_sender: Sender<StatisticsMessage>,
+        validator: ConnectionValidator,
+    ) -> Self {
+        let access_list_cache = create_access_list_cache(&shared_state.access_list);
+        let peer_valid_until = ValidUntil::new(
+            shared_state.server_start_instant,
+            config.cleaning.max_peer_age,
+        );
+
+        let opt_request_latencies = RequestLatencyRecorder::new(&config);
+
+        Self {
+            config,
+            shared_state,
+            statistics,
+            statistics_sender,
+            validator,
+            access_list_cache,
+            buffer: [0; BUFFER_SIZE],
+            rng: SmallRng::from_entropy(),
+            peer_valid_until,
+            opt_request_latencies,
+            opt_swarm_router: None,
+        }
+    }
+
+    /// Send due statistics and update cached time values. Call once per
+    /// event loop iteration.
+    pub(super) fn maintain(&mut self, iter_counter: u64) {
+        if let Some(request_latencies) = self.opt_request_latencies.as_mut() {
+            request_latencies.send_if_due(&self.statistics_sender);
+        }
+
+        self.shared_state.connect_flood_guard.update_window();
+
+        if iter_counter % 256 == 0 {
+            self.validator.update_time();
+
+            self.peer_valid_until = ValidUntil::new(
+                self.shared_state.server_start_instant,
+                self.config.cleaning.max_peer_age,
+            );
+
+            if let Some(router) = self.opt_swarm_router.as_mut() {
+                router.clean_pending_scrape_responses(
+                    &self.config,
+                    self.shared_state.server_start_instant,
+                );
+            }
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        request: Request,
+        src: CanonicalSocketAddr,
+        ipv4_socket: bool,
+        opt_received_at: Option<Instant>,
+    ) -> Option<Response> {
         let access_list_mode = self.config.access_list.mode;
 
         match request {
@@ -154,11 +279,44 @@ impl WorkerSharedData {
                     .validator
                     .connection_id_valid(src, request.connection_id)
                 {
+                    if !self
+                        .shared_state
+                        .client_filter
+                        .load()
+                        .allows(self.config.client_filter.mode, &request.peer_id.0)
+                    {
+                        #[cfg(feature = "prometheus")]
+                        if self.config.statistics.metrics_active() {
+                            self.statistics_sender
+                                .try_send(StatisticsMessage::ClientRejected(request.peer_id))
+                                .expect("statistics channel should be unbounded");
+                        }
+
+                        let response = Response::Error(ErrorResponse {
+                            transaction_id: request.transaction_id,
+                            message: "Client not allowed".into(),
+                        });
+
+                        return Some(response);
+                    }
+
                     if self
                         .access_list_cache
                         .load()
                         .allows(access_list_mode, &request.info_hash.0)
                     {
+                        if let Some(router) = self.opt_swarm_router.as_ref() {
+                            router.send_announce(
+                                &self.config,
+                                request,
+                                src,
+                                ipv4_socket,
+                                opt_received_at,
+                            );
+
+                            return None;
+                        }
+
                         let response = self.shared_state.torrent_maps.announce(
                             &self.config,
                             &self.statistics_sender,
@@ -182,6 +340,18 @@ impl WorkerSharedData {
                     .validator
                     .connection_id_valid(src, request.connection_id)
                 {
+                    if let Some(router) = self.opt_swarm_router.as_mut() {
+                        router.send_scrape(
+                            &self.config,
+                            request,
+                            src,
+                            ipv4_socket,
+                            opt_received_at,
+                        );
+
+                        return None;
+                    }
+
                     return Some(Response::Scrape(
                         self.shared_state.torrent_maps.scrape(request, src),
                     ));
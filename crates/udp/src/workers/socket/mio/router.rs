@@ -0,0 +1,347 @@
+use std::time::Instant;
+
+use aquatic_common::logging::InfoHashPrefix;
+use aquatic_common::{CanonicalSocketAddr, ServerStartInstant, ValidUntil};
+use aquatic_udp_protocol::*;
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use slab::Slab;
+
+use crate::common::*;
+use crate::config::Config;
+
+/// Sends announce and scrape requests to swarm workers and puts together
+/// responses from them
+pub struct SwarmRouter {
+    socket_worker_index: usize,
+    request_senders: Vec<Sender<SwarmWorkerMessage>>,
+    response_receiver: Receiver<SwarmResponse>,
+    pending_scrape_responses: Slab<PendingScrapeResponses>,
+    pending_scrape_valid_until: ValidUntil,
+    next_scrape_id: u64,
+}
+
+impl SwarmRouter {
+    pub fn new(
+        config: &Config,
+        server_start_instant: ServerStartInstant,
+        socket_worker_index: usize,
+        request_senders: Vec<Sender<SwarmWorkerMessage>>,
+        response_receiver: Receiver<SwarmResponse>,
+    ) -> Self {
+        Self {
+            socket_worker_index,
+            request_senders,
+            response_receiver,
+            pending_scrape_responses: Default::default(),
+            pending_scrape_valid_until: ValidUntil::new(
+                server_start_instant,
+                config.cleaning.max_pending_scrape_age,
+            ),
+            next_scrape_id: 0,
+        }
+    }
+
+    pub fn send_announce(
+        &self,
+        config: &Config,
+        request: AnnounceRequest,
+        src: CanonicalSocketAddr,
+        ipv4_socket: bool,
+        opt_received_at: Option<Instant>,
+    ) {
+        let swarm_worker_index = calculate_request_consumer_index(config, request.info_hash);
+
+        self.send(
+            swarm_worker_index,
+            SwarmRequest {
+                socket_worker_index: self.socket_worker_index,
+                ipv4_socket,
+                src,
+                request: ConnectedRequest::Announce(request),
+                opt_received_at,
+            },
+        );
+    }
+
+    /// Split scrape request by swarm worker and send the parts
+    pub fn send_scrape(
+        &mut self,
+        config: &Config,
+        request: ScrapeRequest,
+        src: CanonicalSocketAddr,
+        ipv4_socket: bool,
+        opt_received_at: Option<Instant>,
+    ) {
+        let mut split_info_hashes = vec![Vec::new(); config.swarm_workers];
+
+        for (i, info_hash) in request.info_hashes.iter().copied().enumerate() {
+            split_info_hashes[calculate_request_consumer_index(config, info_hash)]
+                .push((i, info_hash));
+        }
+
+        let scrape_id = self.next_scrape_id;
+
+        self.next_scrape_id = self.next_scrape_id.wrapping_add(1);
+
+        let slab_key = self
+            .pending_scrape_responses
+            .insert(PendingScrapeResponses {
+                scrape_id,
+                transaction_id: request.transaction_id,
+                pending_worker_responses: split_info_hashes
+                    .iter()
+                    .filter(|info_hashes| !info_hashes.is_empty())
+                    .count(),
+                torrent_stats: vec![
+                    TorrentScrapeStatistics {
+                        seeders: NumberOfPeers::new(0),
+                        leechers: NumberOfPeers::new(0),
+                        completed: NumberOfDownloads::new(0),
+                    };
+                    request.info_hashes.len()
+                ],
+                valid_until: self.pending_scrape_valid_until,
+            });
+
+        for (swarm_worker_index, info_hashes) in split_info_hashes.into_iter().enumerate() {
+            if info_hashes.is_empty() {
+                continue;
+            }
+
+            // If sending fails, the pending response is removed when it
+            // expires
+            self.send(
+                swarm_worker_index,
+                SwarmRequest {
+                    socket_worker_index: self.socket_worker_index,
+                    ipv4_socket,
+                    src,
+                    request: ConnectedRequest::Scrape(PendingScrapeRequest {
+                        slab_key,
+                        scrape_id,
+                        info_hashes,
+                    }),
+                    opt_received_at,
+                },
+            );
+        }
+    }
+
+    /// Move responses that are ready to be sent into `responses`
+    pub fn receive_responses(&mut self, responses: &mut Vec<SwarmResponseToSend>) {
+        for swarm_response in self.response_receiver.try_iter() {
+            let response = match swarm_response.response {
+                ConnectedResponse::Announce(response) => response,
+                ConnectedResponse::Scrape(partial) => {
+                    // Slab key may have been reused after pending response
+                    // expired, so also check id
+                    let pending = if let Some(pending) = self
+                        .pending_scrape_responses
+                        .get_mut(partial.slab_key)
+                        .filter(|pending| pending.scrape_id == partial.scrape_id)
+                    {
+                        pending
+                    } else {
+                        ::log::debug!("pending scrape response not found, it may have expired");
+
+                        continue;
+                    };
+
+                    for (i, statistics) in partial.torrent_stats {
+                        if let Some(entry) = pending.torrent_stats.get_mut(i) {
+                            *entry = statistics;
+                        }
+                    }
+
+                    pending.pending_worker_responses =
+                        pending.pending_worker_responses.saturating_sub(1);
+
+                    if pending.pending_worker_responses > 0 {
+                        continue;
+                    }
+
+                    let pending = self.pending_scrape_responses.remove(partial.slab_key);
+
+                    Response::Scrape(ScrapeResponse {
+                        transaction_id: pending.transaction_id,
+                        torrent_stats: pending.torrent_stats,
+                    })
+                }
+            };
+
+            responses.push(SwarmResponseToSend {
+                ipv4_socket: swarm_response.ipv4_socket,
+                src: swarm_response.src,
+                response,
+                opt_received_at: swarm_response.opt_received_at,
+            });
+        }
+    }
+
+    /// Remove expired pending scrape responses and update their validity
+    /// time for new entries
+    pub fn clean_pending_scrape_responses(
+        &mut self,
+        config: &Config,
+        server_start_instant: ServerStartInstant,
+    ) {
+        let now = server_start_instant.seconds_elapsed();
+
+        self.pending_scrape_responses
+            .retain(|_, pending| pending.valid_until.valid(now));
+
+        self.pending_scrape_valid_until =
+            ValidUntil::new(server_start_instant, config.cleaning.max_pending_scrape_age);
+    }
+
+    fn send(&self, swarm_worker_index: usize, request: SwarmRequest) {
+        match self.request_senders[swarm_worker_index]
+            .try_send(SwarmWorkerMessage::Request(request))
+        {
+            Ok(()) => (),
+            Err(TrySendError::Full(SwarmWorkerMessage::Request(request))) => {
+                if let ConnectedRequest::Announce(announce_request) = request.request {
+                    ::log::warn!(
+                        peer_addr:% = request.src.get(),
+                        info_hash:% = InfoHashPrefix(&announce_request.info_hash.0);
+                        "request channel full, dropping announce request"
+                    );
+                } else {
+                    ::log::warn!(
+                        peer_addr:% = request.src.get();
+                        "request channel full, dropping scrape request"
+                    );
+                }
+            }
+            Err(TrySendError::Full(_)) => {
+                ::log::warn!("request channel full, dropping message");
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                panic!("request channel {} is disconnected", swarm_worker_index);
+            }
+        }
+    }
+}
+
+pub struct SwarmResponseToSend {
+    pub ipv4_socket: bool,
+    pub src: CanonicalSocketAddr,
+    pub response: Response,
+    pub opt_received_at: Option<Instant>,
+}
+
+struct PendingScrapeResponses {
+    scrape_id: u64,
+    transaction_id: TransactionId,
+    pending_worker_responses: usize,
+    torrent_stats: Vec<TorrentScrapeStatistics>,
+    valid_until: ValidUntil,
+}
+
+fn calculate_request_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
+    // Use eight bytes so that any number of swarm workers gets an even
+    // share, in an order that doesn't depend on host byte order
+    let key = u64::from_le_bytes(info_hash.0[..8].try_into().unwrap());
+
+    (key % config.swarm_workers as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use crate::config::CleaningConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_late_scrape_response_is_dropped() {
+        let config = Config {
+            swarm_workers: 1,
+            cleaning: CleaningConfig {
+                max_pending_scrape_age: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let server_start_instant = ServerStartInstant::new();
+        let (request_sender, request_receiver) = crossbeam_channel::unbounded();
+        let (response_sender, response_receiver) = crossbeam_channel::unbounded();
+
+        let mut router = SwarmRouter::new(
+            &config,
+            server_start_instant,
+            0,
+            vec![request_sender],
+            response_receiver,
+        );
+
+        let src = CanonicalSocketAddr::new(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1));
+
+        let send_scrape = |router: &mut SwarmRouter, transaction_id| {
+            let request = ScrapeRequest {
+                connection_id: ConnectionId::new(0),
+                transaction_id: TransactionId::new(transaction_id),
+                info_hashes: vec![InfoHash([0; 20])],
+            };
+
+            router.send_scrape(&config, request, src, true, None);
+
+            match request_receiver.try_recv().unwrap() {
+                SwarmWorkerMessage::Request(SwarmRequest {
+                    request: ConnectedRequest::Scrape(request),
+                    ..
+                }) => request,
+                _ => panic!("expected scrape request"),
+            }
+        };
+        let send_response = |request: PendingScrapeRequest| {
+            let response = SwarmResponse {
+                ipv4_socket: true,
+                src,
+                response: ConnectedResponse::Scrape(PendingScrapeResponse {
+                    slab_key: request.slab_key,
+                    scrape_id: request.scrape_id,
+                    torrent_stats: vec![(
+                        0,
+                        TorrentScrapeStatistics {
+                            seeders: NumberOfPeers::new(1),
+                            leechers: NumberOfPeers::new(0),
+                            completed: NumberOfDownloads::new(0),
+                        },
+                    )],
+                }),
+                opt_received_at: None,
+            };
+
+            response_sender.send(response).unwrap();
+        };
+
+        let expired_request = send_scrape(&mut router, 1);
+
+        router.clean_pending_scrape_responses(&config, server_start_instant);
+
+        let request = send_scrape(&mut router, 2);
+
+        assert_eq!(request.slab_key, expired_request.slab_key);
+
+        let mut responses = Vec::new();
+
+        send_response(expired_request);
+        router.receive_responses(&mut responses);
+
+        assert!(responses.is_empty());
+
+        send_response(request);
+        router.receive_responses(&mut responses);
+
+        match responses.as_slice() {
+            [SwarmResponseToSend {
+                response: Response::Scrape(response),
+                ..
+            }] => assert_eq!(response.transaction_id, TransactionId::new(2)),
+            _ => panic!("expected single scrape response"),
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use std::io::{Cursor, ErrorKind};
+use std::io::{self, Cursor, ErrorKind};
 use std::marker::PhantomData;
+use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
+use std::time::Instant;
 
 use anyhow::Context;
 use mio::net::UdpSocket;
@@ -9,8 +11,11 @@ use socket2::{Domain, Protocol, Type};
 use aquatic_common::{privileges::PrivilegeDropper, CanonicalSocketAddr};
 use aquatic_udp_protocol::*;
 
+use crate::common::ResponseType;
 use crate::config::Config;
 
+#[cfg(target_os = "linux")]
+use super::batch::Batch;
 use super::{WorkerSharedData, EXTRA_PACKET_SIZE_IPV4, EXTRA_PACKET_SIZE_IPV6};
 
 pub trait IpVersion {
@@ -35,9 +40,13 @@ impl IpVersion for Ipv6 {
     }
 }
 
+type ResendBuffer = Vec<(CanonicalSocketAddr, Response, Option<Instant>)>;
+
 pub struct Socket<V> {
     pub socket: UdpSocket,
-    opt_resend_buffer: Option<Vec<(CanonicalSocketAddr, Response)>>,
+    opt_resend_buffer: Option<ResendBuffer>,
+    #[cfg(target_os = "linux")]
+    opt_batch: Option<Batch>,
     phantom_data: PhantomData<V>,
 }
 
@@ -73,6 +82,8 @@ impl Socket<Ipv4> {
         let mut s = Self {
             socket: UdpSocket::from_std(::std::net::UdpSocket::from(socket)),
             opt_resend_buffer: None,
+            #[cfg(target_os = "linux")]
+            opt_batch: None,
             phantom_data: Default::default(),
         };
 
@@ -80,6 +91,9 @@ impl Socket<Ipv4> {
             s.opt_resend_buffer = Some(Vec::new());
         }
 
+        #[cfg(target_os = "linux")]
+        s.init_batching(config);
+
         Ok(s)
     }
 }
@@ -121,6 +135,8 @@ impl Socket<Ipv6> {
         let mut s = Self {
             socket: UdpSocket::from_std(::std::net::UdpSocket::from(socket)),
             opt_resend_buffer: None,
+            #[cfg(target_os = "linux")]
+            opt_batch: None,
             phantom_data: Default::default(),
         };
 
@@ -128,175 +144,233 @@ impl Socket<Ipv6> {
             s.opt_resend_buffer = Some(Vec::new());
         }
 
+        #[cfg(target_os = "linux")]
+        s.init_batching(config);
+
         Ok(s)
     }
 }
 
 impl<V: IpVersion> Socket<V> {
+    #[cfg(target_os = "linux")]
+    fn init_batching(&mut self, config: &Config) {
+        if config.network.batch_size > 1 {
+            let use_gso = config.network.use_gso && {
+                let supported = super::batch::gso_supported(&self.socket);
+
+                if !supported {
+                    ::log::warn!("UDP GSO not supported by kernel, not using it");
+                }
+
+                supported
+            };
+
+            self.opt_batch = Some(Batch::new(config.network.batch_size, use_gso));
+        }
+    }
+
     pub fn read_and_handle_requests(&mut self, shared: &mut WorkerSharedData) {
+        #[cfg(target_os = "linux")]
+        if self.opt_batch.is_some() {
+            self.read_and_handle_requests_batched(shared);
+
+            return;
+        }
+
         let max_scrape_torrents = shared.config.protocol.max_scrape_torrents;
 
         loop {
             match self.socket.recv_from(&mut shared.buffer[..]) {
                 Ok((bytes_read, src)) => {
-                    let src_port = src.port();
-                    let src = CanonicalSocketAddr::new(src);
+                    let opt_received_at = shared.opt_request_latencies.is_some().then(Instant::now);
+                    let parse_result =
+                        Request::parse_bytes(&shared.buffer[..bytes_read], max_scrape_torrents);
+
+                    if let Some((src, response)) = handle_packet(
+                        shared,
+                        src,
+                        bytes_read,
+                        parse_result,
+                        V::is_v4(),
+                        opt_received_at,
+                    ) {
+                        self.send_response(shared, src, response, opt_received_at, false);
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => {
+                    ::log::warn!("recv_from error: {:#}", err);
+                }
+            }
+        }
+    }
 
-                    // Use canonical address for statistics
-                    let opt_statistics = if shared.config.statistics.active() {
-                        if src.is_ipv4() {
-                            let statistics = &shared.statistics.ipv4;
+    #[cfg(target_os = "linux")]
+    fn read_and_handle_requests_batched(&mut self, shared: &mut WorkerSharedData) {
+        let max_scrape_torrents = shared.config.protocol.max_scrape_torrents;
 
-                            statistics
-                                .bytes_received
-                                .fetch_add(bytes_read + EXTRA_PACKET_SIZE_IPV4, Ordering::Relaxed);
+        loop {
+            let batch = self.opt_batch.as_mut().unwrap();
 
-                            Some(statistics)
-                        } else {
-                            let statistics = &shared.statistics.ipv6;
+            match batch.recv.recv(&self.socket) {
+                Ok(num_received) => {
+                    let opt_received_at = shared.opt_request_latencies.is_some().then(Instant::now);
 
-                            statistics
-                                .bytes_received
-                                .fetch_add(bytes_read + EXTRA_PACKET_SIZE_IPV6, Ordering::Relaxed);
+                    for i in 0..num_received {
+                        let (opt_src, bytes) = batch.recv.packet(i);
 
-                            Some(statistics)
+                        let src = if let Some(src) = opt_src {
+                            src
+                        } else {
+                            continue;
+                        };
+
+                        let parse_result = Request::parse_bytes(bytes, max_scrape_torrents);
+
+                        if let Some((src, response)) = handle_packet(
+                            shared,
+                            src,
+                            bytes.len(),
+                            parse_result,
+                            V::is_v4(),
+                            opt_received_at,
+                        ) {
+                            batch
+                                .send
+                                .push(src, Self::send_addr(src), response, opt_received_at);
                         }
-                    } else {
-                        None
-                    };
-
-                    if src_port == 0 {
-                        ::log::debug!("Ignored request because source port is zero");
-
-                        continue;
                     }
 
-                    match Request::parse_bytes(&shared.buffer[..bytes_read], max_scrape_torrents) {
-                        Ok(request) => {
-                            if let Some(statistics) = opt_statistics {
-                                statistics.requests.fetch_add(1, Ordering::Relaxed);
-                            }
-
-                            if let Some(response) = shared.handle_request(request, src) {
-                                self.send_response(shared, src, response, false);
-                            }
-                        }
-                        Err(RequestParseError::Sendable {
-                            connection_id,
-                            transaction_id,
-                            err,
-                        }) if shared.validator.connection_id_valid(src, connection_id) => {
-                            let response = ErrorResponse {
-                                transaction_id,
-                                message: err.into(),
-                            };
-
-                            self.send_response(shared, src, Response::Error(response), false);
-
-                            ::log::debug!("request parse error (sent error response): {:?}", err);
-                        }
-                        Err(err) => {
-                            ::log::debug!(
-                                "request parse error (didn't send error response): {:?}",
-                                err
-                            );
-                        }
-                    };
+                    self.send_batch(shared, false);
                 }
                 Err(err) if err.kind() == ErrorKind::WouldBlock => {
                     break;
                 }
                 Err(err) => {
-                    ::log::warn!("recv_from error: {:#}", err);
+                    ::log::warn!("recvmmsg error: {:#}", err);
                 }
             }
         }
     }
+
     pub fn send_response(
         &mut self,
         shared: &mut WorkerSharedData,
         canonical_addr: CanonicalSocketAddr,
         response: Response,
+        opt_received_at: Option<Instant>,
         disable_resend_buffer: bool,
     ) {
         let mut buffer = Cursor::new(&mut shared.buffer[..]);
 
         if let Err(err) = response.write_bytes(&mut buffer) {
-            ::log::error!("failed writing response to buffer: {:#}", err);
+            ::log::error!(
+                peer_addr:% = canonical_addr.get();
+                "failed writing response to buffer: {:#}",
+                err
+            );
 
             return;
         }
 
         let bytes_written = buffer.position() as usize;
 
-        let addr = if V::is_v4() {
-            canonical_addr
-                .get_ipv4()
-                .expect("found peer ipv6 address while running bound to ipv4 address")
-        } else {
-            canonical_addr.get_ipv6_mapped()
-        };
+        let addr = Self::send_addr(canonical_addr);
 
         match self
             .socket
             .send_to(&buffer.into_inner()[..bytes_written], addr)
         {
-            Ok(bytes_sent) if shared.config.statistics.active() => {
-                let stats = if canonical_addr.is_ipv4() {
-                    let stats = &shared.statistics.ipv4;
+            Ok(bytes_sent) => record_response_sent(
+                shared,
+                canonical_addr,
+                &response,
+                bytes_sent,
+                opt_received_at,
+            ),
+            Err(err) => handle_send_error(
+                &mut self.opt_resend_buffer,
+                shared,
+                canonical_addr,
+                response,
+                opt_received_at,
+                err,
+                disable_resend_buffer,
+            ),
+        }
 
-                    stats
-                        .bytes_sent
-                        .fetch_add(bytes_sent + EXTRA_PACKET_SIZE_IPV4, Ordering::Relaxed);
+        ::log::debug!("send response fn finished");
+    }
 
-                    stats
-                } else {
-                    let stats = &shared.statistics.ipv6;
+    /// Send response, or queue it if batching is enabled. Call
+    /// `flush_responses` when done.
+    pub fn send_or_queue_response(
+        &mut self,
+        shared: &mut WorkerSharedData,
+        canonical_addr: CanonicalSocketAddr,
+        response: Response,
+        opt_received_at: Option<Instant>,
+    ) {
+        #[cfg(target_os = "linux")]
+        if let Some(batch) = self.opt_batch.as_mut() {
+            batch.send.push(
+                canonical_addr,
+                Self::send_addr(canonical_addr),
+                response,
+                opt_received_at,
+            );
+
+            if batch.send.is_full() {
+                self.send_batch(shared, false);
+            }
 
-                    stats
-                        .bytes_sent
-                        .fetch_add(bytes_sent + EXTRA_PACKET_SIZE_IPV6, Ordering::Relaxed);
+            return;
+        }
 
-                    stats
-                };
+        self.send_response(shared, canonical_addr, response, opt_received_at, false);
+    }
 
-                match response {
-                    Response::Connect(_) => {
-                        stats.responses_connect.fetch_add(1, Ordering::Relaxed);
-                    }
-                    Response::AnnounceIpv4(_) | Response::AnnounceIpv6(_) => {
-                        stats.responses_announce.fetch_add(1, Ordering::Relaxed);
-                    }
-                    Response::Scrape(_) => {
-                        stats.responses_scrape.fetch_add(1, Ordering::Relaxed);
-                    }
-                    Response::Error(_) => {
-                        stats.responses_error.fetch_add(1, Ordering::Relaxed);
-                    }
-                }
-            }
-            Ok(_) => (),
-            Err(err) => match self.opt_resend_buffer.as_mut() {
-                Some(resend_buffer)
-                    if !disable_resend_buffer
-                        && ((err.raw_os_error() == Some(libc::ENOBUFS))
-                            || (err.kind() == ErrorKind::WouldBlock)) =>
-                {
-                    if resend_buffer.len() < shared.config.network.resend_buffer_max_len {
-                        ::log::debug!("Adding response to resend queue, since sending it to {} failed with: {:#}", addr, err);
+    /// Send any responses queued by `send_or_queue_response`
+    pub fn flush_responses(&mut self, shared: &mut WorkerSharedData) {
+        #[cfg(target_os = "linux")]
+        self.send_batch(shared, false);
 
-                        resend_buffer.push((canonical_addr, response));
-                    } else {
-                        ::log::warn!("Response resend buffer full, dropping response");
-                    }
-                }
-                _ => {
-                    ::log::warn!("Sending response to {} failed: {:#}", addr, err);
-                }
-            },
-        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = shared;
+    }
 
-        ::log::debug!("send response fn finished");
+    /// Send responses queued in batch, if any
+    #[cfg(target_os = "linux")]
+    fn send_batch(&mut self, shared: &mut WorkerSharedData, disable_resend_buffer: bool) {
+        let batch = match self.opt_batch.as_mut() {
+            Some(batch) if !batch.send.is_empty() => batch,
+            _ => return,
+        };
+
+        batch.send.send(&self.socket);
+
+        for (canonical_addr, response, opt_received_at, result) in batch.send.drain() {
+            match result {
+                Ok(bytes_sent) => record_response_sent(
+                    shared,
+                    canonical_addr,
+                    &response,
+                    bytes_sent,
+                    opt_received_at,
+                ),
+                Err(err) => handle_send_error(
+                    &mut self.opt_resend_buffer,
+                    shared,
+                    canonical_addr,
+                    response,
+                    opt_received_at,
+                    err,
+                    disable_resend_buffer,
+                ),
+            }
+        }
     }
 
     /// If resend buffer is enabled, send any responses in it
@@ -311,13 +385,229 @@ impl<V: IpVersion> Socket<V> {
                 ::std::mem::swap(resend_buffer, &mut tmp_resend_buffer);
             }
 
-            for (addr, response) in tmp_resend_buffer.drain(..) {
-                self.send_response(shared, addr, response, true);
+            for (addr, response, opt_received_at) in tmp_resend_buffer.drain(..) {
+                #[cfg(target_os = "linux")]
+                if let Some(batch) = self.opt_batch.as_mut() {
+                    batch
+                        .send
+                        .push(addr, Self::send_addr(addr), response, opt_received_at);
+
+                    if batch.send.is_full() {
+                        self.send_batch(shared, true);
+                    }
+
+                    continue;
+                }
+
+                self.send_response(shared, addr, response, opt_received_at, true);
             }
 
+            #[cfg(target_os = "linux")]
+            self.send_batch(shared, true);
+
             if let Some(resend_buffer) = self.opt_resend_buffer.as_mut() {
                 ::std::mem::swap(resend_buffer, &mut tmp_resend_buffer);
             }
         }
     }
+
+    fn send_addr(canonical_addr: CanonicalSocketAddr) -> SocketAddr {
+        if V::is_v4() {
+            canonical_addr
+                .get_ipv4()
+                .expect("found peer ipv6 address while running bound to ipv4 address")
+        } else {
+            canonical_addr.get_ipv6_mapped()
+        }
+    }
+}
+
+/// Update statistics for received packet and return response to send, if any
+///
+/// When swarm workers are used, announce and scrape requests are passed on
+/// to them instead.
+pub fn handle_packet(
+    shared: &mut WorkerSharedData,
+    src: SocketAddr,
+    bytes_read: usize,
+    parse_result: Result<Request, RequestParseError>,
+    ipv4_socket: bool,
+    opt_received_at: Option<Instant>,
+) -> Option<(CanonicalSocketAddr, Response)> {
+    let src_port = src.port();
+    let src = CanonicalSocketAddr::new(src);
+
+    // Use canonical address for statistics
+    let opt_statistics = if shared.config.statistics.active() {
+        if src.is_ipv4() {
+            let statistics = &shared.statistics.ipv4;
+
+            statistics
+                .bytes_received
+                .fetch_add(bytes_read + EXTRA_PACKET_SIZE_IPV4, Ordering::Relaxed);
+
+            Some(statistics)
+        } else {
+            let statistics = &shared.statistics.ipv6;
+
+            statistics
+                .bytes_received
+                .fetch_add(bytes_read + EXTRA_PACKET_SIZE_IPV6, Ordering::Relaxed);
+
+            Some(statistics)
+        }
+    } else {
+        None
+    };
+
+    if src_port == 0 {
+        ::log::debug!("Ignored request because source port is zero");
+
+        return None;
+    }
+
+    match parse_result {
+        Ok(request) => {
+            if let Some(statistics) = opt_statistics {
+                statistics.requests.fetch_add(1, Ordering::Relaxed);
+            }
+
+            if let Request::Connect(_) = request {
+                if !shared
+                    .shared_state
+                    .connect_flood_guard
+                    .allow_connect(src.get().ip())
+                {
+                    if let Some(statistics) = opt_statistics {
+                        statistics.dropped_connects.fetch_add(1, Ordering::Relaxed);
+                    }
+
+                    ::log::debug!("Dropped connect request from {:?}", src);
+
+                    return None;
+                }
+            }
+
+            shared
+                .handle_request(request, src, ipv4_socket, opt_received_at)
+                .map(|response| (src, response))
+        }
+        Err(RequestParseError::Sendable {
+            connection_id,
+            transaction_id,
+            err,
+        }) if shared.validator.connection_id_valid(src, connection_id) => {
+            let response = ErrorResponse {
+                transaction_id,
+                message: err.into(),
+            };
+
+            ::log::debug!("request parse error (sent error response): {:?}", err);
+
+            Some((src, Response::Error(response)))
+        }
+        Err(err) => {
+            ::log::debug!(
+                "request parse error (didn't send error response): {:?}",
+                err
+            );
+
+            None
+        }
+    }
+}
+
+pub fn record_response_sent(
+    shared: &mut WorkerSharedData,
+    canonical_addr: CanonicalSocketAddr,
+    response: &Response,
+    bytes_sent: usize,
+    opt_received_at: Option<Instant>,
+) {
+    if !shared.config.statistics.active() {
+        return;
+    }
+
+    let stats = if canonical_addr.is_ipv4() {
+        let stats = &shared.statistics.ipv4;
+
+        stats
+            .bytes_sent
+            .fetch_add(bytes_sent + EXTRA_PACKET_SIZE_IPV4, Ordering::Relaxed);
+
+        stats
+    } else {
+        let stats = &shared.statistics.ipv6;
+
+        stats
+            .bytes_sent
+            .fetch_add(bytes_sent + EXTRA_PACKET_SIZE_IPV6, Ordering::Relaxed);
+
+        stats
+    };
+
+    match response {
+        Response::Connect(_) => {
+            stats.responses_connect.fetch_add(1, Ordering::Relaxed);
+        }
+        Response::AnnounceIpv4(_) | Response::AnnounceIpv6(_) => {
+            stats.responses_announce.fetch_add(1, Ordering::Relaxed);
+        }
+        Response::Scrape(_) => {
+            stats.responses_scrape.fetch_add(1, Ordering::Relaxed);
+        }
+        Response::Error(_) => {
+            stats.responses_error.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    if let (Some(request_latencies), Some(received_at)) =
+        (shared.opt_request_latencies.as_mut(), opt_received_at)
+    {
+        request_latencies.record(
+            canonical_addr.is_ipv4(),
+            ResponseType::from_response(response),
+            received_at,
+        );
+    }
+}
+
+fn handle_send_error(
+    opt_resend_buffer: &mut Option<ResendBuffer>,
+    shared: &WorkerSharedData,
+    canonical_addr: CanonicalSocketAddr,
+    response: Response,
+    opt_received_at: Option<Instant>,
+    err: io::Error,
+    disable_resend_buffer: bool,
+) {
+    match opt_resend_buffer.as_mut() {
+        Some(resend_buffer)
+            if !disable_resend_buffer
+                && ((err.raw_os_error() == Some(libc::ENOBUFS))
+                    || (err.kind() == ErrorKind::WouldBlock)) =>
+        {
+            if resend_buffer.len() < shared.config.network.resend_buffer_max_len {
+                ::log::debug!(
+                    "Adding response to resend queue, since sending it to {} failed with: {:#}",
+                    canonical_addr.get(),
+                    err
+                );
+
+                resend_buffer.push((canonical_addr, response, opt_received_at));
+            } else {
+                ::log::warn!(
+                    peer_addr:% = canonical_addr.get();
+                    "Response resend buffer full, dropping response"
+                );
+            }
+        }
+        _ => {
+            ::log::warn!(
+                peer_addr:% = canonical_addr.get();
+                "Sending response failed: {:#}",
+                err
+            );
+        }
+    }
 }
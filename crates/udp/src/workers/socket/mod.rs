@@ -1,23 +1,35 @@
+#[cfg(all(target_os = "linux", feature = "af-xdp"))]
+pub mod af_xdp;
+mod connect_flood;
 mod mio;
 #[cfg(all(target_os = "linux", feature = "io-uring"))]
 mod uring;
 mod validator;
 
+use std::time::{Duration, Instant};
+
 use aquatic_common::privileges::PrivilegeDropper;
 use crossbeam_channel::Sender;
 
 use crate::{
     common::{
-        CachePaddedArc, IpVersionStatistics, SocketWorkerStatistics, State, StatisticsMessage,
+        CachePaddedArc, IpVersionStatistics, RequestLatencyHistograms, ResponseType,
+        SocketWorkerStatistics, State, StatisticsMessage,
     },
     config::Config,
+    workers::swarm::SocketWorkerSwarmChannels,
 };
 
+pub use self::connect_flood::ConnectFloodGuard;
+pub use self::mio::create_poll_with_waker;
 pub use self::validator::ConnectionValidator;
 
 #[cfg(all(not(target_os = "linux"), feature = "io-uring"))]
 compile_error!("io_uring feature is only supported on Linux");
 
+#[cfg(all(not(target_os = "linux"), feature = "af-xdp"))]
+compile_error!("af-xdp feature is only supported on Linux");
+
 /// Bytes of data transmitted when sending an IPv4 UDP packet, in addition to payload size
 ///
 /// Consists of:
@@ -43,6 +55,7 @@ pub fn run_socket_worker(
     statistics_sender: Sender<StatisticsMessage>,
     validator: ConnectionValidator,
     priv_droppers: Vec<PrivilegeDropper>,
+    opt_swarm_channels: Option<SocketWorkerSwarmChannels>,
 ) -> anyhow::Result<()> {
     #[cfg(all(target_os = "linux", feature = "io-uring"))]
     if config.network.use_io_uring {
@@ -67,5 +80,66 @@ pub fn run_socket_worker(
         statistics_sender,
         validator,
         priv_droppers,
+        opt_swarm_channels,
     )
 }
+
+/// Socket worker local request latency histograms, regularly sent to the
+/// statistics worker
+struct RequestLatencyRecorder {
+    histograms: IpVersionStatistics<RequestLatencyHistograms>,
+    send_interval: Duration,
+    last_sent: Instant,
+}
+
+impl RequestLatencyRecorder {
+    fn new(config: &Config) -> Option<Self> {
+        if config.statistics.active() && config.statistics.request_latency_histograms {
+            Some(Self {
+                histograms: Default::default(),
+                send_interval: Duration::from_secs(config.statistics.interval),
+                last_sent: Instant::now(),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn record(
+        &mut self,
+        receiver_is_ipv4: bool,
+        response_type: ResponseType,
+        received_at: Instant,
+    ) {
+        let histograms = if receiver_is_ipv4 {
+            &mut self.histograms.ipv4
+        } else {
+            &mut self.histograms.ipv6
+        };
+
+        histograms.record(response_type, received_at.elapsed());
+    }
+
+    fn send_if_due(&mut self, statistics_sender: &Sender<StatisticsMessage>) {
+        if self.last_sent.elapsed() < self.send_interval {
+            return;
+        }
+
+        self.last_sent = Instant::now();
+
+        let ipv4 = ::std::mem::take(&mut self.histograms.ipv4);
+        let ipv6 = ::std::mem::take(&mut self.histograms.ipv6);
+
+        for message in [
+            (!ipv4.is_empty()).then_some(StatisticsMessage::Ipv4RequestLatencies(ipv4)),
+            (!ipv6.is_empty()).then_some(StatisticsMessage::Ipv6RequestLatencies(ipv6)),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if let Err(err) = statistics_sender.try_send(message) {
+                ::log::error!("couldn't send statistics message: {:#}", err);
+            }
+        }
+    }
+}
@@ -9,6 +9,7 @@ use std::net::UdpSocket;
 use std::ops::DerefMut;
 use std::os::fd::AsRawFd;
 use std::sync::atomic::Ordering;
+use std::time::Instant;
 
 use anyhow::Context;
 use aquatic_common::access_list::AccessListCache;
@@ -32,9 +33,10 @@ use crate::config::Config;
 
 use self::buf_ring::BufRing;
 use self::recv_helper::{RecvHelperV4, RecvHelperV6};
-use self::send_buffers::{ResponseType, SendBuffers};
+use self::send_buffers::SendBuffers;
 
 use super::validator::ConnectionValidator;
+use super::RequestLatencyRecorder;
 use super::{EXTRA_PACKET_SIZE_IPV4, EXTRA_PACKET_SIZE_IPV6};
 
 /// Size of each request buffer
@@ -93,13 +95,14 @@ pub struct SocketWorker {
     send_buffers: SendBuffers,
     recv_helper_v4: RecvHelperV4,
     recv_helper_v6: RecvHelperV6,
-    local_responses: VecDeque<(CanonicalSocketAddr, Response)>,
+    local_responses: VecDeque<(CanonicalSocketAddr, Response, Option<Instant>)>,
     resubmittable_sqe_buf: Vec<io_uring::squeue::Entry>,
     recv_sqe_ipv4: io_uring::squeue::Entry,
     recv_sqe_ipv6: io_uring::squeue::Entry,
     pulse_timeout_sqe: io_uring::squeue::Entry,
     peer_valid_until: ValidUntil,
     rng: SmallRng,
+    opt_request_latencies: Option<RequestLatencyRecorder>,
 }
 
 impl SocketWorker {
@@ -198,6 +201,8 @@ impl SocketWorker {
             config.cleaning.max_peer_age,
         );
 
+        let opt_request_latencies = RequestLatencyRecorder::new(&config);
+
         let mut worker = Self {
             config,
             shared_state,
@@ -218,6 +223,7 @@ impl SocketWorker {
             resubmittable_sqe_buf,
             peer_valid_until,
             rng: SmallRng::from_entropy(),
+            opt_request_latencies,
         };
 
         CurrentRing::with(|ring| worker.run_inner(ring));
@@ -241,7 +247,7 @@ impl SocketWorker {
 
             // Enqueue local responses
             for _ in 0..sq_space {
-                if let Some((addr, response)) = self.local_responses.pop_front() {
+                if let Some((addr, response, opt_received_at)) = self.local_responses.pop_front() {
                     let send_to_ipv4_socket = if addr.is_ipv4() {
                         if self.opt_socket_ipv4.is_some() {
                             true
@@ -256,22 +262,29 @@ impl SocketWorker {
                         panic!("IPv6 response with no IPv6 socket")
                     };
 
-                    match self
-                        .send_buffers
-                        .prepare_entry(send_to_ipv4_socket, response, addr)
-                    {
+                    match self.send_buffers.prepare_entry(
+                        send_to_ipv4_socket,
+                        response,
+                        addr,
+                        opt_received_at,
+                    ) {
                         Ok(entry) => {
                             unsafe { ring.submission().push(&entry).unwrap() };
 
                             num_send_added += 1;
                         }
                         Err(send_buffers::Error::NoBuffers(response)) => {
-                            self.local_responses.push_front((addr, response));
+                            self.local_responses
+                                .push_front((addr, response, opt_received_at));
 
                             break;
                         }
                         Err(send_buffers::Error::SerializationFailed(err)) => {
-                            ::log::error!("Failed serializing response: {:#}", err);
+                            ::log::error!(
+                                peer_addr:% = addr.get();
+                                "Failed serializing response: {:#}",
+                                err
+                            );
                         }
                     }
                 } else {
@@ -286,19 +299,28 @@ impl SocketWorker {
                 .submit_and_wait(num_send_added.max(1))
                 .unwrap();
 
+            self.shared_state.connect_flood_guard.update_window();
+
             for cqe in ring.completion() {
                 self.handle_cqe(cqe);
             }
 
             self.send_buffers.reset_likely_next_free_index();
+
+            if let Some(request_latencies) = self.opt_request_latencies.as_mut() {
+                request_latencies.send_if_due(&self.statistics_sender);
+            }
         }
     }
 
     fn handle_cqe(&mut self, cqe: io_uring::cqueue::Entry) {
         match cqe.user_data() {
             USER_DATA_RECV_V4 => {
+                let opt_received_at = self.opt_request_latencies.is_some().then(Instant::now);
+
                 if let Some((addr, response)) = self.handle_recv_cqe(&cqe, true) {
-                    self.local_responses.push_back((addr, response));
+                    self.local_responses
+                        .push_back((addr, response, opt_received_at));
                 }
 
                 if !io_uring::cqueue::more(cqe.flags()) {
@@ -306,8 +328,11 @@ impl SocketWorker {
                 }
             }
             USER_DATA_RECV_V6 => {
+                let opt_received_at = self.opt_request_latencies.is_some().then(Instant::now);
+
                 if let Some((addr, response)) = self.handle_recv_cqe(&cqe, false) {
-                    self.local_responses.push_back((addr, response));
+                    self.local_responses
+                        .push_back((addr, response, opt_received_at));
                 }
 
                 if !io_uring::cqueue::more(cqe.flags()) {
@@ -315,7 +340,7 @@ impl SocketWorker {
                 }
             }
             USER_DATA_PULSE_TIMEOUT => {
-                self.validator.update_elapsed();
+                self.validator.update_time();
 
                 self.peer_valid_until = ValidUntil::new(
                     self.shared_state.server_start_instant,
@@ -357,6 +382,13 @@ impl SocketWorker {
                     };
 
                     response_counter.fetch_add(1, Ordering::Relaxed);
+
+                    if let (Some(request_latencies), Some(received_at)) = (
+                        self.opt_request_latencies.as_mut(),
+                        self.send_buffers.request_received_at(send_buffer_index),
+                    ) {
+                        request_latencies.record(receiver_is_ipv4, response_type, received_at);
+                    }
                 }
 
                 // Safety: OK because cqe using buffer has been returned and
@@ -426,6 +458,28 @@ impl SocketWorker {
                     statistics.requests.fetch_add(1, Ordering::Relaxed);
                 }
 
+                if let Request::Connect(_) = request {
+                    if !self
+                        .shared_state
+                        .connect_flood_guard
+                        .allow_connect(addr.get().ip())
+                    {
+                        if self.config.statistics.active() {
+                            let statistics = if addr.is_ipv4() {
+                                &self.statistics.ipv4
+                            } else {
+                                &self.statistics.ipv6
+                            };
+
+                            statistics.dropped_connects.fetch_add(1, Ordering::Relaxed);
+                        }
+
+                        ::log::debug!("Dropped connect request from {:?}", addr);
+
+                        return None;
+                    }
+                }
+
                 return self.handle_request(request, addr);
             }
             Err(self::recv_helper::Error::RequestParseError(err, addr)) => {
@@ -500,6 +554,27 @@ impl SocketWorker {
                     .validator
                     .connection_id_valid(src, request.connection_id)
                 {
+                    if !self
+                        .shared_state
+                        .client_filter
+                        .load()
+                        .allows(self.config.client_filter.mode, &request.peer_id.0)
+                    {
+                        #[cfg(feature = "prometheus")]
+                        if self.config.statistics.metrics_active() {
+                            self.statistics_sender
+                                .try_send(StatisticsMessage::ClientRejected(request.peer_id))
+                                .expect("statistics channel should be unbounded");
+                        }
+
+                        let response = Response::Error(ErrorResponse {
+                            transaction_id: request.transaction_id,
+                            message: "Client not allowed".into(),
+                        });
+
+                        return Some((src, response));
+                    }
+
                     if self
                         .access_list_cache
                         .load()
@@ -4,12 +4,15 @@ use std::{
     mem::MaybeUninit,
     net::SocketAddr,
     ptr::{addr_of_mut, null_mut},
+    time::Instant,
 };
 
 use aquatic_common::CanonicalSocketAddr;
 use aquatic_udp_protocol::Response;
 use io_uring::opcode::SendMsg;
 
+use crate::common::ResponseType;
+
 use super::{RESPONSE_BUF_LEN, SOCKET_IDENTIFIER_V4, SOCKET_IDENTIFIER_V6};
 
 pub enum Error {
@@ -40,6 +43,10 @@ impl SendBuffers {
         (meta.response_type, meta.receiver_is_ipv4)
     }
 
+    pub fn request_received_at(&self, index: usize) -> Option<Instant> {
+        self.buffers.get(index).unwrap().0.opt_request_received_at
+    }
+
     /// # Safety
     ///
     /// Only safe to call once buffer is no longer referenced by in-flight
@@ -58,6 +65,7 @@ impl SendBuffers {
         send_to_ipv4_socket: bool,
         response: Response,
         addr: CanonicalSocketAddr,
+        opt_request_received_at: Option<Instant>,
     ) -> Result<io_uring::squeue::Entry, Error> {
         let index = if let Some(index) = self.next_free_index() {
             index
@@ -73,6 +81,7 @@ impl SendBuffers {
         match buffer.prepare_entry(response, addr, send_to_ipv4_socket, buffer_metadata) {
             Ok(entry) => {
                 buffer_metadata.free = false;
+                buffer_metadata.opt_request_received_at = opt_request_received_at;
 
                 self.likely_next_free_index = index + 1;
 
@@ -209,6 +218,8 @@ struct SendBufferMetadata {
     receiver_is_ipv4: bool,
     /// Only used for statistics
     response_type: ResponseType,
+    /// Only used for request latency statistics
+    opt_request_received_at: Option<Instant>,
 }
 
 impl Default for SendBufferMetadata {
@@ -217,26 +228,7 @@ impl Default for SendBufferMetadata {
             free: true,
             receiver_is_ipv4: true,
             response_type: Default::default(),
-        }
-    }
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
-pub enum ResponseType {
-    #[default]
-    Connect,
-    Announce,
-    Scrape,
-    Error,
-}
-
-impl ResponseType {
-    fn from_response(response: &Response) -> Self {
-        match response {
-            Response::Connect(_) => Self::Connect,
-            Response::AnnounceIpv4(_) | Response::AnnounceIpv6(_) => Self::Announce,
-            Response::Scrape(_) => Self::Scrape,
-            Response::Error(_) => Self::Error,
+            opt_request_received_at: None,
         }
     }
 }
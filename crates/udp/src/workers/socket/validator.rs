@@ -1,5 +1,5 @@
 use std::net::IpAddr;
-use std::time::Instant;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
 use constant_time_eq::constant_time_eq;
@@ -10,9 +10,16 @@ use aquatic_udp_protocol::ConnectionId;
 
 use crate::config::Config;
 
+/// Context string for deriving key rotation secret from shared secret file
+/// contents
+const SECRET_CONTEXT: &str = "aquatic_udp 2024 connection id shared secret";
+
+/// Minimum length of shared secret (bytes)
+const MIN_SECRET_LEN: usize = 16;
+
 /// HMAC (BLAKE3) based ConnectionId creator and validator
 ///
-/// Method update_elapsed must be called at least once a minute.
+/// Method update_time must be called at least once a minute.
 ///
 /// The purpose of using ConnectionIds is to make IP spoofing costly, mainly to
 /// prevent the tracker from being used as an amplification vector for DDoS
@@ -22,48 +29,109 @@ use crate::config::Config;
 /// `max_connection_age` seconds, a short duration to get value for the
 /// bandwidth spent brute forcing it.
 ///
+/// Hash keys are derived from a secret, which is either random or read from
+/// a file, and the current key rotation period. Instances sharing the secret
+/// (and having synchronized clocks) accept each other's ConnectionIds. Ids
+/// created with the key of the previous period are accepted until they
+/// expire, and ids created with the key of the next period are accepted to
+/// allow for small clock differences between instances.
+///
 /// Structure of created ConnectionID (bytes making up inner i64):
-/// - &[0..4]: ConnectionId creation time as number of seconds after
-///   ConnectionValidator instance was created, encoded as u32 bytes. A u32
-///   fits around 136 years in seconds.
+/// - &[0..4]: ConnectionId creation time as number of seconds since the
+///   UNIX epoch, encoded as u32 bytes. A u32 fits times until 2106.
 /// - &[4..8]: truncated keyed BLAKE3 hash of:
 ///     - previous 4 bytes
 ///     - octets of client IP address
 #[derive(Clone)]
 pub struct ConnectionValidator {
-    start_time: Instant,
+    secret: [u8; 32],
     max_connection_age: u64,
-    keyed_hasher: blake3::Hasher,
-    seconds_since_start: u32,
+    key_rotation_interval: u64,
+    /// Key rotation periods of keyed_hashers: previous, current and next
+    periods: [u64; 3],
+    keyed_hashers: [blake3::Hasher; 3],
+    now: u32,
 }
 
 impl ConnectionValidator {
     /// Create new instance. Must be created once and cloned if used in several
     /// threads.
     pub fn new(config: &Config) -> anyhow::Result<Self> {
-        let mut key = [0; 32];
+        let secret_path = &config.connection_ids.shared_secret_path;
+
+        let secret = if secret_path.as_os_str().is_empty() {
+            let mut secret = [0; 32];
 
-        getrandom(&mut key)
-            .with_context(|| "Couldn't get random bytes for ConnectionValidator key")?;
+            getrandom(&mut secret)
+                .with_context(|| "Couldn't get random bytes for ConnectionValidator key")?;
+
+            secret
+        } else {
+            let contents = ::std::fs::read(secret_path).with_context(|| {
+                format!("Couldn't read shared secret file {}", secret_path.display())
+            })?;
+
+            // Ignore leading and trailing whitespace, such as newlines
+            let contents = {
+                let is_not_whitespace = |b: &u8| !b.is_ascii_whitespace();
+
+                let start = contents
+                    .iter()
+                    .position(is_not_whitespace)
+                    .unwrap_or(contents.len());
+                let end = contents
+                    .iter()
+                    .rposition(is_not_whitespace)
+                    .map_or(start, |i| i + 1);
+
+                &contents[start..end]
+            };
+
+            if contents.len() < MIN_SECRET_LEN {
+                return Err(anyhow::anyhow!(
+                    "Shared secret must be at least {} bytes long",
+                    MIN_SECRET_LEN
+                ));
+            }
+
+            blake3::derive_key(SECRET_CONTEXT, contents)
+        };
 
-        let keyed_hasher = blake3::Hasher::new_keyed(&key);
+        let max_connection_age = config.cleaning.max_connection_age.into();
+        let key_rotation_interval = config.connection_ids.key_rotation_interval;
 
-        Ok(Self {
-            keyed_hasher,
-            start_time: Instant::now(),
-            max_connection_age: config.cleaning.max_connection_age.into(),
-            seconds_since_start: 0,
-        })
+        if key_rotation_interval != 0 && key_rotation_interval < max_connection_age {
+            return Err(anyhow::anyhow!(
+                "connection_ids.key_rotation_interval must be 0 or at least cleaning.max_connection_age"
+            ));
+        }
+
+        let mut validator = Self {
+            secret,
+            max_connection_age,
+            key_rotation_interval,
+            periods: [u64::MAX; 3],
+            keyed_hashers: [
+                blake3::Hasher::new(),
+                blake3::Hasher::new(),
+                blake3::Hasher::new(),
+            ],
+            now: 0,
+        };
+
+        validator.update_time();
+
+        Ok(validator)
     }
 
     pub fn create_connection_id(&mut self, source_addr: CanonicalSocketAddr) -> ConnectionId {
-        let elapsed = (self.seconds_since_start).to_ne_bytes();
+        let time = self.now.to_ne_bytes();
 
-        let hash = self.hash(elapsed, source_addr.get().ip());
+        let hash = Self::hash(&mut self.keyed_hashers[1], time, source_addr.get().ip());
 
         let mut connection_id_bytes = [0u8; 8];
 
-        connection_id_bytes[..4].copy_from_slice(&elapsed);
+        connection_id_bytes[..4].copy_from_slice(&time);
         connection_id_bytes[4..].copy_from_slice(&hash);
 
         ConnectionId::new(i64::from_ne_bytes(connection_id_bytes))
@@ -75,44 +143,86 @@ impl ConnectionValidator {
         connection_id: ConnectionId,
     ) -> bool {
         let bytes = connection_id.0.get().to_ne_bytes();
-        let (elapsed, hash) = bytes.split_at(4);
-        let elapsed: [u8; 4] = elapsed.try_into().unwrap();
+        let (time, hash) = bytes.split_at(4);
+        let time: [u8; 4] = time.try_into().unwrap();
+
+        let client_time = u64::from(u32::from_ne_bytes(time));
+        let client_period = self.period(client_time);
+
+        let keyed_hasher = if let Some(index) = self
+            .periods
+            .iter()
+            .position(|period| *period == client_period)
+        {
+            &mut self.keyed_hashers[index]
+        } else {
+            return false;
+        };
 
-        if !constant_time_eq(hash, &self.hash(elapsed, source_addr.get().ip())) {
+        if !constant_time_eq(
+            hash,
+            &Self::hash(keyed_hasher, time, source_addr.get().ip()),
+        ) {
             return false;
         }
 
-        let seconds_since_start = self.seconds_since_start as u64;
-        let client_elapsed = u64::from(u32::from_ne_bytes(elapsed));
-        let client_expiration_time = client_elapsed + self.max_connection_age;
+        let now = u64::from(self.now);
+        let client_expiration_time = client_time + self.max_connection_age;
 
         // In addition to checking if the client connection is expired,
-        // disallow client_elapsed values that are too far in future and thus
-        // could not have been sent by the tracker. This prevents brute forcing
-        // with `u32::MAX` as 'elapsed' part of ConnectionId to find a hash that
-        // works until the tracker is restarted.
-        let client_not_expired = client_expiration_time > seconds_since_start;
-        let client_elapsed_not_in_far_future = client_elapsed <= (seconds_since_start + 60);
-
-        client_not_expired & client_elapsed_not_in_far_future
+        // disallow client_time values that are too far in future and thus
+        // could not have been sent by the tracker (or another instance
+        // sharing the secret). This prevents brute forcing with `u32::MAX` as
+        // 'time' part of ConnectionId to find a hash that works until the
+        // key is rotated.
+        let client_not_expired = client_expiration_time > now;
+        let client_time_not_in_far_future = client_time <= (now + 60);
+
+        client_not_expired & client_time_not_in_far_future
+    }
+
+    pub fn update_time(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        self.set_time(now.try_into().unwrap_or(u32::MAX));
+    }
+
+    fn set_time(&mut self, now: u32) {
+        self.now = now;
+
+        let period = self.period(now.into());
+
+        if self.periods[1] != period {
+            self.periods = [period.saturating_sub(1), period, period.saturating_add(1)];
+
+            for (keyed_hasher, period) in self.keyed_hashers.iter_mut().zip(self.periods) {
+                let key = blake3::keyed_hash(&self.secret, &period.to_le_bytes());
+
+                *keyed_hasher = blake3::Hasher::new_keyed(key.as_bytes());
+            }
+        }
     }
 
-    pub fn update_elapsed(&mut self) {
-        self.seconds_since_start = self.start_time.elapsed().as_secs() as u32;
+    /// Key rotation period of time (always 0 if keys aren't rotated)
+    fn period(&self, time: u64) -> u64 {
+        time.checked_div(self.key_rotation_interval).unwrap_or(0)
     }
 
-    fn hash(&mut self, elapsed: [u8; 4], ip_addr: IpAddr) -> [u8; 4] {
-        self.keyed_hasher.update(&elapsed);
+    fn hash(keyed_hasher: &mut blake3::Hasher, time: [u8; 4], ip_addr: IpAddr) -> [u8; 4] {
+        keyed_hasher.update(&time);
 
         match ip_addr {
-            IpAddr::V4(ip) => self.keyed_hasher.update(&ip.octets()),
-            IpAddr::V6(ip) => self.keyed_hasher.update(&ip.octets()),
+            IpAddr::V4(ip) => keyed_hasher.update(&ip.octets()),
+            IpAddr::V6(ip) => keyed_hasher.update(&ip.octets()),
         };
 
         let mut hash = [0u8; 4];
 
-        self.keyed_hasher.finalize_xof().fill(&mut hash);
-        self.keyed_hasher.reset();
+        keyed_hasher.finalize_xof().fill(&mut hash);
+        keyed_hasher.reset();
 
         hash
     }
@@ -162,4 +272,66 @@ mod tests {
             quickcheck::TestResult::from_bool(original_valid)
         }
     }
+
+    #[test]
+    fn test_connection_validator_shared_secret() {
+        use std::io::Write;
+
+        let mut secret_file = tempfile::NamedTempFile::new().unwrap();
+
+        writeln!(secret_file, "0123456789abcdef0123456789abcdef").unwrap();
+
+        let mut config = Config::default();
+
+        config.cleaning.max_connection_age = 120;
+        config.connection_ids.key_rotation_interval = 600;
+
+        let mut random_secret = ConnectionValidator::new(&config).unwrap();
+
+        config.connection_ids.shared_secret_path = secret_file.path().into();
+
+        let mut a = ConnectionValidator::new(&config).unwrap();
+        let mut b = ConnectionValidator::new(&config).unwrap();
+
+        let addr = CanonicalSocketAddr::new(SocketAddr::new([127, 0, 0, 1].into(), 1));
+
+        // Same key rotation period
+        a.set_time(1000);
+        b.set_time(1050);
+        random_secret.set_time(1050);
+
+        let connection_id = a.create_connection_id(addr);
+
+        assert!(b.connection_id_valid(addr, connection_id));
+        assert!(!random_secret.connection_id_valid(addr, connection_id));
+
+        b.set_time(1120);
+
+        assert!(!b.connection_id_valid(addr, connection_id));
+
+        // Previous key is accepted until connection id expires
+        a.set_time(1190);
+        b.set_time(1250);
+
+        let connection_id = a.create_connection_id(addr);
+
+        assert!(b.connection_id_valid(addr, connection_id));
+
+        b.set_time(1310);
+
+        assert!(!b.connection_id_valid(addr, connection_id));
+
+        // Next key is accepted if clocks differ slightly
+        a.set_time(1205);
+        b.set_time(1195);
+
+        let connection_id = a.create_connection_id(addr);
+
+        assert!(b.connection_id_valid(addr, connection_id));
+
+        // Rotation interval shorter than max connection age is not allowed
+        config.connection_ids.key_rotation_interval = 60;
+
+        assert!(ConnectionValidator::new(&config).is_err());
+    }
 }
@@ -1,10 +1,14 @@
 use std::sync::atomic::Ordering;
 use std::time::Instant;
 
+#[cfg(feature = "prometheus")]
+use aquatic_common::statistics::TopTorrentMetrics;
+use aquatic_common::statistics::TopTorrents;
 use hdrhistogram::Histogram;
 use num_format::{Locale, ToFormattedString};
 use serde::Serialize;
 
+use crate::common::RequestLatencyHistograms;
 use crate::config::Config;
 
 use super::{IpVersion, Statistics};
@@ -26,6 +30,10 @@ pub struct StatisticsCollector {
     ip_version: IpVersion,
     last_update: Instant,
     last_complete_histogram: PeerHistogramStatistics,
+    last_top_torrents: Option<TopTorrents>,
+    pending_request_latencies: RequestLatencyHistograms,
+    #[cfg(feature = "prometheus")]
+    top_torrent_metrics: TopTorrentMetrics,
 }
 
 impl StatisticsCollector {
@@ -34,6 +42,10 @@ impl StatisticsCollector {
             statistics,
             last_update: Instant::now(),
             last_complete_histogram: Default::default(),
+            last_top_torrents: None,
+            pending_request_latencies: Default::default(),
+            #[cfg(feature = "prometheus")]
+            top_torrent_metrics: TopTorrentMetrics::new(ip_version.prometheus_str()),
             ip_version,
         }
     }
@@ -42,10 +54,15 @@ impl StatisticsCollector {
         self.last_complete_histogram = PeerHistogramStatistics::new(histogram);
     }
 
-    pub fn collect_from_shared(
-        &mut self,
-        #[cfg(feature = "prometheus")] config: &Config,
-    ) -> CollectedStatistics {
+    pub fn add_top_torrents(&mut self, top_torrents: TopTorrents) {
+        self.last_top_torrents = Some(top_torrents);
+    }
+
+    pub fn add_request_latencies(&mut self, histograms: RequestLatencyHistograms) {
+        self.pending_request_latencies.add(&histograms);
+    }
+
+    pub fn collect_from_shared(&mut self, config: &Config) -> CollectedStatistics {
         let mut requests = 0;
         let mut responses_connect: usize = 0;
         let mut responses_announce: usize = 0;
@@ -53,6 +70,7 @@ impl StatisticsCollector {
         let mut responses_error: usize = 0;
         let mut bytes_received: usize = 0;
         let mut bytes_sent: usize = 0;
+        let mut dropped_connects: usize = 0;
 
         #[cfg(feature = "prometheus")]
         let ip_version_prometheus_str = self.ip_version.prometheus_str();
@@ -70,7 +88,7 @@ impl StatisticsCollector {
                 requests += n;
 
                 #[cfg(feature = "prometheus")]
-                if config.statistics.run_prometheus_endpoint {
+                if config.statistics.metrics_active() {
                     ::metrics::counter!(
                         "aquatic_requests_total",
                         "ip_version" => ip_version_prometheus_str,
@@ -85,7 +103,7 @@ impl StatisticsCollector {
                 responses_connect += n;
 
                 #[cfg(feature = "prometheus")]
-                if config.statistics.run_prometheus_endpoint {
+                if config.statistics.metrics_active() {
                     ::metrics::counter!(
                         "aquatic_responses_total",
                         "type" => "connect",
@@ -103,7 +121,7 @@ impl StatisticsCollector {
                 responses_announce += n;
 
                 #[cfg(feature = "prometheus")]
-                if config.statistics.run_prometheus_endpoint {
+                if config.statistics.metrics_active() {
                     ::metrics::counter!(
                         "aquatic_responses_total",
                         "type" => "announce",
@@ -119,7 +137,7 @@ impl StatisticsCollector {
                 responses_scrape += n;
 
                 #[cfg(feature = "prometheus")]
-                if config.statistics.run_prometheus_endpoint {
+                if config.statistics.metrics_active() {
                     ::metrics::counter!(
                         "aquatic_responses_total",
                         "type" => "scrape",
@@ -135,7 +153,7 @@ impl StatisticsCollector {
                 responses_error += n;
 
                 #[cfg(feature = "prometheus")]
-                if config.statistics.run_prometheus_endpoint {
+                if config.statistics.metrics_active() {
                     ::metrics::counter!(
                         "aquatic_responses_total",
                         "type" => "error",
@@ -151,7 +169,7 @@ impl StatisticsCollector {
                 bytes_received += n;
 
                 #[cfg(feature = "prometheus")]
-                if config.statistics.run_prometheus_endpoint {
+                if config.statistics.metrics_active() {
                     ::metrics::counter!(
                         "aquatic_rx_bytes",
                         "ip_version" => ip_version_prometheus_str,
@@ -166,7 +184,7 @@ impl StatisticsCollector {
                 bytes_sent += n;
 
                 #[cfg(feature = "prometheus")]
-                if config.statistics.run_prometheus_endpoint {
+                if config.statistics.metrics_active() {
                     ::metrics::counter!(
                         "aquatic_tx_bytes",
                         "ip_version" => ip_version_prometheus_str,
@@ -175,6 +193,21 @@ impl StatisticsCollector {
                     .increment(n.try_into().unwrap());
                 }
             }
+            if config.connect_flood.is_active() {
+                let n = statistics.dropped_connects.fetch_and(0, Ordering::Relaxed);
+
+                dropped_connects += n;
+
+                #[cfg(feature = "prometheus")]
+                if config.statistics.metrics_active() {
+                    ::metrics::counter!(
+                        "aquatic_dropped_connect_requests_total",
+                        "ip_version" => ip_version_prometheus_str,
+                        "worker_index" => i.to_string(),
+                    )
+                    .increment(n.try_into().unwrap());
+                }
+            }
         }
 
         let swarm_statistics = &self.statistics.swarm.by_ip_version(self.ip_version);
@@ -183,7 +216,7 @@ impl StatisticsCollector {
             let num_torrents = swarm_statistics.torrents.load(Ordering::Relaxed);
 
             #[cfg(feature = "prometheus")]
-            if config.statistics.run_prometheus_endpoint {
+            if config.statistics.metrics_active() {
                 ::metrics::gauge!(
                     "aquatic_torrents",
                     "ip_version" => ip_version_prometheus_str,
@@ -198,7 +231,7 @@ impl StatisticsCollector {
             let num_peers = swarm_statistics.peers.load(Ordering::Relaxed);
 
             #[cfg(feature = "prometheus")]
-            if config.statistics.run_prometheus_endpoint {
+            if config.statistics.metrics_active() {
                 ::metrics::gauge!(
                     "aquatic_peers",
                     "ip_version" => ip_version_prometheus_str,
@@ -209,6 +242,54 @@ impl StatisticsCollector {
             num_peers
         };
 
+        let torrent_limits = config.torrent_limits.is_active().then(|| {
+            let rejected_torrents = swarm_statistics.rejected_torrents.load(Ordering::Relaxed);
+            let rejected_peers = swarm_statistics.rejected_peers.load(Ordering::Relaxed);
+            let evicted_torrents = swarm_statistics.evicted_torrents.load(Ordering::Relaxed);
+
+            #[cfg(feature = "prometheus")]
+            if config.statistics.metrics_active() {
+                ::metrics::counter!(
+                    "aquatic_torrent_limit_rejections_total",
+                    "type" => "torrent",
+                    "ip_version" => ip_version_prometheus_str,
+                )
+                .absolute(rejected_torrents as u64);
+                ::metrics::counter!(
+                    "aquatic_torrent_limit_rejections_total",
+                    "type" => "peer",
+                    "ip_version" => ip_version_prometheus_str,
+                )
+                .absolute(rejected_peers as u64);
+                ::metrics::counter!(
+                    "aquatic_torrent_evictions_total",
+                    "ip_version" => ip_version_prometheus_str,
+                )
+                .absolute(evicted_torrents as u64);
+            }
+
+            TorrentLimitStatistics {
+                rejected_torrents: rejected_torrents.to_formatted_string(&Locale::en),
+                rejected_peers: rejected_peers.to_formatted_string(&Locale::en),
+                evicted_torrents: evicted_torrents.to_formatted_string(&Locale::en),
+            }
+        });
+
+        let rejected_ip_peers = config.peer_ip_limits.is_active().then(|| {
+            let rejected_ip_peers = swarm_statistics.rejected_ip_peers.load(Ordering::Relaxed);
+
+            #[cfg(feature = "prometheus")]
+            if config.statistics.metrics_active() {
+                ::metrics::counter!(
+                    "aquatic_peer_ip_limit_rejections_total",
+                    "ip_version" => ip_version_prometheus_str,
+                )
+                .absolute(rejected_ip_peers as u64);
+            }
+
+            rejected_ip_peers.to_formatted_string(&Locale::en)
+        });
+
         let elapsed = {
             let now = Instant::now();
 
@@ -220,11 +301,32 @@ impl StatisticsCollector {
         };
 
         #[cfg(feature = "prometheus")]
-        if config.statistics.run_prometheus_endpoint && config.statistics.torrent_peer_histograms {
+        if config.statistics.metrics_active() && config.statistics.torrent_peer_histograms {
             self.last_complete_histogram
                 .update_metrics(ip_version_prometheus_str);
         }
 
+        #[cfg(feature = "prometheus")]
+        if config.statistics.metrics_active() {
+            if let Some(top_torrents) = self.last_top_torrents.as_ref() {
+                self.top_torrent_metrics.export(top_torrents);
+            }
+        }
+
+        let request_latencies = if config.statistics.request_latency_histograms {
+            let histograms = ::std::mem::take(&mut self.pending_request_latencies);
+            let request_latencies = RequestLatencyStatistics::new(&histograms);
+
+            #[cfg(feature = "prometheus")]
+            if config.statistics.metrics_active() {
+                request_latencies.update_metrics(&histograms, ip_version_prometheus_str);
+            }
+
+            Some(request_latencies)
+        } else {
+            None
+        };
+
         let requests_per_second = requests as f64 / elapsed;
         let responses_per_second_connect = responses_connect as f64 / elapsed;
         let responses_per_second_announce = responses_announce as f64 / elapsed;
@@ -232,6 +334,7 @@ impl StatisticsCollector {
         let responses_per_second_error = responses_error as f64 / elapsed;
         let bytes_received_per_second = bytes_received as f64 / elapsed;
         let bytes_sent_per_second = bytes_sent as f64 / elapsed;
+        let dropped_connects_per_second = dropped_connects as f64 / elapsed;
 
         let responses_per_second_total = responses_per_second_connect
             + responses_per_second_announce
@@ -252,9 +355,17 @@ impl StatisticsCollector {
                 .to_formatted_string(&Locale::en),
             rx_mbits: format!("{:.2}", bytes_received_per_second * 8.0 / 1_000_000.0),
             tx_mbits: format!("{:.2}", bytes_sent_per_second * 8.0 / 1_000_000.0),
+            dropped_connects_per_second: config
+                .connect_flood
+                .is_active()
+                .then(|| (dropped_connects_per_second as usize).to_formatted_string(&Locale::en)),
             num_torrents: num_torrents.to_formatted_string(&Locale::en),
             num_peers: num_peers.to_formatted_string(&Locale::en),
+            torrent_limits,
+            rejected_ip_peers,
             peer_histogram: self.last_complete_histogram.clone(),
+            top_torrents: self.last_top_torrents.clone(),
+            request_latencies,
         }
     }
 }
@@ -269,9 +380,24 @@ pub struct CollectedStatistics {
     pub responses_per_second_error: String,
     pub rx_mbits: String,
     pub tx_mbits: String,
+    /// Connect requests dropped by flood protection
+    pub dropped_connects_per_second: Option<String>,
     pub num_torrents: String,
     pub num_peers: String,
+    pub torrent_limits: Option<TorrentLimitStatistics>,
+    /// Peers rejected because of per-address peer limit (total)
+    pub rejected_ip_peers: Option<String>,
     pub peer_histogram: PeerHistogramStatistics,
+    pub top_torrents: Option<TopTorrents>,
+    pub request_latencies: Option<RequestLatencyStatistics>,
+}
+
+/// Totals since tracker start
+#[derive(Clone, Debug, Serialize)]
+pub struct TorrentLimitStatistics {
+    pub rejected_torrents: String,
+    pub rejected_peers: String,
+    pub evicted_torrents: String,
 }
 
 #[derive(Clone, Debug, Serialize, Default)]
@@ -329,3 +455,76 @@ impl PeerHistogramStatistics {
         set_peer_histogram_gauge!(ip_version, self.max, "max");
     }
 }
+
+/// Request latency percentiles in microseconds
+#[derive(Clone, Debug, Serialize)]
+pub struct RequestLatencyStatistics {
+    pub connect: LatencyPercentiles,
+    pub announce: LatencyPercentiles,
+    pub scrape: LatencyPercentiles,
+    pub error: LatencyPercentiles,
+}
+
+impl RequestLatencyStatistics {
+    fn new(histograms: &RequestLatencyHistograms) -> Self {
+        Self {
+            connect: LatencyPercentiles::new(&histograms.connect),
+            announce: LatencyPercentiles::new(&histograms.announce),
+            scrape: LatencyPercentiles::new(&histograms.scrape),
+            error: LatencyPercentiles::new(&histograms.error),
+        }
+    }
+
+    /// Only set gauges for response types that were sent during the
+    /// interval, so that stale values are removed when idle
+    #[cfg(feature = "prometheus")]
+    fn update_metrics(&self, histograms: &RequestLatencyHistograms, ip_version: &'static str) {
+        for (type_label, histogram, percentiles) in [
+            ("connect", &histograms.connect, &self.connect),
+            ("announce", &histograms.announce, &self.announce),
+            ("scrape", &histograms.scrape, &self.scrape),
+            ("error", &histograms.error, &self.error),
+        ] {
+            if histogram.is_empty() {
+                continue;
+            }
+
+            for (percentile_label, value) in [
+                ("p50", percentiles.p50),
+                ("p90", percentiles.p90),
+                ("p99", percentiles.p99),
+                ("p999", percentiles.p999),
+                ("max", percentiles.max),
+            ] {
+                ::metrics::gauge!(
+                    "aquatic_request_latency_microseconds",
+                    "type" => type_label,
+                    "percentile" => percentile_label,
+                    "ip_version" => ip_version,
+                )
+                .set(value as f64);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Default)]
+pub struct LatencyPercentiles {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64,
+}
+
+impl LatencyPercentiles {
+    fn new(h: &Histogram<u64>) -> Self {
+        Self {
+            p50: h.value_at_percentile(50.0),
+            p90: h.value_at_percentile(90.0),
+            p99: h.value_at_percentile(99.0),
+            p999: h.value_at_percentile(99.9),
+            max: h.max(),
+        }
+    }
+}
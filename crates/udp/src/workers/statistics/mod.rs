@@ -1,10 +1,13 @@
 mod collector;
 
-use std::fs::File;
-use std::io::Write;
 use std::time::{Duration, Instant};
 
-use anyhow::Context;
+#[cfg(feature = "prometheus")]
+use aquatic_common::client_filter::rejected_client_label;
+use aquatic_common::statistics::{
+    save_html_to_file, spawn_http_server, Page, PageSection, PageTable, PageTemplate,
+    StatisticsPages,
+};
 use aquatic_common::IndexMap;
 use aquatic_udp_protocol::{PeerClient, PeerId};
 use compact_str::CompactString;
@@ -13,32 +16,18 @@ use num_format::{Locale, ToFormattedString};
 use serde::Serialize;
 use time::format_description::well_known::Rfc2822;
 use time::OffsetDateTime;
-use tinytemplate::TinyTemplate;
 
 use collector::{CollectedStatistics, StatisticsCollector};
 
 use crate::common::*;
 use crate::config::Config;
 
-const TEMPLATE_KEY: &str = "statistics";
-const TEMPLATE_CONTENTS: &str = include_str!("../../../templates/statistics.html");
-const STYLESHEET_CONTENTS: &str = concat!(
-    "<style>",
-    include_str!("../../../templates/statistics.css"),
-    "</style>"
-);
-
 #[derive(Debug, Serialize)]
-struct TemplateData {
-    stylesheet: String,
-    ipv4_active: bool,
-    ipv6_active: bool,
-    extended_active: bool,
-    ipv4: CollectedStatistics,
-    ipv6: CollectedStatistics,
-    last_updated: String,
-    peer_update_interval: String,
-    peer_clients: Vec<(String, String)>,
+struct JsonData<'a> {
+    last_updated: &'a str,
+    ipv4: Option<&'a CollectedStatistics>,
+    ipv6: Option<&'a CollectedStatistics>,
+    peer_clients: &'a [(String, String)],
 }
 
 pub fn run_statistics_worker(
@@ -47,24 +36,31 @@ pub fn run_statistics_worker(
     statistics: Statistics,
     statistics_receiver: Receiver<StatisticsMessage>,
 ) -> anyhow::Result<()> {
+    let render_html = config.statistics.write_html_to_file | config.statistics.run_http_server;
+
     let process_peer_client_data = {
-        let mut collect = config.statistics.write_html_to_file;
+        let mut collect = render_html;
 
         #[cfg(feature = "prometheus")]
         {
-            collect |= config.statistics.run_prometheus_endpoint;
+            collect |= config.statistics.metrics_active();
         }
 
         collect & config.statistics.peer_clients
     };
 
-    let opt_tt = if config.statistics.write_html_to_file {
-        let mut tt = TinyTemplate::new();
+    let opt_template = if render_html {
+        Some(PageTemplate::new()?)
+    } else {
+        None
+    };
+
+    let opt_pages = if config.statistics.run_http_server {
+        let pages = StatisticsPages::default();
 
-        tt.add_template(TEMPLATE_KEY, TEMPLATE_CONTENTS)
-            .context("parse statistics html template")?;
+        spawn_http_server(config.statistics.http_server_address, pages.clone())?;
 
-        Some(tt)
+        Some(pages)
     } else {
         None
     };
@@ -83,6 +79,14 @@ pub fn run_statistics_worker(
             match message {
                 StatisticsMessage::Ipv4PeerHistogram(h) => ipv4_collector.add_histogram(h),
                 StatisticsMessage::Ipv6PeerHistogram(h) => ipv6_collector.add_histogram(h),
+                StatisticsMessage::Ipv4TopTorrents(t) => ipv4_collector.add_top_torrents(t),
+                StatisticsMessage::Ipv6TopTorrents(t) => ipv6_collector.add_top_torrents(t),
+                StatisticsMessage::Ipv4RequestLatencies(h) => {
+                    ipv4_collector.add_request_latencies(h)
+                }
+                StatisticsMessage::Ipv6RequestLatencies(h) => {
+                    ipv6_collector.add_request_latencies(h)
+                }
                 StatisticsMessage::PeerAdded(peer_id) => {
                     if process_peer_client_data {
                         peers
@@ -102,17 +106,23 @@ pub fn run_statistics_worker(
                         }
                     }
                 }
+                #[cfg(feature = "prometheus")]
+                StatisticsMessage::ClientRejected(peer_id) => {
+                    if config.statistics.metrics_active() {
+                        ::metrics::counter!(
+                            "aquatic_client_filter_rejections_total",
+                            "client" => rejected_client_label(&peer_id.0),
+                        )
+                        .increment(1);
+                    }
+                }
+                #[cfg(not(feature = "prometheus"))]
+                StatisticsMessage::ClientRejected(_) => (),
             }
         }
 
-        let statistics_ipv4 = ipv4_collector.collect_from_shared(
-            #[cfg(feature = "prometheus")]
-            &config,
-        );
-        let statistics_ipv6 = ipv6_collector.collect_from_shared(
-            #[cfg(feature = "prometheus")]
-            &config,
-        );
+        let statistics_ipv4 = ipv4_collector.collect_from_shared(&config);
+        let statistics_ipv6 = ipv6_collector.collect_from_shared(&config);
 
         let peer_clients = if process_peer_client_data {
             let mut clients: IndexMap<PeerClient, usize> = IndexMap::default();
@@ -125,7 +135,7 @@ pub fn run_statistics_worker(
                 *clients.entry(peer_client.to_owned()).or_insert(0) += 1;
 
                 #[cfg(feature = "prometheus")]
-                if config.statistics.run_prometheus_endpoint
+                if config.statistics.metrics_active()
                     && config.statistics.prometheus_peer_id_prefixes
                 {
                     *prefixes.entry(prefix.to_owned()).or_insert(0) += 1;
@@ -135,9 +145,7 @@ pub fn run_statistics_worker(
             clients.sort_unstable_by(|_, a, _, b| b.cmp(a));
 
             #[cfg(feature = "prometheus")]
-            if config.statistics.run_prometheus_endpoint
-                && config.statistics.prometheus_peer_id_prefixes
-            {
+            if config.statistics.metrics_active() && config.statistics.prometheus_peer_id_prefixes {
                 for (prefix, count) in prefixes {
                     ::metrics::gauge!(
                         "aquatic_peer_id_prefixes",
@@ -150,12 +158,12 @@ pub fn run_statistics_worker(
             let mut client_vec = Vec::with_capacity(clients.len());
 
             for (client, count) in clients {
-                if config.statistics.write_html_to_file {
+                if render_html {
                     client_vec.push((client.to_string(), count.to_formatted_string(&Locale::en)));
                 }
 
                 #[cfg(feature = "prometheus")]
-                if config.statistics.run_prometheus_endpoint {
+                if config.statistics.metrics_active() {
                     ::metrics::gauge!(
                         "aquatic_peer_clients",
                         "client" => client.to_string(),
@@ -188,23 +196,58 @@ pub fn run_statistics_worker(
             println!();
         }
 
-        if let Some(tt) = opt_tt.as_ref() {
-            let template_data = TemplateData {
-                stylesheet: STYLESHEET_CONTENTS.to_string(),
-                ipv4_active: config.network.ipv4_active(),
-                ipv6_active: config.network.ipv6_active(),
-                extended_active: config.statistics.torrent_peer_histograms,
-                ipv4: statistics_ipv4,
-                ipv6: statistics_ipv6,
-                last_updated: OffsetDateTime::now_utc()
-                    .format(&Rfc2822)
-                    .unwrap_or("(formatting error)".into()),
-                peer_update_interval: format!("{}", config.cleaning.torrent_cleaning_interval),
-                peer_clients,
+        if let Some(template) = opt_template.as_ref() {
+            let last_updated = OffsetDateTime::now_utc()
+                .format(&Rfc2822)
+                .unwrap_or("(formatting error)".into());
+
+            let mut sections = Vec::new();
+
+            if config.network.ipv4_active() {
+                sections.push(page_section(&config, "IPv4", &statistics_ipv4));
+            }
+            if config.network.ipv6_active() {
+                sections.push(page_section(&config, "IPv6", &statistics_ipv6));
+            }
+
+            let page = Page {
+                title: "UDP BitTorrent tracker statistics",
+                homepage_url: &config.statistics.homepage_url,
+                tracker_url: &config.statistics.tracker_url,
+                last_updated: &last_updated,
+                peer_update_interval: config.cleaning.torrent_cleaning_interval,
+                sections,
+                peer_clients_active: config.statistics.peer_clients,
+                peer_clients: &peer_clients,
             };
 
-            if let Err(err) = save_html_to_file(&config, tt, &template_data) {
-                ::log::error!("Couldn't save statistics to file: {:#}", err)
+            match template.render(&page) {
+                Ok(html) => {
+                    if config.statistics.write_html_to_file {
+                        if let Err(err) =
+                            save_html_to_file(&config.statistics.html_file_path, &html)
+                        {
+                            ::log::error!("Couldn't save statistics to file: {:#}", err)
+                        }
+                    }
+
+                    if let Some(pages) = opt_pages.as_ref() {
+                        let json_data = JsonData {
+                            last_updated: &last_updated,
+                            ipv4: config.network.ipv4_active().then_some(&statistics_ipv4),
+                            ipv6: config.network.ipv6_active().then_some(&statistics_ipv6),
+                            peer_clients: &peer_clients,
+                        };
+
+                        match serde_json::to_string(&json_data) {
+                            Ok(json) => pages.update(html, json),
+                            Err(err) => {
+                                ::log::error!("Couldn't render statistics json: {:#}", err)
+                            }
+                        }
+                    }
+                }
+                Err(err) => ::log::error!("Couldn't render statistics html: {:#}", err),
             }
         }
 
@@ -228,6 +271,11 @@ fn print_to_stdout(config: &Config, statistics: &CollectedStatistics) {
         statistics.rx_mbits, statistics.tx_mbits,
     );
     println!("  requests/second: {:>10}", statistics.requests_per_second);
+
+    if let Some(dropped_connects) = statistics.dropped_connects_per_second.as_ref() {
+        println!("  dropped connects: {:>9} (per second)", dropped_connects);
+    }
+
     println!("  responses/second");
     println!(
         "    total:         {:>10}",
@@ -258,6 +306,24 @@ fn print_to_stdout(config: &Config, statistics: &CollectedStatistics) {
         statistics.num_peers, config.cleaning.torrent_cleaning_interval
     );
 
+    if let Some(torrent_limits) = statistics.torrent_limits.as_ref() {
+        println!(
+            "  rejected torrents: {:>8} (total)",
+            torrent_limits.rejected_torrents
+        );
+        println!(
+            "  rejected peers:  {:>10} (total)",
+            torrent_limits.rejected_peers
+        );
+        println!(
+            "  evicted torrents: {:>9} (total)",
+            torrent_limits.evicted_torrents
+        );
+    }
+    if let Some(rejected_ip_peers) = statistics.rejected_ip_peers.as_ref() {
+        println!("  ip-limited peers: {:>9} (total)", rejected_ip_peers);
+    }
+
     if config.statistics.torrent_peer_histograms {
         println!(
             "  peers per torrent (updated every {}s)",
@@ -278,21 +344,156 @@ fn print_to_stdout(config: &Config, statistics: &CollectedStatistics) {
         println!("    p99.9          {:>10}", statistics.peer_histogram.p999);
         println!("    max            {:>10}", statistics.peer_histogram.max);
     }
+
+    if let Some(request_latencies) = statistics.request_latencies.as_ref() {
+        println!("  request latency in microseconds (p50, p90, p99, p99.9, max)");
+
+        for (label, percentiles) in [
+            ("connect:", &request_latencies.connect),
+            ("announce:", &request_latencies.announce),
+            ("scrape:", &request_latencies.scrape),
+            ("error:", &request_latencies.error),
+        ] {
+            println!(
+                "    {:<10} {:>8} {:>8} {:>8} {:>8} {:>8}",
+                label,
+                percentiles.p50,
+                percentiles.p90,
+                percentiles.p99,
+                percentiles.p999,
+                percentiles.max
+            );
+        }
+    }
 }
 
-fn save_html_to_file(
+fn page_section<'a>(
     config: &Config,
-    tt: &TinyTemplate,
-    template_data: &TemplateData,
-) -> anyhow::Result<()> {
-    let mut file = File::create(&config.statistics.html_file_path).with_context(|| {
-        format!(
-            "File path: {}",
-            &config.statistics.html_file_path.to_string_lossy()
-        )
-    })?;
+    title: &'static str,
+    statistics: &'a CollectedStatistics,
+) -> PageSection<'a> {
+    let mut rows = vec![
+        ("Number of torrents", statistics.num_torrents.clone()),
+        ("Number of peers", statistics.num_peers.clone()),
+    ];
+
+    if let Some(torrent_limits) = statistics.torrent_limits.as_ref() {
+        rows.push((
+            "Rejected torrents (total)",
+            torrent_limits.rejected_torrents.clone(),
+        ));
+        rows.push((
+            "Rejected peers (total)",
+            torrent_limits.rejected_peers.clone(),
+        ));
+        rows.push((
+            "Evicted torrents (total)",
+            torrent_limits.evicted_torrents.clone(),
+        ));
+    }
+    if let Some(rejected_ip_peers) = statistics.rejected_ip_peers.as_ref() {
+        rows.push((
+            "Peers rejected by per-address limit (total)",
+            rejected_ip_peers.clone(),
+        ));
+    }
+
+    rows.push(("Requests / second", statistics.requests_per_second.clone()));
+
+    if let Some(dropped_connects) = statistics.dropped_connects_per_second.as_ref() {
+        rows.push((
+            "Dropped connect requests / second",
+            dropped_connects.clone(),
+        ));
+    }
+
+    rows.extend([
+        (
+            "Total responses / second",
+            statistics.responses_per_second_total.clone(),
+        ),
+        (
+            "Connect responses / second",
+            statistics.responses_per_second_connect.clone(),
+        ),
+        (
+            "Announce responses / second",
+            statistics.responses_per_second_announce.clone(),
+        ),
+        (
+            "Scrape responses / second",
+            statistics.responses_per_second_scrape.clone(),
+        ),
+        (
+            "Error responses / second",
+            statistics.responses_per_second_error.clone(),
+        ),
+        ("Bandwidth (RX)", format!("{} mbit/s", statistics.rx_mbits)),
+        ("Bandwidth (TX)", format!("{} mbit/s", statistics.tx_mbits)),
+    ]);
+
+    let mut tables = Vec::new();
 
-    write!(file, "{}", tt.render(TEMPLATE_KEY, template_data)?)?;
+    if config.statistics.torrent_peer_histograms {
+        let h = &statistics.peer_histogram;
+
+        tables.push(PageTable {
+            title: "Peers per torrent",
+            caption: format!(
+                "Updated every {} seconds",
+                config.cleaning.torrent_cleaning_interval
+            ),
+            columns: Vec::new(),
+            rows: [
+                ("Minimum", h.min),
+                ("10th percentile", h.p10),
+                ("20th percentile", h.p20),
+                ("30th percentile", h.p30),
+                ("40th percentile", h.p40),
+                ("50th percentile", h.p50),
+                ("60th percentile", h.p60),
+                ("70th percentile", h.p70),
+                ("80th percentile", h.p80),
+                ("90th percentile", h.p90),
+                ("95th percentile", h.p95),
+                ("99th percentile", h.p99),
+                ("99.9th percentile", h.p999),
+                ("Maximum", h.max),
+            ]
+            .into_iter()
+            .map(|(label, value)| (label, vec![value.to_string()]))
+            .collect(),
+        });
+    }
 
-    Ok(())
+    if let Some(request_latencies) = statistics.request_latencies.as_ref() {
+        tables.push(PageTable {
+            title: "Request latency",
+            caption: "Microseconds from receiving request until sending response".into(),
+            columns: vec!["Response type", "p50", "p90", "p99", "p99.9", "Maximum"],
+            rows: [
+                ("Connect", &request_latencies.connect),
+                ("Announce", &request_latencies.announce),
+                ("Scrape", &request_latencies.scrape),
+                ("Error", &request_latencies.error),
+            ]
+            .into_iter()
+            .map(|(label, p)| {
+                let values = [p.p50, p.p90, p.p99, p.p999, p.max]
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect();
+
+                (label, values)
+            })
+            .collect(),
+        });
+    }
+
+    PageSection {
+        title,
+        rows,
+        top_torrents: statistics.top_torrents.as_ref(),
+        tables,
+    }
 }
@@ -0,0 +1,261 @@
+use std::iter::once;
+use std::sync::Arc;
+
+use anyhow::Context;
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TrySendError};
+use mio::{Poll, Waker};
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+
+use aquatic_common::logging::InfoHashPrefix;
+use aquatic_common::ValidUntil;
+
+use crate::common::*;
+use crate::config::Config;
+use crate::swarm::{update_statistics, CleaningStatistics, WorkerTorrentMaps};
+use crate::workers::socket::create_poll_with_waker;
+
+/// Handle at most this many messages before sending responses
+const MAX_MESSAGES_PER_BATCH: usize = 1024;
+
+/// Channels and poll instance passed to a socket worker when swarm workers
+/// are used
+pub struct SocketWorkerSwarmChannels {
+    pub socket_worker_index: usize,
+    /// Poll instance with waker used by swarm workers
+    pub poll: Poll,
+    pub request_senders: Vec<Sender<SwarmWorkerMessage>>,
+    pub response_receiver: Receiver<SwarmResponse>,
+}
+
+pub struct SwarmWorkerChannels {
+    worker_index: usize,
+    request_receiver: Receiver<SwarmWorkerMessage>,
+    response_senders: Vec<Sender<SwarmResponse>>,
+    socket_worker_wakers: Vec<Arc<Waker>>,
+}
+
+/// Channels between socket and swarm workers
+pub struct Channels {
+    pub socket_workers: Vec<SocketWorkerSwarmChannels>,
+    pub swarm_workers: Vec<SwarmWorkerChannels>,
+    /// Request senders for the cleaning worker
+    pub request_senders: Vec<Sender<SwarmWorkerMessage>>,
+}
+
+pub fn create_channels(config: &Config) -> anyhow::Result<Channels> {
+    let (request_senders, request_receivers): (Vec<_>, Vec<_>) = (0..config.swarm_workers)
+        .map(|_| bounded(config.worker_channel_size))
+        .unzip();
+
+    let mut socket_worker_channels = Vec::new();
+    let mut response_senders = Vec::new();
+    let mut socket_worker_wakers = Vec::new();
+
+    for socket_worker_index in 0..config.socket_workers {
+        let (response_sender, response_receiver) = bounded(config.worker_channel_size);
+        let (poll, waker) = create_poll_with_waker()
+            .with_context(|| format!("create poll for socket worker {}", socket_worker_index))?;
+
+        socket_worker_channels.push(SocketWorkerSwarmChannels {
+            socket_worker_index,
+            poll,
+            request_senders: request_senders.clone(),
+            response_receiver,
+        });
+
+        response_senders.push(response_sender);
+        socket_worker_wakers.push(waker);
+    }
+
+    let swarm_worker_channels = request_receivers
+        .into_iter()
+        .enumerate()
+        .map(|(worker_index, request_receiver)| SwarmWorkerChannels {
+            worker_index,
+            request_receiver,
+            response_senders: response_senders.clone(),
+            socket_worker_wakers: socket_worker_wakers.clone(),
+        })
+        .collect();
+
+    Ok(Channels {
+        socket_workers: socket_worker_channels,
+        swarm_workers: swarm_worker_channels,
+        request_senders,
+    })
+}
+
+pub fn run_swarm_worker(
+    config: Config,
+    state: State,
+    statistics_sender: Sender<StatisticsMessage>,
+    channels: SwarmWorkerChannels,
+) -> anyhow::Result<()> {
+    let mut torrent_maps = WorkerTorrentMaps::new(
+        &config,
+        channels.worker_index,
+        state.peer_ip_counter.clone(),
+    );
+    let mut rng = SmallRng::from_entropy();
+    let mut wake_socket_workers = vec![false; channels.response_senders.len()];
+
+    // Returns when all senders have been dropped
+    while let Ok(message) = channels.request_receiver.recv() {
+        let peer_valid_until =
+            ValidUntil::new(state.server_start_instant, config.cleaning.max_peer_age);
+
+        let messages = once(message).chain(
+            channels
+                .request_receiver
+                .try_iter()
+                .take(MAX_MESSAGES_PER_BATCH - 1),
+        );
+
+        for message in messages {
+            match message {
+                SwarmWorkerMessage::Request(request) => {
+                    let opt_info_hash = match &request.request {
+                        ConnectedRequest::Announce(announce_request) => {
+                            Some(announce_request.info_hash)
+                        }
+                        ConnectedRequest::Scrape(_) => None,
+                    };
+
+                    let response = match request.request {
+                        ConnectedRequest::Announce(announce_request) => {
+                            ConnectedResponse::Announce(torrent_maps.announce(
+                                &config,
+                                &statistics_sender,
+                                &mut rng,
+                                &announce_request,
+                                request.src,
+                                peer_valid_until,
+                            ))
+                        }
+                        ConnectedRequest::Scrape(scrape_request) => ConnectedResponse::Scrape(
+                            torrent_maps.scrape(scrape_request, request.src),
+                        ),
+                    };
+
+                    let response = SwarmResponse {
+                        ipv4_socket: request.ipv4_socket,
+                        src: request.src,
+                        response,
+                        opt_received_at: request.opt_received_at,
+                    };
+
+                    match channels.response_senders[request.socket_worker_index].try_send(response)
+                    {
+                        Ok(()) => {
+                            wake_socket_workers[request.socket_worker_index] = true;
+                        }
+                        Err(TrySendError::Full(_)) => {
+                            if let Some(info_hash) = opt_info_hash {
+                                ::log::warn!(
+                                    peer_addr:% = request.src.get(),
+                                    info_hash:% = InfoHashPrefix(&info_hash.0);
+                                    "response channel full, dropping announce response"
+                                );
+                            } else {
+                                ::log::warn!(
+                                    peer_addr:% = request.src.get();
+                                    "response channel full, dropping scrape response"
+                                );
+                            }
+                        }
+                        Err(TrySendError::Disconnected(_)) => {
+                            panic!(
+                                "response channel {} is disconnected",
+                                request.socket_worker_index
+                            );
+                        }
+                    }
+                }
+                SwarmWorkerMessage::Clean(statistics_reply_sender) => {
+                    let mut statistics_messages = Vec::new();
+
+                    let cleaning_statistics = torrent_maps.clean(
+                        &config,
+                        &mut statistics_messages,
+                        &state.access_list,
+                        state.server_start_instant,
+                    );
+
+                    if statistics_reply_sender
+                        .send((cleaning_statistics, statistics_messages))
+                        .is_err()
+                    {
+                        ::log::error!("couldn't send cleaning statistics");
+                    }
+                }
+            }
+        }
+
+        for (waker, wake) in channels
+            .socket_worker_wakers
+            .iter()
+            .zip(wake_socket_workers.iter_mut())
+        {
+            if *wake {
+                if let Err(err) = waker.wake() {
+                    ::log::error!("couldn't wake socket worker: {:#}", err);
+                }
+
+                *wake = false;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Have swarm workers clean their torrents, then update statistics with
+/// the combined results
+pub fn clean_torrents(
+    config: &Config,
+    statistics: &CachePaddedArc<IpVersionStatistics<SwarmWorkerStatistics>>,
+    statistics_sender: &Sender<StatisticsMessage>,
+    request_senders: &[Sender<SwarmWorkerMessage>],
+) {
+    let (reply_sender, reply_receiver) = unbounded();
+
+    for request_sender in request_senders {
+        if request_sender
+            .send(SwarmWorkerMessage::Clean(reply_sender.clone()))
+            .is_err()
+        {
+            ::log::error!("couldn't send cleaning request to swarm worker");
+        }
+    }
+
+    // Make iteration below end when all swarm workers have replied
+    drop(reply_sender);
+
+    let mut opt_combined: Option<(
+        IpVersionStatistics<CleaningStatistics>,
+        Vec<StatisticsMessage>,
+    )> = None;
+
+    for (cleaning_statistics, mut statistics_messages) in reply_receiver.iter() {
+        if let Some((combined_statistics, combined_messages)) = opt_combined.as_mut() {
+            let IpVersionStatistics { ipv4, ipv6 } = cleaning_statistics;
+
+            combined_statistics.ipv4.merge(ipv4);
+            combined_statistics.ipv6.merge(ipv6);
+            combined_messages.append(&mut statistics_messages);
+        } else {
+            opt_combined = Some((cleaning_statistics, statistics_messages));
+        }
+    }
+
+    if let Some((cleaning_statistics, statistics_messages)) = opt_combined {
+        update_statistics(
+            config,
+            statistics,
+            statistics_sender,
+            cleaning_statistics,
+            statistics_messages,
+        );
+    }
+}
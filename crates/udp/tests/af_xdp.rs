@@ -0,0 +1,170 @@
+#![cfg(all(target_os = "linux", feature = "af-xdp"))]
+
+mod common;
+
+use common::*;
+
+use std::{
+    fs::File,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    num::NonZeroU16,
+    os::unix::io::AsRawFd,
+    process::Command,
+    time::Duration,
+};
+
+use anyhow::Context;
+use aquatic_udp::config::Config;
+use aquatic_udp_protocol::{InfoHash, Response};
+
+const NAMESPACE: &str = "aquatic-xdp-test";
+const HOST_INTERFACE: &str = "aqxdp0";
+const PEER_INTERFACE: &str = "aqxdp1";
+const HOST_IP: Ipv4Addr = Ipv4Addr::new(10, 211, 0, 1);
+const PEER_IP: Ipv4Addr = Ipv4Addr::new(10, 211, 0, 2);
+
+/// Veth pair with one end in a separate network namespace. Removed on drop.
+struct VethPair;
+
+impl VethPair {
+    fn create() -> anyhow::Result<Self> {
+        let pair = Self;
+
+        ip(&["netns", "add", NAMESPACE])?;
+        ip(&[
+            "link",
+            "add",
+            HOST_INTERFACE,
+            "type",
+            "veth",
+            "peer",
+            "name",
+            PEER_INTERFACE,
+            "netns",
+            NAMESPACE,
+        ])?;
+        ip(&[
+            "addr",
+            "add",
+            &format!("{}/24", HOST_IP),
+            "dev",
+            HOST_INTERFACE,
+        ])?;
+        ip(&["link", "set", HOST_INTERFACE, "up"])?;
+        ip(&[
+            "-n",
+            NAMESPACE,
+            "addr",
+            "add",
+            &format!("{}/24", PEER_IP),
+            "dev",
+            PEER_INTERFACE,
+        ])?;
+        ip(&["-n", NAMESPACE, "link", "set", PEER_INTERFACE, "up"])?;
+        ip(&["-n", NAMESPACE, "link", "set", "lo", "up"])?;
+
+        Ok(pair)
+    }
+}
+
+impl Drop for VethPair {
+    fn drop(&mut self) {
+        let _ = ip(&["link", "del", HOST_INTERFACE]);
+        let _ = ip(&["netns", "del", NAMESPACE]);
+    }
+}
+
+fn ip(args: &[&str]) -> anyhow::Result<()> {
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .with_context(|| "run ip")?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "ip {}: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Run function in a thread that has entered the test network namespace
+fn in_namespace<T: Send + 'static>(
+    f: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    ::std::thread::spawn(move || {
+        let file = File::open(format!("/var/run/netns/{}", NAMESPACE))
+            .with_context(|| "open network namespace")?;
+
+        if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+            return Err(::std::io::Error::last_os_error()).with_context(|| "setns");
+        }
+
+        f()
+    })
+    .join()
+    .map_err(|_| anyhow::anyhow!("namespace thread panicked"))?
+}
+
+/// Requires CAP_NET_ADMIN, CAP_NET_RAW and CAP_BPF, so run it explicitly
+/// with `cargo test --features af-xdp -- --ignored`
+#[test]
+#[ignore]
+fn test_af_xdp_connect_announce() -> anyhow::Result<()> {
+    const TRACKER_PORT: u16 = 40_114;
+    const PEER_PORT_START: u16 = 30_000;
+
+    let _veth_pair = VethPair::create().with_context(|| "set up veth pair")?;
+
+    let mut config = Config::default();
+
+    config.network.address_ipv4 = SocketAddrV4::new(HOST_IP, TRACKER_PORT);
+    config.network.use_ipv6 = false;
+    config.network.use_af_xdp = true;
+    config.network.af_xdp_interface = HOST_INTERFACE.into();
+    config.network.af_xdp_queues = "0".into();
+    config.network.af_xdp_fallback = false;
+    config.socket_workers = 1;
+
+    run_tracker(config);
+
+    in_namespace(|| {
+        let tracker_addr = SocketAddr::V4(SocketAddrV4::new(HOST_IP, TRACKER_PORT));
+        let peer_addr = SocketAddr::V4(SocketAddrV4::new(PEER_IP, 0));
+
+        let info_hash = InfoHash([0; 20]);
+
+        for i in 0..5 {
+            let socket = UdpSocket::bind(peer_addr)?;
+            socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+            let connection_id = connect(&socket, tracker_addr).with_context(|| "connect")?;
+
+            let response = announce(
+                &socket,
+                tracker_addr,
+                connection_id,
+                NonZeroU16::new(PEER_PORT_START + i as u16).unwrap(),
+                info_hash,
+                10,
+                false,
+            )
+            .with_context(|| "announce")?;
+
+            if let Response::AnnounceIpv4(response) = response {
+                assert_eq!(response.peers.len(), i);
+                assert!(response
+                    .peers
+                    .iter()
+                    .all(|p| p.ip_address.0 == PEER_IP.octets()));
+            } else {
+                return Err(anyhow::anyhow!("not announce response: {:?}", response));
+            }
+        }
+
+        Ok(())
+    })
+}
@@ -15,18 +15,40 @@ use aquatic_udp_protocol::{InfoHash, Response};
 
 #[test]
 fn test_multiple_connect_announce_scrape() -> anyhow::Result<()> {
-    const TRACKER_PORT: u16 = 40_111;
-    const PEER_PORT_START: u16 = 30_000;
-    const PEERS_WANTED: usize = 10;
+    let mut config = Config::default();
+
+    config.network.address_ipv4.set_port(40_111);
+    config.network.use_ipv6 = false;
+
+    connect_announce_scrape(config)
+}
 
+#[test]
+fn test_multiple_connect_announce_scrape_swarm_workers() -> anyhow::Result<()> {
     let mut config = Config::default();
 
-    config.network.address_ipv4.set_port(TRACKER_PORT);
+    config.network.address_ipv4.set_port(40_115);
     config.network.use_ipv6 = false;
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    {
+        config.network.use_io_uring = false;
+    }
+    config.socket_workers = 2;
+    // Scraped info hashes are handled by different swarm workers
+    config.swarm_workers = 2;
+
+    connect_announce_scrape(config)
+}
+
+fn connect_announce_scrape(config: Config) -> anyhow::Result<()> {
+    const PEER_PORT_START: u16 = 30_000;
+    const PEERS_WANTED: usize = 10;
+
+    let tracker_port = config.network.address_ipv4.port();
 
     run_tracker(config);
 
-    let tracker_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, TRACKER_PORT));
+    let tracker_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, tracker_port));
     let peer_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
 
     let info_hash = InfoHash([0; 20]);
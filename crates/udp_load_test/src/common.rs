@@ -1,22 +1,28 @@
 use std::sync::{atomic::AtomicUsize, Arc};
+use std::time::Instant;
 
+use aquatic_common::peer_lifecycle::LifecycleStatistics;
 use aquatic_common::IndexMap;
 use aquatic_udp_protocol::*;
+use hdrhistogram::Histogram;
 
 #[derive(Clone)]
 pub struct LoadTestState {
     pub info_hashes: Arc<[InfoHash]>,
     pub statistics: Arc<SharedStatistics>,
+    pub start_instant: Instant,
 }
 
 #[derive(Default)]
 pub struct SharedStatistics {
     pub requests: AtomicUsize,
+    pub requests_timed_out: AtomicUsize,
     pub response_peers: AtomicUsize,
     pub responses_connect: AtomicUsize,
     pub responses_announce: AtomicUsize,
     pub responses_scrape: AtomicUsize,
     pub responses_error: AtomicUsize,
+    pub lifecycle: LifecycleStatistics,
 }
 
 pub struct Peer {
@@ -29,4 +35,6 @@ pub struct Peer {
 
 pub enum StatisticsMessage {
     ResponsesPerInfoHash(IndexMap<usize, u64>),
+    /// Response latencies in microseconds (open-loop mode only)
+    ResponseLatencies(Histogram<u64>),
 }
@@ -1,10 +1,12 @@
-use std::net::SocketAddr;
+use std::net::{Ipv6Addr, SocketAddr};
 
 use serde::{Deserialize, Serialize};
 
 use aquatic_common::cli::LogLevel;
 #[cfg(feature = "cpu-pinning")]
 use aquatic_common::cpu_pinning::desc::CpuPinningConfigDesc;
+use aquatic_common::open_loop::OpenLoopConfig;
+use aquatic_common::peer_lifecycle::LifecycleConfig;
 use aquatic_toml_config::TomlConfig;
 
 /// aquatic_udp_load_test configuration
@@ -14,11 +16,20 @@ pub struct Config {
     /// Server address
     ///
     /// If you want to send IPv4 requests to a IPv4+IPv6 tracker, put an IPv4
-    /// address here.
+    /// address here. If this is an IPv6 address, all workers send IPv6
+    /// requests.
     pub server_address: SocketAddr,
+    /// Server address for workers sending IPv6 requests in mixed runs
+    pub server_address_ipv6: SocketAddr,
     pub log_level: LogLevel,
     /// Number of workers sending requests
     pub workers: u8,
+    /// Number of workers that send IPv6 requests to `server_address_ipv6`
+    /// instead of IPv4 requests to `server_address`
+    ///
+    /// Set to a value lower than `workers` to send requests over both IPv4
+    /// and IPv6. Ignored if `server_address` is an IPv6 address.
+    pub ipv6_workers: u8,
     /// Run duration (quit and generate report after this many seconds)
     pub duration: usize,
     /// Only report summary for the last N seconds of run
@@ -29,6 +40,16 @@ pub struct Config {
     pub extra_statistics: bool,
     pub network: NetworkConfig,
     pub requests: RequestConfig,
+    /// Open-loop (fixed request rate) mode
+    ///
+    /// Requests are scheduled per worker.
+    pub open_loop: OpenLoopConfig,
+    /// Peer lifecycle simulation
+    ///
+    /// Connect requests are still sent to keep connection ids valid. Each
+    /// worker simulates at most as many peers as it is assigned by
+    /// `requests.number_of_peers`.
+    pub lifecycle: LifecycleConfig,
     #[cfg(feature = "cpu-pinning")]
     pub cpu_pinning: CpuPinningConfigDesc,
 }
@@ -37,13 +58,17 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             server_address: "127.0.0.1:3000".parse().unwrap(),
+            server_address_ipv6: "[::1]:3000".parse().unwrap(),
             log_level: LogLevel::Error,
             workers: 1,
+            ipv6_workers: 0,
             duration: 0,
             summarize_last: 0,
             extra_statistics: true,
             network: NetworkConfig::default(),
             requests: RequestConfig::default(),
+            open_loop: OpenLoopConfig::default(),
+            lifecycle: LifecycleConfig::default(),
             #[cfg(feature = "cpu-pinning")]
             cpu_pinning: Default::default(),
         }
@@ -66,6 +91,16 @@ pub struct NetworkConfig {
     ///
     /// Setting this to true can cause issues on macOS.
     pub multiple_client_ipv4s: bool,
+    /// True means bind IPv6 workers to one IP each in `client_ipv6_prefix`
+    /// instead of to ::1.
+    ///
+    /// The addresses need to be routed to the loopback interface. On Linux,
+    /// this can be done with:
+    /// $ sudo ip -6 route add local fd00:aa::/64 dev lo
+    pub multiple_client_ipv6s: bool,
+    /// Prefix of client IPv6 addresses when `multiple_client_ipv6s` is true.
+    /// Worker N binds to this address plus N + 1.
+    pub client_ipv6_prefix: Ipv6Addr,
     /// Number of sockets to open per worker
     pub sockets_per_worker: u8,
     /// Size of socket recv buffer. Use 0 for OS default.
@@ -81,14 +116,20 @@ pub struct NetworkConfig {
     /// $ sudo sysctl -w net.core.rmem_max=8000000
     /// $ sudo sysctl -w net.core.rmem_default=8000000
     pub recv_buffer: usize,
+    /// Consider requests lost if no response has been received after this
+    /// many milliseconds (open-loop mode only)
+    pub request_timeout_ms: u64,
 }
 
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
             multiple_client_ipv4s: true,
+            multiple_client_ipv6s: false,
+            client_ipv6_prefix: "fd00:aa::".parse().unwrap(),
             sockets_per_worker: 4,
             recv_buffer: 8_000_000,
+            request_timeout_ms: 1000,
         }
     }
 }
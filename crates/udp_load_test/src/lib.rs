@@ -5,6 +5,7 @@ use std::sync::{atomic::Ordering, Arc};
 use std::thread::{self, Builder};
 use std::time::{Duration, Instant};
 
+use aquatic_common::open_loop::RateSchedule;
 use aquatic_common::IndexMap;
 use aquatic_udp_protocol::{InfoHash, Port};
 use crossbeam_channel::{unbounded, Receiver};
@@ -36,6 +37,14 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         panic!("Error: report_last_seconds can't be larger than duration");
     }
 
+    if config.ipv6_workers > config.workers {
+        panic!("Error: ipv6_workers can't be larger than workers.");
+    }
+
+    if config.lifecycle.active() && config.open_loop.active() {
+        panic!("Error: open-loop mode and lifecycle simulation can't be combined.");
+    }
+
     println!("Starting client with config: {:#?}\n", config);
 
     let info_hash_dist = InfoHashDist::new(&config)?;
@@ -44,19 +53,40 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
     let state = LoadTestState {
         info_hashes: info_hash_dist.into_arc_info_hashes(),
         statistics: Arc::new(SharedStatistics::default()),
+        start_instant: Instant::now(),
     };
 
     let (statistics_sender, statistics_receiver) = unbounded();
 
     // Start workers
 
+    let num_ipv6_workers = if config.server_address.is_ipv6() {
+        config.workers
+    } else {
+        config.ipv6_workers
+    };
+
     for (i, peers) in (0..config.workers).zip(peers_by_worker) {
-        let ip = if config.server_address.is_ipv6() {
-            Ipv6Addr::LOCALHOST.into()
+        let (ip, server_address) = if i < num_ipv6_workers {
+            let ip = if config.network.multiple_client_ipv6s {
+                Ipv6Addr::from(u128::from(config.network.client_ipv6_prefix) + 1 + u128::from(i))
+            } else {
+                Ipv6Addr::LOCALHOST
+            };
+            let server_address = if config.server_address.is_ipv6() {
+                config.server_address
+            } else {
+                config.server_address_ipv6
+            };
+
+            (ip.into(), server_address)
         } else if config.network.multiple_client_ipv4s {
-            Ipv4Addr::new(127, 0, 0, 1 + i).into()
+            (
+                Ipv4Addr::new(127, 0, 0, 1 + i).into(),
+                config.server_address,
+            )
         } else {
-            Ipv4Addr::LOCALHOST.into()
+            (Ipv4Addr::LOCALHOST.into(), config.server_address)
         };
 
         let addr = SocketAddr::new(ip, 0);
@@ -64,9 +94,16 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         let state = state.clone();
         let statistics_sender = statistics_sender.clone();
 
-        Builder::new()
-            .name("load-test".into())
-            .spawn(move || Worker::run(config, state, statistics_sender, peers, addr))?;
+        Builder::new().name("load-test".into()).spawn(move || {
+            Worker::run(
+                config,
+                state,
+                statistics_sender,
+                peers,
+                addr,
+                server_address,
+            )
+        })?;
     }
 
     monitor_statistics(state, &config, statistics_receiver);
@@ -83,11 +120,22 @@ fn monitor_statistics(
     let mut report_avg_announce: Vec<f64> = Vec::new();
     let mut report_avg_scrape: Vec<f64> = Vec::new();
     let mut report_avg_error: Vec<f64> = Vec::new();
+    let mut report_avg_requests: Vec<f64> = Vec::new();
+    let mut report_avg_target: Vec<f64> = Vec::new();
+    let mut report_avg_timed_out: Vec<f64> = Vec::new();
 
     const INTERVAL: u64 = 5;
 
-    let start_time = Instant::now();
+    let start_time = state.start_instant;
     let duration = Duration::from_secs(config.duration as u64);
+    let summarize_after =
+        Duration::from_secs(config.duration.saturating_sub(config.summarize_last) as u64);
+
+    let open_loop_schedule = config
+        .open_loop
+        .active()
+        .then(|| RateSchedule::new(&config.open_loop, 1));
+    let mut summary_latencies = Histogram::<u64>::new(3).unwrap();
 
     let mut last = start_time;
 
@@ -96,6 +144,7 @@ fn monitor_statistics(
 
         let mut opt_responses_per_info_hash: Option<IndexMap<usize, u64>> =
             config.extra_statistics.then_some(Default::default());
+        let mut interval_latencies = Histogram::<u64>::new(3).unwrap();
 
         for message in statistics_receiver.try_iter() {
             match message {
@@ -106,10 +155,16 @@ fn monitor_statistics(
                         }
                     }
                 }
+                StatisticsMessage::ResponseLatencies(histogram) => {
+                    if let Err(err) = interval_latencies.add(histogram) {
+                        eprintln!("Couldn't merge latency histograms: {:#}", err);
+                    }
+                }
             }
         }
 
         let requests = fetch_and_reset(&state.statistics.requests);
+        let requests_timed_out = fetch_and_reset(&state.statistics.requests_timed_out);
         let response_peers = fetch_and_reset(&state.statistics.response_peers);
         let responses_connect = fetch_and_reset(&state.statistics.responses_connect);
         let responses_announce = fetch_and_reset(&state.statistics.responses_announce);
@@ -117,6 +172,7 @@ fn monitor_statistics(
         let responses_error = fetch_and_reset(&state.statistics.responses_error);
 
         let now = Instant::now();
+        let interval_start = last;
 
         let elapsed = (now - last).as_secs_f64();
 
@@ -125,6 +181,7 @@ fn monitor_statistics(
         let peers_per_announce_response = response_peers / responses_announce;
 
         let avg_requests = requests / elapsed;
+        let avg_requests_timed_out = requests_timed_out / elapsed;
         let avg_responses_connect = responses_connect / elapsed;
         let avg_responses_announce = responses_announce / elapsed;
         let avg_responses_scrape = responses_scrape / elapsed;
@@ -139,8 +196,22 @@ fn monitor_statistics(
         report_avg_announce.push(avg_responses_announce);
         report_avg_scrape.push(avg_responses_scrape);
         report_avg_error.push(avg_responses_error);
+        report_avg_requests.push(avg_requests);
+        report_avg_timed_out.push(avg_requests_timed_out);
 
         println!();
+
+        if let Some(schedule) = open_loop_schedule.as_ref() {
+            // Average target rate over interval
+            let target_rate = (schedule.requests_due(now - start_time)
+                - schedule.requests_due(interval_start - start_time))
+                / elapsed;
+
+            report_avg_target.push(target_rate);
+
+            println!("Target requests: {:.2}/second", target_rate);
+        }
+
         println!("Requests out: {:.2}/second", avg_requests);
         println!("Responses in: {:.2}/second", avg_responses);
         println!("  - Connect responses:  {:.2}", avg_responses_connect);
@@ -152,6 +223,21 @@ fn monitor_statistics(
             peers_per_announce_response
         );
 
+        if open_loop_schedule.is_some() {
+            println!("Requests timed out: {:.2}/second", avg_requests_timed_out);
+            print_latency_percentiles(&interval_latencies);
+
+            if now - start_time >= summarize_after {
+                if let Err(err) = summary_latencies.add(&interval_latencies) {
+                    eprintln!("Couldn't merge latency histograms: {:#}", err);
+                }
+            }
+        }
+
+        if config.lifecycle.active() {
+            state.statistics.lifecycle.print_and_reset(elapsed);
+        }
+
         if let Some(responses_per_info_hash) = opt_responses_per_info_hash.as_ref() {
             let mut histogram = Histogram::<u64>::new(2).unwrap();
 
@@ -180,6 +266,9 @@ fn monitor_statistics(
         report_avg_announce = report_avg_announce.split_off(split_at);
         report_avg_scrape = report_avg_scrape.split_off(split_at);
         report_avg_error = report_avg_error.split_off(split_at);
+        report_avg_requests = report_avg_requests.split_off(split_at);
+        report_avg_target = report_avg_target.split_off(split_at.min(report_avg_target.len()));
+        report_avg_timed_out = report_avg_timed_out.split_off(split_at);
     }
 
     let len = report_avg_connect.len() as f64;
@@ -190,6 +279,8 @@ fn monitor_statistics(
     let avg_error: f64 = report_avg_error.into_iter().sum::<f64>() / len;
 
     let avg_total = avg_connect + avg_announce + avg_scrape + avg_error;
+    let avg_requests: f64 = report_avg_requests.into_iter().sum::<f64>() / len;
+    let avg_timed_out: f64 = report_avg_timed_out.into_iter().sum::<f64>() / len;
 
     println!();
     println!("# aquatic load test report");
@@ -208,11 +299,36 @@ fn monitor_statistics(
     println!("  - Announce responses: {:.2}", avg_announce);
     println!("  - Scrape responses:   {:.2}", avg_scrape);
     println!("  - Error responses:    {:.2}", avg_error);
+
+    if open_loop_schedule.is_some() {
+        let avg_target: f64 = report_avg_target.into_iter().sum::<f64>() / len;
+
+        println!("Average target requests per second: {:.2}", avg_target);
+        println!("Average requests per second: {:.2}", avg_requests);
+        println!(
+            "Average timed out requests per second: {:.2}",
+            avg_timed_out
+        );
+        print_latency_percentiles(&summary_latencies);
+    }
+
     println!();
     println!("Config: {:#?}", config);
     println!();
 }
 
+fn print_latency_percentiles(latencies: &Histogram<u64>) {
+    println!("Response latency (milliseconds):");
+
+    for p in PERCENTILES {
+        println!(
+            "  - p{}: {:.2}",
+            p,
+            latencies.value_at_percentile(*p) as f64 / 1000.0
+        );
+    }
+}
+
 fn fetch_and_reset(atomic_usize: &AtomicUsize) -> f64 {
     atomic_usize.fetch_and(0, Ordering::Relaxed) as f64
 }
@@ -1,15 +1,18 @@
 use std::io::{Cursor, ErrorKind};
 use std::net::{SocketAddr, UdpSocket};
 use std::sync::atomic::Ordering;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use aquatic_common::peer_lifecycle::{LifecycleEvent, PeerEvent, PeerPopulation};
 use aquatic_common::IndexMap;
 use crossbeam_channel::Sender;
+use hdrhistogram::Histogram;
 use rand::Rng;
 use rand::{prelude::SmallRng, SeedableRng};
 use rand_distr::{Distribution, WeightedIndex};
 use socket2::{Domain, Protocol, Socket, Type};
 
+use aquatic_common::open_loop::RateSchedule;
 use aquatic_udp_protocol::*;
 
 use crate::common::{LoadTestState, Peer};
@@ -18,6 +21,14 @@ use crate::StatisticsMessage;
 
 const MAX_PACKET_SIZE: usize = 8192;
 
+/// Maximum number of scheduled requests to send in open-loop or lifecycle
+/// mode before checking sockets for responses, in case worker falls behind
+const MAX_SCHEDULED_BURST: usize = 64;
+
+/// How often to request new connection ids in lifecycle mode, where no
+/// connect requests are sent otherwise
+const CONNECTION_ID_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
 pub struct Worker {
     config: Config,
     shared_state: LoadTestState,
@@ -30,6 +41,8 @@ pub struct Worker {
     statistics: LocalStatistics,
     statistics_sender: Sender<StatisticsMessage>,
     announce_responses_per_info_hash: IndexMap<usize, u64>,
+    pending_requests: IndexMap<u32, PendingRequest>,
+    response_latencies: Histogram<u64>,
 }
 
 impl Worker {
@@ -39,11 +52,12 @@ impl Worker {
         statistics_sender: Sender<StatisticsMessage>,
         peers: Box<[Peer]>,
         addr: SocketAddr,
+        server_address: SocketAddr,
     ) {
         let mut sockets = Vec::new();
 
         for _ in 0..config.network.sockets_per_worker {
-            sockets.push(create_socket(&config, addr));
+            sockets.push(create_socket(&config, addr, server_address));
         }
 
         let buffer = [0u8; MAX_PACKET_SIZE];
@@ -63,6 +77,8 @@ impl Worker {
             statistics,
             statistics_sender,
             announce_responses_per_info_hash: Default::default(),
+            pending_requests: Default::default(),
+            response_latencies: Histogram::new(3).unwrap(),
         };
 
         instance.run_inner();
@@ -75,6 +91,17 @@ impl Worker {
             connection_ids.push(self.acquire_connection_id());
         }
 
+        if self.config.lifecycle.active() {
+            self.run_lifecycle(connection_ids)
+        } else if self.config.open_loop.active() {
+            self.run_open_loop(connection_ids)
+        } else {
+            self.run_closed_loop(connection_ids)
+        }
+    }
+
+    /// Send new requests when most responses to previous ones have arrived
+    fn run_closed_loop(&mut self, mut connection_ids: Vec<ConnectionId>) {
         let mut requests_sent = 0usize;
         let mut responses_received = 0usize;
 
@@ -98,12 +125,25 @@ impl Worker {
                                 % self.config.network.sockets_per_worker;
                         }
                         RequestType::Announce => {
-                            self.send_announce_request(&connection_ids, peer_index);
+                            let (event, bytes_left) = self.random_announce_event();
+
+                            self.send_announce_request(
+                                &connection_ids,
+                                peer_index,
+                                index_to_transaction_id(peer_index),
+                                PeerId([0; 20]),
+                                event,
+                                bytes_left,
+                            );
 
                             peer_index = (peer_index + 1) % self.peers.len();
                         }
                         RequestType::Scrape => {
-                            self.send_scrape_request(&connection_ids, peer_index);
+                            self.send_scrape_request(
+                                &connection_ids,
+                                peer_index,
+                                index_to_transaction_id(peer_index),
+                            );
 
                             peer_index = (peer_index + 1) % self.peers.len();
                         }
@@ -123,15 +163,16 @@ impl Worker {
                             Ok(Response::Connect(r)) => {
                                 // If we're sending connect requests, we might
                                 // as well keep connection IDs valid
-                                let connection_id_index =
-                                    u32::from_ne_bytes(r.transaction_id.0.get().to_ne_bytes())
-                                        as usize;
+                                let connection_id_index = transaction_id_to_index(r.transaction_id);
                                 connection_ids[connection_id_index] = r.connection_id;
 
-                                self.handle_response(Response::Connect(r));
+                                self.handle_response(Response::Connect(r), connection_id_index);
                             }
                             Ok(response) => {
-                                self.handle_response(response);
+                                let peer_index =
+                                    transaction_id_to_index(response_transaction_id(&response));
+
+                                self.handle_response(response, peer_index);
                             }
                             Err(err) => {
                                 eprintln!("Received invalid response: {:#?}", err);
@@ -155,6 +196,277 @@ impl Worker {
         }
     }
 
+    /// Send requests according to a fixed schedule, independently of
+    /// responses, and record response latencies
+    ///
+    /// Latency is measured from the time a request was scheduled to be sent,
+    /// so that delays in the load tester itself are not hidden.
+    fn run_open_loop(&mut self, mut connection_ids: Vec<ConnectionId>) {
+        let schedule = RateSchedule::new(&self.config.open_loop, self.config.workers.into());
+        let request_timeout = Duration::from_millis(self.config.network.request_timeout_ms);
+        let start_instant = self.shared_state.start_instant;
+
+        // Don't try to catch up on requests that would have been sent while
+        // acquiring connection ids
+        let mut requests_scheduled = schedule.requests_due(start_instant.elapsed()) as u64;
+
+        let mut next_transaction_id = 0u32;
+        let mut connect_socket_index = 0u8;
+        let mut peer_index = 0usize;
+        let mut loop_index = 0usize;
+
+        loop {
+            for _ in 0..MAX_SCHEDULED_BURST {
+                let scheduled_at = start_instant + schedule.send_time(requests_scheduled);
+
+                if Instant::now() < scheduled_at {
+                    break;
+                }
+
+                let transaction_id = next_transaction_id;
+                let request_type = self.request_type_dist.sample(&mut self.rng);
+
+                let index = match request_type {
+                    RequestType::Connect => {
+                        let socket_index = connect_socket_index;
+
+                        self.send_connect_request(socket_index, transaction_id);
+
+                        connect_socket_index = connect_socket_index.wrapping_add(1)
+                            % self.config.network.sockets_per_worker;
+
+                        socket_index.into()
+                    }
+                    RequestType::Announce => {
+                        let index = peer_index;
+                        let (event, bytes_left) = self.random_announce_event();
+
+                        self.send_announce_request(
+                            &connection_ids,
+                            index,
+                            index_to_transaction_id(transaction_id as usize),
+                            PeerId([0; 20]),
+                            event,
+                            bytes_left,
+                        );
+
+                        peer_index = (peer_index + 1) % self.peers.len();
+
+                        index
+                    }
+                    RequestType::Scrape => {
+                        let index = peer_index;
+
+                        self.send_scrape_request(
+                            &connection_ids,
+                            index,
+                            index_to_transaction_id(transaction_id as usize),
+                        );
+
+                        peer_index = (peer_index + 1) % self.peers.len();
+
+                        index
+                    }
+                };
+
+                self.pending_requests.insert(
+                    transaction_id,
+                    PendingRequest {
+                        scheduled_at,
+                        request_type,
+                        index,
+                    },
+                );
+
+                next_transaction_id = next_transaction_id.wrapping_add(1);
+                requests_scheduled += 1;
+            }
+
+            for socket_index in 0..self.sockets.len() {
+                // Do this instead of iterating over Vec to fix borrow checker complaint
+                let socket = self.sockets.get(socket_index).unwrap();
+
+                match socket.recv(&mut self.buffer[..]) {
+                    Ok(amt) => {
+                        let response = match Response::parse_bytes(
+                            &self.buffer[0..amt],
+                            self.addr.is_ipv4(),
+                        ) {
+                            Ok(response) => response,
+                            Err(err) => {
+                                eprintln!("Received invalid response: {:#?}", err);
+
+                                continue;
+                            }
+                        };
+
+                        let transaction_id =
+                            transaction_id_to_index(response_transaction_id(&response)) as u32;
+
+                        // Ignore responses to requests that have timed out
+                        let pending_request =
+                            match self.pending_requests.swap_remove(&transaction_id) {
+                                Some(pending_request) => pending_request,
+                                None => continue,
+                            };
+
+                        let latency = pending_request.scheduled_at.elapsed();
+
+                        self.response_latencies
+                            .record(latency.as_micros() as u64)
+                            .unwrap();
+
+                        if let (RequestType::Connect, Response::Connect(r)) =
+                            (pending_request.request_type, &response)
+                        {
+                            connection_ids[pending_request.index] = r.connection_id;
+                        }
+
+                        self.handle_response(response, pending_request.index);
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => (),
+                    Err(err) => {
+                        eprintln!("recv error: {:#}", err);
+                    }
+                }
+            }
+
+            if loop_index % 1024 == 0 {
+                let now = Instant::now();
+                let num_pending_before = self.pending_requests.len();
+
+                self.pending_requests
+                    .retain(|_, request| now - request.scheduled_at < request_timeout);
+
+                self.statistics.requests_timed_out +=
+                    num_pending_before - self.pending_requests.len();
+
+                self.update_shared_statistics();
+            }
+
+            loop_index = loop_index.wrapping_add(1);
+        }
+    }
+
+    /// Simulate peers that arrive, announce at the interval returned by the
+    /// tracker, possibly complete and eventually leave
+    fn run_lifecycle(&mut self, mut connection_ids: Vec<ConnectionId>) {
+        let mut population = PeerPopulation::new(
+            &self.config.lifecycle,
+            self.config.requests.peer_seeder_probability,
+            self.config.workers.into(),
+            self.peers.len(),
+            &mut self.rng,
+            Instant::now(),
+        )
+        .expect("create peer population");
+
+        let mut connection_ids_refreshed_at = Instant::now();
+        let mut loop_index = 0usize;
+
+        loop {
+            let now = Instant::now();
+
+            if now - connection_ids_refreshed_at >= CONNECTION_ID_REFRESH_INTERVAL {
+                for socket_index in 0..self.config.network.sockets_per_worker {
+                    self.send_connect_request(socket_index, socket_index.into());
+                }
+
+                connection_ids_refreshed_at = now;
+            }
+
+            for _ in 0..MAX_SCHEDULED_BURST {
+                let event = match population.poll(&mut self.rng, now) {
+                    Some(event) => event,
+                    None => break,
+                };
+
+                self.shared_state.statistics.lifecycle.record(&event);
+
+                if let LifecycleEvent::Announce {
+                    peer_index,
+                    peer_id,
+                    event,
+                    seeding,
+                } = event
+                {
+                    let event = match event {
+                        PeerEvent::Started => AnnounceEvent::Started,
+                        PeerEvent::Completed => AnnounceEvent::Completed,
+                        PeerEvent::Stopped => AnnounceEvent::Stopped,
+                        PeerEvent::None => AnnounceEvent::None,
+                    };
+
+                    let bytes_left = NumberOfBytes::new(if seeding { 0 } else { 50 });
+
+                    self.send_announce_request(
+                        &connection_ids,
+                        peer_index,
+                        index_to_transaction_id(peer_index),
+                        PeerId(peer_id),
+                        event,
+                        bytes_left,
+                    );
+                }
+            }
+
+            for socket_index in 0..self.sockets.len() {
+                // Do this instead of iterating over Vec to fix borrow checker complaint
+                let socket = self.sockets.get(socket_index).unwrap();
+
+                match socket.recv(&mut self.buffer[..]) {
+                    Ok(amt) => {
+                        match Response::parse_bytes(&self.buffer[0..amt], self.addr.is_ipv4()) {
+                            Ok(Response::Connect(r)) => {
+                                let connection_id_index = transaction_id_to_index(r.transaction_id);
+                                connection_ids[connection_id_index] = r.connection_id;
+
+                                self.handle_response(Response::Connect(r), connection_id_index);
+                            }
+                            Ok(response) => {
+                                let peer_index =
+                                    transaction_id_to_index(response_transaction_id(&response));
+
+                                let opt_announce_interval = match &response {
+                                    Response::AnnounceIpv4(r) => Some(r.fixed.announce_interval),
+                                    Response::AnnounceIpv6(r) => Some(r.fixed.announce_interval),
+                                    _ => None,
+                                };
+
+                                if let Some(announce_interval) = opt_announce_interval {
+                                    let announce_interval = Duration::from_secs(
+                                        announce_interval.0.get().max(0) as u64,
+                                    );
+
+                                    population.handle_announce_response(
+                                        peer_index,
+                                        announce_interval,
+                                        Instant::now(),
+                                    );
+                                }
+
+                                self.handle_response(response, peer_index);
+                            }
+                            Err(err) => {
+                                eprintln!("Received invalid response: {:#?}", err);
+                            }
+                        }
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => (),
+                    Err(err) => {
+                        eprintln!("recv error: {:#}", err);
+                    }
+                }
+            }
+
+            if loop_index % 1024 == 0 {
+                self.update_shared_statistics();
+            }
+
+            loop_index = loop_index.wrapping_add(1);
+        }
+    }
+
     fn acquire_connection_id(&mut self) -> ConnectionId {
         loop {
             self.send_connect_request(0, u32::MAX);
@@ -186,7 +498,7 @@ impl Worker {
     }
 
     fn send_connect_request(&mut self, socket_index: u8, transaction_id: u32) {
-        let transaction_id = TransactionId::new(i32::from_ne_bytes(transaction_id.to_ne_bytes()));
+        let transaction_id = index_to_transaction_id(transaction_id as usize);
 
         let request = ConnectRequest { transaction_id };
 
@@ -206,29 +518,34 @@ impl Worker {
         }
     }
 
-    fn send_announce_request(&mut self, connection_ids: &[ConnectionId], peer_index: usize) {
-        let peer = self.peers.get(peer_index).unwrap();
-
-        let (event, bytes_left) = {
-            if self
-                .rng
-                .gen_bool(self.config.requests.peer_seeder_probability)
-            {
-                (AnnounceEvent::Completed, NumberOfBytes::new(0))
-            } else {
-                (AnnounceEvent::Started, NumberOfBytes::new(50))
-            }
-        };
+    fn random_announce_event(&mut self) -> (AnnounceEvent, NumberOfBytes) {
+        if self
+            .rng
+            .gen_bool(self.config.requests.peer_seeder_probability)
+        {
+            (AnnounceEvent::Completed, NumberOfBytes::new(0))
+        } else {
+            (AnnounceEvent::Started, NumberOfBytes::new(50))
+        }
+    }
 
-        let transaction_id =
-            TransactionId::new(i32::from_ne_bytes((peer_index as u32).to_ne_bytes()));
+    fn send_announce_request(
+        &mut self,
+        connection_ids: &[ConnectionId],
+        peer_index: usize,
+        transaction_id: TransactionId,
+        peer_id: PeerId,
+        event: AnnounceEvent,
+        bytes_left: NumberOfBytes,
+    ) {
+        let peer = self.peers.get(peer_index).unwrap();
 
         let request = AnnounceRequest {
             connection_id: connection_ids[peer.socket_index as usize],
             action_placeholder: Default::default(),
             transaction_id,
             info_hash: peer.announce_info_hash,
-            peer_id: PeerId([0; 20]),
+            peer_id,
             bytes_downloaded: NumberOfBytes::new(50),
             bytes_uploaded: NumberOfBytes::new(50),
             bytes_left,
@@ -255,12 +572,14 @@ impl Worker {
         }
     }
 
-    fn send_scrape_request(&mut self, connection_ids: &[ConnectionId], peer_index: usize) {
+    fn send_scrape_request(
+        &mut self,
+        connection_ids: &[ConnectionId],
+        peer_index: usize,
+        transaction_id: TransactionId,
+    ) {
         let peer = self.peers.get(peer_index).unwrap();
 
-        let transaction_id =
-            TransactionId::new(i32::from_ne_bytes((peer_index as u32).to_ne_bytes()));
-
         let mut info_hashes = Vec::with_capacity(peer.scrape_info_hash_indices.len());
 
         for i in peer.scrape_info_hash_indices.iter() {
@@ -289,7 +608,8 @@ impl Worker {
         }
     }
 
-    fn handle_response(&mut self, response: Response) {
+    /// Update statistics. `peer_index` is only used for announce responses.
+    fn handle_response(&mut self, response: Response, peer_index: usize) {
         match response {
             Response::Connect(_) => {
                 self.statistics.responses_connect += 1;
@@ -298,9 +618,6 @@ impl Worker {
                 self.statistics.responses_announce += 1;
                 self.statistics.response_peers += r.peers.len();
 
-                let peer_index =
-                    u32::from_ne_bytes(r.fixed.transaction_id.0.get().to_ne_bytes()) as usize;
-
                 if let Some(peer) = self.peers.get(peer_index) {
                     *self
                         .announce_responses_per_info_hash
@@ -312,9 +629,6 @@ impl Worker {
                 self.statistics.responses_announce += 1;
                 self.statistics.response_peers += r.peers.len();
 
-                let peer_index =
-                    u32::from_ne_bytes(r.fixed.transaction_id.0.get().to_ne_bytes()) as usize;
-
                 if let Some(peer) = self.peers.get(peer_index) {
                     *self
                         .announce_responses_per_info_hash
@@ -352,6 +666,9 @@ impl Worker {
         shared_statistics
             .response_peers
             .fetch_add(self.statistics.response_peers, Ordering::Relaxed);
+        shared_statistics
+            .requests_timed_out
+            .fetch_add(self.statistics.requests_timed_out, Ordering::Relaxed);
 
         if self.config.extra_statistics {
             let message = StatisticsMessage::ResponsesPerInfoHash(
@@ -361,11 +678,24 @@ impl Worker {
             self.statistics_sender.try_send(message).unwrap();
         }
 
+        if self.config.open_loop.active() {
+            let message = StatisticsMessage::ResponseLatencies(::std::mem::replace(
+                &mut self.response_latencies,
+                Histogram::new(3).unwrap(),
+            ));
+
+            self.statistics_sender.try_send(message).unwrap();
+        }
+
         self.statistics = LocalStatistics::default();
     }
 }
 
-fn create_socket(config: &Config, addr: SocketAddr) -> ::std::net::UdpSocket {
+fn create_socket(
+    config: &Config,
+    addr: SocketAddr,
+    server_address: SocketAddr,
+) -> ::std::net::UdpSocket {
     let socket = if addr.is_ipv4() {
         Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
     } else {
@@ -391,12 +721,38 @@ fn create_socket(config: &Config, addr: SocketAddr) -> ::std::net::UdpSocket {
         .unwrap_or_else(|err| panic!("socket: bind to {}: {:?}", addr, err));
 
     socket
-        .connect(&config.server_address.into())
+        .connect(&server_address.into())
         .expect("socket: connect to server");
 
     socket.into()
 }
 
+fn index_to_transaction_id(index: usize) -> TransactionId {
+    TransactionId::new(i32::from_ne_bytes((index as u32).to_ne_bytes()))
+}
+
+fn transaction_id_to_index(transaction_id: TransactionId) -> usize {
+    u32::from_ne_bytes(transaction_id.0.get().to_ne_bytes()) as usize
+}
+
+fn response_transaction_id(response: &Response) -> TransactionId {
+    match response {
+        Response::Connect(r) => r.transaction_id,
+        Response::AnnounceIpv4(r) => r.fixed.transaction_id,
+        Response::AnnounceIpv6(r) => r.fixed.transaction_id,
+        Response::Scrape(r) => r.transaction_id,
+        Response::Error(r) => r.transaction_id,
+    }
+}
+
+/// Request sent in open-loop mode that has not yet received a response
+struct PendingRequest {
+    scheduled_at: Instant,
+    request_type: RequestType,
+    /// Socket index for connect requests, peer index otherwise
+    index: usize,
+}
+
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub enum RequestType {
     Announce,
@@ -431,6 +787,7 @@ impl RequestTypeDist {
 #[derive(Default)]
 pub struct LocalStatistics {
     pub requests: usize,
+    pub requests_timed_out: usize,
     pub response_peers: usize,
     pub responses_connect: usize,
     pub responses_announce: usize,
@@ -1,12 +1,15 @@
 use std::fmt::Debug;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::num::NonZeroU16;
 
 pub use aquatic_peer_id::{PeerClient, PeerId};
 use zerocopy::network_endian::{I32, I64, U16, U32};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
-pub trait Ip: Clone + Copy + Debug + PartialEq + Eq + std::hash::Hash + AsBytes {}
+pub trait Ip:
+    Clone + Copy + Debug + PartialEq + Eq + std::hash::Hash + AsBytes + Into<IpAddr>
+{
+}
 
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, AsBytes, FromBytes, FromZeroes)]
 #[repr(transparent)]
@@ -219,6 +222,12 @@ impl From<Ipv4Addr> for Ipv4AddrBytes {
     }
 }
 
+impl From<Ipv4AddrBytes> for IpAddr {
+    fn from(val: Ipv4AddrBytes) -> Self {
+        IpAddr::V4(val.into())
+    }
+}
+
 #[derive(
     PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug, AsBytes, FromBytes, FromZeroes,
 )]
@@ -239,6 +248,12 @@ impl From<Ipv6Addr> for Ipv6AddrBytes {
     }
 }
 
+impl From<Ipv6AddrBytes> for IpAddr {
+    fn from(val: Ipv6AddrBytes) -> Self {
+        IpAddr::V6(val.into())
+    }
+}
+
 pub fn read_i32_ne(bytes: &mut impl ::std::io::Read) -> ::std::io::Result<I32> {
     let mut tmp = [0u8; 4];
 
@@ -1,6 +1,7 @@
 use std::{net::IpAddr, sync::Arc};
 
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::client_filter::ClientFilterArcSwap;
 
 pub use aquatic_common::ValidUntil;
 use aquatic_ws_protocol::common::{InfoHash, PeerId};
@@ -26,6 +27,7 @@ impl IpVersion {
 #[derive(Default, Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub client_filter: Arc<ClientFilterArcSwap>,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -2,12 +2,21 @@ use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::rc::Rc;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+#[cfg(feature = "metrics")]
+use aquatic_common::client_filter::rejected_client_label;
+use aquatic_common::client_filter::ClientFilterArcSwap;
+#[cfg(feature = "otlp")]
+use aquatic_common::otlp::Span;
 use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::statistics::{
+    CachePaddedArc, IpVersionStatistics, PeerClientCounter, SocketWorkerStatistics,
+};
 use aquatic_common::ServerStartInstant;
 use aquatic_ws_protocol::common::{InfoHash, PeerId, ScrapeAction};
 use aquatic_ws_protocol::incoming::{
@@ -48,6 +57,7 @@ type PeerClientGauge = (Gauge, Option<Gauge>);
 pub struct ConnectionRunner {
     pub config: Rc<Config>,
     pub access_list: Arc<AccessListArcSwap>,
+    pub client_filter: Arc<ClientFilterArcSwap>,
     pub in_message_senders: Rc<Senders<(InMessageMeta, InMessage)>>,
     pub connection_valid_until: Rc<RefCell<ValidUntil>>,
     pub out_message_sender: Rc<LocalSender<(OutMessageMeta, OutMessage)>>,
@@ -57,6 +67,8 @@ pub struct ConnectionRunner {
     pub connection_id: ConnectionId,
     pub opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
     pub ip_version: IpVersion,
+    pub statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
+    pub peer_clients: PeerClientCounter,
 }
 
 impl ConnectionRunner {
@@ -69,6 +81,9 @@ impl ConnectionRunner {
         let clean_up_data = ConnectionCleanupData {
             announced_info_hashes: Default::default(),
             ip_version: self.ip_version,
+            statistics: self.statistics.clone(),
+            peer_clients: self.peer_clients.clone(),
+            opt_statistics_peer_id: Default::default(),
             #[cfg(feature = "metrics")]
             opt_peer_client: Default::default(),
             #[cfg(feature = "metrics")]
@@ -178,6 +193,7 @@ impl ConnectionRunner {
             let mut reader = ConnectionReader {
                 config: self.config.clone(),
                 access_list_cache,
+                client_filter: self.client_filter,
                 in_message_senders: self.in_message_senders,
                 out_message_sender: self.out_message_sender,
                 pending_scrape_slab,
@@ -227,6 +243,7 @@ impl ConnectionRunner {
 struct ConnectionReader<S> {
     config: Rc<Config>,
     access_list_cache: AccessListCache,
+    client_filter: Arc<ClientFilterArcSwap>,
     in_message_senders: Rc<Senders<(InMessageMeta, InMessage)>>,
     out_message_sender: Rc<LocalSender<(OutMessageMeta, OutMessage)>>,
     pending_scrape_slab: Rc<RefCell<Slab<PendingScrapeResponse>>>,
@@ -254,10 +271,22 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
                 tungstenite::Message::Text(_) | tungstenite::Message::Binary(_) => {
                     match InMessage::from_ws_message(message) {
                         Ok(InMessage::AnnounceRequest(request)) => {
+                            #[cfg(feature = "otlp")]
+                            let opt_span = Span::start_sampled("announce");
+
                             self.handle_announce_request(request).await?;
+
+                            #[cfg(feature = "otlp")]
+                            self.finish_span(opt_span);
                         }
                         Ok(InMessage::ScrapeRequest(request)) => {
+                            #[cfg(feature = "otlp")]
+                            let opt_span = Span::start_sampled("scrape");
+
                             self.handle_scrape_request(request).await?;
+
+                            #[cfg(feature = "otlp")]
+                            self.finish_span(opt_span);
                         }
                         Err(err) => {
                             ::log::debug!("Couldn't parse in_message: {:#}", err);
@@ -280,7 +309,10 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
                     break Ok(());
                 }
                 tungstenite::Message::Frame(_) => {
-                    ::log::warn!("Read raw websocket frame, this should not happen");
+                    ::log::warn!(
+                        connection_id:? = self.connection_id;
+                        "Read raw websocket frame, this should not happen"
+                    );
                 }
             }
 
@@ -294,8 +326,35 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
         #[cfg(feature = "metrics")]
         self.total_announce_requests_counter.increment(1);
 
+        self.clean_up_data
+            .statistics()
+            .requests_announce
+            .fetch_add(1, Ordering::Relaxed);
+
         let info_hash = request.info_hash;
 
+        if !self
+            .client_filter
+            .load()
+            .allows(self.config.client_filter.mode, &request.peer_id.0)
+        {
+            #[cfg(feature = "metrics")]
+            ::metrics::counter!(
+                "aquatic_client_filter_rejections_total",
+                "client" => rejected_client_label(&request.peer_id.0),
+            )
+            .increment(1);
+
+            self.send_error_response(
+                "Client not allowed".into(),
+                Some(ErrorResponseAction::Announce),
+                Some(info_hash),
+            )
+            .await?;
+
+            return Ok(());
+        }
+
         if self
             .access_list_cache
             .load()
@@ -325,9 +384,20 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
                 Entry::Vacant(entry) => {
                     entry.insert(request.peer_id);
 
+                    // Count connection towards peer client statistics once
+                    if self.config.statistics.peer_clients
+                        && self.clean_up_data.opt_statistics_peer_id.borrow().is_none()
+                    {
+                        let peer_id = aquatic_peer_id::PeerId(request.peer_id.0);
+
+                        self.clean_up_data.peer_clients.add(peer_id);
+
+                        *self.clean_up_data.opt_statistics_peer_id.borrow_mut() = Some(peer_id);
+                    }
+
                     // Set peer client info if not set
                     #[cfg(feature = "metrics")]
-                    if self.config.metrics.run_prometheus_endpoint
+                    if self.config.metrics.active()
                         && self.config.metrics.peer_clients
                         && self.clean_up_data.opt_peer_client.borrow().is_none()
                     {
@@ -393,6 +463,11 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
         #[cfg(feature = "metrics")]
         self.total_scrape_requests_counter.increment(1);
 
+        self.clean_up_data
+            .statistics()
+            .requests_scrape
+            .fetch_add(1, Ordering::Relaxed);
+
         let info_hashes = if let Some(info_hashes) = request.info_hashes {
             info_hashes
         } else {
@@ -450,6 +525,20 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
         Ok(())
     }
 
+    /// Spans cover handling in socket worker up to passing on requests to
+    /// swarm workers, since responses are sent asynchronously
+    #[cfg(feature = "otlp")]
+    fn finish_span(&self, opt_span: Option<Span>) {
+        if let Some(mut span) = opt_span {
+            span.set_attribute("ip_version", ip_version_to_metrics_str(self.ip_version));
+            span.set_attribute(
+                "worker_index",
+                WORKER_INDEX.with(|index| index.get()).to_string(),
+            );
+            span.finish();
+        }
+    }
+
     async fn send_error_response(
         &self,
         failure_reason: Cow<'static, str>,
@@ -558,6 +647,20 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionWriter<S> {
             );
         }
 
+        {
+            let statistics = self.clean_up_data.statistics();
+
+            let counter = match out_message {
+                OutMessage::OfferOutMessage(_) => &statistics.responses_offer,
+                OutMessage::AnswerOutMessage(_) => &statistics.responses_answer,
+                OutMessage::AnnounceResponse(_) => &statistics.responses_announce,
+                OutMessage::ScrapeResponse(_) => &statistics.responses_scrape,
+                OutMessage::ErrorResponse(_) => &statistics.responses_error,
+            };
+
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+
         #[cfg(feature = "metrics")]
         {
             let out_message_type = match &out_message {
@@ -599,6 +702,10 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionWriter<S> {
 struct ConnectionCleanupData {
     announced_info_hashes: Rc<RefCell<HashMap<InfoHash, PeerId>>>,
     ip_version: IpVersion,
+    statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
+    peer_clients: PeerClientCounter,
+    /// Peer id counted towards peer client statistics, if any
+    opt_statistics_peer_id: Rc<RefCell<Option<aquatic_peer_id::PeerId>>>,
     #[cfg(feature = "metrics")]
     opt_peer_client: Rc<RefCell<Option<PeerClientGauge>>>,
     #[cfg(feature = "metrics")]
@@ -606,7 +713,16 @@ struct ConnectionCleanupData {
 }
 
 impl ConnectionCleanupData {
+    fn statistics(&self) -> &SocketWorkerStatistics {
+        self.statistics
+            .get(matches!(self.ip_version, IpVersion::V4))
+    }
+
     fn before_open(&self) {
+        self.statistics()
+            .connections
+            .fetch_add(1, Ordering::Relaxed);
+
         #[cfg(feature = "metrics")]
         self.active_connections_gauge.increment(1.0);
     }
@@ -638,6 +754,14 @@ impl ConnectionCleanupData {
                 .expect("control message receiver open");
         }
 
+        self.statistics()
+            .connections
+            .fetch_sub(1, Ordering::Relaxed);
+
+        if let Some(peer_id) = self.opt_statistics_peer_id.take() {
+            self.peer_clients.remove(peer_id);
+        }
+
         #[cfg(feature = "metrics")]
         self.active_connections_gauge.decrement(1.0);
 
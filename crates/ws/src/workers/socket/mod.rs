@@ -7,6 +7,9 @@ use std::time::Duration;
 use anyhow::Context;
 use aquatic_common::privileges::PrivilegeDropper;
 use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::statistics::{
+    CachePaddedArc, IpVersionStatistics, PeerClientCounter, SocketWorkerStatistics,
+};
 use aquatic_common::ServerStartInstant;
 use aquatic_ws_protocol::common::InfoHash;
 use aquatic_ws_protocol::incoming::InMessage;
@@ -58,6 +61,8 @@ pub async fn run_socket_worker(
     out_message_mesh_builder: MeshBuilder<(OutMessageMeta, OutMessage), Partial>,
     priv_dropper: PrivilegeDropper,
     server_start_instant: ServerStartInstant,
+    statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
+    peer_clients: PeerClientCounter,
     worker_index: usize,
 ) -> anyhow::Result<()> {
     #[cfg(feature = "metrics")]
@@ -65,6 +70,7 @@ pub async fn run_socket_worker(
 
     let config = Rc::new(config);
     let access_list = state.access_list;
+    let client_filter = state.client_filter;
 
     let listener = create_tcp_listener(&config, priv_dropper).context("create tcp listener")?;
 
@@ -170,15 +176,19 @@ pub async fn run_socket_worker(
                     enclose!((
                         config,
                         access_list,
+                        client_filter,
                         in_message_senders,
                         connection_valid_until,
                         opt_tls_config,
                         control_message_senders,
-                        connection_handles
+                        connection_handles,
+                        statistics,
+                        peer_clients
                     ) async move {
                         let runner = ConnectionRunner {
                             config,
                             access_list,
+                            client_filter,
                             in_message_senders,
                             connection_valid_until,
                             out_message_sender,
@@ -187,7 +197,9 @@ pub async fn run_socket_worker(
                             out_message_consumer_id,
                             connection_id,
                             opt_tls_config,
-                            ip_version
+                            ip_version,
+                            statistics,
+                            peer_clients,
                         };
 
                         runner.run(control_message_senders, close_conn_receiver, stream).await;
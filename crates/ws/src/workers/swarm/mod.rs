@@ -13,6 +13,9 @@ use glommio::prelude::*;
 use glommio::timer::TimerActionRepeat;
 use rand::{rngs::SmallRng, SeedableRng};
 
+#[cfg(feature = "metrics")]
+use aquatic_common::statistics::SwarmTopTorrentMetrics;
+use aquatic_common::statistics::{CachePaddedArc, IpVersionStatistics, SwarmWorkerStatistics};
 use aquatic_common::ServerStartInstant;
 
 use crate::common::*;
@@ -21,6 +24,7 @@ use crate::SHARED_IN_CHANNEL_SIZE;
 
 use self::storage::TorrentMaps;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn run_swarm_worker(
     config: Config,
     state: State,
@@ -28,6 +32,8 @@ pub async fn run_swarm_worker(
     in_message_mesh_builder: MeshBuilder<(InMessageMeta, InMessage), Partial>,
     out_message_mesh_builder: MeshBuilder<(OutMessageMeta, OutMessage), Partial>,
     server_start_instant: ServerStartInstant,
+    statistics: CachePaddedArc<IpVersionStatistics<SwarmWorkerStatistics>>,
+    #[cfg(feature = "metrics")] top_torrent_metrics: SwarmTopTorrentMetrics,
     worker_index: usize,
 ) -> anyhow::Result<()> {
     let (_, mut control_message_receivers) = control_message_mesh_builder
@@ -45,7 +51,13 @@ pub async fn run_swarm_worker(
 
     let out_message_senders = Rc::new(out_message_senders);
 
-    let torrents = Rc::new(RefCell::new(TorrentMaps::new(worker_index)));
+    let torrents = Rc::new(RefCell::new(TorrentMaps::new(
+        &config,
+        worker_index,
+        statistics,
+        #[cfg(feature = "metrics")]
+        top_torrent_metrics,
+    )));
     let access_list = state.access_list;
 
     // Periodically clean torrents
@@ -1,6 +1,15 @@
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+#[cfg(feature = "metrics")]
+use aquatic_common::statistics::SwarmTopTorrentMetrics;
+use aquatic_common::statistics::{
+    CachePaddedArc, IpVersionStatistics, SwarmWorkerStatistics, TopTorrent, TopTorrents,
+};
+use aquatic_common::torrent_limits::{
+    EvictionCandidate, PeerCountChange, TorrentLimitCounters, TorrentLimits, EVICTION_SAMPLE_SIZE,
+};
 use aquatic_ws_protocol::incoming::{
     AnnounceEvent, AnnounceRequest, AnnounceRequestOffer, ScrapeRequest,
 };
@@ -21,13 +30,28 @@ use crate::config::Config;
 pub struct TorrentMaps {
     ipv4: TorrentMap,
     ipv6: TorrentMap,
+    statistics: CachePaddedArc<IpVersionStatistics<SwarmWorkerStatistics>>,
+    #[cfg(feature = "metrics")]
+    top_torrent_metrics: SwarmTopTorrentMetrics,
 }
 
 impl TorrentMaps {
-    pub fn new(worker_index: usize) -> Self {
+    pub fn new(
+        config: &Config,
+        worker_index: usize,
+        statistics: CachePaddedArc<IpVersionStatistics<SwarmWorkerStatistics>>,
+        #[cfg(feature = "metrics")] top_torrent_metrics: SwarmTopTorrentMetrics,
+    ) -> Self {
+        let limits = config
+            .torrent_limits
+            .split(config.swarm_workers, worker_index);
+
         Self {
-            ipv4: TorrentMap::new(worker_index, IpVersion::V4),
-            ipv6: TorrentMap::new(worker_index, IpVersion::V6),
+            ipv4: TorrentMap::new(limits, worker_index, IpVersion::V4),
+            ipv6: TorrentMap::new(limits, worker_index, IpVersion::V6),
+            statistics,
+            #[cfg(feature = "metrics")]
+            top_torrent_metrics,
         }
     }
 
@@ -73,8 +97,16 @@ impl TorrentMaps {
         let mut access_list_cache = create_access_list_cache(access_list);
         let now = server_start_instant.seconds_elapsed();
 
-        self.ipv4.clean(config, &mut access_list_cache, now);
-        self.ipv6.clean(config, &mut access_list_cache, now);
+        self.ipv4
+            .clean(config, &mut access_list_cache, &self.statistics.ipv4, now);
+        self.ipv6
+            .clean(config, &mut access_list_cache, &self.statistics.ipv6, now);
+
+        #[cfg(feature = "metrics")]
+        if config.metrics.active() && config.statistics.top_torrents > 0 {
+            self.top_torrent_metrics.update(true);
+            self.top_torrent_metrics.update(false);
+        }
     }
 
     #[cfg(feature = "metrics")]
@@ -104,14 +136,22 @@ impl TorrentMaps {
 
 struct TorrentMap {
     torrents: IndexMap<InfoHash, TorrentData>,
+    limits: TorrentLimits,
+    num_peers: usize,
+    /// Rejections and evictions since previous cleaning
+    limit_counters: TorrentLimitCounters,
     #[cfg(feature = "metrics")]
     torrent_gauge: ::metrics::Gauge,
     #[cfg(feature = "metrics")]
     peer_gauge: ::metrics::Gauge,
+    #[cfg(feature = "metrics")]
+    ip_version: IpVersion,
+    #[cfg(feature = "metrics")]
+    worker_index: usize,
 }
 
 impl TorrentMap {
-    pub fn new(worker_index: usize, ip_version: IpVersion) -> Self {
+    pub fn new(limits: TorrentLimits, worker_index: usize, ip_version: IpVersion) -> Self {
         #[cfg(feature = "metrics")]
         let peer_gauge = match ip_version {
             IpVersion::V4 => ::metrics::gauge!(
@@ -141,10 +181,17 @@ impl TorrentMap {
 
         Self {
             torrents: Default::default(),
+            limits,
+            num_peers: 0,
+            limit_counters: Default::default(),
             #[cfg(feature = "metrics")]
             peer_gauge,
             #[cfg(feature = "metrics")]
             torrent_gauge,
+            #[cfg(feature = "metrics")]
+            ip_version,
+            #[cfg(feature = "metrics")]
+            worker_index,
         }
     }
 
@@ -157,7 +204,37 @@ impl TorrentMap {
         request_sender_meta: InMessageMeta,
         request: AnnounceRequest,
     ) {
-        let torrent_data = self.torrents.entry(request.info_hash).or_default();
+        if !self.torrents.contains_key(&request.info_hash)
+            && self.limits.torrent_limit_reached(self.torrents.len())
+            && !self.evict_sampled_torrent(rng, &request.info_hash)
+        {
+            self.limit_counters.rejected_torrents += 1;
+
+            let response = OutMessage::AnnounceResponse(AnnounceResponse {
+                action: AnnounceAction::Announce,
+                info_hash: request.info_hash,
+                complete: 0,
+                incomplete: 0,
+                announce_interval: config.protocol.peer_announce_interval,
+            });
+
+            out_messages.push((request_sender_meta.into(), response));
+
+            return;
+        }
+
+        let valid_until = ValidUntil::new(server_start_instant, config.cleaning.max_peer_age);
+        let peer_limit_reached = self.limits.peer_limit_reached(self.num_peers);
+
+        let torrent_data = self
+            .torrents
+            .entry(request.info_hash)
+            .or_insert_with(|| TorrentData {
+                peers: Default::default(),
+                num_seeders: 0,
+                last_announce: valid_until,
+                num_announces: 0,
+            });
 
         // If there is already a peer with this peer_id, check that connection id
         // is same as that of request sender. Otherwise, ignore request. Since
@@ -171,11 +248,14 @@ impl TorrentMap {
 
         ::log::trace!("received request from {:?}", request_sender_meta);
 
-        let peer_status = torrent_data.insert_or_update_peer(
-            config,
-            server_start_instant,
+        torrent_data.last_announce = valid_until;
+        torrent_data.num_announces += 1;
+
+        let (peer_status, peer_count_change) = torrent_data.insert_or_update_peer(
+            valid_until,
             request_sender_meta,
             &request,
+            !(peer_limit_reached && self.limits.rejects_new()),
             #[cfg(feature = "metrics")]
             &self.peer_gauge,
         );
@@ -222,6 +302,71 @@ impl TorrentMap {
         });
 
         out_messages.push((request_sender_meta.into(), response));
+
+        match peer_count_change {
+            PeerCountChange::Added => {
+                self.num_peers += 1;
+
+                if peer_limit_reached {
+                    self.evict_sampled_torrent(rng, &request.info_hash);
+                }
+            }
+            PeerCountChange::Removed => {
+                self.num_peers = self.num_peers.saturating_sub(1);
+            }
+            PeerCountChange::Rejected => {
+                self.limit_counters.rejected_peers += 1;
+            }
+            PeerCountChange::Unchanged => (),
+        }
+    }
+
+    /// Evict a torrent chosen among a random sample according to eviction
+    /// policy, never the one with `protected_info_hash`
+    ///
+    /// Returns false if no torrent was evicted.
+    fn evict_sampled_torrent(
+        &mut self,
+        rng: &mut SmallRng,
+        protected_info_hash: &InfoHash,
+    ) -> bool {
+        if self.limits.rejects_new() || self.torrents.is_empty() {
+            return false;
+        }
+
+        let num_torrents = self.torrents.len();
+        let torrents = &self.torrents;
+
+        let candidates = (0..EVICTION_SAMPLE_SIZE).filter_map(|_| {
+            let index = rng.gen_range(0..num_torrents);
+            let (info_hash, torrent_data) = torrents.get_index(index)?;
+
+            (info_hash != protected_info_hash).then(|| EvictionCandidate {
+                key: index,
+                last_announce: torrent_data.last_announce,
+                num_peers: torrent_data.peers.len(),
+            })
+        });
+
+        if let Some(index) = self.limits.select_eviction(candidates) {
+            if let Some((_, torrent_data)) = self.torrents.swap_remove_index(index) {
+                self.on_torrent_evicted(torrent_data);
+
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn on_torrent_evicted(&mut self, torrent_data: TorrentData) {
+        let num_peers = torrent_data.peers.len();
+
+        self.num_peers = self.num_peers.saturating_sub(num_peers);
+        self.limit_counters.evicted_torrents += 1;
+
+        #[cfg(feature = "metrics")]
+        self.peer_gauge.decrement(num_peers as f64);
     }
 
     pub fn handle_scrape_request(
@@ -261,11 +406,15 @@ impl TorrentMap {
 
     pub fn handle_connection_closed(&mut self, info_hash: InfoHash, peer_id: PeerId) {
         if let Some(torrent_data) = self.torrents.get_mut(&info_hash) {
-            torrent_data.handle_connection_closed(
+            let removed = torrent_data.handle_connection_closed(
                 peer_id,
                 #[cfg(feature = "metrics")]
                 &self.peer_gauge,
             );
+
+            if removed {
+                self.num_peers = self.num_peers.saturating_sub(1);
+            }
         }
     }
 
@@ -278,9 +427,12 @@ impl TorrentMap {
         &mut self,
         config: &Config,
         access_list_cache: &mut AccessListCache,
+        statistics: &SwarmWorkerStatistics,
         now: SecondsSinceServerStart,
     ) {
         let mut total_num_peers = 0u64;
+        let mut opt_top_torrents = (config.statistics.top_torrents > 0)
+            .then(|| TopTorrents::new(config.statistics.top_torrents));
 
         self.torrents.retain(|info_hash, torrent_data| {
             if !access_list_cache
@@ -294,23 +446,112 @@ impl TorrentMap {
 
             total_num_peers += num_peers as u64;
 
+            let num_announces = ::std::mem::take(&mut torrent_data.num_announces);
+
+            if let Some(top_torrents) = opt_top_torrents.as_mut() {
+                top_torrents.add(TopTorrent {
+                    info_hash: info_hash.0,
+                    peers: num_peers,
+                    announces: num_announces,
+                });
+            }
+
             num_peers > 0
         });
 
+        self.num_peers = total_num_peers as usize;
+
+        self.enforce_limits();
+
         self.torrents.shrink_to_fit();
 
+        statistics
+            .torrents
+            .store(self.torrents.len(), Ordering::Relaxed);
+        statistics.peers.store(self.num_peers, Ordering::Relaxed);
+
+        let limit_counters = ::std::mem::take(&mut self.limit_counters);
+
+        statistics.add_torrent_limit_counters(limit_counters);
+
         #[cfg(feature = "metrics")]
-        self.peer_gauge.set(total_num_peers as f64);
+        {
+            self.peer_gauge.set(self.num_peers as f64);
+
+            if config.metrics.active() {
+                self.update_torrent_limit_metrics(limit_counters);
+            }
+        }
 
         #[cfg(feature = "metrics")]
         self.update_torrent_gauge();
+
+        if let Some(top_torrents) = opt_top_torrents {
+            *statistics.top_torrents.lock().unwrap() = top_torrents;
+        }
+    }
+
+    /// Evict torrents in policy order until limits are met
+    fn enforce_limits(&mut self) {
+        if self.limits.rejects_new() || !self.limits.exceeded(self.torrents.len(), self.num_peers) {
+            return;
+        }
+
+        let candidates = self
+            .torrents
+            .iter()
+            .map(|(info_hash, torrent_data)| EvictionCandidate {
+                key: *info_hash,
+                last_announce: torrent_data.last_announce,
+                num_peers: torrent_data.peers.len(),
+            })
+            .collect();
+
+        for info_hash in self.limits.select_evictions(candidates) {
+            if let Some(torrent_data) = self.torrents.swap_remove(&info_hash) {
+                self.on_torrent_evicted(torrent_data);
+            }
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn update_torrent_limit_metrics(&self, limit_counters: TorrentLimitCounters) {
+        let ip_version = match self.ip_version {
+            IpVersion::V4 => "4",
+            IpVersion::V6 => "6",
+        };
+        let worker_index = self.worker_index.to_string();
+
+        ::metrics::counter!(
+            "aquatic_torrent_limit_rejections_total",
+            "type" => "torrent",
+            "ip_version" => ip_version,
+            "worker_index" => worker_index.clone(),
+        )
+        .increment(limit_counters.rejected_torrents as u64);
+        ::metrics::counter!(
+            "aquatic_torrent_limit_rejections_total",
+            "type" => "peer",
+            "ip_version" => ip_version,
+            "worker_index" => worker_index.clone(),
+        )
+        .increment(limit_counters.rejected_peers as u64);
+        ::metrics::counter!(
+            "aquatic_torrent_evictions_total",
+            "ip_version" => ip_version,
+            "worker_index" => worker_index,
+        )
+        .increment(limit_counters.evicted_torrents as u64);
     }
 }
 
-#[derive(Default)]
 struct TorrentData {
     peers: IndexMap<PeerId, Peer>,
     num_seeders: usize,
+    /// Peer validity set on latest announce
+    last_announce: ValidUntil,
+    /// Announces since previous cleaning
+    num_announces: usize,
 }
 
 impl TorrentData {
@@ -318,22 +559,24 @@ impl TorrentData {
         self.peers.len() - self.num_seeders
     }
 
+    /// Insert, update or remove peer
+    ///
+    /// Peers that are not already present are only inserted if
+    /// `allow_new_peer` is true.
     pub fn insert_or_update_peer(
         &mut self,
-        config: &Config,
-        server_start_instant: ServerStartInstant,
+        valid_until: ValidUntil,
         request_sender_meta: InMessageMeta,
         request: &AnnounceRequest,
+        allow_new_peer: bool,
         #[cfg(feature = "metrics")] peer_gauge: &::metrics::Gauge,
-    ) -> PeerStatus {
-        let valid_until = ValidUntil::new(server_start_instant, config.cleaning.max_peer_age);
-
+    ) -> (PeerStatus, PeerCountChange) {
         let peer_status = PeerStatus::from_event_and_bytes_left(
             request.event.unwrap_or_default(),
             request.bytes_left,
         );
 
-        match self.peers.entry(request.peer_id) {
+        let peer_count_change = match self.peers.entry(request.peer_id) {
             ::indexmap::map::Entry::Occupied(mut entry) => match peer_status {
                 PeerStatus::Leeching => {
                     let peer = entry.get_mut();
@@ -344,6 +587,8 @@ impl TorrentData {
 
                     peer.seeder = false;
                     peer.valid_until = valid_until;
+
+                    PeerCountChange::Unchanged
                 }
                 PeerStatus::Seeding => {
                     let peer = entry.get_mut();
@@ -354,6 +599,8 @@ impl TorrentData {
 
                     peer.seeder = true;
                     peer.valid_until = valid_until;
+
+                    PeerCountChange::Unchanged
                 }
                 PeerStatus::Stopped => {
                     let peer = entry.swap_remove();
@@ -364,8 +611,14 @@ impl TorrentData {
 
                     #[cfg(feature = "metrics")]
                     peer_gauge.decrement(1.0);
+
+                    PeerCountChange::Removed
                 }
             },
+            ::indexmap::map::Entry::Vacant(_) if !allow_new_peer => match peer_status {
+                PeerStatus::Leeching | PeerStatus::Seeding => PeerCountChange::Rejected,
+                PeerStatus::Stopped => PeerCountChange::Unchanged,
+            },
             ::indexmap::map::Entry::Vacant(entry) => match peer_status {
                 PeerStatus::Leeching => {
                     let peer = Peer {
@@ -379,7 +632,9 @@ impl TorrentData {
                     entry.insert(peer);
 
                     #[cfg(feature = "metrics")]
-                    peer_gauge.increment(1.0)
+                    peer_gauge.increment(1.0);
+
+                    PeerCountChange::Added
                 }
                 PeerStatus::Seeding => {
                     self.num_seeders += 1;
@@ -396,12 +651,14 @@ impl TorrentData {
 
                     #[cfg(feature = "metrics")]
                     peer_gauge.increment(1.0);
+
+                    PeerCountChange::Added
                 }
-                PeerStatus::Stopped => (),
+                PeerStatus::Stopped => PeerCountChange::Unchanged,
             },
-        }
+        };
 
-        peer_status
+        (peer_status, peer_count_change)
     }
 
     /// Pass on offers to random peers
@@ -514,11 +771,12 @@ impl TorrentData {
         }
     }
 
+    /// Remove peer if present and return true if it was removed
     pub fn handle_connection_closed(
         &mut self,
         peer_id: PeerId,
         #[cfg(feature = "metrics")] peer_gauge: &::metrics::Gauge,
-    ) {
+    ) -> bool {
         if let Some(peer) = self.peers.swap_remove(&peer_id) {
             if peer.seeder {
                 self.num_seeders -= 1;
@@ -526,6 +784,10 @@ impl TorrentData {
 
             #[cfg(feature = "metrics")]
             peer_gauge.decrement(1.0);
+
+            true
+        } else {
+            false
         }
     }
 
@@ -1,6 +1,9 @@
-use std::sync::{atomic::AtomicUsize, Arc};
+use std::sync::{atomic::AtomicUsize, Arc, Mutex};
+use std::time::Instant;
 
+use aquatic_common::peer_lifecycle::LifecycleStatistics;
 use aquatic_ws_protocol::common::InfoHash;
+use hdrhistogram::Histogram;
 use rand_distr::Gamma;
 
 #[derive(Default)]
@@ -12,7 +15,11 @@ pub struct Statistics {
     pub responses_answer: AtomicUsize,
     pub responses_scrape: AtomicUsize,
     pub responses_error: AtomicUsize,
+    pub answers_sent: AtomicUsize,
+    /// Answers received by the connection that sent the offer
+    pub answers_delivered: AtomicUsize,
     pub connections: AtomicUsize,
+    pub lifecycle: LifecycleStatistics,
 }
 
 #[derive(Clone)]
@@ -20,6 +27,11 @@ pub struct LoadTestState {
     pub info_hashes: Arc<[InfoHash]>,
     pub statistics: Arc<Statistics>,
     pub gamma: Arc<Gamma<f64>>,
+    pub start_instant: Instant,
+    /// Response latencies in microseconds (open-loop mode only)
+    pub response_latencies: Arc<Mutex<Histogram<u64>>>,
+    /// Offer to answer round trip latencies in microseconds
+    pub answer_latencies: Arc<Mutex<Histogram<u64>>>,
 }
 
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -1,6 +1,8 @@
 use std::net::SocketAddr;
 
 use aquatic_common::cli::LogLevel;
+use aquatic_common::open_loop::OpenLoopConfig;
+use aquatic_common::peer_lifecycle::LifecycleConfig;
 use aquatic_toml_config::TomlConfig;
 use serde::Deserialize;
 
@@ -16,6 +18,19 @@ pub struct Config {
     pub duration: usize,
     pub measure_after_max_connections_reached: bool,
     pub torrents: TorrentConfig,
+    /// Open-loop (fixed request rate) mode
+    ///
+    /// Requests are scheduled per connection, and latency is measured until
+    /// the corresponding announce or scrape response arrives.
+    pub open_loop: OpenLoopConfig,
+    /// Peer lifecycle simulation
+    ///
+    /// Each connection simulates its own peers, with arrivals divided
+    /// between connections. Announce requests are sent on their behalf
+    /// instead of random requests, using `torrents.peer_seeder_probability`
+    /// for arriving peers. Offers to torrents of active simulated peers are
+    /// still answered.
+    pub lifecycle: LifecycleConfig,
 }
 
 impl aquatic_common::cli::Config for Config {
@@ -35,6 +50,8 @@ impl Default for Config {
             duration: 0,
             measure_after_max_connections_reached: true,
             torrents: TorrentConfig::default(),
+            open_loop: OpenLoopConfig::default(),
+            lifecycle: LifecycleConfig::default(),
         }
     }
 }
@@ -56,6 +73,11 @@ pub struct TorrentConfig {
     pub torrent_gamma_shape: f64,
     /// Peers choose torrents according to this Gamma distribution scale
     pub torrent_gamma_scale: f64,
+    /// Consider offers lost if no answer has been received within this many
+    /// seconds. Offers received from other peers are not answered after
+    /// this period either. Should match `cleaning.max_offer_age` in tracker
+    /// config.
+    pub max_offer_age: u64,
 }
 
 impl Default for TorrentConfig {
@@ -68,6 +90,7 @@ impl Default for TorrentConfig {
             weight_scrape: 0,
             torrent_gamma_shape: 0.2,
             torrent_gamma_scale: 100.0,
+            max_offer_age: 120,
         }
     }
 }
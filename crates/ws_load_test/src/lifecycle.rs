@@ -0,0 +1,178 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::time::Instant;
+
+use aquatic_common::peer_lifecycle::{LifecycleEvent, PeerEvent, PeerPopulation};
+use aquatic_ws_protocol::common::{InfoHash, PeerId};
+use aquatic_ws_protocol::incoming::AnnounceEvent;
+use rand::Rng;
+
+use crate::common::LoadTestState;
+use crate::config::Config;
+use crate::utils::select_info_hash_index;
+
+/// Number of times to try selecting a torrent not already claimed on the
+/// connection before giving up on an arriving peer
+const MAX_INFO_HASH_ATTEMPTS: usize = 16;
+
+/// Announce request to be sent on behalf of a simulated peer
+pub struct SimulatedAnnounce {
+    pub peer_index: usize,
+    pub peer_id: PeerId,
+    pub info_hash: InfoHash,
+    pub event: AnnounceEvent,
+    pub bytes_left: usize,
+}
+
+/// Simulated peers of a connection
+///
+/// The tracker only accepts a single peer id per torrent and connection, so
+/// peers are pinned to a connection and never share a torrent.
+pub struct SimulatedPeers {
+    population: PeerPopulation,
+    /// Torrent of each active peer
+    peers: HashMap<usize, InfoHash>,
+    /// Torrents that have been announced on the connection
+    ///
+    /// Entries are only removed on stopped events, since the tracker keeps
+    /// requiring the same peer id for a torrent until then. This includes
+    /// torrents of peers that left without sending one.
+    claimed_info_hashes: HashMap<InfoHash, ClaimedInfoHash>,
+}
+
+struct ClaimedInfoHash {
+    peer_id: PeerId,
+    /// Bytes left according to last announce request
+    bytes_left: usize,
+    /// Whether peer is still active
+    active: bool,
+}
+
+impl SimulatedPeers {
+    pub fn new(config: &Config, rng: &mut impl Rng) -> anyhow::Result<Self> {
+        let population = PeerPopulation::new(
+            &config.lifecycle,
+            config.torrents.peer_seeder_probability,
+            config.num_workers * config.num_connections_per_worker,
+            usize::MAX,
+            rng,
+            Instant::now(),
+        )?;
+
+        Ok(Self {
+            population,
+            peers: Default::default(),
+            claimed_info_hashes: Default::default(),
+        })
+    }
+
+    /// Return next announce request that is due, if any
+    pub fn next_announce(
+        &mut self,
+        config: &Config,
+        state: &LoadTestState,
+        rng: &mut impl Rng,
+        now: Instant,
+    ) -> Option<SimulatedAnnounce> {
+        loop {
+            let event = self.population.poll(rng, now)?;
+
+            state.statistics.lifecycle.record(&event);
+
+            let (peer_index, peer_id, event, seeding) = match event {
+                LifecycleEvent::Announce {
+                    peer_index,
+                    peer_id,
+                    event,
+                    seeding,
+                } => (peer_index, PeerId(peer_id), event, seeding),
+                LifecycleEvent::Abandon { peer_index } => {
+                    if let Some(info_hash) = self.peers.remove(&peer_index) {
+                        if let Some(claimed) = self.claimed_info_hashes.get_mut(&info_hash) {
+                            claimed.active = false;
+                        }
+                    }
+
+                    continue;
+                }
+                LifecycleEvent::ArrivalDropped => continue,
+            };
+
+            // Peer indices are reused, so pick new torrent. If no unclaimed
+            // torrent is found, the peer stays silent for its whole session.
+            if event == PeerEvent::Started {
+                if let Some(info_hash) = self.claim_info_hash(config, state, rng, peer_id) {
+                    self.peers.insert(peer_index, info_hash);
+                }
+            }
+
+            let info_hash = match self.peers.get(&peer_index) {
+                Some(info_hash) => *info_hash,
+                None => continue,
+            };
+
+            let event = match event {
+                PeerEvent::Started => AnnounceEvent::Started,
+                PeerEvent::Completed => AnnounceEvent::Completed,
+                PeerEvent::Stopped => {
+                    self.peers.remove(&peer_index);
+                    self.claimed_info_hashes.remove(&info_hash);
+
+                    AnnounceEvent::Stopped
+                }
+                PeerEvent::None => AnnounceEvent::Update,
+            };
+
+            let bytes_left = if seeding { 0 } else { 50 };
+
+            if let Some(claimed) = self.claimed_info_hashes.get_mut(&info_hash) {
+                claimed.bytes_left = bytes_left;
+            }
+
+            return Some(SimulatedAnnounce {
+                peer_index,
+                peer_id,
+                info_hash,
+                event,
+                bytes_left,
+            });
+        }
+    }
+
+    /// Peer id and bytes left of active peer announcing torrent on the
+    /// connection, if any
+    pub fn active_peer(&self, info_hash: &InfoHash) -> Option<(PeerId, usize)> {
+        self.claimed_info_hashes
+            .get(info_hash)
+            .filter(|claimed| claimed.active)
+            .map(|claimed| (claimed.peer_id, claimed.bytes_left))
+    }
+
+    pub fn population(&mut self) -> &mut PeerPopulation {
+        &mut self.population
+    }
+
+    fn claim_info_hash(
+        &mut self,
+        config: &Config,
+        state: &LoadTestState,
+        rng: &mut impl Rng,
+        peer_id: PeerId,
+    ) -> Option<InfoHash> {
+        for _ in 0..MAX_INFO_HASH_ATTEMPTS {
+            let info_hash = state.info_hashes[select_info_hash_index(config, state, rng)];
+
+            if let Entry::Vacant(entry) = self.claimed_info_hashes.entry(info_hash) {
+                entry.insert(ClaimedInfoHash {
+                    peer_id,
+                    bytes_left: 0,
+                    active: true,
+                });
+
+                return Some(info_hash);
+            }
+        }
+
+        None
+    }
+}
@@ -1,14 +1,17 @@
-use std::sync::{atomic::Ordering, Arc};
+use std::sync::{atomic::Ordering, Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use aquatic_common::open_loop::RateSchedule;
 use aquatic_ws_protocol::common::InfoHash;
 use glommio::LocalExecutorBuilder;
+use hdrhistogram::Histogram;
 use rand::prelude::*;
 use rand_distr::Gamma;
 
 mod common;
 mod config;
+mod lifecycle;
 mod network;
 mod utils;
 
@@ -19,6 +22,8 @@ use network::*;
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
+const PERCENTILES: &[f64] = &[10.0, 25.0, 50.0, 75.0, 90.0, 95.0, 99.0, 99.9, 100.0];
+
 pub fn main() {
     aquatic_common::cli::run_app_with_cli_and_config::<Config>(
         "aquatic_ws_load_test: WebTorrent load tester",
@@ -32,6 +37,9 @@ fn run(config: Config) -> ::anyhow::Result<()> {
     if config.torrents.weight_announce + config.torrents.weight_scrape == 0 {
         panic!("Error: at least one weight must be larger than zero.");
     }
+    if config.lifecycle.active() && config.open_loop.active() {
+        panic!("Error: open-loop mode and lifecycle simulation can't be combined.");
+    }
 
     println!("Starting client with config: {:#?}", config);
 
@@ -53,6 +61,9 @@ fn run(config: Config) -> ::anyhow::Result<()> {
         info_hashes: Arc::from(info_hashes.into_boxed_slice()),
         statistics: Arc::new(Statistics::default()),
         gamma: Arc::new(gamma),
+        start_instant: Instant::now(),
+        response_latencies: Arc::new(Mutex::new(Histogram::new(3).unwrap())),
+        answer_latencies: Arc::new(Mutex::new(Histogram::new(3).unwrap())),
     };
 
     let tls_config = create_tls_config().unwrap();
@@ -133,9 +144,19 @@ fn create_tls_config() -> anyhow::Result<Arc<rustls::ClientConfig>> {
 }
 
 fn monitor_statistics(state: LoadTestState, config: &Config) {
-    let start_time = Instant::now();
+    let start_time = state.start_instant;
     let mut time_max_connections_reached = None;
     let mut report_avg_response_vec: Vec<f64> = Vec::new();
+    let mut report_latencies = Histogram::<u64>::new(3).unwrap();
+    let mut report_answer_latencies = Histogram::<u64>::new(3).unwrap();
+    let mut report_answers_sent = 0usize;
+    let mut report_answers_delivered = 0usize;
+
+    let open_loop_schedule = config
+        .open_loop
+        .active()
+        .then(|| RateSchedule::new(&config.open_loop, 1));
+    let mut last = start_time;
 
     let interval = 5;
     let interval_f64 = interval as f64;
@@ -162,6 +183,9 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
         let responses_error_per_second =
             statistics.responses_error.fetch_and(0, Ordering::Relaxed) as f64 / interval_f64;
 
+        let answers_sent = statistics.answers_sent.fetch_and(0, Ordering::Relaxed);
+        let answers_delivered = statistics.answers_delivered.fetch_and(0, Ordering::Relaxed);
+
         let responses_announce_per_second = responses_announce / interval_f64;
 
         let connections = statistics.connections.load(Ordering::Relaxed);
@@ -172,8 +196,32 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
             + responses_scrape_per_second
             + responses_error_per_second;
 
+        let opt_latencies = open_loop_schedule.is_some().then(|| {
+            ::std::mem::replace(
+                &mut *state.response_latencies.lock().unwrap(),
+                Histogram::new(3).unwrap(),
+            )
+        });
+
+        let answer_latencies = ::std::mem::replace(
+            &mut *state.answer_latencies.lock().unwrap(),
+            Histogram::new(3).unwrap(),
+        );
+
         if !config.measure_after_max_connections_reached || time_max_connections_reached.is_some() {
             report_avg_response_vec.push(responses_per_second);
+            report_answers_sent += answers_sent;
+            report_answers_delivered += answers_delivered;
+
+            if let Err(err) = report_answer_latencies.add(&answer_latencies) {
+                eprintln!("Couldn't merge latency histograms: {:#}", err);
+            }
+
+            if let Some(latencies) = opt_latencies.as_ref() {
+                if let Err(err) = report_latencies.add(latencies) {
+                    eprintln!("Couldn't merge latency histograms: {:#}", err);
+                }
+            }
         } else if connections >= config.num_workers * config.num_connections_per_worker {
             time_max_connections_reached = Some(Instant::now());
 
@@ -183,6 +231,19 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
         }
 
         println!();
+
+        if let Some(schedule) = open_loop_schedule.as_ref() {
+            let now = Instant::now();
+
+            let target_per_second = (schedule.requests_due(now - start_time)
+                - schedule.requests_due(last - start_time))
+                / (now - last).as_secs_f64();
+
+            last = now;
+
+            println!("Target requests: {:.2}/second", target_per_second);
+        }
+
         println!("Requests out: {:.2}/second", requests_per_second);
         println!("Responses in: {:.2}/second", responses_per_second);
         println!(
@@ -194,6 +255,24 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
         println!("  - Scrape responses:   {:.2}", responses_scrape_per_second);
         println!("  - Error responses:   {:.2}", responses_error_per_second);
         println!("Active connections: {}", connections);
+        println!(
+            "Answers sent: {:.2}/second",
+            answers_sent as f64 / interval_f64
+        );
+        println!(
+            "Answers delivered to offering peer: {:.2}/second",
+            answers_delivered as f64 / interval_f64
+        );
+
+        if config.lifecycle.active() {
+            statistics.lifecycle.print_and_reset(interval_f64);
+        }
+
+        if let Some(latencies) = opt_latencies.as_ref() {
+            print_latency_percentiles("Response latency", latencies);
+        }
+
+        print_latency_percentiles("Offer to answer latency", &answer_latencies);
 
         if config.measure_after_max_connections_reached {
             if let Some(start) = time_max_connections_reached {
@@ -202,7 +281,15 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
                 if config.duration != 0
                     && time_elapsed >= Duration::from_secs(config.duration as u64)
                 {
-                    report(config, report_avg_response_vec, time_elapsed);
+                    report(
+                        config,
+                        report_avg_response_vec,
+                        &report_latencies,
+                        &report_answer_latencies,
+                        report_answers_sent,
+                        report_answers_delivered,
+                        time_elapsed,
+                    );
 
                     break;
                 }
@@ -211,7 +298,15 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
             let time_elapsed = start_time.elapsed();
 
             if config.duration != 0 && time_elapsed >= Duration::from_secs(config.duration as u64) {
-                report(config, report_avg_response_vec, time_elapsed);
+                report(
+                    config,
+                    report_avg_response_vec,
+                    &report_latencies,
+                    &report_answer_latencies,
+                    report_answers_sent,
+                    report_answers_delivered,
+                    time_elapsed,
+                );
 
                 break;
             }
@@ -219,7 +314,15 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
     }
 }
 
-fn report(config: &Config, report_avg_response_vec: Vec<f64>, time_elapsed: Duration) {
+fn report(
+    config: &Config,
+    report_avg_response_vec: Vec<f64>,
+    report_latencies: &Histogram<u64>,
+    report_answer_latencies: &Histogram<u64>,
+    report_answers_sent: usize,
+    report_answers_delivered: usize,
+    time_elapsed: Duration,
+) {
     let report_len = report_avg_response_vec.len() as f64;
     let report_sum: f64 = report_avg_response_vec.into_iter().sum();
     let report_avg: f64 = report_sum / report_len;
@@ -228,10 +331,33 @@ fn report(config: &Config, report_avg_response_vec: Vec<f64>, time_elapsed: Dura
         concat!(
             "\n# aquatic load test report\n\n",
             "Test ran for {} seconds.\n",
-            "Average responses per second: {:.2}\n\nConfig: {:#?}\n"
+            "Average responses per second: {:.2}",
         ),
         time_elapsed.as_secs(),
         report_avg,
-        config
     );
+
+    if config.open_loop.active() {
+        print_latency_percentiles("Response latency", report_latencies);
+    }
+
+    println!(
+        "Answers delivered to offering peer: {:.2}%",
+        100.0 * report_answers_delivered as f64 / report_answers_sent.max(1) as f64
+    );
+    print_latency_percentiles("Offer to answer latency", report_answer_latencies);
+
+    println!("\nConfig: {:#?}\n", config);
+}
+
+fn print_latency_percentiles(title: &str, latencies: &Histogram<u64>) {
+    println!("{} (milliseconds):", title);
+
+    for p in PERCENTILES {
+        println!(
+            "  - p{}: {:.2}",
+            p,
+            latencies.value_at_percentile(*p) as f64 / 1000.0
+        );
+    }
 }
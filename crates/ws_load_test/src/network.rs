@@ -1,11 +1,13 @@
 use std::{
     cell::RefCell,
+    collections::{HashMap, VecDeque},
     convert::TryInto,
     rc::Rc,
     sync::{atomic::Ordering, Arc},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use aquatic_common::open_loop::RateSchedule;
 use aquatic_ws_protocol::incoming::{
     AnnounceEvent, AnnounceRequest, AnnounceRequestOffer, InMessage, ScrapeRequestInfoHashes,
 };
@@ -18,19 +20,46 @@ use aquatic_ws_protocol::{
     incoming::ScrapeRequest,
 };
 use async_tungstenite::{client_async, WebSocketStream};
+use futures::future::Either;
 use futures::{SinkExt, StreamExt};
 use futures_rustls::{client::TlsStream, TlsConnector};
 use glommio::net::TcpStream;
 use glommio::{prelude::*, timer::TimerActionRepeat};
+use hdrhistogram::Histogram;
 use rand::{prelude::SmallRng, Rng, SeedableRng};
 use rand_distr::{Distribution, WeightedIndex};
 
 use crate::{
     common::{LoadTestState, RequestType},
     config::Config,
+    lifecycle::{SimulatedAnnounce, SimulatedPeers},
     utils::select_info_hash_index,
 };
 
+const SDP: &str = "abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg";
+
+/// Wait at most this long before checking for due lifecycle events again
+const MAX_LIFECYCLE_WAIT: Duration = Duration::from_millis(100);
+
+type LocalLatencies = Rc<RefCell<Latencies>>;
+
+/// Latencies recorded by connections on current thread, in microseconds
+struct Latencies {
+    /// Response latencies (open-loop mode only)
+    responses: Histogram<u64>,
+    /// Time from sending offer until receiving answer to it
+    answers: Histogram<u64>,
+}
+
+impl Latencies {
+    fn new() -> Self {
+        Self {
+            responses: Histogram::new(3).unwrap(),
+            answers: Histogram::new(3).unwrap(),
+        }
+    }
+}
+
 pub async fn run_socket_thread(
     config: Config,
     tls_config: Arc<rustls::ClientConfig>,
@@ -39,9 +68,19 @@ pub async fn run_socket_thread(
     let config = Rc::new(config);
     let rng = Rc::new(RefCell::new(SmallRng::from_entropy()));
     let num_active_connections = Rc::new(RefCell::new(0usize));
+    let latencies: LocalLatencies = Rc::new(RefCell::new(Latencies::new()));
     let connection_creation_interval =
         Duration::from_millis(config.connection_creation_interval_ms);
 
+    {
+        let latencies = latencies.clone();
+        let load_test_state = load_test_state.clone();
+
+        TimerActionRepeat::repeat(move || {
+            flush_latencies(load_test_state.clone(), latencies.clone())
+        });
+    }
+
     TimerActionRepeat::repeat(move || {
         periodically_open_connections(
             config.clone(),
@@ -49,6 +88,7 @@ pub async fn run_socket_thread(
             load_test_state.clone(),
             num_active_connections.clone(),
             rng.clone(),
+            latencies.clone(),
             connection_creation_interval,
         )
     })
@@ -64,6 +104,7 @@ async fn periodically_open_connections(
     load_test_state: LoadTestState,
     num_active_connections: Rc<RefCell<usize>>,
     rng: Rc<RefCell<SmallRng>>,
+    latencies: LocalLatencies,
     connection_creation_interval: Duration,
 ) -> Option<Duration> {
     if *num_active_connections.borrow() < config.num_connections_per_worker {
@@ -74,6 +115,7 @@ async fn periodically_open_connections(
                 load_test_state,
                 num_active_connections,
                 rng,
+                latencies,
             )
             .await
             {
@@ -86,13 +128,82 @@ async fn periodically_open_connections(
     Some(connection_creation_interval)
 }
 
+/// Move latencies recorded on this thread to shared histograms
+async fn flush_latencies(
+    load_test_state: LoadTestState,
+    latencies: LocalLatencies,
+) -> Option<Duration> {
+    let local_latencies = latencies.replace(Latencies::new());
+
+    for (shared, local) in [
+        (
+            &load_test_state.response_latencies,
+            local_latencies.responses,
+        ),
+        (&load_test_state.answer_latencies, local_latencies.answers),
+    ] {
+        if let Err(err) = shared.lock().unwrap().add(local) {
+            ::log::error!("couldn't merge latency histograms: {:#}", err);
+        }
+    }
+
+    Some(Duration::from_secs(1))
+}
+
+/// Offers sent on a connection that have not yet been answered
+#[derive(Default)]
+struct PendingOffers {
+    sent_at: HashMap<OfferId, Instant>,
+    /// Offer ids in the order they were sent, used for expiring them
+    expiry_queue: VecDeque<(Instant, OfferId)>,
+}
+
+impl PendingOffers {
+    fn insert(&mut self, offer_id: OfferId, now: Instant) {
+        self.sent_at.insert(offer_id, now);
+        self.expiry_queue.push_back((now, offer_id));
+    }
+
+    /// Remove offer, returning time it was sent
+    fn remove(&mut self, offer_id: &OfferId) -> Option<Instant> {
+        self.sent_at.remove(offer_id)
+    }
+
+    /// Forget offers sent before `cutoff`
+    fn expire(&mut self, cutoff: Instant) {
+        while let Some((sent_at, offer_id)) = self.expiry_queue.front().copied() {
+            if sent_at >= cutoff {
+                break;
+            }
+
+            self.expiry_queue.pop_front();
+
+            if self.sent_at.get(&offer_id) == Some(&sent_at) {
+                self.sent_at.remove(&offer_id);
+            }
+        }
+    }
+}
+
+/// Offer from other peer waiting to be answered
+struct ReceivedOffer {
+    received_at: Instant,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    offer_id: OfferId,
+}
+
 struct Connection {
     config: Rc<Config>,
     load_test_state: LoadTestState,
     rng: Rc<RefCell<SmallRng>>,
     peer_id: PeerId,
-    can_send_answer: Option<(InfoHash, PeerId, OfferId)>,
+    offers_to_answer: VecDeque<ReceivedOffer>,
+    pending_offers: PendingOffers,
     stream: WebSocketStream<TlsStream<TcpStream>>,
+    opt_schedule: Option<RateSchedule>,
+    requests_scheduled: u64,
+    latencies: LocalLatencies,
 }
 
 impl Connection {
@@ -102,8 +213,14 @@ impl Connection {
         load_test_state: LoadTestState,
         num_active_connections: Rc<RefCell<usize>>,
         rng: Rc<RefCell<SmallRng>>,
+        latencies: LocalLatencies,
     ) -> anyhow::Result<()> {
         let peer_id = PeerId(rng.borrow_mut().gen());
+        let opt_peers = if config.lifecycle.active() {
+            Some(SimulatedPeers::new(&config, &mut *rng.borrow_mut())?)
+        } else {
+            None
+        };
         let stream = TcpStream::connect(config.server_address)
             .await
             .map_err(|err| anyhow::anyhow!("connect: {:?}", err))?;
@@ -119,19 +236,40 @@ impl Connection {
 
         let statistics = load_test_state.statistics.clone();
 
+        let opt_schedule = config.open_loop.active().then(|| {
+            RateSchedule::new(
+                &config.open_loop,
+                config.num_workers * config.num_connections_per_worker,
+            )
+        });
+        // Don't try to catch up on requests that would have been sent before
+        // connection was opened
+        let requests_scheduled = opt_schedule
+            .map(|schedule| schedule.requests_due(load_test_state.start_instant.elapsed()) as u64)
+            .unwrap_or(0);
+
         let mut connection = Connection {
             config,
             load_test_state,
             rng,
             stream,
             peer_id,
-            can_send_answer: None,
+            offers_to_answer: Default::default(),
+            pending_offers: Default::default(),
+            opt_schedule,
+            requests_scheduled,
+            latencies,
         };
 
         *num_active_connections.borrow_mut() += 1;
         statistics.connections.fetch_add(1, Ordering::Relaxed);
 
-        if let Err(err) = connection.run_connection_loop().await {
+        let result = match opt_peers {
+            Some(peers) => connection.run_lifecycle_loop(peers).await,
+            None => connection.run_connection_loop().await,
+        };
+
+        if let Err(err) = result {
             ::log::info!("connection error: {:#}", err);
         }
 
@@ -143,14 +281,113 @@ impl Connection {
 
     async fn run_connection_loop(&mut self) -> anyhow::Result<()> {
         loop {
-            self.send_message().await?;
-            self.read_message().await?;
+            if let Some(schedule) = self.opt_schedule {
+                let scheduled_at = self.load_test_state.start_instant
+                    + schedule.send_time(self.requests_scheduled);
+
+                self.requests_scheduled += 1;
+
+                let now = Instant::now();
+
+                if scheduled_at > now {
+                    glommio::timer::sleep(scheduled_at - now).await;
+                }
+
+                self.send_message().await?;
+                self.read_until_response().await?;
+
+                // Measure from scheduled time so that requests delayed by
+                // slow responses to previous ones are accounted for
+                let latency = scheduled_at.elapsed();
+
+                self.latencies
+                    .borrow_mut()
+                    .responses
+                    .record(latency.as_micros() as u64)
+                    .unwrap();
+            } else {
+                self.send_message().await?;
+                self.read_until_response().await?;
+            }
+        }
+    }
+
+    /// Send announce requests on behalf of simulated peers as their events
+    /// become due, and answer offers to their torrents in between
+    async fn run_lifecycle_loop(&mut self, mut peers: SimulatedPeers) -> anyhow::Result<()> {
+        loop {
+            let now = Instant::now();
+
+            self.expire_offers(now);
+
+            let opt_announce = peers.next_announce(
+                &self.config,
+                &self.load_test_state,
+                &mut *self.rng.borrow_mut(),
+                now,
+            );
+
+            if let Some(announce) = opt_announce {
+                let peer_index = announce.peer_index;
+                let request = self.create_lifecycle_request(announce, now);
+
+                self.send_request(request).await?;
+
+                if let OutMessage::AnnounceResponse(response) = self.read_until_response().await? {
+                    peers.population().handle_announce_response(
+                        peer_index,
+                        Duration::from_secs(response.announce_interval as u64),
+                        Instant::now(),
+                    );
+                }
+            } else if let Some(offer) = self.offers_to_answer.pop_front() {
+                // Offers to torrents of departed peers are ignored
+                if let Some((peer_id, bytes_left)) = peers.active_peer(&offer.info_hash) {
+                    let request = self.create_answer(offer, peer_id, bytes_left);
+
+                    self.send_request(request).await?;
+                    self.read_until_response().await?;
+                }
+            } else {
+                let wait = peers
+                    .population()
+                    .next_event_at()
+                    .saturating_duration_since(now)
+                    .clamp(Duration::from_millis(1), MAX_LIFECYCLE_WAIT);
+
+                // Keep handling offers and answers while waiting
+                let read = self.read_message();
+                let sleep = glommio::timer::sleep(wait);
+
+                futures::pin_mut!(read, sleep);
+
+                if let Either::Left((result, _)) = futures::future::select(read, sleep).await {
+                    result?;
+                }
+            }
+        }
+    }
+
+    /// Read messages until response to last request arrives
+    ///
+    /// Offers and answers from other peers may arrive before the response.
+    /// Handling them here too keeps them from piling up, which would delay
+    /// answers and make them look lost.
+    async fn read_until_response(&mut self) -> anyhow::Result<OutMessage> {
+        loop {
+            if let Some(response) = self.read_message().await? {
+                return Ok(response);
+            }
         }
     }
 
     async fn send_message(&mut self) -> anyhow::Result<()> {
         let request = self.create_request();
 
+        self.send_request(request).await
+    }
+
+    async fn send_request(&mut self, request: InMessage) -> anyhow::Result<()> {
         self.stream.send(request.to_ws_message()).await?;
 
         self.load_test_state
@@ -161,52 +398,55 @@ impl Connection {
         Ok(())
     }
 
+    /// Forget offers that are too old to be answered
+    fn expire_offers(&mut self, now: Instant) {
+        let max_offer_age = Duration::from_secs(self.config.torrents.max_offer_age);
+
+        // The tracker won't accept answers to offers that are too old, and
+        // answers to our own old offers are no longer tracked
+        if let Some(cutoff) = now.checked_sub(max_offer_age) {
+            self.pending_offers.expire(cutoff);
+
+            while let Some(offer) = self.offers_to_answer.front() {
+                if offer.received_at >= cutoff {
+                    break;
+                }
+
+                self.offers_to_answer.pop_front();
+            }
+        }
+    }
+
     fn create_request(&mut self) -> InMessage {
-        let mut rng = self.rng.borrow_mut();
+        let now = Instant::now();
+
+        self.expire_offers(now);
 
-        let request = match random_request_type(&self.config, &mut *rng) {
+        let request_type = random_request_type(&self.config, &mut *self.rng.borrow_mut());
+
+        match request_type {
             RequestType::Announce => {
                 let (event, bytes_left) = {
-                    if rng.gen_bool(self.config.torrents.peer_seeder_probability) {
+                    if self
+                        .rng
+                        .borrow_mut()
+                        .gen_bool(self.config.torrents.peer_seeder_probability)
+                    {
                         (AnnounceEvent::Completed, 0)
                     } else {
                         (AnnounceEvent::Started, 50)
                     }
                 };
 
-                const SDP: &str = "abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg";
-
-                if let Some((info_hash, peer_id, offer_id)) = self.can_send_answer {
-                    InMessage::AnnounceRequest(AnnounceRequest {
-                        info_hash,
-                        answer_to_peer_id: Some(peer_id),
-                        answer_offer_id: Some(offer_id),
-                        answer: Some(RtcAnswer {
-                            t: RtcAnswerType::Answer,
-                            sdp: SDP.into(),
-                        }),
-                        event: None,
-                        offers: None,
-                        action: AnnounceAction::Announce,
-                        peer_id: self.peer_id,
-                        bytes_left: Some(bytes_left),
-                        numwant: Some(0),
-                    })
+                if let Some(offer) = self.offers_to_answer.pop_front() {
+                    self.create_answer(offer, self.peer_id, bytes_left)
                 } else {
-                    let info_hash_index =
-                        select_info_hash_index(&self.config, &self.load_test_state, &mut *rng);
-
-                    let mut offers = Vec::with_capacity(self.config.torrents.offers_per_request);
-
-                    for _ in 0..self.config.torrents.offers_per_request {
-                        offers.push(AnnounceRequestOffer {
-                            offer_id: OfferId(rng.gen()),
-                            offer: RtcOffer {
-                                t: RtcOfferType::Offer,
-                                sdp: SDP.into(),
-                            },
-                        })
-                    }
+                    let info_hash_index = select_info_hash_index(
+                        &self.config,
+                        &self.load_test_state,
+                        &mut *self.rng.borrow_mut(),
+                    );
+                    let offers = self.create_offers(now);
 
                     InMessage::AnnounceRequest(AnnounceRequest {
                         action: AnnounceAction::Announce,
@@ -223,6 +463,7 @@ impl Connection {
                 }
             }
             RequestType::Scrape => {
+                let mut rng = self.rng.borrow_mut();
                 let mut scrape_hashes = Vec::with_capacity(5);
 
                 for _ in 0..5 {
@@ -237,14 +478,80 @@ impl Connection {
                     info_hashes: Some(ScrapeRequestInfoHashes::Multiple(scrape_hashes)),
                 })
             }
+        }
+    }
+
+    fn create_lifecycle_request(&mut self, announce: SimulatedAnnounce, now: Instant) -> InMessage {
+        // Departing peers don't need new connections
+        let offers = if announce.event == AnnounceEvent::Stopped {
+            Vec::new()
+        } else {
+            self.create_offers(now)
         };
 
-        self.can_send_answer = None;
+        InMessage::AnnounceRequest(AnnounceRequest {
+            action: AnnounceAction::Announce,
+            info_hash: announce.info_hash,
+            peer_id: announce.peer_id,
+            bytes_left: Some(announce.bytes_left),
+            event: Some(announce.event),
+            numwant: Some(offers.len()),
+            offers: Some(offers),
+            answer: None,
+            answer_to_peer_id: None,
+            answer_offer_id: None,
+        })
+    }
+
+    fn create_answer(&self, offer: ReceivedOffer, peer_id: PeerId, bytes_left: usize) -> InMessage {
+        self.load_test_state
+            .statistics
+            .answers_sent
+            .fetch_add(1, Ordering::Relaxed);
 
-        request
+        InMessage::AnnounceRequest(AnnounceRequest {
+            info_hash: offer.info_hash,
+            answer_to_peer_id: Some(offer.peer_id),
+            answer_offer_id: Some(offer.offer_id),
+            answer: Some(RtcAnswer {
+                t: RtcAnswerType::Answer,
+                sdp: SDP.into(),
+            }),
+            event: None,
+            offers: None,
+            action: AnnounceAction::Announce,
+            peer_id,
+            bytes_left: Some(bytes_left),
+            numwant: Some(0),
+        })
     }
 
-    async fn read_message(&mut self) -> anyhow::Result<()> {
+    fn create_offers(&mut self, now: Instant) -> Vec<AnnounceRequestOffer> {
+        let mut rng = self.rng.borrow_mut();
+        let mut offers = Vec::with_capacity(self.config.torrents.offers_per_request);
+
+        for _ in 0..self.config.torrents.offers_per_request {
+            let offer_id = OfferId(rng.gen());
+
+            self.pending_offers.insert(offer_id, now);
+
+            offers.push(AnnounceRequestOffer {
+                offer_id,
+                offer: RtcOffer {
+                    t: RtcOfferType::Offer,
+                    sdp: SDP.into(),
+                },
+            })
+        }
+
+        offers
+    }
+
+    /// Read a message and update statistics
+    ///
+    /// Returns message if it was a response to a request sent on this
+    /// connection
+    async fn read_message(&mut self) -> anyhow::Result<Option<OutMessage>> {
         let message = match self
             .stream
             .next()
@@ -260,36 +567,66 @@ impl Connection {
                     message
                 );
 
-                return Ok(());
+                return Ok(None);
             }
         };
 
-        match OutMessage::from_ws_message(message) {
+        let opt_response = match OutMessage::from_ws_message(message) {
             Ok(OutMessage::OfferOutMessage(offer)) => {
                 self.load_test_state
                     .statistics
                     .responses_offer
                     .fetch_add(1, Ordering::Relaxed);
 
-                self.can_send_answer = Some((offer.info_hash, offer.peer_id, offer.offer_id));
+                self.offers_to_answer.push_back(ReceivedOffer {
+                    received_at: Instant::now(),
+                    info_hash: offer.info_hash,
+                    peer_id: offer.peer_id,
+                    offer_id: offer.offer_id,
+                });
+
+                None
             }
-            Ok(OutMessage::AnswerOutMessage(_)) => {
+            Ok(OutMessage::AnswerOutMessage(answer)) => {
                 self.load_test_state
                     .statistics
                     .responses_answer
                     .fetch_add(1, Ordering::Relaxed);
+
+                if let Some(sent_at) = self.pending_offers.remove(&answer.offer_id) {
+                    self.load_test_state
+                        .statistics
+                        .answers_delivered
+                        .fetch_add(1, Ordering::Relaxed);
+
+                    let latency = sent_at.elapsed();
+
+                    self.latencies
+                        .borrow_mut()
+                        .answers
+                        .record(latency.as_micros() as u64)
+                        .unwrap();
+                } else {
+                    ::log::warn!("received answer to unknown offer: {:?}", answer.offer_id);
+                }
+
+                None
             }
-            Ok(OutMessage::AnnounceResponse(_)) => {
+            Ok(response @ OutMessage::AnnounceResponse(_)) => {
                 self.load_test_state
                     .statistics
                     .responses_announce
                     .fetch_add(1, Ordering::Relaxed);
+
+                Some(response)
             }
-            Ok(OutMessage::ScrapeResponse(_)) => {
+            Ok(response @ OutMessage::ScrapeResponse(_)) => {
                 self.load_test_state
                     .statistics
                     .responses_scrape
                     .fetch_add(1, Ordering::Relaxed);
+
+                Some(response)
             }
             Ok(OutMessage::ErrorResponse(response)) => {
                 self.load_test_state
@@ -298,13 +635,17 @@ impl Connection {
                     .fetch_add(1, Ordering::Relaxed);
 
                 ::log::warn!("received error response: {:?}", response.failure_reason);
+
+                Some(OutMessage::ErrorResponse(response))
             }
             Err(err) => {
                 ::log::error!("error deserializing message: {:#}", err);
+
+                None
             }
-        }
+        };
 
-        Ok(())
+        Ok(opt_response)
     }
 }
 
@@ -320,3 +661,34 @@ pub fn random_request_type(config: &Config, rng: &mut impl Rng) -> RequestType {
 
     items[dist.sample(rng)]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_offers_expire() {
+        let start = Instant::now();
+        let offer_ids = [OfferId([0; 20]), OfferId([1; 20]), OfferId([2; 20])];
+
+        let mut pending_offers = PendingOffers::default();
+
+        for (i, offer_id) in offer_ids.iter().enumerate() {
+            pending_offers.insert(*offer_id, start + Duration::from_secs(i as u64));
+        }
+
+        assert_eq!(
+            pending_offers.remove(&offer_ids[1]),
+            Some(start + Duration::from_secs(1))
+        );
+
+        pending_offers.expire(start + Duration::from_secs(2));
+
+        assert_eq!(pending_offers.remove(&offer_ids[0]), None);
+        assert_eq!(
+            pending_offers.remove(&offer_ids[2]),
+            Some(start + Duration::from_secs(2))
+        );
+        assert!(pending_offers.sent_at.is_empty());
+    }
+}